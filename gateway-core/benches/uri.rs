@@ -0,0 +1,44 @@
+//! Cost of building the upstream URI of a request from the prefix of its api, compared with the
+//! `format!` it replaced.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use gateway_core::api::{ApiDefinition, ApiDefinitionSpec};
+use serde_json::json;
+
+fn bench_api() -> ApiDefinition {
+    let spec: ApiDefinitionSpec = serde_json::from_value(json!({
+        "app_name": "/tiles",
+        "host": "tiles.default.svc.cluster.local:8080",
+        "forward_path": "/api/v2",
+        "mode": { "kind": "forward_all" },
+    }))
+    .unwrap();
+    let mut api = ApiDefinition::new("tiles", spec);
+    api.build_uri();
+    api
+}
+
+fn bench_http_uri(c: &mut Criterion) {
+    let api = bench_api();
+    let forwarded_uri = "/layers/roads/12/2048/1361.pbf?style=night&lang=fr";
+
+    let mut group = c.benchmark_group("http_uri");
+    group.bench_function("format", |b| {
+        b.iter(|| {
+            format!(
+                "{}{}",
+                black_box(&api.spec.uri_http),
+                black_box(forwarded_uri)
+            )
+        })
+    });
+    group.bench_function("join_uri", |b| {
+        b.iter(|| black_box(&api).http_uri(black_box(forwarded_uri)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_http_uri);
+criterion_main!(benches);
@@ -0,0 +1,129 @@
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use hyper::Request;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::log_sink::ACCESS_LOG;
+use crate::redact::redact;
+use crate::runtime_config::runtime_config;
+
+/// Fields of an access log record, which can be selected with `access_log.fields`.
+pub const ACCESS_LOG_FIELDS: [&str; 17] = [
+    "timestamp",
+    "method",
+    "path",
+    "uri",
+    "status_code",
+    "app",
+    "user_sub",
+    "token_id",
+    "tenant",
+    "perm",
+    "upstream_uri",
+    "error",
+    "upstream_duration_ms",
+    "duration_ms",
+    "tunnel_id",
+    "client_ip",
+    "dry_run_denied",
+];
+
+/// A single record describing a proxied request, filled along its handling then emitted once as
+/// JSON when its response is known.
+#[derive(Debug, Default, Serialize)]
+pub struct AccessLog {
+    pub timestamp: String,
+    pub method: String,
+    pub path: String,
+    pub uri: String,
+    pub status_code: u16,
+    pub app: Option<String>,
+    pub user_sub: Option<String>,
+    pub token_id: Option<String>,
+    pub tenant: Option<String>,
+    pub perm: Option<String>,
+    pub upstream_uri: Option<String>,
+    pub error: Option<String>,
+    pub upstream_duration_ms: Option<u128>,
+    pub duration_ms: u128,
+    pub tunnel_id: Option<u64>,
+    pub client_ip: Option<IpAddr>,
+    /// Set if the permission was missing but the request forwarded by a dry run.
+    pub dry_run_denied: Option<bool>,
+}
+
+impl AccessLog {
+    pub fn new<B>(req: &Request<B>) -> Self {
+        Self {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            uri: redact(&req.uri().to_string()).into_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Describe the request as `req`, such as the original request of a forward-auth request.
+    pub fn set_request<B>(&mut self, req: &Request<B>) {
+        self.method = req.method().to_string();
+        self.path = req.uri().path().to_string();
+        self.uri = redact(&req.uri().to_string()).into_owned();
+    }
+
+    /// Set the error of the request, its secrets being redacted.
+    pub fn set_error(&mut self, error: impl Into<String>) {
+        self.error = Some(redact(&error.into()).into_owned());
+    }
+
+    /// Set the URI the request is forwarded to, its secrets being redacted.
+    pub fn set_upstream_uri(&mut self, uri: &str) {
+        self.upstream_uri = Some(redact(uri).into_owned());
+    }
+
+    fn to_json(&self) -> String {
+        let mut record = match serde_json::to_value(self) {
+            Ok(Value::Object(record)) => record,
+            _ => return String::new(),
+        };
+
+        if let Some(fields) = &runtime_config().access_log.fields {
+            record.retain(|key, _| fields.contains(key));
+        }
+
+        Value::Object(record).to_string()
+    }
+
+    /// Log the record to the configured sink, as a warning if the request ended with an error.
+    pub fn emit(&self) {
+        let is_error = self.error.is_some();
+        let Some(line) = ACCESS_LOG.send(is_error, self.to_json()) else {
+            return;
+        };
+
+        if is_error {
+            warn!(target: "access_log", "{line}");
+        } else {
+            info!(target: "access_log", "{line}");
+        }
+    }
+}
+
+/// The access log of a request, shared through its extensions by the layers handling it.
+#[derive(Clone, Default)]
+pub struct SharedAccessLog(Arc<Mutex<AccessLog>>);
+
+impl SharedAccessLog {
+    pub fn new(access_log: AccessLog) -> Self {
+        Self(Arc::new(Mutex::new(access_log)))
+    }
+
+    /// Lock the record, the guard must not be held across an await point.
+    pub fn lock(&self) -> MutexGuard<'_, AccessLog> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
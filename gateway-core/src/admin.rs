@@ -0,0 +1,666 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use humantime::{format_duration, parse_duration};
+use hyper::body::Incoming;
+use hyper::header::{
+    HeaderName, ACCEPT, ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION, CONTENT_ENCODING,
+    CONTENT_TYPE, VARY,
+};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use ipnet::IpNet;
+use prometheus::{Encoder, TextEncoder};
+use ring::constant_time::verify_slices_are_equal;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use url::form_urlencoded;
+
+use crate::api::ApiMode;
+use crate::client_ip::get_client_ip;
+use crate::fetch_crd::{persist_maintenance, refresh_apis, set_maintenance, ApiLock};
+use crate::fetch_dir::refresh_api_dir;
+use crate::log_level::{get_log_filter, set_log_filter};
+use crate::middleware::GatewayState;
+use crate::openmetrics::OpenMetricsEncoder;
+use crate::permission::{has_perm, refresh_perm};
+use crate::redact::redact_settings;
+use crate::runtime_config::{get_config_path, get_profile, runtime_config};
+use crate::websocket::set_tunnels_draining;
+use crate::{into_boxed_response, serve, BoxResponse, FORBIDDEN, NOT_FOUND, OK};
+
+const UNAUTHORIZED: &[u8] = b"Unauthorized";
+const DRAINING: &[u8] = b"Draining";
+const PAYLOAD_TOO_LARGE: &[u8] = b"Payload Too Large";
+
+/// Bytes of the body of an admin request, larger ones being answered with `413`.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Set by `POST /admin/drain`, failing the health check until `DELETE /admin/drain`.
+static IS_DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Timer closing the websocket tunnels at the `deadline` of the current drain.
+static DRAIN_DEADLINE: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Whether the instance is taken out of rotation, its connections being closed after their
+/// current response.
+pub fn is_draining() -> bool {
+    IS_DRAINING.load(Ordering::Relaxed)
+}
+
+fn get_status_response(status_code: StatusCode, content: &'static [u8]) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status_code)
+        .body(content.into())
+        .unwrap()
+}
+
+/// Check the source and bearer token of a request to the `endpoints` named in the logs,
+/// returning the response to send if access is denied.
+fn check_access<B>(
+    req: &Request<B>,
+    client_ip: IpAddr,
+    allowed_sources: &[IpNet],
+    bearer_token: Option<&str>,
+    endpoints: &str,
+) -> Option<Response<Full<Bytes>>> {
+    if !allowed_sources.is_empty()
+        && !allowed_sources
+            .iter()
+            .any(|source| source.contains(&client_ip))
+    {
+        info!("event='{endpoints} access denied to {client_ip}'");
+        return Some(get_status_response(StatusCode::FORBIDDEN, FORBIDDEN));
+    }
+
+    if let Some(bearer_token) = bearer_token {
+        let authorized = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            // Compared in constant time not to leak how much of the token a guess got right.
+            .is_some_and(|token| {
+                verify_slices_are_equal(token.as_bytes(), bearer_token.as_bytes()).is_ok()
+            });
+        if !authorized {
+            info!("event='{endpoints} access denied to {client_ip}: invalid bearer token'");
+            return Some(get_status_response(StatusCode::UNAUTHORIZED, UNAUTHORIZED));
+        }
+    }
+
+    None
+}
+
+/// Check the `metrics_auth` policy, returning the response to send if access is denied.
+fn check_metrics_access<B>(req: &Request<B>, client_ip: IpAddr) -> Option<Response<Full<Bytes>>> {
+    let runtime_config = runtime_config();
+    let config = &runtime_config.metrics_auth;
+    check_access(
+        req,
+        client_ip,
+        &config.allowed_sources,
+        config.bearer_token.as_deref(),
+        "Metrics",
+    )
+}
+
+/// Check the `admin_auth` policy, returning the response to send if access is denied.
+fn check_admin_access<B>(req: &Request<B>, client_ip: IpAddr) -> Option<Response<Full<Bytes>>> {
+    let runtime_config = runtime_config();
+    let config = &runtime_config.admin_auth;
+    check_access(
+        req,
+        client_ip,
+        &config.allowed_sources,
+        config.bearer_token.as_deref(),
+        "Admin",
+    )
+}
+
+fn accepts(headers: &HeaderMap, header: HeaderName, value: &str) -> bool {
+    headers
+        .get_all(header)
+        .iter()
+        .filter_map(|header_value| header_value.to_str().ok())
+        .any(|header_value| header_value.contains(value))
+}
+
+/// Encode the metrics in the OpenMetrics format if the scraper accepts it (the Prometheus text
+/// format otherwise), gzip-compressed if it accepts it too.
+async fn metrics(headers: &HeaderMap) -> Result<Response<Full<Bytes>>> {
+    let metric_families = prometheus::gather();
+    let mut buffer = vec![];
+    let format_type = if accepts(headers, ACCEPT, "application/openmetrics-text") {
+        let encoder = OpenMetricsEncoder;
+        encoder.encode(&metric_families, &mut buffer)?;
+        encoder.format_type().to_string()
+    } else {
+        let encoder = TextEncoder::new();
+        encoder.encode(&metric_families, &mut buffer)?;
+        encoder.format_type().to_string()
+    };
+
+    let mut response = Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, format_type)
+        .header(VARY, "Accept, Accept-Encoding");
+
+    if accepts(headers, ACCEPT_ENCODING, "gzip") {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&buffer)?;
+        buffer = encoder.finish()?;
+        response = response.header(CONTENT_ENCODING, "gzip");
+    }
+
+    Ok(response.body(buffer.into())?)
+}
+
+async fn health() -> Result<Response<Full<Bytes>>> {
+    if is_draining() {
+        return Ok(get_status_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            DRAINING,
+        ));
+    }
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(ACCESS_CONTROL_ALLOW_HEADERS, "*")
+        .header(ACCESS_CONTROL_ALLOW_METHODS, "*")
+        .body(OK.into())
+        .unwrap())
+}
+
+/// Serve the internal endpoints (`/metrics` and `/health`) if `req` targets one of them, `None`
+/// meaning the request should be proxied. On the public listener, `/metrics` is hidden when
+/// `metrics_auth.admin_listener_only` is set.
+pub async fn internal_response<B>(
+    req: &Request<B>,
+    client_ip: IpAddr,
+    is_admin_listener: bool,
+) -> Option<Result<BoxResponse<Bytes>>> {
+    match req.uri().path() {
+        "/metrics" => {
+            debug!("event='Metrics endpoint'");
+            if !is_admin_listener && runtime_config().metrics_auth.admin_listener_only {
+                return Some(Ok(into_boxed_response(get_status_response(
+                    StatusCode::NOT_FOUND,
+                    NOT_FOUND,
+                ))));
+            }
+            if let Some(denied) = check_metrics_access(req, client_ip) {
+                return Some(Ok(into_boxed_response(denied)));
+            }
+            Some(metrics(req.headers()).await.map(into_boxed_response))
+        }
+        "/health" => {
+            debug!("event='Health endpoint'");
+            Some(health().await.map(into_boxed_response))
+        }
+        _ => None,
+    }
+}
+
+async fn get_log_level() -> Result<Response<Full<Bytes>>> {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(format!("{}\n", get_log_filter()).into())?)
+}
+
+/// Replace the log filter with the directives in the request body.
+/// Read the body of an admin request, `None` meaning it is larger than `MAX_BODY_SIZE`.
+async fn read_body(req: Request<Incoming>) -> Result<Option<Bytes>> {
+    match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+        Ok(collected) => Ok(Some(collected.to_bytes())),
+        Err(e) if e.is::<LengthLimitError>() => Ok(None),
+        Err(e) => Err(anyhow!(e)),
+    }
+}
+
+async fn put_log_level(req: Request<Incoming>) -> Result<Response<Full<Bytes>>> {
+    let Some(body) = read_body(req).await? else {
+        return Ok(get_status_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            PAYLOAD_TOO_LARGE,
+        ));
+    };
+    let directives = String::from_utf8_lossy(&body);
+
+    match set_log_filter(&directives) {
+        Ok(()) => {
+            warn!("event='Log filter set to {}'", directives.trim());
+            get_log_level().await
+        }
+        Err(e) => Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(format!("{e}\n").into())?),
+    }
+}
+
+/// Describe the apis currently served, by app name.
+async fn get_apis(api_lock: &ApiLock) -> Result<Response<Full<Bytes>>> {
+    let apis = api_lock.load();
+    let mut apis: Vec<_> = apis.values().map(|(api, _)| api).collect();
+    apis.sort_by(|a, b| a.spec.app_name.cmp(&b.spec.app_name));
+
+    let apis: Vec<_> = apis
+        .into_iter()
+        .map(|api| {
+            let (mode, endpoints) = match &api.spec.mode {
+                ApiMode::ForwardAll => ("forward_all", None),
+                ApiMode::ForwardStrict(endpoints) => ("forward_strict", Some(endpoints.len())),
+            };
+            json!({
+                "name": api.metadata.name,
+                "namespace": api.metadata.namespace,
+                "app_name": api.spec.app_name,
+                "mode": mode,
+                "endpoints": endpoints,
+                "maintenance": api.in_maintenance(),
+                "upstream": api.spec.uri_http,
+                "enabled": api.spec.enabled,
+                "loaded_at": api
+                    .spec
+                    .loaded_at
+                    .map(|loaded_at| humantime::format_rfc3339_millis(loaded_at).to_string()),
+            })
+        })
+        .collect();
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&apis)?.into())?)
+}
+
+/// Replay the permission decision of a request of the `user` query parameter (a `token_id`) to
+/// `app`, with the `method` and the `path` forwarded to the api.
+async fn check_authz(
+    req: &Request<Incoming>,
+    state: &GatewayState,
+) -> Result<Response<Full<Bytes>>> {
+    let query: HashMap<String, String> =
+        form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+            .into_owned()
+            .collect();
+    let (Some(user), Some(app), Some(method), Some(path)) = (
+        query.get("user"),
+        query.get("app"),
+        query.get("method"),
+        query.get("path"),
+    ) else {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("The user, app, method and path parameters are required\n".into())?);
+    };
+
+    let perm_index = state.perm_lock.load();
+    let roles = state.role_lock.load();
+    let mut check = json!({
+        "user": user,
+        "app": app,
+        "method": method,
+        "path": path,
+        "user_roles": app
+            .get(1..)
+            .and_then(|app_name| roles.get(user)?.get(app_name)),
+        "user_permissions": perm_index
+            .permissions_of(user)
+            .into_iter()
+            .filter(|permission| {
+                app.get(1..).is_some_and(|app_name| {
+                    permission
+                        .strip_prefix(app_name)
+                        .is_some_and(|rest| rest.starts_with("::"))
+                })
+            })
+            .collect::<Vec<_>>(),
+    });
+
+    let apis = state.api_lock.load();
+    let (decision, reason) = match apis.get(app.as_str()) {
+        None => ("not_found", "No api with this app name".to_string()),
+        Some((api, _)) if !api.spec.enabled => ("disabled", "Api disabled".to_string()),
+        Some((api, node)) => {
+            let endpoint = match api.spec.mode {
+                ApiMode::ForwardAll => Some(node.forward_all(method, app)),
+                ApiMode::ForwardStrict(_) => node.match_path(path, method).cloned(),
+            };
+            match endpoint {
+                None => ("not_found", "Endpoint not found in service".to_string()),
+                Some(endpoint) => {
+                    check["endpoint"] = json!({
+                        "method": endpoint.method,
+                        "path": endpoint.path,
+                        "is_websocket": endpoint.is_websocket,
+                    });
+                    check["permission"] = json!(endpoint.permission);
+                    let dry_run =
+                        api.spec.dry_run_permissions || runtime_config().dry_run_permissions;
+                    if !endpoint.check_permission {
+                        (
+                            "allow",
+                            "The endpoint does not check permissions".to_string(),
+                        )
+                    } else if has_perm(&state.perm_lock, endpoint.permission_id, user) {
+                        (
+                            "allow",
+                            format!("{user} is granted {}", endpoint.permission),
+                        )
+                    } else if dry_run {
+                        (
+                            "dry_run",
+                            format!(
+                                "{user} is not granted {}, forwarded by dry run",
+                                endpoint.permission
+                            ),
+                        )
+                    } else {
+                        (
+                            "deny",
+                            format!("{user} is not granted {}", endpoint.permission),
+                        )
+                    }
+                }
+            }
+        }
+    };
+    check["decision"] = json!(decision);
+    check["reason"] = json!(reason);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&check)?.into())?)
+}
+
+/// Fail the health check and close connections after their current response, then close the
+/// websocket tunnels after the `deadline` query parameter, right away without it.
+async fn drain(req: &Request<Incoming>) -> Result<Response<Full<Bytes>>> {
+    let query: HashMap<String, String> =
+        form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+            .into_owned()
+            .collect();
+    let deadline = match query
+        .get("deadline")
+        .map(|deadline| parse_duration(deadline))
+    {
+        None => Duration::ZERO,
+        Some(Ok(deadline)) => deadline,
+        Some(Err(e)) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(format!("Invalid deadline: {e}\n").into())?);
+        }
+    };
+
+    if !IS_DRAINING.swap(true, Ordering::Relaxed) {
+        warn!("event='Draining, the health check fails from now on'");
+    }
+    warn!(
+        "event='Websocket tunnels close in {}'",
+        format_duration(deadline)
+    );
+    // A later drain replaces the deadline of the previous one.
+    let timer = tokio::spawn(async move {
+        sleep(deadline).await;
+        set_tunnels_draining(true);
+    });
+    if let Some(previous) = DRAIN_DEADLINE.lock().unwrap().replace(timer) {
+        previous.abort();
+    }
+
+    Ok(get_status_response(StatusCode::ACCEPTED, DRAINING))
+}
+
+/// Cancel a drain: the health check succeeds again, and the websocket tunnels still open are
+/// kept, as are those opened from now on.
+async fn undrain() -> Result<Response<Full<Bytes>>> {
+    if let Some(timer) = DRAIN_DEADLINE.lock().unwrap().take() {
+        timer.abort();
+    }
+    set_tunnels_draining(false);
+    if IS_DRAINING.swap(false, Ordering::Relaxed) {
+        warn!("event='Drain cancelled, the health check succeeds again'");
+    }
+
+    Ok(get_status_response(StatusCode::OK, OK))
+}
+
+/// The runtime config in use with its secrets redacted, and the state derived from it.
+async fn get_config(state: &GatewayState) -> Result<Response<Full<Bytes>>> {
+    let runtime_config = runtime_config();
+    let mut settings = serde_json::to_value(&runtime_config.settings)?;
+    redact_settings(&mut settings);
+    let limits = &runtime_config.request_limits;
+
+    let config = json!({
+        "path": get_config_path(),
+        "profile": get_profile(),
+        "settings": settings,
+        "derived": {
+            "auth_sources": runtime_config.auth_sources.len(),
+            "perm_uris": runtime_config.perm_uris.len(),
+            "apis": state.api_lock.load().len(),
+            "workers": runtime_config.workers,
+            "request_limits": {
+                "max_uri_length": limits.max_uri_length,
+                "max_header_size": limits.max_header_size,
+                "max_header_bytes": limits.max_header_bytes,
+                "max_headers": limits.max_headers,
+            },
+            "perm_update_delay": format_duration(runtime_config.perm_update_delay).to_string(),
+            "shutdown_grace_period": format_duration(runtime_config.shutdown_grace_period)
+                .to_string(),
+            "dry_run_permissions": runtime_config.dry_run_permissions,
+            "draining": is_draining(),
+        },
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&config)?.into())?)
+}
+
+/// Fetch the permissions now, outside of the `perm_update_delay` loop.
+async fn post_refresh_permissions(state: &GatewayState) -> Result<Response<Full<Bytes>>> {
+    match refresh_perm(&state.perm_lock, &state.role_lock).await {
+        Ok(()) => {
+            warn!("event='Permissions refreshed on demand'");
+            Ok(get_status_response(StatusCode::OK, OK))
+        }
+        Err(e) => {
+            error!("event='Could not refresh permissions: {e}'");
+            Ok(Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(format!("Could not fetch permissions: {e}\n").into())?)
+        }
+    }
+}
+
+/// List the `ApiDefinition`s of the cluster, or read the files of `api_dir`, now.
+async fn post_refresh_apis(state: &GatewayState) -> Result<Response<Full<Bytes>>> {
+    let runtime_config = runtime_config();
+    let refreshed = match &runtime_config.api_dir {
+        Some(api_dir) => refresh_api_dir(&state.api_lock, api_dir),
+        None => {
+            refresh_apis(
+                &state.api_lock,
+                &runtime_config.crd_label,
+                runtime_config.crds_namespaces.as_deref(),
+            )
+            .await
+        }
+    };
+
+    match refreshed {
+        Ok(count) => {
+            warn!("event='Apis refreshed on demand'");
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .body(format!("{count} apis refreshed\n").into())?)
+        }
+        Err(e) => {
+            error!("event='Could not refresh apis: {e}'");
+            Ok(Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(format!("Could not refresh apis: {e}\n").into())?)
+        }
+    }
+}
+
+/// Turn the maintenance mode of the api of `app` on or off with a `true` or `false` body,
+/// persisting it as an annotation of the `ApiDefinition` when watched in the cluster.
+async fn put_maintenance(
+    req: Request<Incoming>,
+    app: &str,
+    state: &GatewayState,
+) -> Result<Response<Full<Bytes>>> {
+    let Some(body) = read_body(req).await? else {
+        return Ok(get_status_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            PAYLOAD_TOO_LARGE,
+        ));
+    };
+    let on = match String::from_utf8_lossy(&body).trim() {
+        "true" => true,
+        "false" => false,
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("The body must be true or false\n".into())?);
+        }
+    };
+
+    let app_name = format!("/{app}");
+    let Some(api) = set_maintenance(&state.api_lock, &app_name, on) else {
+        return Ok(get_status_response(StatusCode::NOT_FOUND, NOT_FOUND));
+    };
+    warn!("event='Maintenance of {app_name} set to {on}'");
+
+    let persisted = match &runtime_config().api_dir {
+        Some(_) => Err(anyhow!("apidefinitions are read from api_dir")),
+        None => persist_maintenance(&api, on).await,
+    };
+    if let Err(e) = &persisted {
+        warn!("event='Maintenance of {app_name} not persisted: {e}'");
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(
+            serde_json::to_vec(&json!({
+                "app_name": app_name,
+                "maintenance": on,
+                "persisted": persisted.is_ok(),
+            }))?
+            .into(),
+        )?)
+}
+
+async fn admin_response(
+    req: Request<Incoming>,
+    remote_addr: SocketAddr,
+    state: GatewayState,
+) -> Result<BoxResponse<Bytes>> {
+    let client_ip = get_client_ip(req.headers(), remote_addr.ip());
+    if req.uri().path().starts_with("/admin/") {
+        if let Some(denied) = check_admin_access(&req, client_ip) {
+            return Ok(into_boxed_response(denied));
+        }
+    }
+
+    if req.method() == Method::PUT {
+        let app = req
+            .uri()
+            .path()
+            .strip_prefix("/admin/apis/")
+            .and_then(|path| path.strip_suffix("/maintenance"))
+            .map(str::to_string);
+        if let Some(app) = app {
+            return put_maintenance(req, &app, &state)
+                .await
+                .map(into_boxed_response);
+        }
+    }
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/admin/config") => {
+            return get_config(&state).await.map(into_boxed_response);
+        }
+        (&Method::GET, "/admin/apis") => {
+            return get_apis(&state.api_lock).await.map(into_boxed_response);
+        }
+        (&Method::POST, "/admin/refresh/permissions") => {
+            return post_refresh_permissions(&state)
+                .await
+                .map(into_boxed_response);
+        }
+        (&Method::POST, "/admin/refresh/apis") => {
+            return post_refresh_apis(&state).await.map(into_boxed_response);
+        }
+        (&Method::POST, "/admin/drain") => {
+            return drain(&req).await.map(into_boxed_response);
+        }
+        (&Method::DELETE, "/admin/drain") => {
+            return undrain().await.map(into_boxed_response);
+        }
+        (&Method::GET, "/admin/authz/check") => {
+            return check_authz(&req, &state).await.map(into_boxed_response);
+        }
+        (&Method::GET, "/admin/loglevel") => {
+            return get_log_level().await.map(into_boxed_response);
+        }
+        (&Method::PUT, "/admin/loglevel") => {
+            return put_log_level(req).await.map(into_boxed_response);
+        }
+        _ => (),
+    }
+
+    match internal_response(&req, client_ip, true).await {
+        Some(response) => response,
+        None => Ok(into_boxed_response(get_status_response(
+            StatusCode::NOT_FOUND,
+            NOT_FOUND,
+        ))),
+    }
+}
+
+/// Serve the internal endpoints, and the `/admin` ones, on `admin_bind_to` if it is configured.
+pub async fn run_admin_listener(state: GatewayState) -> Result<()> {
+    let Some(admin_bind_to) = runtime_config().admin_bind_to.clone() else {
+        return Ok(());
+    };
+
+    let addr: SocketAddr = admin_bind_to
+        .parse()
+        .map_err(|_| anyhow!("Address admin_bind_to is not valid"))?;
+    let listener = TcpListener::bind(&addr)
+        .await
+        .map_err(|err| anyhow!("Could not listen on {addr}: {err}"))?;
+
+    info!("event='Admin listening on http://{}'", addr);
+    let admin_auth = &runtime_config().admin_auth;
+    if admin_auth.bearer_token.is_none() && admin_auth.allowed_sources.is_empty() {
+        warn!("event='The /admin endpoints are open to any client of {addr}, set `admin_auth`'");
+    }
+
+    serve(listener, "admin", move |req, remote_addr| {
+        admin_response(req, remote_addr, state.clone())
+    })
+    .await
+}
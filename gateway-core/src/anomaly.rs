@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::access_log::AccessLog;
+use crate::audit::audit_block;
+use crate::metrics::commit_client_block;
+use crate::runtime_config::runtime_config;
+
+/// Source of requests whose responses are tracked, blocked on its own.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Client {
+    Token(String),
+    Ip(IpAddr),
+}
+
+impl Client {
+    /// Label of the client in `http_client_blocks_total`.
+    fn kind(&self) -> &'static str {
+        match self {
+            Client::Token(_) => "token",
+            Client::Ip(_) => "ip",
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Client::Token(token_id) => write!(f, "token {token_id}"),
+            Client::Ip(ip) => write!(f, "IP {ip}"),
+        }
+    }
+}
+
+/// Responses of a client over its current window, and the end of its block.
+struct Tracker {
+    window_start: Instant,
+    requests: u64,
+    errors: u64,
+    blocked_until: Option<Instant>,
+}
+
+static CLIENTS: LazyLock<Mutex<HashMap<Client, Tracker>>> = LazyLock::new(Default::default);
+
+/// Remaining duration of the block of a client, if it is blocked.
+pub(crate) fn blocked_for(client: &Client) -> Option<Duration> {
+    runtime_config().anomaly_detection.as_ref()?;
+
+    let now = Instant::now();
+    CLIENTS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(client)?
+        .blocked_until
+        .filter(|blocked_until| *blocked_until > now)
+        .map(|blocked_until| blocked_until - now)
+}
+
+/// Count a response of a client, blocking it once it gets too many errors in its window.
+fn record(client: Client, is_error: bool) -> Option<String> {
+    let runtime_config = runtime_config();
+    let config = runtime_config.anomaly_detection.as_ref()?;
+    let now = Instant::now();
+
+    let mut clients = CLIENTS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if clients.len() >= config.max_clients && !clients.contains_key(&client) {
+        clients.retain(|_, tracker| {
+            now.duration_since(tracker.window_start) < config.window
+                || tracker.blocked_until.is_some_and(|until| until > now)
+        });
+        if clients.len() >= config.max_clients {
+            return None;
+        }
+    }
+
+    let tracker = clients.entry(client).or_insert(Tracker {
+        window_start: now,
+        requests: 0,
+        errors: 0,
+        blocked_until: None,
+    });
+    if tracker.blocked_until.is_some_and(|until| until > now) {
+        return None;
+    }
+    if now.duration_since(tracker.window_start) >= config.window {
+        tracker.window_start = now;
+        tracker.requests = 0;
+        tracker.errors = 0;
+    }
+    tracker.requests += 1;
+    tracker.errors += u64::from(is_error);
+
+    let ratio = tracker.errors as f64 / tracker.requests as f64;
+    if tracker.requests < config.min_requests || ratio < config.max_error_ratio {
+        return None;
+    }
+    let reason = format!(
+        "{} errors in {} requests, blocked for {}s",
+        tracker.errors,
+        tracker.requests,
+        config.block_duration.as_secs()
+    );
+    tracker.blocked_until = Some(now + config.block_duration);
+    tracker.requests = 0;
+    tracker.errors = 0;
+    Some(reason)
+}
+
+/// Count the response of a request for its source IP and its token, blocking them when they get
+/// too many errors.
+pub(crate) fn track_response(access_log: &AccessLog, client_ip: IpAddr) {
+    let is_error = {
+        let runtime_config = runtime_config();
+        let Some(config) = &runtime_config.anomaly_detection else {
+            return;
+        };
+        if config
+            .exempt_sources
+            .iter()
+            .any(|source| source.contains(&client_ip))
+        {
+            return;
+        }
+        config.error_statuses.contains(&access_log.status_code)
+    };
+
+    let mut clients = vec![Client::Ip(client_ip)];
+    if let Some(token_id) = &access_log.token_id {
+        clients.push(Client::Token(token_id.clone()));
+    }
+    for client in clients {
+        let kind = client.kind();
+        let description = client.to_string();
+        if let Some(reason) = record(client, is_error) {
+            warn!("event='Blocking {description}: {reason}'");
+            commit_client_block(kind);
+            audit_block(access_log, client_ip, &format!("{description}: {reason}"));
+        }
+    }
+}
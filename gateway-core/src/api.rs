@@ -0,0 +1,561 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use hyper::header::HeaderName;
+use hyper::StatusCode;
+use ipnet::IpNet;
+use kube::core::DynamicObject;
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::auth::check_claim_template;
+use crate::endpoint::Endpoint;
+use crate::ext_authz::ExtAuthzSpec;
+use crate::message_filter::MessageFilterSpec;
+use crate::metrics::Direction;
+use crate::openapi::{load_openapi, OpenApiSpec};
+use crate::predicate::check_predicate;
+use crate::quota::QuotaSpec;
+use crate::script::{check_script, ScriptSpec};
+use crate::security_headers::SecurityHeadersSpec;
+use crate::token_binding::TokenBindingSpec;
+use crate::transform::{RequestTransformSpec, ResponseTransformSpec};
+use crate::wasm_filter::{load_wasm_filter, WasmFilterSpec};
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+#[serde(rename_all(deserialize = "snake_case"))]
+#[serde(tag = "kind", content = "endpoints")]
+pub enum ApiMode {
+    ForwardAll,
+    ForwardStrict(Vec<Endpoint>),
+}
+
+/// Close tunnels whose token expired or whose permission was revoked.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, JsonSchema)]
+pub struct TokenEnforcementSpec {
+    /// Seconds tunnels are kept open after their token expired.
+    #[serde(default)]
+    pub grace_period: u64,
+    /// Accept new tokens sent by clients as `gateway-renew Bearer <token>` text messages. These
+    /// messages are dropped, and never relayed to the server, when renewal is not allowed.
+    #[serde(default)]
+    pub allow_renewal: bool,
+}
+
+/// Bytes of messages written to each side of tunnels before waiting for them to be flushed. Once
+/// reached, messages are not read from the other side anymore until the buffer is flushed.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, JsonSchema)]
+pub struct BufferSpec {
+    #[serde(default)]
+    pub client_to_server: usize,
+    #[serde(default)]
+    pub server_to_client: usize,
+}
+
+impl BufferSpec {
+    pub(crate) fn limit(&self, direction: Direction) -> usize {
+        match direction {
+            Direction::Received => self.client_to_server,
+            Direction::Sent => self.server_to_client,
+        }
+    }
+}
+
+/// Sources allowed to reach an API, checked against the client IP before authentication.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, JsonSchema)]
+pub struct IpFilterSpec {
+    /// Any source if empty.
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub allow: Vec<IpNet>,
+    /// Sources denied even if allowed.
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub deny: Vec<IpNet>,
+}
+
+impl IpFilterSpec {
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+            && !self.deny.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Settings of the websocket tunnels of an API.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, JsonSchema)]
+pub struct WebsocketSpec {
+    /// Connect to the backend with `wss://` instead of `ws://`.
+    #[serde(default)]
+    pub tls: bool,
+    /// Relay the raw connection instead of each message, letting the client and the backend
+    /// negotiate extensions such as `permessage-deflate`.
+    #[serde(default)]
+    pub passthrough: bool,
+    /// Close tunnels without any message in either direction for this many seconds, not
+    /// enforced with `passthrough`.
+    pub idle_timeout: Option<u64>,
+    /// Close tunnels opened for this many seconds, not enforced with `passthrough`.
+    pub max_duration: Option<u64>,
+    /// Ping both sides of tunnels every this many seconds, not done with `passthrough`.
+    pub ping_interval: Option<u64>,
+    /// Drop tunnels whose sides miss pongs for this many seconds after a ping, defaults to
+    /// `ping_interval`.
+    pub pong_timeout: Option<u64>,
+    /// Filters run in order on each data message, not run with `passthrough`.
+    #[serde(default)]
+    pub filters: Vec<MessageFilterSpec>,
+    /// Not enforced with `passthrough`.
+    pub token_enforcement: Option<TokenEnforcementSpec>,
+    /// Maximum number of simultaneous tunnels, further upgrades being rejected with `429`.
+    pub max_tunnels: Option<usize>,
+    /// Maximum number of simultaneous tunnels of a single `token_id`.
+    pub max_tunnels_per_user: Option<usize>,
+    /// Not used with `passthrough`, defaults to flushing every message.
+    #[serde(default)]
+    pub buffer: BufferSpec,
+}
+
+#[derive(CustomResource, Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[kube(
+    group = "gateway.dgexsol.fr",
+    version = "v2",
+    kind = "ApiDefinition",
+    namespaced
+)]
+pub struct ApiDefinitionSpec {
+    pub app_name: String,
+    pub host: String,
+    pub mode: ApiMode,
+    #[serde(default = "forward_path_default")]
+    pub forward_path: String,
+    /// Serve the API, requests to a disabled API being answered with `disabled_status`.
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    #[serde(default = "disabled_status_default")]
+    pub disabled_status: u16,
+    /// Answer requests with `503` while the upstream servers are under maintenance, as does the
+    /// `gateway.dgexsol.fr/maintenance: "true"` annotation set by `PUT /admin/apis/<app>/maintenance`.
+    #[serde(default)]
+    pub maintenance: bool,
+    /// A Rhai expression over the `headers`, `client_ip`, `token_type` and `claims` of requests,
+    /// such as `token_type == "service"`, which must be true for them to reach the API, others
+    /// being answered with `404`.
+    pub predicate: Option<String>,
+    /// Requests from other sources are answered with `403`.
+    pub ip_filter: Option<IpFilterSpec>,
+    /// Takes precedence over the quota of the app in the runtime config.
+    pub quota: Option<QuotaSpec>,
+    /// Each setting takes precedence over the global `security_headers` of the runtime config.
+    pub security_headers: Option<SecurityHeadersSpec>,
+    /// Reject stolen tokens used from another client than the first one.
+    pub token_binding: Option<TokenBindingSpec>,
+    /// Log the start of request and response bodies of every endpoint.
+    #[serde(default)]
+    pub capture_bodies: bool,
+    /// Keep the `Authorization` header of forwarded requests, for backends validating the token
+    /// themselves.
+    #[serde(default)]
+    pub forward_authorization: bool,
+    /// Headers set from the claims of the token, such as `X-Org-Id: "{claims.org_id}"`, those
+    /// sent by clients being removed. A header is not set if any claim of its template is missing.
+    #[serde(default)]
+    pub claim_headers: BTreeMap<String, String>,
+    /// Check permissions without enforcing them, missing ones being logged and counted.
+    #[serde(default)]
+    pub dry_run_permissions: bool,
+    #[serde(default)]
+    pub websocket: WebsocketSpec,
+    pub wasm_filter: Option<WasmFilterSpec>,
+    pub script: Option<ScriptSpec>,
+    pub ext_authz: Option<ExtAuthzSpec>,
+    pub request_transform: Option<RequestTransformSpec>,
+    pub response_transform: Option<ResponseTransformSpec>,
+    pub openapi: Option<OpenApiSpec>,
+    #[serde(skip)]
+    pub uri_http: String,
+    #[serde(skip)]
+    pub uri_ws: String,
+    /// When the api was last built to be served.
+    #[serde(skip)]
+    pub loaded_at: Option<SystemTime>,
+}
+
+/// Concatenate with a single allocation, unlike `format!` which grows its buffer.
+fn join_uri(prefix: &str, forwarded_uri: &str) -> String {
+    let mut uri = String::with_capacity(prefix.len() + forwarded_uri.len());
+    uri.push_str(prefix);
+    uri.push_str(forwarded_uri);
+    uri
+}
+
+fn forward_path_default() -> String {
+    "".to_string()
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+fn disabled_status_default() -> u16 {
+    404
+}
+
+/// Annotation of the `ApiDefinition`s turning their maintenance mode on, without editing their
+/// spec.
+pub const MAINTENANCE_ANNOTATION: &str = "gateway.dgexsol.fr/maintenance";
+
+impl ApiDefinition {
+    /// Whether requests are answered with `503`, by the spec or the annotation.
+    pub fn in_maintenance(&self) -> bool {
+        self.spec.maintenance
+            || self
+                .metadata
+                .annotations
+                .as_ref()
+                .and_then(|annotations| annotations.get(MAINTENANCE_ANNOTATION))
+                .is_some_and(|value| value == "true")
+    }
+
+    pub fn check_fields(&self) -> Result<(), String> {
+        self.check_app_name()?;
+        self.check_host()?;
+        self.check_endpoints()?;
+        self.check_forward_path()?;
+        self.check_disabled_status()?;
+        self.check_predicate()?;
+        self.check_quota()?;
+        self.check_websocket()?;
+        self.check_claim_headers()?;
+        self.check_security_headers()?;
+        self.check_token_binding()?;
+        self.check_script()?;
+        self.check_ext_authz()?;
+        self.check_request_transform()?;
+        self.check_response_transform()?;
+        self.check_openapi()?;
+
+        Ok(())
+    }
+
+    /// Compile the module of `wasm_filter`, which is only done by the server as the path refers
+    /// to its filesystem.
+    pub fn load_wasm_filter(&self) -> Result<(), String> {
+        let Some(wasm_filter) = &self.spec.wasm_filter else {
+            return Ok(());
+        };
+        load_wasm_filter(wasm_filter).map_err(|e| {
+            let err_msg = format!("wasm_filter: {e}");
+            info!("event='{}'", err_msg);
+            err_msg
+        })
+    }
+
+    /// Parse the document of `openapi`, which is only done by the server as the path refers to
+    /// its filesystem, and add its operations to the endpoints if `import_endpoints` is set.
+    pub fn load_openapi(&mut self) -> Result<(), String> {
+        let Some(openapi) = &self.spec.openapi else {
+            return Ok(());
+        };
+        let imported = load_openapi(openapi).and_then(|document| match openapi.import_endpoints {
+            true => document.endpoints(),
+            false => Ok(Vec::new()),
+        });
+        let imported = imported.map_err(|e| {
+            let err_msg = format!("openapi: {e}");
+            info!("event='{}'", err_msg);
+            err_msg
+        })?;
+
+        if let ApiMode::ForwardStrict(endpoints) = &mut self.spec.mode {
+            for endpoint in imported {
+                if !endpoints.iter().any(|listed| {
+                    listed.path == endpoint.path
+                        && listed.method.eq_ignore_ascii_case(&endpoint.method)
+                }) {
+                    endpoints.push(endpoint);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn build_uri(&mut self) {
+        self.spec.uri_http = format!("http://{}{}", &self.spec.host, &self.spec.forward_path);
+        let ws_scheme = if self.spec.websocket.tls { "wss" } else { "ws" };
+        self.spec.uri_ws = format!(
+            "{ws_scheme}://{}{}",
+            &self.spec.host, &self.spec.forward_path
+        );
+    }
+
+    /// Upstream URI of a forwarded path and query, appended to the prefix built by `build_uri`.
+    pub fn http_uri(&self, forwarded_uri: &str) -> String {
+        join_uri(&self.spec.uri_http, forwarded_uri)
+    }
+
+    /// Upstream websocket URI of a forwarded path and query.
+    pub fn ws_uri(&self, forwarded_uri: &str) -> String {
+        join_uri(&self.spec.uri_ws, forwarded_uri)
+    }
+
+    fn check_app_name(&self) -> Result<(), String> {
+        if self.spec.app_name.len() < 2 {
+            let err_msg = format!(
+                "app_name: {} must be at least 2 characters",
+                self.spec.app_name
+            );
+            info!("event='{}", err_msg);
+            return Err(err_msg);
+        }
+        if !self.spec.app_name.starts_with('/') {
+            let err_msg = format!("app_name: {} should start with `/`", self.spec.app_name);
+            info!("event='{}", err_msg);
+            return Err(err_msg);
+        }
+        if self.spec.app_name[1..].contains('/') {
+            let err_msg = format!("app_name: {} should only have one `/`", self.spec.app_name);
+            info!("event='{}", err_msg);
+            return Err(err_msg);
+        }
+        if self.spec.app_name == "/metrics" || self.spec.app_name == "/health" {
+            let err_msg = format!(
+                "app_name: {} cannot be `/metrics` or `/health`",
+                self.spec.app_name
+            );
+            info!("event='{}", err_msg);
+            return Err(err_msg);
+        }
+
+        Ok(())
+    }
+
+    fn check_host(&self) -> Result<(), String> {
+        match Url::parse(&format!("http://{}", self.spec.host))
+            .map(|_| ())
+            .map_err(|_| format!("host: {} isn't valid", self.spec.host))
+        {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                info!("event='{:?}", e);
+                anyhow::Result::Err(e)
+            }
+        }
+    }
+
+    fn check_forward_path(&self) -> Result<(), String> {
+        if self.spec.forward_path.is_empty() || self.spec.forward_path.starts_with('/') {
+            return Ok(());
+        }
+        let err_msg = format!(
+            "Forward_path: {} should start with `/`",
+            self.spec.forward_path
+        );
+        info!("event='{}'", err_msg);
+        Err(err_msg)
+    }
+
+    fn check_disabled_status(&self) -> Result<(), String> {
+        if StatusCode::from_u16(self.spec.disabled_status).is_ok() {
+            return Ok(());
+        }
+        let err_msg = format!(
+            "disabled_status: {} isn't a valid status code",
+            self.spec.disabled_status
+        );
+        info!("event='{}'", err_msg);
+        Err(err_msg)
+    }
+
+    fn check_predicate(&self) -> Result<(), String> {
+        let Some(predicate) = &self.spec.predicate else {
+            return Ok(());
+        };
+        check_predicate(predicate).map_err(|e| {
+            let err_msg = format!("predicate: {e}");
+            info!("event='{}'", err_msg);
+            err_msg
+        })
+    }
+
+    fn check_quota(&self) -> Result<(), String> {
+        match &self.spec.quota {
+            Some(quota) if quota.window == 0 => {
+                let err_msg = "quota: window must be positive".to_string();
+                info!("event='{}'", err_msg);
+                Err(err_msg)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_websocket(&self) -> Result<(), String> {
+        let websocket = &self.spec.websocket;
+        for (name, seconds) in [
+            ("idle_timeout", websocket.idle_timeout),
+            ("max_duration", websocket.max_duration),
+            ("ping_interval", websocket.ping_interval),
+            ("pong_timeout", websocket.pong_timeout),
+        ] {
+            if seconds == Some(0) {
+                let err_msg = format!("websocket: {name} must be positive");
+                info!("event='{}'", err_msg);
+                return Err(err_msg);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_claim_headers(&self) -> Result<(), String> {
+        for (name, template) in &self.spec.claim_headers {
+            let checked = match HeaderName::from_bytes(name.as_bytes()) {
+                Ok(_) => check_claim_template(template),
+                Err(_) => Err(format!("{name} isn't a valid header name")),
+            };
+            if let Err(e) = checked {
+                let err_msg = format!("claim_headers: {e}");
+                info!("event='{}'", err_msg);
+                return Err(err_msg);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_security_headers(&self) -> Result<(), String> {
+        let Some(security_headers) = &self.spec.security_headers else {
+            return Ok(());
+        };
+        security_headers.check().map_err(|e| {
+            let err_msg = format!("security_headers: {e}");
+            info!("event='{}'", err_msg);
+            err_msg
+        })
+    }
+
+    fn check_token_binding(&self) -> Result<(), String> {
+        let Some(token_binding) = &self.spec.token_binding else {
+            return Ok(());
+        };
+        token_binding.check().map_err(|e| {
+            let err_msg = format!("token_binding: {e}");
+            info!("event='{}'", err_msg);
+            err_msg
+        })
+    }
+
+    fn check_script(&self) -> Result<(), String> {
+        let Some(script) = &self.spec.script else {
+            return Ok(());
+        };
+        check_script(script).map_err(|e| {
+            let err_msg = format!("script: {e}");
+            info!("event='{}'", err_msg);
+            err_msg
+        })
+    }
+
+    fn check_ext_authz(&self) -> Result<(), String> {
+        let Some(ext_authz) = &self.spec.ext_authz else {
+            return Ok(());
+        };
+        if ext_authz
+            .uri
+            .parse::<hyper::Uri>()
+            .is_ok_and(|uri| uri.scheme_str() == Some("http") && uri.host().is_some())
+        {
+            return Ok(());
+        }
+        let err_msg = format!("ext_authz: {} isn't a valid http:// uri", ext_authz.uri);
+        info!("event='{}'", err_msg);
+        Err(err_msg)
+    }
+
+    fn check_request_transform(&self) -> Result<(), String> {
+        let Some(request_transform) = &self.spec.request_transform else {
+            return Ok(());
+        };
+        request_transform.check().map_err(|e| {
+            let err_msg = format!("request_transform: {e}");
+            info!("event='{}'", err_msg);
+            err_msg
+        })
+    }
+
+    fn check_response_transform(&self) -> Result<(), String> {
+        let Some(response_transform) = &self.spec.response_transform else {
+            return Ok(());
+        };
+        response_transform.check().map_err(|e| {
+            let err_msg = format!("response_transform: {e}");
+            info!("event='{}'", err_msg);
+            err_msg
+        })
+    }
+
+    fn check_openapi(&self) -> Result<(), String> {
+        match (&self.spec.openapi, &self.spec.mode) {
+            (Some(openapi), ApiMode::ForwardAll) if openapi.import_endpoints => {
+                let err_msg =
+                    "openapi: import_endpoints requires the forward_strict mode".to_string();
+                info!("event='{}'", err_msg);
+                Err(err_msg)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_endpoints(&self) -> Result<(), String> {
+        if let ApiMode::ForwardStrict(endpoints) = &self.spec.mode {
+            for endpoint in endpoints {
+                endpoint.check_fields()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn try_from(value: &DynamicObject) -> Result<Self> {
+        // It more simple to let kube and serde crate do object deserialization as we just have to
+        // maintain the ApiDefinitionSpec struct and not all the boiler plate around.
+        serde_yaml::from_str(serde_yaml::to_string(value)?.as_str()).map_err(anyhow::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn api(websocket: serde_json::Value) -> ApiDefinition {
+        let spec: ApiDefinitionSpec = serde_json::from_value(json!({
+            "app_name": "/chat",
+            "host": "chat:8080",
+            "mode": { "kind": "forward_all" },
+            "websocket": websocket,
+        }))
+        .unwrap();
+        ApiDefinition::new("chat", spec)
+    }
+
+    #[test]
+    fn zero_websocket_durations_are_rejected() {
+        assert!(api(json!({ "ping_interval": 30, "pong_timeout": 10 }))
+            .check_fields()
+            .is_ok());
+        for name in [
+            "idle_timeout",
+            "max_duration",
+            "ping_interval",
+            "pong_timeout",
+        ] {
+            let error = api(json!({ name: 0 })).check_fields().unwrap_err();
+            assert_eq!(error, format!("websocket: {name} must be positive"));
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::net::IpAddr;
+
+use hyper::StatusCode;
+use serde::Serialize;
+
+use crate::access_log::AccessLog;
+use crate::log_sink::AUDIT_LOG;
+
+/// Version of the audit record schema, to be bumped on any breaking change of its fields.
+const AUDIT_SCHEMA_VERSION: u32 = 1;
+
+/// A record of an authorization denial, kept stable for security reviews.
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    schema_version: u32,
+    timestamp: &'a str,
+    decision: &'static str,
+    status_code: u16,
+    reason: Option<&'a str>,
+    app: Option<&'a str>,
+    user_sub: Option<&'a str>,
+    token_id: Option<&'a str>,
+    permission: Option<&'a str>,
+    source_ip: IpAddr,
+    method: &'a str,
+    path: &'a str,
+}
+
+/// Emit an audit record if the request was denied (401 or 403).
+pub fn audit_denial(access_log: &AccessLog, source_ip: IpAddr) {
+    let status_code = access_log.status_code;
+    if status_code != StatusCode::UNAUTHORIZED.as_u16()
+        && status_code != StatusCode::FORBIDDEN.as_u16()
+    {
+        return;
+    }
+
+    emit(access_log, source_ip, "deny", access_log.error.as_deref());
+}
+
+/// Emit an audit record of the decision to block a client, taken on the response of the request
+/// of `access_log`.
+pub fn audit_block(access_log: &AccessLog, source_ip: IpAddr, reason: &str) {
+    emit(access_log, source_ip, "block", Some(reason));
+}
+
+fn emit(access_log: &AccessLog, source_ip: IpAddr, decision: &'static str, reason: Option<&str>) {
+    let record = AuditRecord {
+        schema_version: AUDIT_SCHEMA_VERSION,
+        timestamp: &access_log.timestamp,
+        decision,
+        status_code: access_log.status_code,
+        reason,
+        app: access_log.app.as_deref(),
+        user_sub: access_log.user_sub.as_deref(),
+        token_id: access_log.token_id.as_deref(),
+        permission: access_log.perm.as_deref(),
+        source_ip,
+        method: &access_log.method,
+        path: &access_log.path,
+    };
+
+    let line = match serde_json::to_string(&record) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("event='Fail to serialize audit record: {e}'");
+            return;
+        }
+    };
+
+    if let Some(line) = AUDIT_LOG.send(true, line) {
+        warn!(target: "audit", "{line}");
+    }
+}
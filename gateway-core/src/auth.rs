@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use hyper::header::HeaderValue;
+use jsonwebtoken::errors::{Error, ErrorKind};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, Client};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tokio::fs;
+
+use crate::metrics::{commit_auth_failure, commit_auth_success};
+use crate::quota::use_token_in_store;
+use crate::runtime_config::{AuthSource, SecretKeyRef};
+
+#[allow(dead_code)] // some fields are only used by the validator
+#[derive(Deserialize, Debug, Clone)]
+pub struct Claims {
+    pub sub: String,
+    iss: String,
+    pub exp: usize,
+    pub preferred_username: String,
+    pub given_name: String,
+    pub family_name: String,
+    pub email: String,
+    pub token_id: String,
+    /// Claims without a field, reachable from the templates of `claim_headers`.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// Part of a `claim_headers` template.
+enum TemplatePart<'a> {
+    Text(&'a str),
+    /// Dotted path of a `{claims.<path>}` placeholder.
+    Claim(&'a str),
+}
+
+fn parse_template(template: &str) -> Result<Vec<TemplatePart<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(length) = rest[start..].find('}') else {
+            return Err(format!("`{template}` has an unclosed `{{`"));
+        };
+        let placeholder = &rest[start + 1..start + length];
+        let Some(path) = placeholder
+            .strip_prefix("claims.")
+            .filter(|path| path.split('.').all(|segment| !segment.is_empty()))
+        else {
+            return Err(format!(
+                "`{{{placeholder}}}` of `{template}` isn't a `{{claims.<name>}}` placeholder"
+            ));
+        };
+        parts.push(TemplatePart::Text(&rest[..start]));
+        parts.push(TemplatePart::Claim(path));
+        rest = &rest[start + length + 1..];
+    }
+    parts.push(TemplatePart::Text(rest));
+
+    Ok(parts)
+}
+
+/// Check that a template has only `{claims.<path>}` placeholders and that its text is a valid
+/// header value.
+pub(crate) fn check_claim_template(template: &str) -> Result<(), String> {
+    let text: String = parse_template(template)?
+        .into_iter()
+        .filter_map(|part| match part {
+            TemplatePart::Text(text) => Some(text),
+            TemplatePart::Claim(_) => None,
+        })
+        .collect();
+    match HeaderValue::from_str(&text) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("`{template}` isn't a valid header value")),
+    }
+}
+
+/// Render a claim as a header value, the elements of arrays being joined with `,`.
+fn render_claim(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Array(values) => values
+            .iter()
+            .map(render_claim)
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(",")),
+        Value::Object(_) | Value::Null => None,
+    }
+}
+
+impl Claims {
+    /// Get a claim by its dotted path, such as `realm_access.roles`.
+    fn get_claim(&self, path: &str) -> Option<String> {
+        let known = match path {
+            "sub" => Some(&self.sub),
+            "iss" => Some(&self.iss),
+            "preferred_username" => Some(&self.preferred_username),
+            "given_name" => Some(&self.given_name),
+            "family_name" => Some(&self.family_name),
+            "email" => Some(&self.email),
+            "token_id" => Some(&self.token_id),
+            "exp" => return Some(self.exp.to_string()),
+            _ => None,
+        };
+        if let Some(value) = known {
+            return Some(value.clone());
+        }
+
+        let mut segments = path.split('.');
+        let mut value = self.other.get(segments.next()?)?;
+        for segment in segments {
+            value = value.get(segment)?;
+        }
+        render_claim(value)
+    }
+
+    /// Get all the claims as a JSON object.
+    pub(crate) fn to_value(&self) -> Value {
+        let mut claims = self.other.clone();
+        for (name, value) in [
+            ("sub", &self.sub),
+            ("iss", &self.iss),
+            ("preferred_username", &self.preferred_username),
+            ("given_name", &self.given_name),
+            ("family_name", &self.family_name),
+            ("email", &self.email),
+            ("token_id", &self.token_id),
+        ] {
+            claims.insert(name.to_string(), Value::String(value.clone()));
+        }
+        claims.insert("exp".to_string(), self.exp.into());
+        Value::Object(claims)
+    }
+
+    /// Render a template such as `org-{claims.org_id}`, which is missing if any of its claims is.
+    pub(crate) fn render_template(&self, template: &str) -> Option<String> {
+        let mut rendered = String::new();
+        for part in parse_template(template).ok()? {
+            match part {
+                TemplatePart::Text(text) => rendered.push_str(text),
+                TemplatePart::Claim(path) => rendered.push_str(&self.get_claim(path)?),
+            }
+        }
+        Some(rendered)
+    }
+}
+
+fn get_aud_or_iss(aud_or_iss: String) -> HashSet<String> {
+    let mut hs = HashSet::new();
+    hs.insert(aud_or_iss);
+    hs
+}
+
+struct TokenSource {
+    pub name: String,
+    pub token_type: String,
+    pub validation: Validation,
+    pub public_key: DecodingKey,
+    pub single_use: bool,
+}
+
+impl TokenSource {
+    pub fn new(auth_source: &AuthSource, public_key: &str) -> Result<Self> {
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.leeway = 0;
+        validation.leeway = 0;
+        validation.validate_exp = true;
+        validation.validate_nbf = false;
+        validation.iss = Some(get_aud_or_iss(auth_source.issuer.to_string()));
+        validation.aud = Some(get_aud_or_iss(auth_source.audience.to_string()));
+        validation.sub = None;
+        let public_key = DecodingKey::from_rsa_pem(public_key.as_bytes())?;
+        Ok(Self {
+            name: auth_source.name.to_string(),
+            token_type: auth_source.token_type.to_string(),
+            validation,
+            public_key,
+            single_use: auth_source.single_use,
+        })
+    }
+}
+
+/// The token sources built from `auth_sources`, with their public keys.
+pub struct TokenSources(Vec<TokenSource>);
+
+static TOKEN_SOURCES: LazyLock<RwLock<Arc<TokenSources>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(TokenSources(Vec::new()))));
+
+async fn get_secret_value(secret_ref: &SecretKeyRef) -> Result<String> {
+    let client = Client::try_default().await?;
+    let secrets: Api<Secret> = match &secret_ref.namespace {
+        Some(namespace) => Api::namespaced(client, namespace),
+        None => Api::default_namespaced(client),
+    };
+
+    let value = secrets
+        .get(&secret_ref.name)
+        .await?
+        .data
+        .and_then(|mut data| data.remove(&secret_ref.key))
+        .ok_or_else(|| {
+            anyhow!(
+                "No key `{}` in Secret `{}`",
+                secret_ref.key,
+                secret_ref.name
+            )
+        })?;
+
+    Ok(String::from_utf8(value.0)?)
+}
+
+/// Get the PEM public key of an auth source, inlined or from a file or a Secret.
+async fn get_public_key(auth_source: &AuthSource) -> Result<String> {
+    if let Some(public_key) = &auth_source.public_key {
+        Ok(public_key.clone())
+    } else if let Some(path) = &auth_source.public_key_file {
+        fs::read_to_string(path)
+            .await
+            .map_err(|e| anyhow!("Cannot read {}: {e}", path.display()))
+    } else if let Some(secret_ref) = &auth_source.public_key_secret {
+        get_secret_value(secret_ref).await
+    } else {
+        bail!("No public key")
+    }
+}
+
+/// Build the token sources of `auth_sources`, reading or fetching their public keys.
+pub async fn load_token_sources(auth_sources: &[AuthSource]) -> Result<TokenSources> {
+    let mut token_sources = Vec::new();
+    for auth_source in auth_sources {
+        let token_source = async {
+            let public_key = get_public_key(auth_source).await?;
+            TokenSource::new(auth_source, &public_key)
+        }
+        .await
+        .map_err(|e| anyhow!("Invalid auth source `{}`: {e}", auth_source.name))?;
+        token_sources.push(token_source);
+    }
+
+    Ok(TokenSources(token_sources))
+}
+
+/// Replace the token sources, at startup and when the runtime config is reloaded.
+pub fn set_token_sources(token_sources: TokenSources) {
+    *TOKEN_SOURCES.write().unwrap() = Arc::new(token_sources);
+}
+
+/// Expiration of the tokens of `single_use` sources already used, by source name and `jti`.
+#[derive(Default)]
+struct UsedTokens {
+    tokens: HashMap<(String, String), usize>,
+    /// Number of tokens above which the expired ones are removed before inserting another.
+    prune_at: usize,
+}
+
+const MIN_PRUNE_AT: usize = 1024;
+
+static USED_TOKENS: LazyLock<Mutex<UsedTokens>> = LazyLock::new(Default::default);
+
+/// Record the use of a token of a `single_use` source, failing with the reason of its rejection
+/// if it has no `jti` or was already used. Used tokens are shared by the instances through
+/// `quotas.store` if set, and remembered by the instance while the store does not answer.
+async fn use_token(token_source: &TokenSource, claims: &Claims) -> Result<(), &'static str> {
+    let Some(jti) = claims.other.get("jti").and_then(Value::as_str) else {
+        return Err("malformed");
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as usize);
+
+    let ttl = Duration::from_secs(claims.exp.saturating_sub(now) as u64);
+    match use_token_in_store(&token_source.name, jti, ttl).await {
+        Some(true) => return Ok(()),
+        Some(false) => return Err("replayed"),
+        None => {}
+    }
+
+    let mut used_tokens = USED_TOKENS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let key = (token_source.name.clone(), jti.to_string());
+    if used_tokens.tokens.get(&key).is_some_and(|exp| *exp > now) {
+        return Err("replayed");
+    }
+    if used_tokens.tokens.len() >= used_tokens.prune_at {
+        used_tokens.tokens.retain(|_, exp| *exp > now);
+        used_tokens.prune_at = MIN_PRUNE_AT.max(used_tokens.tokens.len() * 2);
+    }
+    used_tokens.tokens.insert(key, claims.exp);
+    Ok(())
+}
+
+const AUTH_SHIFT: usize = "Bearer ".len();
+
+/// Classify a decoding error into a small set of reasons suitable for a metric label.
+fn get_error_reason(error: &Error) -> &'static str {
+    match error.kind() {
+        ErrorKind::ExpiredSignature | ErrorKind::ImmatureSignature => "expired",
+        ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm => "bad_signature",
+        ErrorKind::InvalidAudience => "wrong_audience",
+        ErrorKind::InvalidIssuer => "wrong_issuer",
+        ErrorKind::InvalidToken
+        | ErrorKind::Base64(_)
+        | ErrorKind::Json(_)
+        | ErrorKind::Utf8(_)
+        | ErrorKind::MissingRequiredClaim(_) => "malformed",
+        _ => "other",
+    }
+}
+
+pub async fn get_claims(authorization: &str) -> Option<(Claims, String)> {
+    if authorization.len() <= AUTH_SHIFT {
+        warn!("event='An error occurs while getting claim, no claim'");
+        commit_auth_failure("none", "missing");
+        return None;
+    }
+    let mut errors = Vec::new();
+    let token_sources = TOKEN_SOURCES.read().unwrap().clone();
+    for token_source in token_sources.0.iter() {
+        match decode::<Claims>(
+            &authorization[AUTH_SHIFT..],
+            &token_source.public_key,
+            &token_source.validation,
+        ) {
+            Ok(token) => {
+                if token_source.single_use {
+                    if let Err(reason) = use_token(token_source, &token.claims).await {
+                        warn!(
+                            "event='Single-use token of {} rejected: {reason}'",
+                            token_source.name
+                        );
+                        commit_auth_failure(&token_source.name, reason);
+                        return None;
+                    }
+                }
+                commit_auth_success(&token_source.name, &token_source.token_type);
+                return Some((token.claims, token_source.token_type.to_string()));
+            }
+            Err(e) => {
+                errors.push((token_source, e));
+            }
+        }
+    }
+    // Failures are only counted when no source accepted the token, otherwise each valid token
+    // would be reported as a failure by all the sources tried before the matching one.
+    for (token_source, error) in &errors {
+        commit_auth_failure(&token_source.name, get_error_reason(error));
+    }
+    let errors: Vec<_> = errors
+        .iter()
+        .map(|(token_source, e)| format!("{}: {}", token_source.name, e))
+        .collect();
+    warn!("event='An error occurs while getting claim: {:?}'", errors);
+    None
+}
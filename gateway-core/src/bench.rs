@@ -0,0 +1,405 @@
+//! Synthetic traffic sent through the whole pipeline to a mock upstream, to measure the cost of
+//! routing, authentication and permission changes without external tooling.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use futures::{stream, SinkExt, StreamExt};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Either, Full, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::header::AUTHORIZATION;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response};
+use hyper_tungstenite::{is_upgrade_request, upgrade};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_tungstenite::connect_async;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::Message;
+
+use crate::api::{ApiDefinition, ApiDefinitionSpec};
+use crate::auth::{load_token_sources, set_token_sources};
+use crate::runtime_config::AuthSource;
+use crate::websocket::init_websocket_tls;
+use crate::Gateway;
+
+/// Key pair signing the tokens of the bench, whose public key is the only auth source of the
+/// bench process. It is never accepted by a served gateway.
+const BENCH_PRIVATE_KEY: &str = include_str!("../bench/key.pem");
+const BENCH_PUBLIC_KEY: &str = include_str!("../bench/key.pub.pem");
+
+pub(crate) const BENCH_APP: &str = "/bench";
+const BENCH_USER: &str = "bench";
+/// Size of the frames of the streamed request bodies.
+pub(crate) const STREAMED_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Traffic sent by [`run_bench`].
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Number of HTTP requests.
+    pub requests: usize,
+    /// Number of HTTP requests in flight at once.
+    pub concurrency: usize,
+    /// Number of HTTP requests streaming a body of `body_size` bytes, echoed by the upstream.
+    pub streamed_requests: usize,
+    pub body_size: usize,
+    /// Number of websocket tunnels opened at once.
+    pub websocket_connections: usize,
+    /// Number of messages echoed through each websocket tunnel.
+    pub websocket_messages: usize,
+}
+
+/// Measures of one kind of traffic.
+#[derive(Debug)]
+pub struct BenchResult {
+    pub name: &'static str,
+    /// Operations which succeeded, whose latencies are measured.
+    pub count: usize,
+    /// Failed HTTP requests or websocket tunnels.
+    pub errors: usize,
+    pub elapsed: Duration,
+    /// Bytes of the bodies echoed by the successful operations.
+    pub bytes: usize,
+    /// Sorted latencies of the successful operations.
+    latencies: Vec<Duration>,
+}
+
+impl BenchResult {
+    fn new(name: &'static str, elapsed: Duration, results: Vec<Result<Vec<Duration>>>) -> Self {
+        let mut latencies = Vec::new();
+        let mut errors = 0;
+        for result in results {
+            match result {
+                Ok(durations) => latencies.extend(durations),
+                Err(e) => {
+                    warn!("event='Bench {name} failed: {e}'");
+                    errors += 1;
+                }
+            }
+        }
+        latencies.sort_unstable();
+        BenchResult {
+            name,
+            count: latencies.len(),
+            errors,
+            elapsed,
+            bytes: 0,
+            latencies,
+        }
+    }
+
+    /// Successful operations per second.
+    pub fn rate(&self) -> f64 {
+        self.count as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Echoed bytes per second.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Latency under which `percent` of the successful operations completed.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (percent / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+/// Answer `200` to any request, echoing its body or the messages of websocket upgrades.
+async fn mock_upstream(req: Request<Incoming>) -> Result<Response<Either<Incoming, Full<Bytes>>>> {
+    if !is_upgrade_request(&req) {
+        return Ok(Response::new(Either::Left(req.into_body())));
+    }
+
+    let (response, websocket) = upgrade(req, None)?;
+    tokio::spawn(async move {
+        let Ok(mut websocket) = websocket.await else {
+            return;
+        };
+        while let Some(Ok(message)) = websocket.next().await {
+            if (message.is_text() || message.is_binary()) && websocket.send(message).await.is_err()
+            {
+                return;
+            }
+        }
+    });
+    Ok(response.map(Either::Right))
+}
+
+async fn serve_mock_upstream(listener: TcpListener) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(
+            http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service_fn(mock_upstream))
+                .with_upgrades(),
+        );
+    }
+}
+
+fn bench_api(upstream: SocketAddr) -> Result<ApiDefinition> {
+    let spec: ApiDefinitionSpec = serde_json::from_value(json!({
+        "app_name": BENCH_APP,
+        "host": upstream.to_string(),
+        "mode": {
+            "kind": "forward_strict",
+            "endpoints": [
+                { "path": "/http", "method": "GET" },
+                { "path": "/stream", "method": "POST" },
+                { "path": "/ws", "method": "GET", "is_websocket": true },
+            ],
+        },
+    }))?;
+    Ok(ApiDefinition::new(&BENCH_APP[1..], spec))
+}
+
+/// Accept the tokens of the bench key only, returning the authorization header of one of them.
+async fn init_bench_auth() -> Result<String> {
+    let auth_source = AuthSource {
+        name: "bench".to_string(),
+        token_type: "bench".to_string(),
+        issuer: "gateway-bench".to_string(),
+        audience: "gateway-bench".to_string(),
+        public_key: Some(BENCH_PUBLIC_KEY.to_string()),
+        public_key_file: None,
+        public_key_secret: None,
+        single_use: false,
+    };
+    set_token_sources(load_token_sources(&[auth_source]).await?);
+
+    let exp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + 24 * 3600;
+    let claims = json!({
+        "sub": BENCH_USER,
+        "iss": "gateway-bench",
+        "aud": "gateway-bench",
+        "exp": exp,
+        "preferred_username": BENCH_USER,
+        "given_name": BENCH_USER,
+        "family_name": BENCH_USER,
+        "email": "bench@localhost",
+        "token_id": BENCH_USER,
+    });
+    let token = encode(
+        &Header::new(Algorithm::RS256),
+        &claims,
+        &EncodingKey::from_rsa_pem(BENCH_PRIVATE_KEY.as_bytes())?,
+    )?;
+    Ok(format!("Bearer {token}"))
+}
+
+/// Body of `size` bytes sent frame by frame, each frame sharing the memory of `chunk`.
+fn streamed_body(chunk: Bytes, size: usize) -> BoxBody<Bytes, Infallible> {
+    let frames = (0..size)
+        .step_by(chunk.len())
+        .map(move |offset| Ok(Frame::data(chunk.slice(..chunk.len().min(size - offset)))));
+    BoxBody::new(StreamBody::new(stream::iter(frames)))
+}
+
+/// Requests of one kind sent by the workers of `bench_http`.
+struct HttpTraffic {
+    name: &'static str,
+    method: Method,
+    path: &'static str,
+    requests: usize,
+    /// Size of the streamed request bodies, echoed by the mock upstream.
+    body_size: usize,
+}
+
+async fn bench_http(
+    gateway: SocketAddr,
+    authorization: &str,
+    concurrency: usize,
+    traffic: HttpTraffic,
+) -> BenchResult {
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let uri = format!("http://{gateway}{BENCH_APP}{}", traffic.path);
+    let remaining = Arc::new(AtomicUsize::new(traffic.requests));
+    let chunk = Bytes::from(vec![b'x'; STREAMED_CHUNK_SIZE]);
+
+    let start = Instant::now();
+    let mut workers = JoinSet::new();
+    for _ in 0..concurrency.min(traffic.requests) {
+        let (client, uri, authorization, remaining, chunk, method) = (
+            client.clone(),
+            uri.clone(),
+            authorization.to_string(),
+            remaining.clone(),
+            chunk.clone(),
+            traffic.method.clone(),
+        );
+        let body_size = traffic.body_size;
+        workers.spawn(async move {
+            let mut results = Vec::new();
+            while remaining
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                let sent = Instant::now();
+                let result = async {
+                    let req = Request::builder()
+                        .method(&method)
+                        .uri(&uri)
+                        .header(AUTHORIZATION, &authorization)
+                        .body(streamed_body(chunk.clone(), body_size))?;
+                    let mut body = client.request(req).await?.into_body();
+                    // Counted frame by frame, to measure a streamed body rather than its buffering.
+                    let mut received = 0;
+                    while let Some(frame) = body.frame().await {
+                        received += frame?.data_ref().map_or(0, Bytes::len);
+                    }
+                    if received != body_size {
+                        bail!("{received} bytes echoed instead of {body_size}");
+                    }
+                    Ok(vec![sent.elapsed()])
+                }
+                .await;
+                results.push(result);
+            }
+            results
+        });
+    }
+    let results = workers.join_all().await.into_iter().flatten().collect();
+
+    let mut result = BenchResult::new(traffic.name, start.elapsed(), results);
+    result.bytes = result.count * traffic.body_size;
+    result
+}
+
+/// Open a tunnel and measure the round trip of each of its messages.
+async fn run_websocket(uri: &str, authorization: &str, messages: usize) -> Result<Vec<Duration>> {
+    let mut req = uri.into_client_request()?;
+    req.headers_mut()
+        .insert(AUTHORIZATION, authorization.parse()?);
+    let (mut websocket, _) = connect_async(req).await?;
+
+    let mut latencies = Vec::with_capacity(messages);
+    for i in 0..messages {
+        let sent = Instant::now();
+        websocket.send(Message::text(i.to_string())).await?;
+        match websocket.next().await {
+            Some(Ok(Message::Text(_))) => latencies.push(sent.elapsed()),
+            Some(Ok(message)) => bail!("unexpected message {message:?}"),
+            Some(Err(e)) => return Err(e.into()),
+            None => bail!("tunnel closed"),
+        }
+    }
+    websocket.close(None).await?;
+    Ok(latencies)
+}
+
+async fn bench_websocket(
+    gateway: SocketAddr,
+    authorization: &str,
+    options: &BenchOptions,
+) -> BenchResult {
+    let uri = format!("ws://{gateway}{BENCH_APP}/ws");
+
+    let start = Instant::now();
+    let mut tunnels = JoinSet::new();
+    for _ in 0..options.websocket_connections {
+        let (uri, authorization, messages) = (
+            uri.clone(),
+            authorization.to_string(),
+            options.websocket_messages,
+        );
+        tunnels.spawn(async move { run_websocket(&uri, &authorization, messages).await });
+    }
+    let results = tunnels.join_all().await;
+
+    BenchResult::new("websocket", start.elapsed(), results)
+}
+
+/// Serve a mock upstream behind a gateway with the runtime config, returning the address of the
+/// gateway, the authorization header of the bench user and the task serving the gateway.
+pub(crate) async fn serve_bench_gateway() -> Result<(SocketAddr, String, JoinHandle<Result<()>>)> {
+    let upstream_listener = TcpListener::bind("127.0.0.1:0").await?;
+    let upstream = upstream_listener.local_addr()?;
+    tokio::spawn(serve_mock_upstream(upstream_listener));
+
+    init_websocket_tls()?;
+    let authorization = init_bench_auth().await?;
+
+    let permissions = [
+        "bench::GET::/http",
+        "bench::POST::/stream",
+        "bench::GET::/ws",
+    ]
+    .into_iter()
+    .map(|permission| {
+        (
+            permission.to_string(),
+            HashSet::from([BENCH_USER.to_string()]),
+        )
+    })
+    .collect();
+    let gateway = Gateway::builder()
+        .register_api(bench_api(upstream)?)
+        .permissions(permissions, HashMap::new())
+        .build()
+        .await?;
+    let gateway_listener = TcpListener::bind("127.0.0.1:0").await?;
+    let gateway_addr = gateway_listener.local_addr()?;
+    let serve = tokio::spawn(async move { gateway.serve(gateway_listener).await });
+    Ok((gateway_addr, authorization, serve))
+}
+
+/// Serve a mock upstream behind a gateway with the runtime config, and send it the traffic of
+/// `options`: HTTP requests, HTTP requests streaming large bodies, then messages through websocket
+/// tunnels.
+///
+/// The auth sources of the runtime config are replaced by a key of the bench, and the only api
+/// and permissions are those of the bench user.
+pub async fn run_bench(options: &BenchOptions) -> Result<Vec<BenchResult>> {
+    let (gateway_addr, authorization, serve) = serve_bench_gateway().await?;
+
+    let mut results = Vec::new();
+    if options.requests > 0 {
+        let traffic = HttpTraffic {
+            name: "http",
+            method: Method::GET,
+            path: "/http",
+            requests: options.requests,
+            body_size: 0,
+        };
+        results.push(bench_http(gateway_addr, &authorization, options.concurrency, traffic).await);
+    }
+    if options.streamed_requests > 0 && options.body_size > 0 {
+        let traffic = HttpTraffic {
+            name: "streamed",
+            method: Method::POST,
+            path: "/stream",
+            requests: options.streamed_requests,
+            body_size: options.body_size,
+        };
+        results.push(bench_http(gateway_addr, &authorization, options.concurrency, traffic).await);
+    }
+    if options.websocket_connections > 0 && options.websocket_messages > 0 {
+        results.push(bench_websocket(gateway_addr, &authorization, options).await);
+    }
+
+    if serve.is_finished() {
+        return Err(serve
+            .await?
+            .err()
+            .unwrap_or_else(|| anyhow!("Gateway stopped")));
+    }
+    serve.abort();
+    Ok(results)
+}
@@ -0,0 +1,126 @@
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use hyper::body::{Body, Frame, SizeHint};
+use regex::Regex;
+use serde_json::json;
+
+use crate::runtime_config::runtime_config;
+
+/// Matches `key: value`, `"key": "value"` or `key=value` for each redacted key, the value being
+/// in the last group.
+static REDACT: LazyLock<Option<Regex>> = LazyLock::new(|| {
+    let runtime_config = runtime_config();
+    let keys = &runtime_config.body_capture.redacted_keys;
+    if keys.is_empty() {
+        return None;
+    }
+    let keys = keys
+        .iter()
+        .map(|key| regex::escape(key))
+        .collect::<Vec<_>>()
+        .join("|");
+    Some(Regex::new(&format!(r#"(?i)("?(?:{keys})"?\s*[:=]\s*"?)[^"&,\s}}]*"#)).unwrap())
+});
+
+fn redact(body: &str) -> String {
+    match REDACT.as_ref() {
+        Some(redact) => redact.replace_all(body, "${1}[REDACTED]").to_string(),
+        None => body.to_string(),
+    }
+}
+
+/// Whether the capture was requested with the trigger header by a trusted source.
+pub fn is_capture_requested(headers: &hyper::HeaderMap, client_ip: IpAddr) -> bool {
+    let runtime_config = runtime_config();
+    let config = &runtime_config.body_capture;
+    headers.contains_key(&config.trigger_header)
+        && config
+            .trusted_sources
+            .iter()
+            .any(|source| source.contains(&client_ip))
+}
+
+/// Information logged along a captured body.
+#[derive(Clone)]
+pub struct CaptureInfo {
+    pub app: String,
+    pub method: String,
+    pub path: String,
+    pub token_id: String,
+}
+
+/// A body forwarded untouched while its first `body_capture.max_bytes` bytes are kept, then
+/// logged with redacted secrets when the body is dropped.
+pub struct CapturedBody<B> {
+    inner: B,
+    direction: &'static str,
+    info: CaptureInfo,
+    buffer: BytesMut,
+    size: usize,
+}
+
+impl<B> CapturedBody<B> {
+    pub fn new(inner: B, direction: &'static str, info: CaptureInfo) -> Self {
+        Self {
+            inner,
+            direction,
+            info,
+            buffer: BytesMut::new(),
+            size: 0,
+        }
+    }
+}
+
+impl<B> Body for CapturedBody<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                let max_bytes = runtime_config().body_capture.max_bytes;
+                let kept = data.len().min(max_bytes.saturating_sub(self.buffer.len()));
+                self.buffer.extend_from_slice(&data[..kept]);
+                self.size += data.len();
+            }
+        }
+
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl<B> Drop for CapturedBody<B> {
+    fn drop(&mut self) {
+        let record = json!({
+            "direction": self.direction,
+            "app": self.info.app,
+            "method": self.info.method,
+            "path": self.info.path,
+            "token_id": self.info.token_id,
+            "size_bytes": self.size,
+            "truncated": self.size > self.buffer.len(),
+            "body": redact(&String::from_utf8_lossy(&self.buffer)),
+        });
+        info!(target: "body_capture", "{record}");
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use ring::hmac;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+use crate::api::ApiDefinition;
+use crate::permission::Permissions;
+use crate::runtime_config::{runtime_config, ChangeWebhookConfig};
+
+/// Prefix of the CloudEvents types.
+const EVENT_TYPE_PREFIX: &str = "fr.dgexsol.gateway";
+
+/// Header holding the HMAC-SHA256 of the body, when `change_webhook.secret` is set.
+const SIGNATURE_HEADER: &str = "X-Gateway-Signature";
+
+static EVENTS: OnceLock<UnboundedSender<Value>> = OnceLock::new();
+
+static EVENT_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Queue a CloudEvent to the `change_webhook`, if configured, its `source` being set once posted.
+fn notify(event_type: &str, subject: &str, data: Value) {
+    let Some(events) = EVENTS.get() else {
+        return;
+    };
+
+    let now = SystemTime::now();
+    let started_at = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let _ = events.send(json!({
+        "specversion": "1.0",
+        "id": format!("{:x}-{}", started_at.as_nanos(), EVENT_COUNT.fetch_add(1, Ordering::Relaxed)),
+        "type": format!("{EVENT_TYPE_PREFIX}.{event_type}"),
+        "subject": subject,
+        "time": humantime::format_rfc3339_millis(now).to_string(),
+        "datacontenttype": "application/json",
+        "data": data,
+    }));
+}
+
+fn describe_api(api: &ApiDefinition) -> Value {
+    json!({
+        "name": api.metadata.name,
+        "namespace": api.metadata.namespace,
+        "host": api.spec.host,
+        "enabled": api.spec.enabled,
+    })
+}
+
+/// Notify that `api` was added or, if its spec changed, updated.
+pub(crate) fn notify_api_applied(previous: Option<&ApiDefinition>, api: &ApiDefinition) {
+    let change = match previous {
+        None => "added",
+        Some(previous)
+            if serde_json::to_value(&previous.spec).ok()
+                == serde_json::to_value(&api.spec).ok() =>
+        {
+            return;
+        }
+        Some(_) => "updated",
+    };
+    notify(
+        &format!("apidefinition.{change}"),
+        &api.spec.app_name,
+        describe_api(api),
+    );
+}
+
+/// Notify that `api` is not served anymore.
+pub(crate) fn notify_api_removed(api: &ApiDefinition) {
+    notify(
+        "apidefinition.removed",
+        &api.spec.app_name,
+        describe_api(api),
+    );
+}
+
+/// Notify the users granted or revoked each permission, if any.
+pub(crate) fn notify_permissions_changed(previous: &Permissions, permissions: &Permissions) {
+    let empty = HashSet::new();
+    let mut changes = BTreeMap::new();
+    for name in previous.keys().chain(permissions.keys()) {
+        let before = previous.get(name).unwrap_or(&empty);
+        let after = permissions.get(name).unwrap_or(&empty);
+        if before == after {
+            continue;
+        }
+        let granted: BTreeSet<_> = after.difference(before).collect();
+        let revoked: BTreeSet<_> = before.difference(after).collect();
+        changes.insert(name, json!({ "granted": granted, "revoked": revoked }));
+    }
+
+    if !changes.is_empty() {
+        notify(
+            "permissions.changed",
+            "permissions",
+            json!({ "permissions": changes }),
+        );
+    }
+}
+
+async fn post_event(
+    client: &Client<HttpConnector, Full<Bytes>>,
+    config: &ChangeWebhookConfig,
+    mut event: Value,
+) -> Result<()> {
+    event["source"] = config.source.clone().into();
+    let body = event.to_string();
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(config.webhook.clone())
+        .header(CONTENT_TYPE, "application/cloudevents+json");
+    for (name, value) in &config.headers {
+        request = request.header(name, value);
+    }
+    if let Some(secret) = &config.secret {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let signature: String = hmac::sign(&key, body.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        request = request.header(SIGNATURE_HEADER, format!("sha256={signature}"));
+    }
+    let request = request.body(Full::new(Bytes::from(body)))?;
+
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        bail!("Unexpected status {}", response.status());
+    }
+
+    Ok(())
+}
+
+/// Post the changes of the served apis and of the permissions to the `change_webhook`, if
+/// configured.
+pub async fn run_change_notifier() -> Result<()> {
+    let runtime_config = runtime_config();
+    let Some(config) = &runtime_config.change_webhook else {
+        return Ok(());
+    };
+
+    let (tx, mut rx) = unbounded_channel();
+    // The notifier is only started once.
+    let _ = EVENTS.set(tx);
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+
+    while let Some(event) = rx.recv().await {
+        if let Err(e) = post_event(&client, config, event).await {
+            warn!("event='Fail to notify change to {}: {e}'", config.webhook);
+        }
+    }
+
+    Ok(())
+}
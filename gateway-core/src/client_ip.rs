@@ -0,0 +1,75 @@
+use std::net::IpAddr;
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+
+use crate::runtime_config::runtime_config;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+fn is_trusted(ip: IpAddr) -> bool {
+    runtime_config()
+        .trusted_proxies
+        .iter()
+        .any(|proxy| proxy.contains(&ip))
+}
+
+/// Addresses of `X-Forwarded-For`, from the client to the last proxy.
+fn get_forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect()
+}
+
+/// Get the IP of the client, read from `X-Forwarded-For` or `X-Real-IP` only if the request comes
+/// from one of the `trusted_proxies`. The first untrusted address of `X-Forwarded-For` starting
+/// from the last proxy is the client one, as addresses before it may be forged.
+pub fn get_client_ip(headers: &HeaderMap, remote_ip: IpAddr) -> IpAddr {
+    if !is_trusted(remote_ip) {
+        return remote_ip;
+    }
+
+    let forwarded_for = get_forwarded_for(headers);
+    if let Some(client_ip) = forwarded_for
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(**ip))
+        .or(forwarded_for.first())
+    {
+        return *client_ip;
+    }
+
+    headers
+        .get(X_REAL_IP)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(remote_ip)
+}
+
+/// Set `X-Forwarded-For` and `X-Real-IP` of a forwarded request, the headers sent by untrusted
+/// sources being replaced.
+pub fn inject_forwarded_headers(headers: &mut HeaderMap, client_ip: IpAddr, remote_ip: IpAddr) {
+    let mut forwarded_for = if is_trusted(remote_ip) {
+        get_forwarded_for(headers)
+    } else {
+        Vec::new()
+    };
+    forwarded_for.push(remote_ip);
+
+    let forwarded_for = forwarded_for
+        .iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&client_ip.to_string()) {
+        headers.insert(X_REAL_IP, value);
+    }
+}
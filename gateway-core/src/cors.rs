@@ -0,0 +1,141 @@
+use hyper::header::{
+    HeaderName, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    VARY,
+};
+use hyper::{HeaderMap, Method, Request};
+
+use crate::runtime_config::{runtime_config, CorsConfig};
+
+/// Headers of a request needed to build the CORS headers of its response, kept as the request is
+/// moved when forwarded.
+pub struct CorsRequest {
+    origin: Option<String>,
+    preflight: bool,
+    request_method: Option<String>,
+    request_headers: Option<String>,
+}
+
+impl CorsRequest {
+    pub fn new<B>(req: &Request<B>) -> Self {
+        let get_header = |name: HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        Self {
+            origin: get_header(ORIGIN),
+            preflight: req.method() == Method::OPTIONS,
+            request_method: get_header(ACCESS_CONTROL_REQUEST_METHOD),
+            request_headers: get_header(ACCESS_CONTROL_REQUEST_HEADERS),
+        }
+    }
+}
+
+/// Whether `value` matches `pattern`, where `*` matches any characters.
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = value.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Value of an allowed list, `*` being replaced by the requested value when credentials are
+/// allowed as browsers then take it literally.
+fn get_allowed(allowed: &[String], requested: Option<&str>, allow_credentials: bool) -> String {
+    if allow_credentials && allowed.iter().any(|value| value == "*") {
+        return requested.unwrap_or_default().to_string();
+    }
+    allowed.join(", ")
+}
+
+fn insert_header(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    match value.parse() {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(_) => warn!("event='Invalid {name} header: {value}'"),
+    }
+}
+
+/// Replace the CORS headers of a response with those of the `cors` policy.
+pub fn inject_cors(cors_request: &CorsRequest, headers: &mut HeaderMap) {
+    let runtime_config = runtime_config();
+    let config: &CorsConfig = &runtime_config.cors;
+
+    let any_origin = !config.allow_credentials && config.allowed_origins.iter().all(|o| o == "*");
+    if !any_origin {
+        headers.append(VARY, ORIGIN.as_str().parse().unwrap());
+    }
+    let Some(origin) = &cors_request.origin else {
+        return;
+    };
+    if !config
+        .allowed_origins
+        .iter()
+        .any(|pattern| matches_pattern(pattern, origin))
+    {
+        debug!("event='Origin {origin} not allowed'");
+        return;
+    }
+
+    insert_header(
+        headers,
+        ACCESS_CONTROL_ALLOW_ORIGIN,
+        if any_origin { "*" } else { origin },
+    );
+    if config.allow_credentials {
+        insert_header(headers, ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+    }
+    insert_header(
+        headers,
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        &config.expose_headers.join(", "),
+    );
+
+    if !cors_request.preflight {
+        return;
+    }
+    insert_header(
+        headers,
+        ACCESS_CONTROL_ALLOW_METHODS,
+        &get_allowed(
+            &config.allowed_methods,
+            cors_request.request_method.as_deref(),
+            config.allow_credentials,
+        ),
+    );
+    insert_header(
+        headers,
+        ACCESS_CONTROL_ALLOW_HEADERS,
+        &get_allowed(
+            &config.allowed_headers,
+            cors_request.request_headers.as_deref(),
+            config.allow_credentials,
+        ),
+    );
+    insert_header(
+        headers,
+        ACCESS_CONTROL_MAX_AGE,
+        &config.max_age.as_secs().to_string(),
+    );
+}
@@ -6,6 +6,8 @@ use regex::Regex;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::permission::{find_permission, intern_permission, PermissionId};
+
 static PATH_TO_PERM: LazyLock<Regex> = LazyLock::new(|| Regex::new("\\{[^/]*\\}").unwrap());
 
 #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
@@ -16,8 +18,17 @@ pub struct Endpoint {
     pub is_websocket: bool,
     #[serde(skip)]
     pub permission: String,
+    #[serde(skip)]
+    pub permission_id: PermissionId,
     #[serde(default = "check_permission_default")]
     pub check_permission: bool,
+    /// Log the start of request and response bodies.
+    #[serde(default)]
+    pub capture_bodies: bool,
+    /// Media types of the accepted request bodies, such as `application/json` or `text/*`, others
+    /// being rejected with `415`. Any is accepted if empty.
+    #[serde(default)]
+    pub content_types: Vec<String>,
 }
 
 fn is_websocket_default() -> bool {
@@ -29,23 +40,61 @@ fn check_permission_default() -> bool {
 }
 
 impl Endpoint {
-    pub(crate) fn from_forward_all(path: String, method: String, app: &str) -> Self {
+    /// Endpoint of any path of a `forward_all` api, whose permission is interned unless the
+    /// method comes from a request.
+    pub(crate) fn from_forward_all(method: &str, app: &str, intern: bool) -> Self {
+        let permission = format!("{}::{}::FULL_ACCESS", &app[1..], method);
+        let permission_id = if intern {
+            intern_permission(&permission)
+        } else {
+            find_permission(&permission).unwrap_or_default()
+        };
         Self {
-            permission: format!("{}::{}::FULL_ACCESS", &app[1..], &method),
-            path,
-            method,
+            permission,
+            permission_id,
+            path: "/".to_string(),
+            method: method.to_string(),
             is_websocket: false,
             check_permission: true,
+            capture_bodies: false,
+            content_types: Vec::new(),
         }
     }
     pub(crate) fn check_fields(&self) -> Result<(), String> {
         self.check_path()?;
         self.check_parameters()?;
         self.check_method()?;
+        self.check_content_types()?;
 
         Ok(())
     }
 
+    /// Whether a request body of type `content_type` is accepted, its parameters such as
+    /// `charset` being ignored.
+    pub(crate) fn accepts_content_type(&self, content_type: Option<&str>) -> bool {
+        if self.content_types.is_empty() {
+            return true;
+        }
+        let Some(content_type) = content_type else {
+            return false;
+        };
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        self.content_types.iter().any(|accepted| {
+            let accepted = accepted.to_ascii_lowercase();
+            match accepted.strip_suffix('*') {
+                Some("*/") => true,
+                Some(prefix) => media_type.starts_with(prefix),
+                None => media_type == accepted,
+            }
+        })
+    }
+
     pub fn build_permission(&mut self, app: &str) {
         self.permission = format!(
             "{}::{}::{}",
@@ -53,6 +102,7 @@ impl Endpoint {
             self.method,
             PATH_TO_PERM.replace_all(&self.path, "{}")
         );
+        self.permission_id = intern_permission(&self.permission);
     }
 
     fn check_parameters(&self) -> Result<(), String> {
@@ -103,6 +153,30 @@ impl Endpoint {
         Ok(())
     }
 
+    fn check_content_types(&self) -> Result<(), String> {
+        for content_type in &self.content_types {
+            let valid = content_type.split_once('/').is_some_and(|(kind, subtype)| {
+                let is_token = |part: &str| {
+                    !part.is_empty()
+                        && part
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+                };
+                (is_token(kind) || kind == "*" && subtype == "*")
+                    && (is_token(subtype) || subtype == "*")
+            });
+            if !valid {
+                let err_msg = format!(
+                    "content_types: {content_type} must be a media type such as `application/json` or `text/*`"
+                );
+                info!("event='{}'", err_msg);
+                return Err(err_msg);
+            }
+        }
+
+        Ok(())
+    }
+
     fn check_method(&self) -> Result<(), String> {
         match Method::from_str(&self.method)
             .map(|_| ())
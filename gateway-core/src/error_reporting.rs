@@ -0,0 +1,120 @@
+use std::backtrace::Backtrace;
+use std::future::Future;
+use std::panic::{self, PanicHookInfo};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+use crate::runtime_config::{runtime_config, ErrorReportingConfig};
+
+tokio::task_local! {
+    /// Description of what the current task is doing, attached to its panic reports.
+    static TASK_CONTEXT: String;
+}
+
+static REPORTS: OnceLock<UnboundedSender<Value>> = OnceLock::new();
+
+/// Run `future` with a context describing it in the reports of its panics.
+pub async fn with_task_context<F: Future>(context: String, future: F) -> F::Output {
+    TASK_CONTEXT.scope(context, future).await
+}
+
+fn get_panic_message(info: &PanicHookInfo) -> String {
+    let payload = info.payload();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+/// Log panics with the context of their task, then queue them to the webhook if
+/// `error_reporting` is configured. Panics are otherwise only seen on stderr, while tokio keeps
+/// running without the panicked task.
+pub fn init_panic_hook() {
+    let default_hook = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        let context = TASK_CONTEXT
+            .try_with(|context| context.clone())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let message = get_panic_message(info);
+        let location = info
+            .location()
+            .map(|location| location.to_string())
+            .unwrap_or_default();
+
+        error!("event='Panic in {context} at {location}: {message}'");
+
+        if let Some(reports) = REPORTS.get() {
+            let _ = reports.send(json!({
+                "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                "version": env!("CARGO_PKG_VERSION"),
+                "thread": thread::current().name().unwrap_or("unnamed"),
+                "context": context,
+                "message": message,
+                "location": location,
+                "backtrace": Backtrace::force_capture().to_string(),
+            }));
+        }
+
+        default_hook(info);
+    }));
+}
+
+async fn post_report(
+    client: &Client<HttpConnector, Full<Bytes>>,
+    config: &ErrorReportingConfig,
+    report: Value,
+) -> Result<()> {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(config.webhook.clone())
+        .header(CONTENT_TYPE, "application/json");
+    for (name, value) in &config.headers {
+        request = request.header(name, value);
+    }
+    let request = request.body(Full::new(Bytes::from(report.to_string())))?;
+
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        bail!("Unexpected status {}", response.status());
+    }
+
+    Ok(())
+}
+
+/// Post the panic reports to the `error_reporting` webhook, if configured.
+pub async fn run_error_reporter() -> Result<()> {
+    let runtime_config = runtime_config();
+    let Some(config) = &runtime_config.error_reporting else {
+        return Ok(());
+    };
+
+    let (tx, mut rx) = unbounded_channel();
+    // The reporter is only started once.
+    let _ = REPORTS.set(tx);
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+
+    while let Some(report) = rx.recv().await {
+        if let Err(e) = post_report(&client, config, report).await {
+            warn!("event='Fail to report panic to {}: {e}'", config.webhook);
+        }
+    }
+
+    Ok(())
+}
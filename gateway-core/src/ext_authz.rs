@@ -0,0 +1,141 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::header::{HeaderName, CONTENT_TYPE};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tokio::time::timeout;
+
+use crate::auth::Claims;
+use crate::HttpClient;
+
+/// Bytes of the body of a denial kept to answer the request.
+const MAX_DENIAL_BODY_SIZE: usize = 64 * 1024;
+
+/// An external service deciding whether the requests of an API are forwarded, after their
+/// permission is checked.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct ExtAuthzSpec {
+    /// `http://` URI the requests are described to with a `POST`.
+    pub uri: String,
+    /// Milliseconds to wait for a decision.
+    #[serde(default = "timeout_ms_default")]
+    pub timeout_ms: u64,
+    /// Headers of an allowing response set on the forwarded request.
+    #[serde(default)]
+    pub upstream_headers: Vec<String>,
+    /// Forward requests when the service cannot be reached or times out, instead of answering
+    /// them with `403`.
+    #[serde(default)]
+    pub allow_on_error: bool,
+}
+
+fn timeout_ms_default() -> u64 {
+    1000
+}
+
+/// Decision of the external service for a single request.
+pub enum Decision {
+    /// Forward the request with these headers set.
+    Allow(HeaderMap),
+    /// Answer the request with the response of the service.
+    Deny(Response<Full<Bytes>>),
+}
+
+/// The request as described to the external service.
+pub struct CheckRequest<'a> {
+    pub app: &'a str,
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub headers: &'a HeaderMap,
+    pub claims: &'a Claims,
+    pub client_ip: IpAddr,
+}
+
+impl CheckRequest<'_> {
+    fn to_json(&self) -> Value {
+        let headers: Map<String, Value> = self
+            .headers
+            .keys()
+            .filter_map(|name| {
+                let value = self.headers.get(name)?.to_str().ok()?;
+                Some((name.to_string(), value.into()))
+            })
+            .collect();
+
+        json!({
+            "app": self.app,
+            "method": self.method.as_str(),
+            "path": self.path,
+            "headers": headers,
+            "client_ip": self.client_ip.to_string(),
+            "user": {
+                "sub": self.claims.sub,
+                "token_id": self.claims.token_id,
+                "preferred_username": self.claims.preferred_username,
+                "email": self.claims.email,
+            },
+        })
+    }
+}
+
+/// Describe the request to the external service as JSON, a `2xx` response allowing it and any
+/// other denying it.
+pub async fn check(
+    client: &HttpClient,
+    spec: &ExtAuthzSpec,
+    request: &CheckRequest<'_>,
+) -> Result<Decision> {
+    let check_request = Request::post(&spec.uri)
+        .header(CONTENT_TYPE, "application/json")
+        .body(
+            Full::from(request.to_json().to_string())
+                .map_err(|never| match never {})
+                .boxed(),
+        )?;
+
+    let response = timeout(
+        Duration::from_millis(spec.timeout_ms),
+        client.request(check_request),
+    )
+    .await
+    .map_err(|_| anyhow!("No decision after {}ms", spec.timeout_ms))??;
+
+    if response.status().is_success() {
+        let mut headers = HeaderMap::new();
+        for name in &spec.upstream_headers {
+            let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+                continue;
+            };
+            for value in response.headers().get_all(&name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        return Ok(Decision::Allow(headers));
+    }
+
+    // Statuses which do not deny, such as redirections, are replaced by `403`.
+    let status_code = if response.status().is_client_error() || response.status().is_server_error()
+    {
+        response.status()
+    } else {
+        StatusCode::FORBIDDEN
+    };
+    let content_type = response.headers().get(CONTENT_TYPE).cloned();
+    let body = Limited::new(response.into_body(), MAX_DENIAL_BODY_SIZE)
+        .collect()
+        .await
+        .map(|collected| collected.to_bytes())
+        .unwrap_or_default();
+
+    let mut denial = Response::builder().status(status_code);
+    if let Some(content_type) = content_type {
+        denial = denial.header(CONTENT_TYPE, content_type);
+    }
+    Ok(Decision::Deny(denial.body(Full::new(body))?))
+}
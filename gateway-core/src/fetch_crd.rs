@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use arc_swap::ArcSwap;
+use futures::{future, Stream, StreamExt, TryStreamExt};
+use kube::api::{Api, ApiResource, DynamicObject, ListParams, Patch, PatchParams};
+use kube::core::GroupVersionKind;
+use kube::{discovery, Client};
+use kube_runtime::utils::WatchStreamExt;
+use kube_runtime::watcher;
+use kube_runtime::watcher::Config;
+use serde_json::json;
+
+use crate::api::{ApiDefinition, MAINTENANCE_ANNOTATION};
+use crate::change_events::notify_api_applied;
+use crate::error_reporting::with_task_context;
+use crate::route::Node;
+
+/// The served api definitions with their routing tree, by app name.
+pub type Apis = HashMap<String, (Arc<ApiDefinition>, Arc<Node>)>;
+
+/// Snapshot of the served apis, read without locking by requests and replaced as a whole by
+/// writers.
+pub type ApiLock = Arc<ArcSwap<Apis>>;
+
+/// Check `apidefinition` and build its routing tree.
+pub fn build_api(
+    mut apidefinition: ApiDefinition,
+) -> Result<(Arc<ApiDefinition>, Arc<Node>), String> {
+    apidefinition.check_fields()?;
+    apidefinition.load_wasm_filter()?;
+    apidefinition.load_openapi()?;
+
+    let node = Node::new(&apidefinition);
+    let mut built_apidefinition = apidefinition;
+    built_apidefinition.build_uri();
+    built_apidefinition.spec.loaded_at = Some(SystemTime::now());
+    Ok((Arc::new(built_apidefinition), Arc::new(node)))
+}
+
+/// Check `apidefinition` and serve it, replacing the api with the same app name.
+pub fn insert_api(api_lock: &ApiLock, apidefinition: ApiDefinition) -> Result<(), String> {
+    let (built_apidefinition, node) = build_api(apidefinition)?;
+    let app_name = &built_apidefinition.spec.app_name;
+    let previous = api_lock.rcu(|apis| {
+        let mut apis = Apis::clone(apis);
+        apis.insert(
+            app_name.clone(),
+            (built_apidefinition.clone(), node.clone()),
+        );
+        apis
+    });
+    notify_api_applied(
+        previous.get(app_name).map(|(api, _)| &**api),
+        &built_apidefinition,
+    );
+    Ok(())
+}
+
+async fn read_crds(
+    mut stream: Pin<Box<dyn Stream<Item = Result<DynamicObject, watcher::Error>> + Send>>,
+    api_lock: ApiLock,
+) -> Result<()> {
+    loop {
+        match stream.try_next().await {
+            Err(e) => {
+                let err_msg = format!("Crd stream: {:?}", e);
+                error!("event='{}'", err_msg);
+                bail!(err_msg);
+            }
+            Ok(None) => {
+                info!("event='No apidefinition found'");
+            }
+            Ok(Some(ref apidefinition)) => match ApiDefinition::try_from(apidefinition) {
+                Err(e) => {
+                    let err_msg = format!(
+                        "event='An error occurs during apidefinition parsing: {}'",
+                        e
+                    );
+                    error!("event='{}'", err_msg);
+                }
+                Ok(apidefinition) => {
+                    let app_name = apidefinition.spec.app_name.clone();
+                    let name = apidefinition.metadata.name.clone();
+                    match insert_api(&api_lock, apidefinition) {
+                        Err(e) => {
+                            let err_msg = format!("Invalid apidefinition: {}", e);
+                            error!("event='{}'", err_msg);
+                        }
+                        Ok(_) => {
+                            info!(
+                                "event='{} api updated from {:?}'",
+                                &app_name,
+                                name.as_deref().unwrap_or("NO_NAME_DEFINED")
+                            );
+                        }
+                    }
+                }
+            },
+        };
+    }
+}
+
+async fn update_api_namespaced(
+    api_lock: ApiLock,
+    namespaces: Vec<String>,
+    api_resource: ApiResource,
+    client: Client,
+    watcher_config: watcher::Config,
+) -> Result<()> {
+    future::try_join_all(namespaces.iter().map(|ns| {
+        let apidefinitions =
+            Api::<DynamicObject>::namespaced_with(client.clone(), ns.as_str(), &api_resource);
+        let watcher = watcher(apidefinitions, watcher_config.clone());
+        let apply_apidefinitions = watcher.applied_objects().boxed();
+        tokio::spawn(with_task_context(
+            format!("apidefinitions watcher of namespace {ns}"),
+            read_crds(apply_apidefinitions, api_lock.clone()),
+        ))
+    }))
+    .await?;
+
+    Ok(())
+}
+
+async fn update_api_cluster(
+    api_lock: ApiLock,
+    api_resource: ApiResource,
+    client: Client,
+    watcher_config: watcher::Config,
+) -> Result<()> {
+    let apidefinitions = Api::<DynamicObject>::all_with(client.clone(), &api_resource);
+    let watcher = watcher(apidefinitions, watcher_config.clone());
+    let apply_apidefinitions = watcher.applied_objects().boxed();
+    read_crds(apply_apidefinitions, api_lock.clone()).await
+}
+
+async fn get_api_resource() -> Result<(Client, ApiResource)> {
+    let client = match Client::try_default().await {
+        Ok(client) => client,
+        Err(e) => {
+            let err_msg = format!("kube client: {:?}", e);
+            error!("event='{}'", err_msg);
+            bail!(err_msg);
+        }
+    };
+    let group = "gateway.dgexsol.fr";
+    let version = "v2";
+    let kind = "ApiDefinition";
+
+    let gvk = GroupVersionKind::gvk(group, version, kind);
+    // Use API discovery to identify more information about the type (like its plural)
+    let (ar, _caps) = discovery::pinned_kind(&client, &gvk).await?;
+
+    Ok((client, ar))
+}
+
+/// Check that the `ApiDefinition` CRD is installed and that its objects can be listed.
+pub async fn check_api_access(crds_namespace: Option<&[String]>) -> Result<()> {
+    let (client, ar) = get_api_resource().await?;
+    let lp = ListParams::default().limit(1);
+
+    match crds_namespace {
+        Some(namespaces) => {
+            for ns in namespaces {
+                Api::<DynamicObject>::namespaced_with(client.clone(), ns, &ar)
+                    .list(&lp)
+                    .await?;
+            }
+        }
+        None => {
+            Api::<DynamicObject>::all_with(client, &ar)
+                .list(&lp)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Turn the maintenance mode of the api of `app_name` on or off with its annotation, returning
+/// the updated api, or `None` if it is not served. It lasts until the api is loaded again.
+pub fn set_maintenance(api_lock: &ApiLock, app_name: &str, on: bool) -> Option<Arc<ApiDefinition>> {
+    let mut updated = None;
+    api_lock.rcu(|apis| {
+        let mut apis = Apis::clone(apis);
+        updated = apis.get_mut(app_name).map(|(api, _)| {
+            let mut api_definition = ApiDefinition::clone(api);
+            api_definition
+                .metadata
+                .annotations
+                .get_or_insert_with(Default::default)
+                .insert(MAINTENANCE_ANNOTATION.to_string(), on.to_string());
+            *api = Arc::new(api_definition);
+            api.clone()
+        });
+        apis
+    });
+    updated
+}
+
+/// Set the maintenance annotation of `api` in the cluster, so that it outlives the next loading
+/// of the api.
+pub async fn persist_maintenance(api: &ApiDefinition, on: bool) -> Result<()> {
+    let (Some(name), Some(namespace)) = (&api.metadata.name, &api.metadata.namespace) else {
+        bail!("The apidefinition has no name or namespace");
+    };
+    let (client, ar) = get_api_resource().await?;
+    let patch = json!({
+        "metadata": {
+            "annotations": { MAINTENANCE_ANNOTATION: on.to_string() },
+        },
+    });
+    Api::<DynamicObject>::namespaced_with(client, namespace, &ar)
+        .patch(name, &PatchParams::default(), &Patch::Merge(&patch))
+        .await?;
+    Ok(())
+}
+
+/// List the valid `ApiDefinition`s now and serve them as the watcher does, returning how many
+/// were applied.
+pub async fn refresh_apis(
+    api_lock: &ApiLock,
+    label_filter: &str,
+    crds_namespace: Option<&[String]>,
+) -> Result<usize> {
+    let mut applied = 0;
+    for apidefinition in list_apis(label_filter, crds_namespace).await? {
+        let app_name = apidefinition.spec.app_name.clone();
+        match insert_api(api_lock, apidefinition) {
+            Ok(()) => applied += 1,
+            Err(e) => error!("event='Invalid apidefinition {app_name}: {e}'"),
+        }
+    }
+    info!("event='{applied} apis refreshed'");
+    Ok(applied)
+}
+
+/// List the valid `ApiDefinition`s once, instead of watching them.
+pub async fn list_apis(
+    label_filter: &str,
+    crds_namespace: Option<&[String]>,
+) -> Result<Vec<ApiDefinition>> {
+    let (client, ar) = get_api_resource().await?;
+    let lp = ListParams::default().labels(label_filter);
+
+    let objects = match crds_namespace {
+        Some(namespaces) => {
+            let mut objects = Vec::new();
+            for ns in namespaces {
+                let apidefinitions = Api::<DynamicObject>::namespaced_with(client.clone(), ns, &ar);
+                objects.extend(apidefinitions.list(&lp).await?.items);
+            }
+            objects
+        }
+        None => {
+            Api::<DynamicObject>::all_with(client, &ar)
+                .list(&lp)
+                .await?
+                .items
+        }
+    };
+
+    let mut apis = Vec::new();
+    for object in &objects {
+        match ApiDefinition::try_from(object) {
+            Ok(api) if api.check_fields().is_ok() => apis.push(api),
+            Ok(_) => warn!(
+                "event='Skipping invalid apidefinition {:?}'",
+                object.metadata.name
+            ),
+            Err(e) => warn!(
+                "event='Skipping apidefinition {:?}: {e}'",
+                object.metadata.name
+            ),
+        }
+    }
+
+    Ok(apis)
+}
+
+pub async fn update_api(
+    api_lock: ApiLock,
+    label_filter: String,
+    crds_namespace: Option<Vec<String>>,
+) -> Result<()> {
+    let (client, ar) = get_api_resource().await?;
+
+    let lp = Config::default().labels(&label_filter);
+
+    match crds_namespace {
+        Some(namespaces) => update_api_namespaced(api_lock, namespaces, ar, client, lp).await,
+        None => update_api_cluster(api_lock, ar, client, lp).await,
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use kube::core::TypeMeta;
+use kube::Resource;
+use serde::Deserialize;
+use serde_yaml::Value;
+use tokio::time::sleep;
+
+use crate::api::ApiDefinition;
+use crate::change_events::{notify_api_applied, notify_api_removed};
+use crate::fetch_crd::{build_api, ApiLock};
+use crate::runtime_config::ApiDirConfig;
+
+/// Extensions of the files read from the directory.
+const API_EXTENSIONS: [&str; 2] = ["yaml", "yml"];
+
+/// Modification time of each file of the directory, to detect changes.
+type Snapshot = BTreeMap<PathBuf, SystemTime>;
+
+fn take_snapshot(dir: &Path) -> Result<Snapshot> {
+    let mut snapshot = Snapshot::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_api_file = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| API_EXTENSIONS.contains(&extension));
+        if !is_api_file {
+            continue;
+        }
+        // Symlinks are followed, as in the mounted config maps.
+        let metadata = fs::metadata(&path)?;
+        if metadata.is_file() {
+            snapshot.insert(path, metadata.modified()?);
+        }
+    }
+
+    Ok(snapshot)
+}
+
+/// Read the `ApiDefinition`s of a file, separated by `---`, as they would be applied to the
+/// cluster.
+fn read_file(path: &Path) -> Result<Vec<ApiDefinition>> {
+    let content = fs::read_to_string(path)?;
+    let mut apis = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(&content) {
+        let value = Value::deserialize(document)?;
+        let types: TypeMeta = serde_yaml::from_value(value.clone())?;
+        if types.api_version != ApiDefinition::api_version(&())
+            || types.kind != ApiDefinition::kind(&())
+        {
+            bail!(
+                "{} of {} is not an ApiDefinition",
+                types.kind,
+                types.api_version
+            );
+        }
+        apis.push(serde_yaml::from_value(value)?);
+    }
+
+    Ok(apis)
+}
+
+/// Read the `ApiDefinition`s of the directory once, skipping invalid files and definitions.
+pub fn read_api_dir(dir: &Path) -> Result<Vec<ApiDefinition>> {
+    let mut apis = Vec::new();
+    for path in take_snapshot(dir)?.into_keys() {
+        match read_file(&path) {
+            Ok(file_apis) => apis.extend(file_apis.into_iter().filter(|api| {
+                let valid = api.check_fields().is_ok();
+                if !valid {
+                    warn!(
+                        "event='Skipping invalid apidefinition {} of {}'",
+                        api.spec.app_name,
+                        path.display()
+                    );
+                }
+                valid
+            })),
+            Err(e) => warn!("event='Skipping {}: {e}'", path.display()),
+        }
+    }
+
+    Ok(apis)
+}
+
+/// Serve the `ApiDefinition`s of the changed files, keeping the previous version of those which
+/// are not valid anymore and removing those whose file was removed.
+fn load_api_dir(
+    api_lock: &ApiLock,
+    snapshot: &Snapshot,
+    previous: &Snapshot,
+    files: &mut HashMap<PathBuf, Vec<ApiDefinition>>,
+) {
+    files.retain(|path, _| snapshot.contains_key(path));
+    for (path, modified) in snapshot {
+        if previous.get(path) == Some(modified) {
+            continue;
+        }
+        match read_file(path) {
+            Ok(apis) => {
+                files.insert(path.clone(), apis);
+            }
+            Err(e) => error!("event='Invalid apidefinition file {}: {e}'", path.display()),
+        }
+    }
+
+    let mut apis = HashMap::new();
+    let mut invalid = Vec::new();
+    for path in snapshot.keys() {
+        for apidefinition in files.get(path).into_iter().flatten() {
+            let app_name = apidefinition.spec.app_name.clone();
+            if apis.contains_key(&app_name) {
+                warn!(
+                    "event='{app_name} api of {} replaces a previous one'",
+                    path.display()
+                );
+            }
+            match build_api(apidefinition.clone()) {
+                Ok(built) => {
+                    apis.insert(app_name, built);
+                }
+                Err(e) => {
+                    error!("event='Invalid apidefinition: {e}'");
+                    apis.remove(&app_name);
+                    invalid.push(app_name);
+                }
+            }
+        }
+    }
+
+    let current = api_lock.load();
+    for (app_name, (api, _)) in current.iter() {
+        if !apis.contains_key(app_name) && !invalid.contains(app_name) {
+            info!("event='{app_name} api removed'");
+            notify_api_removed(api);
+        }
+    }
+    for (app_name, (api, _)) in &apis {
+        notify_api_applied(current.get(app_name).map(|(previous, _)| &**previous), api);
+    }
+    for app_name in invalid {
+        if let Some(kept) = current.get(&app_name).cloned() {
+            apis.insert(app_name, kept);
+        }
+    }
+    info!(
+        "event='{} apis loaded from {} files'",
+        apis.len(),
+        snapshot.len()
+    );
+    api_lock.store(Arc::new(apis));
+}
+
+/// Load the `ApiDefinition`s of all the files of `config.path` now, as if each of them changed,
+/// returning how many apis are served.
+pub fn refresh_api_dir(api_lock: &ApiLock, config: &ApiDirConfig) -> Result<usize> {
+    let snapshot = take_snapshot(&config.path)?;
+    load_api_dir(api_lock, &snapshot, &Snapshot::new(), &mut HashMap::new());
+    Ok(api_lock.load().len())
+}
+
+/// Serve the `ApiDefinition`s of the files of `config.path`, loading them again whenever a file
+/// is added, changed or removed.
+pub async fn update_api_from_dir(api_lock: ApiLock, config: &ApiDirConfig) -> Result<()> {
+    let mut previous = Snapshot::new();
+    let mut files = HashMap::new();
+
+    loop {
+        match take_snapshot(&config.path) {
+            Ok(snapshot) if snapshot != previous => {
+                load_api_dir(&api_lock, &snapshot, &previous, &mut files);
+                previous = snapshot;
+            }
+            Ok(_) => (),
+            Err(e) => error!("event='Cannot read {}: {e}'", config.path.display()),
+        }
+
+        sleep(config.poll_interval).await;
+    }
+}
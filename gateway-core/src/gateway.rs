@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
+use tokio::net::TcpListener;
+
+use crate::api::ApiDefinition;
+use crate::change_events::{notify_api_removed, notify_permissions_changed, run_change_notifier};
+use crate::fetch_crd::{insert_api, Apis};
+use crate::middleware::GatewayState;
+use crate::permission::{get_perm, update_perm, PermissionIndex, Permissions, Roles};
+use crate::{http_client, serve_gateway, HttpClient};
+
+/// Builder of a [`Gateway`] whose apis are registered by the caller instead of being watched in
+/// the cluster.
+#[derive(Default)]
+pub struct GatewayBuilder {
+    apis: Vec<ApiDefinition>,
+    permissions: Option<(Permissions, Roles)>,
+    client: Option<HttpClient>,
+}
+
+impl GatewayBuilder {
+    /// Serve `api`, replacing any registered before with the same app name.
+    pub fn register_api(mut self, api: ApiDefinition) -> Self {
+        self.apis.push(api);
+        self
+    }
+
+    /// Check requests against these permissions and roles, which are otherwise fetched from the
+    /// `perm_uris` of the runtime config and refreshed while serving.
+    pub fn permissions(mut self, permissions: Permissions, roles: Roles) -> Self {
+        self.permissions = Some((permissions, roles));
+        self
+    }
+
+    /// Forward requests with `client` instead of a new one.
+    pub fn client(mut self, client: HttpClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Check the registered apis and fetch the permissions if none were given.
+    pub async fn build(self) -> Result<Gateway> {
+        let fetch_permissions = self.permissions.is_none();
+        let (permissions, roles) = match self.permissions {
+            Some(permissions) => permissions,
+            None => get_perm().await?,
+        };
+
+        let gateway = Gateway {
+            state: GatewayState {
+                client: self.client.unwrap_or_else(http_client),
+                perm_lock: Arc::new(ArcSwap::from_pointee(PermissionIndex::new(permissions))),
+                role_lock: Arc::new(ArcSwap::from_pointee(roles)),
+                api_lock: Arc::new(ArcSwap::from_pointee(HashMap::new())),
+            },
+            fetch_permissions,
+        };
+        for api in self.apis {
+            gateway.register_api(api).await?;
+        }
+        Ok(gateway)
+    }
+
+    /// Build the gateway and serve it on `listener`.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        self.build().await?.serve(listener).await
+    }
+}
+
+/// A gateway embedded in another program, whose apis and permissions can be changed while it
+/// serves. Clones share them.
+#[derive(Clone)]
+pub struct Gateway {
+    state: GatewayState,
+    fetch_permissions: bool,
+}
+
+impl Gateway {
+    pub fn builder() -> GatewayBuilder {
+        GatewayBuilder::default()
+    }
+
+    /// Check `api` and serve it, replacing the api with the same app name.
+    pub async fn register_api(&self, api: ApiDefinition) -> Result<()> {
+        let app_name = api.spec.app_name.clone();
+        insert_api(&self.state.api_lock, api)
+            .map_err(|e| anyhow!("Invalid apidefinition {app_name}: {e}"))?;
+        info!("event='{app_name} api registered'");
+        Ok(())
+    }
+
+    /// Stop serving the api of `app_name`, returning whether it was served.
+    pub async fn deregister_api(&self, app_name: &str) -> bool {
+        let mut removed = None;
+        self.state.api_lock.rcu(|apis| {
+            let mut apis = Apis::clone(apis);
+            removed = apis.remove(app_name);
+            apis
+        });
+        let Some((api, _)) = removed else {
+            return false;
+        };
+        info!("event='{app_name} api deregistered'");
+        notify_api_removed(&api);
+        true
+    }
+
+    /// Replace the permissions and roles requests are checked against.
+    pub async fn set_permissions(&self, permissions: Permissions, roles: Roles) {
+        notify_permissions_changed(&self.state.perm_lock.load().to_permissions(), &permissions);
+        self.state
+            .perm_lock
+            .store(Arc::new(PermissionIndex::new(permissions)));
+        self.state.role_lock.store(Arc::new(roles));
+    }
+
+    /// State of the pipeline, to build it with `gateway_service`.
+    pub fn state(&self) -> &GatewayState {
+        &self.state
+    }
+
+    /// Accept connections on `listener` forever, refreshing the permissions fetched by `build`
+    /// and posting changes to the `change_webhook`.
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        let serve = serve_gateway(listener, self.state.clone());
+        if !self.fetch_permissions {
+            return tokio::try_join!(run_change_notifier(), serve).map(|_| ());
+        }
+
+        let update_perm = update_perm(self.state.perm_lock.clone(), self.state.role_lock.clone());
+        tokio::try_join!(run_change_notifier(), update_perm, serve).map(|_| ())
+    }
+}
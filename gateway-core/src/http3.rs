@@ -0,0 +1,270 @@
+//! HTTP/3 listener, serving the gateway over QUIC next to the TCP listener of `bind_to`.
+//!
+//! Requests go through the same pipeline, as HTTP/1.1 requests whose body is read from their
+//! QUIC stream, and are forwarded to the upstream servers over HTTP/1.1.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, Bytes};
+use h3::error::{Code, StreamError};
+use h3::server::{RequestResolver, RequestStream};
+use http_body_util::{BodyExt, Either};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Request, Response, Version};
+use log::{error, info};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Endpoint, ServerConfig};
+use tower::ServiceExt;
+
+use crate::admin::is_draining;
+use crate::error_reporting::with_task_context;
+use crate::metrics::ConnectionMetricsGuard;
+use crate::middleware::{gateway_service, GatewayState, Next, RemoteAddr};
+use crate::runtime_config::{runtime_config, Http3Config, TlsPolicyConfig};
+use crate::tls::quic_server_config_builder;
+
+/// Headers specific to a HTTP/1.1 connection, forbidden in HTTP/3 responses.
+const CONNECTION_HEADERS: [&str; 4] = ["connection", "keep-alive", "transfer-encoding", "upgrade"];
+
+type H3Connection = h3::server::Connection<h3_quinn::Connection, Bytes>;
+
+/// Body of a request read from its QUIC stream, followed by its trailers if any.
+pub struct QuicBody {
+    stream: RequestStream<h3_quinn::RecvStream, Bytes>,
+    size_hint: SizeHint,
+    data_done: bool,
+    done: bool,
+}
+
+impl QuicBody {
+    fn new(
+        stream: RequestStream<h3_quinn::RecvStream, Bytes>,
+        content_length: Option<u64>,
+    ) -> Self {
+        Self {
+            stream,
+            size_hint: content_length.map_or_else(SizeHint::default, SizeHint::with_exact),
+            data_done: false,
+            done: false,
+        }
+    }
+}
+
+impl Body for QuicBody {
+    type Data = Bytes;
+    type Error = StreamError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, StreamError>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        if !self.data_done {
+            match ready!(self.stream.poll_recv_data(cx)) {
+                Ok(Some(mut data)) => {
+                    let data = data.copy_to_bytes(data.remaining());
+                    return Poll::Ready(Some(Ok(Frame::data(data))));
+                }
+                Ok(None) => self.data_done = true,
+                Err(err) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
+
+        let trailers = ready!(self.stream.poll_recv_trailers(cx));
+        self.done = true;
+        Poll::Ready(
+            trailers
+                .transpose()
+                .map(|trailers| trailers.map(Frame::trailers)),
+        )
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.size_hint.clone()
+    }
+}
+
+/// QUIC configuration of the listener, with its certificate and the `tls_policy`.
+fn server_config(config: &Http3Config, policy: &TlsPolicyConfig) -> Result<ServerConfig> {
+    let open = |path: &std::path::Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| anyhow!("Cannot open {}: {e}", path.display()))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(&config.cert_file)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("Invalid {}: {e}", config.cert_file.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate in {}", config.cert_file.display()));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&config.key_file)?)
+        .map_err(|e| anyhow!("Invalid {}: {e}", config.key_file.display()))?
+        .ok_or_else(|| anyhow!("No private key in {}", config.key_file.display()))?;
+
+    let mut tls_config = quic_server_config_builder(policy)
+        .map_err(|e| anyhow!("Invalid `tls_policy`: {e}"))?
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow!("Invalid certificate or key: {e}"))?;
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+
+    let quic_config = QuicServerConfig::try_from(tls_config)
+        .map_err(|e| anyhow!("Invalid `tls_policy` for QUIC: {e}"))?;
+    Ok(ServerConfig::with_crypto(Arc::new(quic_config)))
+}
+
+/// Check the certificate and key of the `http3` listener, if any.
+pub(crate) fn check_http3() -> Result<()> {
+    let runtime_config = runtime_config();
+    match &runtime_config.http3 {
+        Some(http3) => server_config(http3, &runtime_config.tls_policy).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Accept QUIC connections on `http3.bind_to` forever, if set, serving their requests with the
+/// pipeline of `gateway_service`.
+pub(crate) async fn run_http3_listener(state: GatewayState) -> Result<()> {
+    let (addr, server_config) = {
+        let runtime_config = runtime_config();
+        let Some(http3) = &runtime_config.http3 else {
+            return Ok(());
+        };
+        let addr: SocketAddr = http3
+            .bind_to
+            .parse()
+            .map_err(|_| anyhow!("Address http3.bind_to is not valid"))?;
+        (addr, server_config(http3, &runtime_config.tls_policy)?)
+    };
+
+    let endpoint = Endpoint::server(server_config, addr)
+        .map_err(|err| anyhow!("Could not listen on {addr}: {err}"))?;
+    info!("event='Listening on https://{} over HTTP/3'", addr);
+
+    let service = gateway_service(state);
+    while let Some(incoming) = endpoint.accept().await {
+        let service = service.clone();
+        let remote_addr = incoming.remote_address();
+
+        let context = format!("http3 connection from {remote_addr}");
+        tokio::task::spawn(with_task_context(context, async move {
+            let mut connection_metrics = ConnectionMetricsGuard::new("http3");
+
+            let res = match incoming.await {
+                Ok(connection) => serve_connection(connection, remote_addr, service).await,
+                Err(err) => Err(err.into()),
+            };
+            match res {
+                Ok(()) => connection_metrics.set_reason("normal"),
+                Err(err) => {
+                    connection_metrics.set_reason("error");
+                    error!("Failed to serve connection: {err:?}");
+                }
+            }
+        }));
+    }
+
+    Ok(())
+}
+
+/// Serve the requests of a QUIC connection until it is closed, each on its own task.
+async fn serve_connection(
+    connection: quinn::Connection,
+    remote_addr: SocketAddr,
+    service: Next,
+) -> Result<()> {
+    let max_field_section_size = runtime_config().request_limits.max_header_bytes as u64;
+    let mut connection: H3Connection = h3::server::builder()
+        .max_field_section_size(max_field_section_size)
+        .build(h3_quinn::Connection::new(connection))
+        .await?;
+
+    loop {
+        match connection.accept().await {
+            Ok(Some(resolver)) => {
+                let service = service.clone();
+                tokio::task::spawn(async move {
+                    if let Err(err) = serve_request(resolver, remote_addr, service).await {
+                        error!("Failed to serve request: {err:?}");
+                    }
+                });
+                if is_draining() {
+                    // Clients open their next connections to another instance.
+                    connection.shutdown(0).await?;
+                }
+            }
+            Ok(None) => return Ok(()),
+            Err(err) if err.is_h3_no_error() => return Ok(()),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Serve a request of a QUIC stream with `service`, streaming the response body back.
+async fn serve_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    remote_addr: SocketAddr,
+    service: Next,
+) -> Result<()> {
+    let (req, stream) = resolver.resolve_request().await?;
+    let (mut send_stream, recv_stream) = stream.split();
+
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    let (mut parts, ()) = req.into_parts();
+    // The pipeline and the upstream servers speak HTTP/1.1.
+    parts.version = Version::HTTP_11;
+    parts.extensions.insert(RemoteAddr(remote_addr));
+    let req = Request::from_parts(
+        parts,
+        Either::Right(QuicBody::new(recv_stream, content_length)),
+    );
+
+    let (mut parts, body) = service.oneshot(req).await?.into_parts();
+    for header in CONNECTION_HEADERS {
+        parts.headers.remove(header);
+    }
+    send_stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+
+    let mut body = pin!(body);
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(err) => {
+                send_stream.stop_stream(Code::H3_INTERNAL_ERROR);
+                return Err(err);
+            }
+        };
+        match frame.into_data() {
+            Ok(data) => send_stream.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    send_stream.send_trailers(trailers).await?;
+                }
+            }
+        }
+    }
+
+    Ok(send_stream.finish().await?)
+}
@@ -0,0 +1,1015 @@
+//! Core of the gateway, which authenticates the requests of users, checks their permissions
+//! against the `ApiDefinition`s of the cluster and forwards them to their upstream servers,
+//! websockets included.
+//!
+//! The `gateway` binary runs it with [`run`], watching the `ApiDefinition`s of the cluster, while
+//! embedders and other control planes register them with a [`Gateway`]:
+//!
+//! ```no_run
+//! use gateway_core::api::{ApiDefinition, ApiDefinitionSpec};
+//! use gateway_core::runtime_config::set_config_path;
+//! use gateway_core::Gateway;
+//! use tokio::net::TcpListener;
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! set_config_path("runtime_config.yaml".into());
+//! let spec: ApiDefinitionSpec = serde_yaml::from_str(
+//!     "
+//!     app_name: /example
+//!     host: example.default.svc:8080
+//!     mode:
+//!       kind: forward_all
+//!     ",
+//! )?;
+//!
+//! Gateway::builder()
+//!     .register_api(ApiDefinition::new("example", spec))
+//!     .serve(TcpListener::bind("127.0.0.1:8000").await?)
+//!     .await
+//! # }
+//! ```
+//!
+//! The pipeline can also be built with [`gateway_service`] from a [`GatewayState`], and served
+//! with [`serve_gateway`].
+//!
+//! The runtime config is read from the file set with [`runtime_config::set_config_path`], which
+//! is required before anything else is called.
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::process::exit;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Either, Full};
+use hyper::body::{Body, Incoming};
+use hyper::header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{HeaderMap, Request, Response, StatusCode};
+use hyper_tungstenite::is_upgrade_request;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use opentelemetry::trace::SpanKind;
+use opentelemetry::Context;
+use ring::hmac;
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::runtime;
+use tokio::signal::ctrl_c;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tower::ServiceExt;
+
+mod access_log;
+mod admin;
+mod anomaly;
+pub mod api;
+mod audit;
+pub mod auth;
+pub mod bench;
+mod body_capture;
+mod change_events;
+mod client_ip;
+mod cors;
+pub mod endpoint;
+pub mod error_reporting;
+mod ext_authz;
+pub mod fetch_crd;
+pub mod fetch_dir;
+pub mod gateway;
+mod http3;
+pub mod log_level;
+mod log_sink;
+mod message_filter;
+pub mod metrics;
+pub mod middleware;
+pub mod openapi;
+mod openmetrics;
+mod otlp_metrics;
+pub mod permission;
+pub mod permission_audit;
+mod predicate;
+mod quota;
+mod redact;
+pub mod route;
+pub mod runtime_config;
+mod script;
+mod security_headers;
+mod self_check;
+mod smuggling;
+mod telemetry;
+mod tenancy;
+mod tls;
+mod token_binding;
+mod transform;
+mod wasm_filter;
+pub mod websocket;
+
+use crate::admin::{is_draining, run_admin_listener};
+use crate::api::ApiDefinition;
+use crate::auth::{load_token_sources, set_token_sources, Claims};
+use crate::body_capture::{CaptureInfo, CapturedBody};
+use crate::change_events::run_change_notifier;
+use crate::error_reporting::{run_error_reporter, with_task_context};
+use crate::fetch_crd::update_api;
+use crate::fetch_dir::update_api_from_dir;
+use crate::http3::{check_http3, run_http3_listener};
+use crate::log_sink::run_log_sinks;
+use crate::metrics::{commit_upstream_metrics, ConnectionMetricsGuard};
+use crate::middleware::{access_log, App, CaptureRequested, EnforcedPermission, Identity, Route};
+use crate::openapi::{reject, BodySchema};
+use crate::otlp_metrics::export_metrics;
+use crate::permission::{get_perm, update_perm, PermissionIndex, RoleLock};
+use crate::runtime_config::{load_runtime_config, runtime_config, set_runtime_config};
+use crate::self_check::run_self_check;
+use crate::smuggling::{remove_hop_by_hop_headers, FramingGuard, FramingState};
+use crate::telemetry::{end_span, init_tracing, inject_context, start_child_span};
+use crate::transform::scrub_response_headers;
+use crate::wasm_filter::{filtered_body, WasmFilter};
+use crate::websocket::{drain_tunnels, handle_upgrade, init_websocket_tls, TokenSession};
+
+pub use crate::gateway::{Gateway, GatewayBuilder};
+pub use crate::http3::QuicBody;
+pub use crate::middleware::{gateway_service, GatewayState, RemoteAddr};
+
+#[macro_use]
+extern crate log;
+
+pub type BoxResponse<D> = Response<BoxBody<D, anyhow::Error>>;
+/// Body of requests served by the gateway, streamed from a TCP connection or a QUIC stream.
+pub type RequestBody = Either<Incoming, QuicBody>;
+/// Body of requests forwarded to upstream servers.
+pub type ProxyBody = BoxBody<Bytes, anyhow::Error>;
+/// Client forwarding requests to upstream servers, shared by all of them.
+pub type HttpClient = Client<HttpConnector, ProxyBody>;
+
+const OK: &[u8] = b"Ok";
+const NOT_FOUND: &[u8] = b"Not Found";
+const FORBIDDEN: &[u8] = b"Forbidden";
+const BAD_GATEWAY: &[u8] = b"Bad Gateway";
+const BAD_REQUEST: &[u8] = b"Bad Request";
+const TOO_MANY_REQUESTS: &[u8] = b"Too Many Requests";
+const NO_CONTENT: &[u8] = b"";
+
+/// Pending connections of each listener of `workers`.
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// A list of headers that will NOT be forwarded to the server.
+const REMOVED_HEADERS: [&str; 1] = ["Authorization"];
+
+/// Identity headers signed with `identity_signature.key`, in the order of the signed string.
+const IDENTITY_HEADERS: [&str; 7] = [
+    "X-Forwarded-User",
+    "X-Forwarded-User-Username",
+    "X-Forwarded-User-First-Name",
+    "X-Forwarded-User-Last-Name",
+    "X-Forwarded-User-Email",
+    "X-Forwarded-User-Roles",
+    "X-Forwarded-User-Type",
+];
+const IDENTITY_TIMESTAMP_HEADER: &str = "X-Forwarded-User-Timestamp";
+const IDENTITY_SIGNATURE_HEADER: &str = "X-Forwarded-User-Signature";
+
+fn into_boxed_response<B>(response: Response<B>) -> BoxResponse<B::Data>
+where
+    B: Body + Send + Sync + 'static,
+    B::Error: std::error::Error + Send + Sync,
+{
+    response.map(|body| body.map_err(|err| anyhow!("Invalid Body: {err}")).boxed())
+}
+
+/// Box a forwarded body, streamed from the connection or buffered by a filter, only once. Its
+/// frames are passed on as they are, and only copied up to `body_capture.max_bytes` if captured.
+fn forwarded_body<B>(
+    body: B,
+    direction: &'static str,
+    capture_info: Option<CaptureInfo>,
+) -> ProxyBody
+where
+    B: Body<Data = Bytes> + Send + Sync + Unpin + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let body_error = |err: B::Error| anyhow!(err.into());
+    match capture_info {
+        Some(capture_info) => CapturedBody::new(body, direction, capture_info)
+            .map_err(body_error)
+            .boxed(),
+        None => body.map_err(body_error).boxed(),
+    }
+}
+
+#[inline(always)]
+fn get_response(status_code: StatusCode, content: &'static [u8]) -> Result<Response<Full<Bytes>>> {
+    let response: Response<Full<Bytes>> = Response::builder()
+        .status(status_code)
+        .body(content.into())?;
+
+    debug!("event='Response built'");
+    Ok(response)
+}
+
+fn inject_headers(
+    headers: &mut HeaderMap<HeaderValue>,
+    claims: &Claims,
+    app_user_roles: &str,
+    token_type: &str,
+    forward_authorization: bool,
+    claim_headers: &BTreeMap<String, String>,
+) {
+    if !forward_authorization {
+        for header in REMOVED_HEADERS {
+            headers.remove(header);
+        }
+    }
+    // Identity headers sent by the client are never forwarded, even when a claim is not a valid
+    // header value, so that only the ones set below are trusted and signed.
+    for header in IDENTITY_HEADERS {
+        headers.remove(header);
+    }
+    headers.remove(IDENTITY_TIMESTAMP_HEADER);
+    headers.remove(IDENTITY_SIGNATURE_HEADER);
+    if let Ok(value) = claims.token_id.parse() {
+        headers.insert("X-Forwarded-User", value);
+    } else {
+        info!("event='No token_id in token'");
+    }
+    if let Ok(value) = claims.preferred_username.parse() {
+        headers.insert("X-Forwarded-User-Username", value);
+    } else {
+        info!("event='No username in token'");
+    }
+    if let Ok(value) = claims.given_name.parse() {
+        headers.insert("X-Forwarded-User-First-Name", value);
+    } else {
+        info!("event='No user first name in token'");
+    }
+    if let Ok(value) = claims.family_name.parse() {
+        headers.insert("X-Forwarded-User-Last-Name", value);
+    } else {
+        info!("event='No user last name in token'");
+    }
+    if let Ok(value) = claims.email.parse() {
+        headers.insert("X-Forwarded-User-Email", value);
+    } else {
+        info!("event='No user email in token'");
+    }
+    if let Ok(value) = app_user_roles.parse() {
+        headers.insert("X-Forwarded-User-Roles", value);
+    } else {
+        info!("event='No user roles in token'");
+    }
+    if let Ok(value) = token_type.parse() {
+        headers.insert("X-Forwarded-User-Type", value);
+    } else {
+        info!("event='No token type in token'");
+    }
+    for (name, template) in claim_headers {
+        let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+            continue;
+        };
+        headers.remove(&name);
+        match claims
+            .render_template(template)
+            .map(|value| HeaderValue::from_str(&value))
+        {
+            Some(Ok(value)) => {
+                headers.insert(name, value);
+            }
+            Some(Err(_)) => info!("event='Claims of {name} are not a valid header value'"),
+            None => debug!("event='Missing claims of {name} in token'"),
+        }
+    }
+}
+
+/// Sign the identity headers for the upstream server, with the HMAC-SHA256 of the current Unix
+/// timestamp followed by the value of each of `IDENTITY_HEADERS`, one per line, missing headers
+/// being empty.
+fn sign_identity_headers(headers: &mut HeaderMap<HeaderValue>, key: &str) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_string();
+    let mut signed = timestamp.as_bytes().to_vec();
+    for name in IDENTITY_HEADERS {
+        signed.push(b'\n');
+        if let Some(value) = headers.get(name) {
+            signed.extend_from_slice(value.as_bytes());
+        }
+    }
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+    let signature: String = hmac::sign(&key, &signed)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    if let Ok(value) = HeaderValue::from_str(&format!("sha256={signature}")) {
+        headers.insert(IDENTITY_SIGNATURE_HEADER, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&timestamp) {
+        headers.insert(IDENTITY_TIMESTAMP_HEADER, value);
+    }
+}
+
+/// Inject the identity headers of the user of `api`, signed with `identity_signature` if set.
+fn inject_identity(
+    headers: &mut HeaderMap<HeaderValue>,
+    claims: &Claims,
+    token_type: &str,
+    api: &ApiDefinition,
+    role_lock: &RoleLock,
+) {
+    let roles_snapshot = role_lock.load();
+
+    let roles = roles_snapshot
+        .get(&claims.token_id)
+        .and_then(|roles| roles.get(&api.spec.app_name[1..]))
+        .map(String::as_str)
+        .unwrap_or("");
+
+    inject_headers(
+        headers,
+        claims,
+        roles,
+        token_type,
+        api.spec.forward_authorization,
+        &api.spec.claim_headers,
+    );
+    if let Some(identity_signature) = &runtime_config().identity_signature {
+        sign_identity_headers(headers, &identity_signature.key);
+    }
+}
+
+/// Forward the request to its route, the innermost service of the pipeline built by
+/// `gateway_service`.
+async fn proxy(mut req: Request<RequestBody>, state: GatewayState) -> Result<BoxResponse<Bytes>> {
+    let access_log = access_log(&req);
+    let cx = Context::current();
+    let App(app) = req
+        .extensions_mut()
+        .remove()
+        .ok_or_else(|| anyhow!("Missing app of the request"))?;
+    let Identity { claims, token_type } = req
+        .extensions_mut()
+        .remove()
+        .ok_or_else(|| anyhow!("Missing identity of the request"))?;
+    let Route {
+        api,
+        endpoint,
+        forwarded_uri,
+        http_uri,
+        ..
+    } = req
+        .extensions_mut()
+        .remove()
+        .ok_or_else(|| anyhow!("Missing route of the request"))?;
+    let EnforcedPermission(permission) = req
+        .extensions_mut()
+        .remove()
+        .unwrap_or(EnforcedPermission(None));
+    let CaptureRequested(capture_requested) = req
+        .extensions_mut()
+        .remove()
+        .unwrap_or(CaptureRequested(false));
+    let body_schema: Option<BodySchema> = req.extensions_mut().remove();
+
+    let mut wasm_filter = match api
+        .spec
+        .wasm_filter
+        .as_ref()
+        .map(WasmFilter::new)
+        .transpose()
+    {
+        Ok(wasm_filter) => wasm_filter,
+        Err(rejection) => return rejection.into_response(&access_log),
+    };
+    let filter_bodies = api
+        .spec
+        .wasm_filter
+        .as_ref()
+        .is_some_and(|wasm_filter| wasm_filter.bodies);
+
+    if !(endpoint.is_websocket && is_upgrade_request(&req)) {
+        remove_hop_by_hop_headers(req.headers_mut());
+    }
+
+    inject_identity(
+        req.headers_mut(),
+        &claims,
+        &token_type,
+        &api,
+        &state.role_lock,
+    );
+
+    if let Some(wasm_filter) = &mut wasm_filter {
+        if let Err(rejection) = wasm_filter.on_request_headers(req.headers_mut()) {
+            return rejection.into_response(&access_log);
+        }
+    }
+
+    if endpoint.is_websocket && is_upgrade_request(&req) {
+        inject_context(&cx, req.headers_mut());
+        let ws_uri = api.ws_uri(&forwarded_uri);
+        access_log.lock().set_upstream_uri(&ws_uri);
+        return handle_upgrade(
+            &app,
+            req,
+            &ws_uri,
+            &api.spec.websocket,
+            TokenSession {
+                token_id: claims.token_id.clone(),
+                exp: claims.exp as u64,
+                permission,
+                perm_lock: state.perm_lock,
+            },
+            &cx,
+            &access_log,
+        )
+        .await
+        .map(into_boxed_response);
+    }
+
+    if endpoint.is_websocket {
+        debug!("event='Websocket require upgrade'");
+
+        return get_response(StatusCode::UPGRADE_REQUIRED, NO_CONTENT).map(into_boxed_response);
+    }
+
+    match http_uri.parse() {
+        Ok(uri) => *req.uri_mut() = uri,
+        Err(e) => {
+            error!("error='Uri parsing error: {:?}'", e);
+            access_log
+                .lock()
+                .set_error(format!("Uri parsing error: {e:?}"));
+
+            return get_response(StatusCode::NOT_FOUND, NOT_FOUND).map(into_boxed_response);
+        }
+    };
+
+    let method = req.method().clone();
+
+    let capture_info = (capture_requested || api.spec.capture_bodies || endpoint.capture_bodies)
+        .then(|| CaptureInfo {
+            app: app.clone(),
+            method: method.to_string(),
+            path: req.uri().path().to_string(),
+            token_id: claims.token_id.clone(),
+        });
+
+    let (mut parts, body) = req.into_parts();
+    let body = match body_schema {
+        Some(body_schema) => match body_schema.validate(body).await {
+            Ok(body) => Either::Right(filtered_body(&mut parts.headers, body)),
+            Err((status_code, error)) => return reject(status_code, error, &access_log),
+        },
+        None => Either::Left(body),
+    };
+    let body = match &mut wasm_filter {
+        Some(wasm_filter) if filter_bodies => match wasm_filter.on_request_body(body).await {
+            Ok(body) => Either::Right(filtered_body(&mut parts.headers, body)),
+            Err(rejection) => return rejection.into_response(&access_log),
+        },
+        _ => body,
+    };
+    let mut req = Request::from_parts(parts, forwarded_body(body, "request", capture_info.clone()));
+
+    let upstream_cx = start_child_span(&cx, "upstream", SpanKind::Client);
+    inject_context(&upstream_cx, req.headers_mut());
+
+    let request_start_time = Instant::now();
+
+    let response = state.client.request(req).await;
+
+    let request_duration = request_start_time.elapsed();
+
+    end_span(&upstream_cx, response.as_ref().ok().map(Response::status));
+    access_log.lock().upstream_duration_ms = Some(request_duration.as_millis());
+
+    match response {
+        Ok(mut response) => {
+            commit_upstream_metrics(&app, &method, response.status(), request_duration);
+
+            scrub_response_headers(response.headers_mut());
+
+            let mut drop_body = false;
+            if let Some(response_transform) = &api.spec.response_transform {
+                let (mut parts, body) = response.into_parts();
+                response_transform.apply(&api.spec, &mut parts.status, &mut parts.headers);
+                if matches!(
+                    parts.status,
+                    StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+                ) {
+                    parts.headers.remove(CONTENT_LENGTH);
+                    drop_body = true;
+                }
+                response = Response::from_parts(parts, body);
+            }
+
+            if let Some(wasm_filter) = &mut wasm_filter {
+                if let Err(rejection) = wasm_filter.on_response_headers(response.headers_mut()) {
+                    return rejection.into_response(&access_log);
+                }
+            }
+
+            let (mut parts, body) = response.into_parts();
+            // A partial body cannot be rewritten without invalidating its `Content-Range`.
+            let partial = parts.status == StatusCode::PARTIAL_CONTENT;
+            let body = match &mut wasm_filter {
+                Some(wasm_filter) if filter_bodies && !partial => {
+                    match wasm_filter.on_response_body(body).await {
+                        Ok(body) => Either::Right(filtered_body(&mut parts.headers, body)),
+                        Err(rejection) => return rejection.into_response(&access_log),
+                    }
+                }
+                _ if drop_body => Either::Right(Full::default()),
+                _ => Either::Left(body),
+            };
+
+            Ok(Response::from_parts(
+                parts,
+                forwarded_body(body, "response", capture_info),
+            ))
+        }
+        Err(error) => {
+            access_log.lock().set_error(format!("{error:?}"));
+
+            commit_upstream_metrics(&app, &method, StatusCode::BAD_GATEWAY, request_duration);
+
+            get_response(StatusCode::BAD_GATEWAY, BAD_GATEWAY).map(into_boxed_response)
+        }
+    }
+}
+
+/// Accept connections on `listener` forever, serving each of them with `service`. The listener
+/// name labels the connection metrics.
+async fn serve<S, F>(listener: TcpListener, name: &'static str, service: S) -> Result<()>
+where
+    S: Fn(Request<Incoming>, SocketAddr) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<BoxResponse<Bytes>>> + Send + 'static,
+{
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("Failed to accept connection: {err:?}");
+                continue;
+            }
+        };
+
+        let service = service.clone();
+
+        let context = format!("{name} connection from {remote_addr}");
+        tokio::task::spawn(with_task_context(context, async move {
+            let mut connection_metrics = ConnectionMetricsGuard::new(name);
+
+            let mut builder = http1::Builder::new();
+            let max_buf_size = {
+                let runtime_config = runtime_config();
+                let limits = &runtime_config.request_limits;
+                builder
+                    .max_buf_size(limits.max_buf_size())
+                    .max_headers(limits.max_headers);
+                limits.max_buf_size()
+            };
+            let framing = Arc::new(FramingState::default());
+            let io = TokioIo::new(FramingGuard::new(stream, max_buf_size, framing.clone()));
+
+            match builder
+                .preserve_header_case(true)
+                .title_case_headers(true)
+                .serve_connection(
+                    io,
+                    service_fn(move |req| {
+                        serve_framed(req, remote_addr, framing.clone(), service.clone())
+                    }),
+                )
+                .with_upgrades()
+                .await
+            {
+                Ok(()) => connection_metrics.set_reason("normal"),
+                Err(err) if err.is_timeout() => connection_metrics.set_reason("timeout"),
+                Err(err) => {
+                    connection_metrics.set_reason("error");
+                    error!("Failed to serve connection: {err:?}");
+                }
+            }
+        }));
+    }
+}
+
+/// Serve a request with `service`, unless its connection has an ambiguous framing, in which case
+/// it is rejected with `400` and the connection closed.
+async fn serve_framed<S, F>(
+    req: Request<Incoming>,
+    remote_addr: SocketAddr,
+    framing: Arc<FramingState>,
+    service: S,
+) -> Result<BoxResponse<Bytes>>
+where
+    S: Fn(Request<Incoming>, SocketAddr) -> F,
+    F: Future<Output = Result<BoxResponse<Bytes>>>,
+{
+    if framing.violation().is_some() {
+        let mut response = get_response(StatusCode::BAD_REQUEST, BAD_REQUEST)?;
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+        return Ok(into_boxed_response(response));
+    }
+
+    let mut response = service(req, remote_addr).await?;
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+        framing.set_upgraded();
+    } else if is_draining() {
+        // Keep-alive clients reconnect to another instance.
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    Ok(response)
+}
+
+/// Load the runtime config file and the public keys of its auth sources, replacing the current
+/// ones only if all of them are valid.
+async fn reload_config() -> Result<()> {
+    let runtime_config = load_runtime_config().map_err(|e| anyhow!("{e}"))?;
+    let token_sources = load_token_sources(&runtime_config.auth_sources).await?;
+
+    set_runtime_config(runtime_config);
+    set_token_sources(token_sources);
+    Ok(())
+}
+
+/// Reload the runtime config on `SIGHUP`, keeping the current one if the new one is invalid.
+async fn reload_config_on_sighup() -> Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    while sighup.recv().await.is_some() {
+        match reload_config().await {
+            Ok(()) => warn!("event='Runtime config reloaded'"),
+            Err(e) => error!("event='Runtime config reload rejected: {e}'"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve once the process receives `SIGINT` or `SIGTERM`.
+async fn shutdown_signal() -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = ctrl_c() => res?,
+        _ = sigterm.recv() => (),
+    }
+    Ok(())
+}
+
+/// Build the client forwarding requests to upstream servers.
+pub fn http_client() -> HttpClient {
+    Client::builder(TokioExecutor::new()).build_http()
+}
+
+/// Accept connections on `listener` forever, serving each of them with the pipeline built by
+/// `gateway_service` from `state`.
+pub async fn serve_gateway(listener: TcpListener, state: GatewayState) -> Result<()> {
+    let gateway_service = gateway_service(state);
+    let service = move |req: Request<Incoming>, remote_addr| {
+        let mut req = req.map(Either::Left);
+        req.extensions_mut().insert(RemoteAddr(remote_addr));
+        gateway_service.clone().oneshot(req)
+    };
+
+    serve(listener, "main", service).await
+}
+
+/// Bind a listener to `addr` with `SO_REUSEPORT`, the kernel spreading the connections between
+/// the listeners bound to the same address.
+fn bind_reuseport(addr: SocketAddr) -> Result<std::net::TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    Ok(socket.listen(LISTEN_BACKLOG)?.into_std()?)
+}
+
+/// Serve the gateway on `addr`, on the shared runtime or on `workers` threads each with its own
+/// listener, runtime and client, so that connections and upstream pools are not shared between
+/// cores.
+async fn serve_workers(addr: SocketAddr, state: GatewayState, workers: usize) -> Result<()> {
+    if workers == 1 {
+        let listener = TcpListener::bind(&addr)
+            .await
+            .map_err(|err| anyhow!("Could not listen on {addr}: {err}"))?;
+        info!("event='Listening on http://{}'", addr);
+        return serve_gateway(listener, state).await;
+    }
+
+    // The other listeners are bound to the port of the first one, chosen by the system with
+    // port 0.
+    let mut listeners = Vec::with_capacity(workers);
+    let mut bound_addr = addr;
+    for _ in 0..workers {
+        let listener = bind_reuseport(bound_addr)
+            .map_err(|err| anyhow!("Could not listen on {addr}: {err}"))?;
+        bound_addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    info!("event='Listening on http://{bound_addr} with {workers} workers'");
+
+    let (stopped_tx, mut stopped_rx) = mpsc::unbounded_channel();
+    for (worker, listener) in listeners.into_iter().enumerate() {
+        let state = GatewayState {
+            client: http_client(),
+            ..state.clone()
+        };
+        let stopped_tx = stopped_tx.clone();
+        thread::Builder::new()
+            .name(format!("gateway-worker-{worker}"))
+            .spawn(move || {
+                let res = runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|runtime| {
+                        runtime.block_on(async {
+                            serve_gateway(TcpListener::from_std(listener)?, state).await
+                        })
+                    });
+                let _ = stopped_tx.send(res.map_err(|e| anyhow!("Worker {worker} stopped: {e}")));
+            })?;
+    }
+    drop(stopped_tx);
+
+    stopped_rx.recv().await.unwrap_or(Ok(()))
+}
+
+/// Check the runtime config file, with the public keys and CAs it refers to.
+pub async fn validate() -> Result<()> {
+    let runtime_config = match load_runtime_config() {
+        Ok(runtime_config) => runtime_config,
+        Err(e) => {
+            error!("event='Runtime config is not valid: {e}'");
+            exit(1);
+        }
+    };
+    if let Err(e) = load_token_sources(&runtime_config.auth_sources).await {
+        error!("event='Could not load the auth sources: {e}'");
+        exit(1);
+    }
+    if let Err(e) = init_websocket_tls() {
+        error!("event='Could not initialize websocket TLS: {e}'");
+        exit(1);
+    }
+    if let Err(e) = check_http3() {
+        error!("event='Could not initialize the HTTP/3 listener: {e}'");
+        exit(1);
+    }
+    println!("Runtime config is valid");
+
+    Ok(())
+}
+
+/// Run the gateway with the runtime config until the process receives `SIGINT` or `SIGTERM`,
+/// watching the permissions and `ApiDefinition`s of the cluster.
+pub async fn run() -> Result<()> {
+    let addr: SocketAddr = match runtime_config().bind_to.parse() {
+        Ok(addr) => addr,
+        Err(_) => {
+            error!("event='Address bind_to is not valid'");
+            exit(1);
+        }
+    };
+
+    run_self_check().await;
+
+    let tracer_provider = match init_tracing() {
+        Ok(tracer_provider) => tracer_provider,
+        Err(e) => {
+            error!("event='Could not initialize tracing: {e}'");
+            exit(1);
+        }
+    };
+
+    // permissions fetching
+    let (perm, role) = match get_perm().await {
+        Ok(perm) => perm,
+        Err(e) => {
+            error!("event='Could not fetch permissions: {e}'");
+            exit(1);
+        }
+    };
+    let perm_lock = Arc::new(ArcSwap::from_pointee(PermissionIndex::new(perm)));
+    let role_lock = Arc::new(ArcSwap::from_pointee(role));
+    let update_perm = update_perm(perm_lock.clone(), role_lock.clone());
+
+    // apidefinitions fetching
+    let api_lock = Arc::new(ArcSwap::from_pointee(HashMap::new()));
+    let update_api = {
+        let api_lock = api_lock.clone();
+        let runtime_config = runtime_config();
+        async move {
+            match &runtime_config.api_dir {
+                Some(api_dir) => update_api_from_dir(api_lock, api_dir).await,
+                None => {
+                    update_api(
+                        api_lock,
+                        runtime_config.crd_label.to_owned(),
+                        runtime_config.crds_namespaces.to_owned(),
+                    )
+                    .await
+                }
+            }
+        }
+    };
+
+    // Share a `Client` with all `Service`s
+    let state = GatewayState {
+        client: http_client(),
+        perm_lock,
+        role_lock,
+        api_lock,
+    };
+
+    let res = tokio::select! {
+        res = async {
+            tokio::try_join!(
+                update_perm,
+                update_api,
+                export_metrics(),
+                run_log_sinks(),
+                run_error_reporter(),
+                run_change_notifier(),
+                run_admin_listener(state.clone()),
+                run_http3_listener(state.clone()),
+                reload_config_on_sighup(),
+                serve_workers(addr, state, runtime_config().workers),
+            )
+        } => res.map(|_| ()),
+        res = shutdown_signal() => match res {
+            Ok(()) => {
+                // Connections are not accepted anymore, the open tunnels are closed.
+                info!("event='Shutting down'");
+                drain_tunnels(runtime_config().shutdown_grace_period).await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        },
+    };
+
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(e) = tracer_provider.shutdown() {
+            error!("event='Could not flush spans: {e}'");
+        }
+    }
+
+    match res {
+        Ok(_) => info!("That went well"),
+        Err(e) => {
+            error!("Error in join: {:?}", e);
+            exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use futures::channel::mpsc;
+    use futures::SinkExt;
+    use http_body_util::StreamBody;
+    use hyper::body::Frame;
+    use hyper::header::AUTHORIZATION;
+    use hyper::Method;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::bench::{serve_bench_gateway, BENCH_APP, STREAMED_CHUNK_SIZE};
+    use crate::runtime_config::set_config_path;
+
+    /// Time given to the response and to each frame to go through the gateway and back.
+    const STREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn forged_identity_headers_are_not_signed() {
+        let claims: Claims = serde_json::from_value(serde_json::json!({
+            "sub": "sub",
+            "iss": "iss",
+            "exp": 0,
+            "preferred_username": "jdoe",
+            "given_name": "José",
+            "family_name": "Doe\r\n",
+            "email": "jdoe@example.com",
+            "token_id": "jdoe",
+        }))
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Forwarded-User-First-Name",
+            HeaderValue::from_static("Mallory"),
+        );
+        headers.insert(
+            "X-Forwarded-User-Last-Name",
+            HeaderValue::from_static("Mallory"),
+        );
+        headers.insert(IDENTITY_TIMESTAMP_HEADER, HeaderValue::from_static("0"));
+        headers.insert(
+            IDENTITY_SIGNATURE_HEADER,
+            HeaderValue::from_static("sha256=00"),
+        );
+
+        inject_headers(&mut headers, &claims, "", "user", false, &BTreeMap::new());
+        // Non-ASCII values are forwarded as their UTF-8 bytes, invalid ones not at all.
+        assert_eq!(
+            headers["X-Forwarded-User-First-Name"].as_bytes(),
+            "José".as_bytes()
+        );
+        assert!(headers.get("X-Forwarded-User-Last-Name").is_none());
+        assert!(headers.get(IDENTITY_TIMESTAMP_HEADER).is_none());
+        assert!(headers.get(IDENTITY_SIGNATURE_HEADER).is_none());
+
+        sign_identity_headers(&mut headers, "secret");
+        let timestamp = headers[IDENTITY_TIMESTAMP_HEADER].to_str().unwrap();
+        let signed = format!("{timestamp}\njdoe\njdoe\nJosé\n\njdoe@example.com\n\nuser");
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let signature: String = hmac::sign(&key, signed.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        assert_eq!(
+            headers[IDENTITY_SIGNATURE_HEADER],
+            format!("sha256={signature}")
+        );
+    }
+
+    #[tokio::test]
+    async fn large_bodies_are_streamed_frame_by_frame() {
+        set_config_path(PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/bench/config.yaml"
+        )));
+        let (gateway, authorization, serve) = serve_bench_gateway().await.unwrap();
+
+        let sent: Vec<u8> = (0..8 * 1024 * 1024)
+            .map(|i: usize| (i % 251) as u8)
+            .collect();
+        let (mut tx, rx) = mpsc::channel::<Result<Frame<Bytes>, Infallible>>(0);
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{gateway}{BENCH_APP}/stream"))
+            .header(AUTHORIZATION, authorization)
+            .body(StreamBody::new(rx))
+            .unwrap();
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        let mut body = timeout(STREAM_TIMEOUT, client.request(req))
+            .await
+            .expect("response waiting for the request body")
+            .unwrap()
+            .into_body();
+
+        // Each chunk is only sent once the previous one was echoed back, which a gateway
+        // collecting either body before forwarding it would never do.
+        let mut received = Vec::with_capacity(sent.len());
+        for chunk in sent.chunks(STREAMED_CHUNK_SIZE) {
+            tx.send(Ok(Frame::data(Bytes::copy_from_slice(chunk))))
+                .await
+                .unwrap();
+            let expected = received.len() + chunk.len();
+            while received.len() < expected {
+                let frame = timeout(STREAM_TIMEOUT, body.frame())
+                    .await
+                    .expect("frame waiting for the end of the body")
+                    .expect("body ended early")
+                    .unwrap();
+                received.extend_from_slice(frame.data_ref().unwrap());
+            }
+        }
+        drop(tx);
+
+        while let Some(frame) = body.frame().await {
+            received.extend_from_slice(frame.unwrap().data_ref().unwrap());
+        }
+        assert!(
+            received == sent,
+            "the echoed body differs from the sent one"
+        );
+        serve.abort();
+    }
+}
@@ -0,0 +1,75 @@
+use std::env;
+use std::sync::{LazyLock, RwLock};
+
+use anyhow::{anyhow, Result};
+use env_filter::{Builder, Filter};
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Filter used when `RUST_LOG` is not set, as with `env_logger::init`.
+const DEFAULT_FILTER: &str = "error";
+
+/// The current filter along with the directives it was parsed from.
+struct LogFilter {
+    directives: String,
+    filter: Filter,
+}
+
+static LOG_FILTER: LazyLock<RwLock<LogFilter>> = LazyLock::new(|| {
+    let directives = env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_FILTER.to_string());
+    let filter = Builder::new().parse(&directives).build();
+    RwLock::new(LogFilter { directives, filter })
+});
+
+/// An `env_logger` logger whose filter can be replaced at runtime.
+struct ReloadableLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        LOG_FILTER.read().unwrap().filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if LOG_FILTER.read().unwrap().filter.matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install the logger, configured from `RUST_LOG` and `RUST_LOG_STYLE` like `env_logger::init`.
+pub fn init_logger() {
+    // Records are filtered by `LOG_FILTER`, the inner logger writes all of them.
+    let inner = env_logger::Builder::from_default_env()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    log::set_max_level(LOG_FILTER.read().unwrap().filter.filter());
+    log::set_boxed_logger(Box::new(ReloadableLogger { inner })).unwrap();
+}
+
+/// Get the directives of the current filter.
+pub fn get_log_filter() -> String {
+    LOG_FILTER.read().unwrap().directives.clone()
+}
+
+/// Replace the filter with `directives`, using the `RUST_LOG` syntax (for example
+/// `info,gateway::auth=debug`).
+pub fn set_log_filter(directives: &str) -> Result<()> {
+    let directives = directives.trim();
+    let filter = Builder::new()
+        .try_parse(directives)
+        .map_err(|e| anyhow!("Invalid log filter: {e}"))?
+        .build();
+
+    log::set_max_level(filter.filter());
+    *LOG_FILTER.write().unwrap() = LogFilter {
+        directives: directives.to_string(),
+        filter,
+    };
+
+    Ok(())
+}
@@ -0,0 +1,262 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::task::spawn_blocking;
+
+use crate::runtime_config::{runtime_config, LogSinkConfig};
+
+/// Maximum number of records waiting to be written, further records are dropped.
+const SINK_QUEUE_SIZE: usize = 10_000;
+
+/// Syslog facility `local0`.
+const SYSLOG_FACILITY: u8 = 16;
+
+struct Record {
+    is_error: bool,
+    line: String,
+}
+
+/// A stream of records written to its own sink.
+pub struct LogStream {
+    name: &'static str,
+    sender: OnceLock<Sender<Record>>,
+}
+
+pub static ACCESS_LOG: LogStream = LogStream::new("access_log");
+pub static AUDIT_LOG: LogStream = LogStream::new("audit");
+
+impl LogStream {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            sender: OnceLock::new(),
+        }
+    }
+
+    /// Queue a record to the configured sink without blocking the request handling. The line is
+    /// given back if the sink is not running (the `log` sink is used).
+    pub fn send(&self, is_error: bool, line: String) -> Option<String> {
+        let Some(sender) = self.sender.get() else {
+            return Some(line);
+        };
+
+        if sender.try_send(Record { is_error, line }).is_err() {
+            warn!(
+                "event='{} sink is full or closed, record dropped'",
+                self.name
+            );
+        }
+        None
+    }
+
+    /// Write the records to the configured sink until the process exits. Nothing is done for the
+    /// `log` sink as records then go through the log target named after the stream.
+    async fn run(&'static self, config: &LogSinkConfig) -> Result<()> {
+        let Some(mut sink) = open_sink(config)? else {
+            return Ok(());
+        };
+
+        let (tx, mut rx) = channel(SINK_QUEUE_SIZE);
+        // The sink is only started once.
+        let _ = self.sender.set(tx);
+
+        spawn_blocking(move || {
+            while let Some(record) = rx.blocking_recv() {
+                if let Err(e) = sink.write(&record) {
+                    error!("event='Fail to write {}: {e}'", self.name);
+                }
+            }
+        })
+        .await?;
+
+        Ok(())
+    }
+}
+
+trait Sink {
+    fn write(&mut self, record: &Record) -> io::Result<()>;
+}
+
+struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        writeln!(io::stdout().lock(), "{}", record.line)
+    }
+}
+
+/// A file rotated when it grows over `max_size` bytes or gets older than `rotate_every`, the
+/// previous files are renamed with a numbered suffix (`access.log.1` being the most recent).
+struct FileSink {
+    path: PathBuf,
+    max_size: Option<u64>,
+    rotate_every: Option<Duration>,
+    max_files: usize,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl FileSink {
+    fn new(
+        path: &Path,
+        max_size: Option<u64>,
+        rotate_every: Option<Duration>,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            rotate_every,
+            max_files,
+            file,
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+
+    fn should_rotate(&self, next_write: u64) -> bool {
+        let too_big = self
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + next_write > max_size);
+        let too_old = self
+            .rotate_every
+            .is_some_and(|rotate_every| self.opened_at.elapsed() >= rotate_every);
+        too_big || too_old
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let next_write = record.line.len() as u64 + 1;
+        if self.should_rotate(next_write) {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", record.line)?;
+        self.size += next_write;
+        Ok(())
+    }
+}
+
+enum SyslogSocket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+/// Send records as RFC 5424 messages, either over UDP (`udp://host:port`) or to a local unix
+/// datagram socket (for example `/dev/log`).
+struct SyslogSink {
+    socket: SyslogSocket,
+    hostname: String,
+}
+
+impl SyslogSink {
+    fn new(address: &str) -> io::Result<Self> {
+        let socket = match address.strip_prefix("udp://") {
+            Some(address) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(address)?;
+                SyslogSocket::Udp(socket)
+            }
+            None => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(address)?;
+                SyslogSocket::Unix(socket)
+            }
+        };
+        let hostname = fs::read_to_string("/etc/hostname")
+            .map(|hostname| hostname.trim().to_string())
+            .unwrap_or_else(|_| "-".to_string());
+
+        Ok(Self { socket, hostname })
+    }
+}
+
+impl Sink for SyslogSink {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        // Severity is `warning` (4) for errors and `informational` (6) otherwise.
+        let severity = if record.is_error { 4 } else { 6 };
+        let message = format!(
+            "<{}>1 {} {} gateway {} - - {}",
+            SYSLOG_FACILITY * 8 + severity,
+            humantime::format_rfc3339_millis(SystemTime::now()),
+            self.hostname,
+            std::process::id(),
+            record.line,
+        );
+
+        match &self.socket {
+            SyslogSocket::Udp(socket) => socket.send(message.as_bytes()),
+            SyslogSocket::Unix(socket) => socket.send(message.as_bytes()),
+        }
+        .map(|_| ())
+    }
+}
+
+fn open_sink(config: &LogSinkConfig) -> io::Result<Option<Box<dyn Sink + Send>>> {
+    Ok(match config {
+        LogSinkConfig::Log => None,
+        LogSinkConfig::Stdout => Some(Box::new(StdoutSink)),
+        LogSinkConfig::File {
+            path,
+            max_size,
+            rotate_every,
+            max_files,
+        } => Some(Box::new(FileSink::new(
+            path,
+            *max_size,
+            *rotate_every,
+            *max_files,
+        )?)),
+        LogSinkConfig::Syslog { address } => Some(Box::new(SyslogSink::new(address)?)),
+    })
+}
+
+/// Run the sinks of the access and audit logs.
+pub async fn run_log_sinks() -> Result<()> {
+    let runtime_config = runtime_config();
+    tokio::try_join!(
+        ACCESS_LOG.run(&runtime_config.access_log.sink),
+        AUDIT_LOG.run(&runtime_config.audit_log.sink),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::metrics::Direction;
+
+/// Outcome of a filter for a single message.
+pub enum FilterAction {
+    Forward(Message),
+    Drop,
+    /// Close the tunnel with this code and reason.
+    Close(CloseCode, &'static str),
+}
+
+/// A hook run on each data message (text or binary) going through a websocket tunnel, which can
+/// forward it (possibly rewritten), drop it or close the tunnel.
+pub trait MessageFilter {
+    fn filter(&self, direction: Direction, message: Message) -> FilterAction;
+}
+
+/// Run the filters in order, stopping at the first one not forwarding the message.
+pub fn apply_filters<F: MessageFilter>(
+    filters: &[F],
+    direction: Direction,
+    message: Message,
+) -> FilterAction {
+    let mut message = message;
+    for filter in filters {
+        match filter.filter(direction, message) {
+            FilterAction::Forward(filtered) => message = filtered,
+            action => return action,
+        }
+    }
+    FilterAction::Forward(message)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterDirection {
+    ClientToServer,
+    ServerToClient,
+    #[default]
+    Both,
+}
+
+impl FilterDirection {
+    fn matches(&self, direction: Direction) -> bool {
+        matches!(
+            (self, direction),
+            (FilterDirection::Both, _)
+                | (FilterDirection::ClientToServer, Direction::Received)
+                | (FilterDirection::ServerToClient, Direction::Sent)
+        )
+    }
+}
+
+/// What to do with a message violating a filter.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Violation {
+    #[default]
+    Drop,
+    Close,
+}
+
+/// Built-in filters, configured per API.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MessageFilterSpec {
+    /// Reject messages larger than `max_bytes`, closing with `1009` on violation.
+    MaxSize {
+        max_bytes: usize,
+        #[serde(default)]
+        direction: FilterDirection,
+        #[serde(default)]
+        on_violation: Violation,
+    },
+    /// Reject binary messages and text messages which are not valid JSON, closing with `1007` on
+    /// violation.
+    Json {
+        #[serde(default)]
+        direction: FilterDirection,
+        #[serde(default)]
+        on_violation: Violation,
+    },
+}
+
+impl MessageFilter for MessageFilterSpec {
+    fn filter(&self, direction: Direction, message: Message) -> FilterAction {
+        let (filter_direction, on_violation, is_valid, close_code, reason) = match self {
+            MessageFilterSpec::MaxSize {
+                max_bytes,
+                direction,
+                on_violation,
+            } => (
+                direction,
+                on_violation,
+                message.len() <= *max_bytes,
+                CloseCode::Size,
+                "message too big",
+            ),
+            MessageFilterSpec::Json {
+                direction,
+                on_violation,
+            } => (
+                direction,
+                on_violation,
+                matches!(&message, Message::Text(text)
+                    if serde_json::from_str::<serde::de::IgnoredAny>(text).is_ok()),
+                CloseCode::Invalid,
+                "message is not valid JSON",
+            ),
+        };
+
+        if is_valid || !filter_direction.matches(direction) {
+            return FilterAction::Forward(message);
+        }
+
+        debug!(
+            "event='Websocket message from {} rejected: {reason}'",
+            direction.source()
+        );
+        match on_violation {
+            Violation::Drop => FilterAction::Drop,
+            Violation::Close => FilterAction::Close(close_code, reason),
+        }
+    }
+}
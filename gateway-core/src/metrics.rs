@@ -0,0 +1,1180 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Result};
+use http_body::SizeHint;
+use hyper::Method;
+use hyper::StatusCode;
+use opentelemetry::trace::{SpanId, TraceContextExt, TraceId};
+use opentelemetry::Context;
+use prometheus::{
+    exponential_buckets, opts, register_counter_vec, register_gauge_vec, register_histogram_vec,
+    Counter, CounterVec, GaugeVec, Histogram, HistogramOpts, HistogramVec,
+};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::runtime_config::runtime_config;
+
+const HTTP_LABEL_NAMES: [&str; 3] = ["app", "method", "status_code"];
+const AUTH_SUCCESS_LABEL_NAMES: [&str; 2] = ["source", "token_type"];
+const AUTH_FAILURE_LABEL_NAMES: [&str; 2] = ["source", "reason"];
+const LISTENER_LABEL_NAMES: [&str; 1] = ["listener"];
+const CONNECTION_CLOSED_LABEL_NAMES: [&str; 2] = ["listener", "reason"];
+const APP_LABEL_NAMES: [&str; 1] = ["app"];
+const SLO_LABEL_NAMES: [&str; 3] = ["app", "slo", "result"];
+const PERMISSION_LABEL_NAMES: [&str; 3] = ["app", "result", "dry_run"];
+const EXT_AUTHZ_LABEL_NAMES: [&str; 2] = ["app", "result"];
+const QUOTA_LABEL_NAMES: [&str; 2] = ["app", "quota"];
+const SMUGGLING_LABEL_NAMES: [&str; 1] = ["reason"];
+const CLIENT_BLOCK_LABEL_NAMES: [&str; 1] = ["client"];
+const USER_LABEL_NAMES: [&str; 2] = ["app", "user"];
+const TENANT_LABEL_NAMES: [&str; 3] = ["app", "tenant", "status_code"];
+const TENANT_DURATION_LABEL_NAMES: [&str; 2] = ["app", "tenant"];
+const TENANT_IN_FLIGHT_LABEL_NAMES: [&str; 1] = ["tenant"];
+const SOCKET_LABEL_NAMES: [&str; 1] = ["app"];
+const SOCKET_DIRECTION_LABEL_NAMES: [&str; 2] = ["app", "direction"];
+const SOCKET_MESSAGE_LABEL_NAMES: [&str; 3] = ["app", "direction", "message_type"];
+
+/// TODO: move this
+enum Protocol {
+    Http,
+    Socket,
+    Extension,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let as_str = match self {
+            Protocol::Http => "http",
+            Protocol::Socket => "socket",
+            Protocol::Extension => "ext",
+        };
+
+        write!(f, "{as_str}")
+    }
+}
+
+/// The children of the HTTP metrics for the labels of a request, those of the upper sizes being
+/// only created once observed.
+struct HttpMetrics {
+    requests: Counter,
+    latency: Histogram,
+    req_size_low: Histogram,
+    req_size_high: OnceLock<Histogram>,
+    res_size_low: Histogram,
+    res_size_high: OnceLock<Histogram>,
+}
+
+impl HttpMetrics {
+    fn new(labels: &[&str]) -> Self {
+        Self {
+            requests: HTTP_COUNTER.with_label_values(labels),
+            latency: HTTP_REQ_LAT_HISTOGRAM.with_label_values(labels),
+            req_size_low: HTTP_REQ_SIZE_HISTOGRAM_LOW.with_label_values(labels),
+            req_size_high: OnceLock::new(),
+            res_size_low: HTTP_RES_SIZE_HISTOGRAM_LOW.with_label_values(labels),
+            res_size_high: OnceLock::new(),
+        }
+    }
+}
+
+/// Maximum number of label sets whose children are cached by each thread, those of other ones
+/// being resolved by each request, as apps come from the paths of requests.
+const MAX_CACHED_HTTP_METRICS: usize = 10_000;
+
+/// The HTTP metrics children already resolved, by app then method and status code.
+#[derive(Default)]
+struct HttpMetricsCache {
+    apps: HashMap<String, HashMap<(Method, StatusCode), Rc<HttpMetrics>>>,
+    len: usize,
+}
+
+thread_local! {
+    /// Sharded by thread, each worker and runtime thread resolving the children it uses without
+    /// synchronizing with the others.
+    static HTTP_METRICS_CACHE: RefCell<HttpMetricsCache> = RefCell::default();
+}
+
+/// Get the HTTP metrics children of the labels of a request, resolving them once per thread.
+fn get_http_metrics(app: &str, method: &Method, status_code: StatusCode) -> Rc<HttpMetrics> {
+    HTTP_METRICS_CACHE.with_borrow_mut(|cache| {
+        let key = (method.clone(), status_code);
+        if let Some(metrics) = cache.apps.get(app).and_then(|metrics| metrics.get(&key)) {
+            return metrics.clone();
+        }
+
+        let metrics = Rc::new(HttpMetrics::new(&[
+            app,
+            method.as_str(),
+            status_code.as_str(),
+        ]));
+        if cache.len < MAX_CACHED_HTTP_METRICS {
+            cache
+                .apps
+                .entry(app.to_string())
+                .or_default()
+                .insert(key, metrics.clone());
+            cache.len += 1;
+        }
+        metrics
+    })
+}
+
+/// Update HTTP metrics with a newly processed request.
+#[inline(always)]
+pub(crate) fn commit_http_metrics(
+    app: &str,
+    method: &Method,
+    start_time: &Instant,
+    status_code: StatusCode,
+    req_size: &SizeHint,
+    res_size: &SizeHint,
+) {
+    let full_labels = [app, method.as_str(), status_code.as_str()];
+    let metrics = get_http_metrics(app, method, status_code);
+    metrics.requests.inc();
+
+    let duration = start_time.elapsed().as_secs_f64();
+    commit_slos(app, status_code, duration);
+
+    metrics.latency.observe(duration);
+    record_exemplar(
+        &HTTP_REQ_LAT_NAME,
+        &HTTP_LABEL_NAMES,
+        &full_labels,
+        &HTTP_REQ_LAT_BUCKETS,
+        duration,
+    );
+
+    metrics.req_size_low.observe(req_size.lower() as f64);
+
+    if let Some(size) = req_size.upper() {
+        metrics
+            .req_size_high
+            .get_or_init(|| HTTP_REQ_SIZE_HISTOGRAM_HIGH.with_label_values(&full_labels))
+            .observe(size as f64)
+    }
+
+    metrics.res_size_low.observe(res_size.lower() as f64);
+
+    if let Some(size) = req_size.upper() {
+        metrics
+            .res_size_high
+            .get_or_init(|| HTTP_RES_SIZE_HISTOGRAM_HIGH.with_label_values(&full_labels))
+            .observe(size as f64)
+    }
+}
+
+/// Count the request as good or bad for each SLO of its app.
+fn commit_slos(app: &str, status_code: StatusCode, duration: f64) {
+    for slo in &runtime_config().slos {
+        if !slo.apps.is_empty() && !slo.apps.iter().any(|slo_app| slo_app == app) {
+            continue;
+        }
+
+        let is_good = status_code.as_u16() <= slo.max_status_code
+            && slo
+                .latency_threshold
+                .is_none_or(|threshold| duration <= threshold.as_secs_f64());
+        let result = if is_good { "good" } else { "bad" };
+
+        SLO_COUNTER
+            .with_label_values(&[app, &slo.name, result])
+            .inc();
+    }
+}
+
+/// The latest observation of an histogram bucket made within a sampled trace.
+#[derive(Clone)]
+pub(crate) struct Exemplar {
+    pub(crate) trace_id: TraceId,
+    pub(crate) span_id: SpanId,
+    pub(crate) value: f64,
+    pub(crate) timestamp: SystemTime,
+}
+
+/// Exemplars indexed by metric name and sorted label pairs, with one slot per bucket (including
+/// `+Inf`).
+type ExemplarStore = HashMap<(String, Vec<(String, String)>), Vec<Option<Exemplar>>>;
+
+static EXEMPLARS: LazyLock<Mutex<ExemplarStore>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Keep the observation as the exemplar of its bucket if it was made within a sampled trace,
+/// which is only possible when `tracing` is enabled.
+fn record_exemplar(
+    name: &str,
+    label_names: &[&str],
+    label_values: &[&str],
+    buckets: &[f64],
+    value: f64,
+) {
+    let cx = Context::current();
+    let span_context = cx.span().span_context().clone();
+    if !span_context.is_sampled() {
+        return;
+    }
+
+    let mut labels: Vec<(String, String)> = label_names
+        .iter()
+        .zip(label_values)
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    labels.sort();
+
+    let bucket = buckets
+        .iter()
+        .position(|upper_bound| value <= *upper_bound)
+        .unwrap_or(buckets.len());
+
+    let mut exemplars = EXEMPLARS.lock().unwrap();
+    let slots = exemplars
+        .entry((name.to_string(), labels))
+        .or_insert_with(|| vec![None; buckets.len() + 1]);
+    slots[bucket] = Some(Exemplar {
+        trace_id: span_context.trace_id(),
+        span_id: span_context.span_id(),
+        value,
+        timestamp: SystemTime::now(),
+    });
+}
+
+/// Get the exemplars of a gathered histogram, one slot per bucket (including `+Inf`).
+pub(crate) fn get_exemplars(
+    name: &str,
+    metric: &prometheus::proto::Metric,
+) -> Vec<Option<Exemplar>> {
+    let mut labels: Vec<(String, String)> = metric
+        .get_label()
+        .iter()
+        .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+        .collect();
+    labels.sort();
+
+    EXEMPLARS
+        .lock()
+        .unwrap()
+        .get(&(name.to_string(), labels))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Update upstream metrics with the duration of a single round trip to the backend.
+#[inline(always)]
+pub(crate) fn commit_upstream_metrics(
+    app: &str,
+    method: &Method,
+    status_code: StatusCode,
+    duration: Duration,
+) {
+    HTTP_UPSTREAM_LAT_HISTOGRAM
+        .with_label_values(&[app, method.as_str(), status_code.as_str()])
+        .observe(duration.as_secs_f64());
+
+    let is_failure = matches!(
+        status_code,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    );
+    commit_upstream_outcome(app, is_failure);
+}
+
+/// Reachability of an upstream server, exported as the value of `http_upstream_health`.
+#[derive(Clone, Copy)]
+enum UpstreamHealth {
+    Down = 0,
+    Degraded = 1,
+    Healthy = 2,
+}
+
+/// Number of requests and failures of an upstream, over the current and the previous windows.
+#[derive(Default)]
+struct OutcomeWindow {
+    started_at: Option<Instant>,
+    current: (u64, u64),
+    previous: (u64, u64),
+}
+
+static UPSTREAM_OUTCOMES: LazyLock<Mutex<HashMap<String, OutcomeWindow>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Record whether a request to an upstream failed, then update its reachability from the ratio
+/// of failures over roughly the last `upstream_health.window` seconds. This is also meant to be
+/// fed by active health checks.
+pub(crate) fn commit_upstream_outcome(app: &str, is_failure: bool) {
+    let runtime_config = runtime_config();
+    let config = &runtime_config.upstream_health;
+    let window_duration = config.window;
+
+    let ratio = {
+        let mut outcomes = UPSTREAM_OUTCOMES.lock().unwrap();
+        let window = outcomes.entry(app.to_string()).or_default();
+
+        let elapsed = window.started_at.map(|started_at| started_at.elapsed());
+        if elapsed.is_none_or(|elapsed| elapsed >= window_duration) {
+            window.previous = if elapsed.is_some_and(|elapsed| elapsed < window_duration * 2) {
+                window.current
+            } else {
+                (0, 0)
+            };
+            window.current = (0, 0);
+            window.started_at = Some(Instant::now());
+        }
+
+        window.current.0 += 1;
+        window.current.1 += is_failure as u64;
+
+        let total = window.current.0 + window.previous.0;
+        let failures = window.current.1 + window.previous.1;
+        failures as f64 / total as f64
+    };
+
+    let health = if ratio >= config.down_ratio {
+        UpstreamHealth::Down
+    } else if ratio >= config.degraded_ratio {
+        UpstreamHealth::Degraded
+    } else {
+        UpstreamHealth::Healthy
+    };
+
+    UPSTREAM_HEALTH_GAUGE
+        .with_label_values(&[app])
+        .set(health as i64 as f64);
+}
+
+/// Count a token successfully decoded by an auth source.
+pub(crate) fn commit_auth_success(source: &str, token_type: &str) {
+    AUTH_SUCCESS_COUNTER
+        .with_label_values(&[source, token_type])
+        .inc();
+}
+
+/// Count a token rejected by an auth source.
+pub(crate) fn commit_auth_failure(source: &str, reason: &str) {
+    AUTH_FAILURE_COUNTER
+        .with_label_values(&[source, reason])
+        .inc();
+}
+
+/// Count a permission check, `dry_run` telling whether its result was enforced.
+pub(crate) fn commit_permission_check(app: &str, allowed: bool, dry_run: bool) {
+    let result = if allowed { "allowed" } else { "denied" };
+    PERMISSION_COUNTER
+        .with_label_values(&[app, result, &dry_run.to_string()])
+        .inc();
+}
+
+/// Count a decision of an external authorization service, `result` being `allowed`, `denied` or
+/// `error`.
+pub(crate) fn commit_ext_authz_decision(app: &str, result: &str) {
+    EXT_AUTHZ_COUNTER.with_label_values(&[app, result]).inc();
+}
+
+/// Count a request rejected by a quota, `quota` being `app` or `global`.
+pub(crate) fn commit_quota_rejection(app: &str, quota: &str) {
+    QUOTA_REJECTION_COUNTER
+        .with_label_values(&[app, quota])
+        .inc();
+}
+
+pub(crate) fn commit_smuggling_rejection(reason: &str) {
+    SMUGGLING_REJECTION_COUNTER
+        .with_label_values(&[reason])
+        .inc();
+}
+
+pub(crate) fn commit_client_block(client: &str) {
+    CLIENT_BLOCK_COUNTER.with_label_values(&[client]).inc();
+}
+
+/// Label value of the users not among the most active ones.
+const OTHER_USER: &str = "other";
+
+/// Track the most active users so that only `user_metrics.top_n` (app, user) pairs get their own
+/// label value. The pairs are admitted as long as there is room, then elected every
+/// `user_metrics.election_interval` from the request counts of the elapsed interval, the series
+/// of the demoted pairs being removed.
+struct UserTracker {
+    tracked: HashSet<(String, String)>,
+    counts: HashMap<(String, String), u64>,
+    elected_at: Instant,
+}
+
+static USER_TRACKER: LazyLock<Mutex<UserTracker>> = LazyLock::new(|| {
+    Mutex::new(UserTracker {
+        tracked: HashSet::new(),
+        counts: HashMap::new(),
+        elected_at: Instant::now(),
+    })
+});
+
+impl UserTracker {
+    fn elect(&mut self, top_n: usize) {
+        let mut counts: Vec<_> = self.counts.drain().collect();
+        counts.sort_unstable_by(|(_, a), (_, b)| b.cmp(a));
+        let elected: HashSet<_> = counts.into_iter().take(top_n).map(|(key, _)| key).collect();
+
+        for (app, user) in self.tracked.difference(&elected) {
+            let _ = USER_COUNTER.remove_label_values(&[app, user]);
+        }
+
+        self.tracked = elected;
+        self.elected_at = Instant::now();
+    }
+
+    /// Get the label value of the user, and count its request for the next election.
+    fn get_label(&mut self, app: &str, user: String, top_n: usize) -> String {
+        let key = (app.to_string(), user);
+
+        // Only a bounded number of candidates are counted between elections.
+        if self.counts.len() < top_n * 10 || self.counts.contains_key(&key) {
+            *self.counts.entry(key.clone()).or_default() += 1;
+        }
+
+        if self.tracked.contains(&key) {
+            return key.1;
+        }
+        if self.tracked.len() < top_n {
+            self.tracked.insert(key.clone());
+            return key.1;
+        }
+        OTHER_USER.to_string()
+    }
+}
+
+/// Count a request made by a user, when `user_metrics` is enabled.
+pub(crate) fn commit_user_request(app: &str, token_id: &str) {
+    let runtime_config = runtime_config();
+    let Some(config) = &runtime_config.user_metrics else {
+        return;
+    };
+
+    let user = if config.hash_token_id {
+        let mut hasher = DefaultHasher::new();
+        token_id.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    } else {
+        token_id.to_string()
+    };
+
+    let label = {
+        let mut tracker = USER_TRACKER.lock().unwrap();
+        if tracker.elected_at.elapsed() >= config.election_interval {
+            tracker.elect(config.top_n);
+        }
+        tracker.get_label(app, user, config.top_n)
+    };
+
+    USER_COUNTER.with_label_values(&[app, &label]).inc();
+}
+
+/// Label value of the tenants over `tenancy.max_tenants`.
+const OTHER_TENANT: &str = "other";
+
+/// Tenants with their own label value, admitted in order of arrival so that the label of a tenant
+/// never changes.
+static TENANT_LABELS: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Get the label value of a tenant in the tenant metrics.
+pub(crate) fn tenant_label(tenant: &str) -> String {
+    let max_tenants = runtime_config()
+        .tenancy
+        .as_ref()
+        .map_or(0, |tenancy| tenancy.max_tenants);
+
+    let mut labels = TENANT_LABELS.lock().unwrap();
+    if labels.contains(tenant) {
+        return tenant.to_string();
+    }
+    if labels.len() < max_tenants {
+        labels.insert(tenant.to_string());
+        return tenant.to_string();
+    }
+    OTHER_TENANT.to_string()
+}
+
+/// Count a request of a tenant, labeled by `tenant_label`.
+pub(crate) fn commit_tenant_request(
+    app: &str,
+    tenant: &str,
+    status_code: StatusCode,
+    start_time: &Instant,
+) {
+    TENANT_COUNTER
+        .with_label_values(&[app, tenant, status_code.as_str()])
+        .inc();
+    TENANT_LAT_HISTOGRAM
+        .with_label_values(&[app, tenant])
+        .observe(start_time.elapsed().as_secs_f64());
+}
+
+/// Count a request of a tenant starting (`1`) or ending (`-1`).
+pub(crate) fn commit_tenant_in_flight(tenant: &str, delta: f64) {
+    TENANT_IN_FLIGHT_GAUGE
+        .with_label_values(&[tenant])
+        .add(delta);
+}
+
+/// Label value of the series of an extension metric over `custom_metrics.max_series`.
+const OTHER_SERIES: &str = "other";
+
+enum ExtensionCollector {
+    Counter(CounterVec),
+    Histogram(HistogramVec),
+}
+
+/// A metric registered by an extension, with the label values of its series so far.
+struct ExtensionMetric {
+    label_names: Vec<String>,
+    collector: ExtensionCollector,
+    series: Mutex<HashSet<Vec<String>>>,
+}
+
+static EXTENSION_METRICS: LazyLock<Mutex<HashMap<String, Arc<ExtensionMetric>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+impl ExtensionMetric {
+    /// Get the label values of a series, those of the series over `custom_metrics.max_series`
+    /// being all `other`.
+    fn get_labels<'a>(&self, label_values: &[&'a str]) -> Result<Vec<&'a str>> {
+        if label_values.len() != self.label_names.len() {
+            bail!(
+                "{} label values given for labels {}",
+                label_values.len(),
+                self.label_names.join(", ")
+            );
+        }
+
+        let key: Vec<String> = label_values.iter().map(|value| value.to_string()).collect();
+        let mut series = self.series.lock().unwrap();
+        if series.contains(&key) {
+            return Ok(label_values.to_vec());
+        }
+        if series.len() < runtime_config().custom_metrics.max_series {
+            series.insert(key);
+            return Ok(label_values.to_vec());
+        }
+        Ok(vec![OTHER_SERIES; label_values.len()])
+    }
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let is_valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if !is_valid {
+        bail!("`{name}` is not a valid name, it must match `[a-z][a-z0-9_]*`");
+    }
+    Ok(())
+}
+
+/// Register a metric as `gateway_<metrics_prefix>_ext_<name>`, or get it if it is already
+/// registered with the same type and labels.
+fn register_extension_metric(
+    name: &str,
+    label_names: &[&str],
+    is_counter: bool,
+    register: impl FnOnce(String) -> Result<ExtensionCollector>,
+) -> Result<Arc<ExtensionMetric>> {
+    let mut metrics = EXTENSION_METRICS.lock().unwrap();
+    if let Some(metric) = metrics.get(name) {
+        let same_type = matches!(metric.collector, ExtensionCollector::Counter(_)) == is_counter;
+        if !same_type || metric.label_names != label_names {
+            bail!("Metric `{name}` is already registered with another type or other labels");
+        }
+        return Ok(metric.clone());
+    }
+
+    check_name(name)?;
+    for (i, label_name) in label_names.iter().enumerate() {
+        check_name(label_name)?;
+        if label_names[..i].contains(label_name) {
+            bail!("Label `{label_name}` of metric `{name}` is given twice");
+        }
+    }
+    let max_metrics = runtime_config().custom_metrics.max_metrics;
+    if metrics.len() >= max_metrics {
+        bail!("Cannot register `{name}`, {max_metrics} metrics are already registered");
+    }
+
+    let metric = Arc::new(ExtensionMetric {
+        label_names: label_names.iter().map(|name| name.to_string()).collect(),
+        collector: register(get_metric_name(name, Protocol::Extension))?,
+        series: Mutex::new(HashSet::new()),
+    });
+    metrics.insert(name.to_string(), metric.clone());
+    Ok(metric)
+}
+
+/// A counter registered by an extension with [`register_counter`].
+#[derive(Clone)]
+pub struct ExtensionCounter(Arc<ExtensionMetric>);
+
+impl ExtensionCounter {
+    /// Increment the series of `label_values`, given in the order of the label names.
+    pub fn inc(&self, label_values: &[&str]) -> Result<()> {
+        self.inc_by(label_values, 1.0)
+    }
+
+    /// Add `value`, which cannot be negative, to the series of `label_values`.
+    pub fn inc_by(&self, label_values: &[&str], value: f64) -> Result<()> {
+        if !value.is_finite() || value < 0.0 {
+            bail!("A counter cannot be increased by {value}");
+        }
+        let labels = self.0.get_labels(label_values)?;
+        if let ExtensionCollector::Counter(counter) = &self.0.collector {
+            counter.with_label_values(&labels).inc_by(value);
+        }
+        Ok(())
+    }
+}
+
+/// An histogram registered by an extension with [`register_histogram`].
+#[derive(Clone)]
+pub struct ExtensionHistogram(Arc<ExtensionMetric>);
+
+impl ExtensionHistogram {
+    /// Observe `value` in the series of `label_values`, given in the order of the label names.
+    pub fn observe(&self, label_values: &[&str], value: f64) -> Result<()> {
+        if !value.is_finite() {
+            bail!("An histogram cannot observe {value}");
+        }
+        let labels = self.0.get_labels(label_values)?;
+        if let ExtensionCollector::Histogram(histogram) = &self.0.collector {
+            histogram.with_label_values(&labels).observe(value);
+        }
+        Ok(())
+    }
+}
+
+/// Register a counter of an extension (script, WASM filter or embedding program) as
+/// `gateway_<metrics_prefix>_ext_<name>`, or get the one already registered with this name.
+///
+/// At most `custom_metrics.max_metrics` metrics can be registered, and each of them has at most
+/// `custom_metrics.max_series` series, further label values being counted as `other`.
+pub fn register_counter(name: &str, help: &str, label_names: &[&str]) -> Result<ExtensionCounter> {
+    register_extension_metric(name, label_names, true, |full_name| {
+        let counter = CounterVec::new(opts!(full_name, help), label_names)?;
+        prometheus::register(Box::new(counter.clone()))?;
+        Ok(ExtensionCollector::Counter(counter))
+    })
+    .map(ExtensionCounter)
+}
+
+/// Register an histogram of an extension as `gateway_<metrics_prefix>_ext_<name>`, or get the one
+/// already registered with this name. `histogram_buckets` of `ext_<name>` takes precedence over
+/// `buckets`, which defaults to the buckets of the request durations.
+///
+/// The limits of [`register_counter`] apply.
+pub fn register_histogram(
+    name: &str,
+    help: &str,
+    label_names: &[&str],
+    buckets: Option<Vec<f64>>,
+) -> Result<ExtensionHistogram> {
+    register_extension_metric(name, label_names, false, |full_name| {
+        let buckets = get_buckets(
+            name,
+            Protocol::Extension,
+            buckets.unwrap_or_else(|| prometheus::DEFAULT_BUCKETS.to_vec()),
+        );
+        // Otherwise only checked, with a panic, once a series is observed.
+        if buckets.is_empty() || buckets.windows(2).any(|w| w[0] >= w[1]) {
+            bail!("Buckets of `{name}` must be non-empty and in strictly increasing order");
+        }
+        let histogram = HistogramVec::new(
+            HistogramOpts::new(full_name, help).buckets(buckets),
+            label_names,
+        )?;
+        prometheus::register(Box::new(histogram.clone()))?;
+        Ok(ExtensionCollector::Histogram(histogram))
+    })
+    .map(ExtensionHistogram)
+}
+
+/// A guard used to log metrics of a single accepted connection, it ensures that the active
+/// connection gauge is decremented and the closing counted exactly once.
+pub(crate) struct ConnectionMetricsGuard {
+    listener: &'static str,
+    reason: &'static str,
+}
+
+impl ConnectionMetricsGuard {
+    pub(crate) fn new(listener: &'static str) -> Self {
+        CONNECTION_ACCEPTED_COUNTER
+            .with_label_values(&[listener])
+            .inc();
+        CONNECTION_ACTIVE_GAUGE.with_label_values(&[listener]).inc();
+        Self {
+            listener,
+            // Only changed if the connection ends normally or with a known error.
+            reason: "aborted",
+        }
+    }
+
+    /// Record how the connection was closed, either `normal`, `timeout` or `error`.
+    pub(crate) fn set_reason(&mut self, reason: &'static str) {
+        self.reason = reason;
+    }
+}
+
+impl Drop for ConnectionMetricsGuard {
+    fn drop(&mut self) {
+        CONNECTION_ACTIVE_GAUGE
+            .with_label_values(&[self.listener])
+            .dec();
+        CONNECTION_CLOSED_COUNTER
+            .with_label_values(&[self.listener, self.reason])
+            .inc();
+    }
+}
+
+/// Direction of a message going through a socket.
+#[derive(Clone, Copy)]
+pub(crate) enum Direction {
+    /// From the server to the client.
+    Sent,
+    /// From the client to the server.
+    Received,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        }
+    }
+
+    /// Side the messages come from.
+    pub(crate) fn source(&self) -> &'static str {
+        match self {
+            Direction::Sent => "server",
+            Direction::Received => "client",
+        }
+    }
+
+    /// Side the messages go to.
+    pub(crate) fn destination(&self) -> &'static str {
+        match self {
+            Direction::Sent => "client",
+            Direction::Received => "server",
+        }
+    }
+}
+
+fn get_message_type(message: &Message) -> &'static str {
+    match message {
+        Message::Text(_) => "text",
+        Message::Binary(_) => "binary",
+        Message::Ping(_) => "ping",
+        Message::Pong(_) => "pong",
+        Message::Close(_) => "close",
+        Message::Frame(_) => "frame",
+    }
+}
+
+/// A guard used to log metrics of a single socket connection, it ensures that the connection
+/// counter will be incremented then decremented exactly once, even in case of a panic.
+pub(crate) struct SocketMetricsGuard<'a> {
+    app: &'a str,
+    start_time: Instant,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+}
+
+impl<'a> SocketMetricsGuard<'a> {
+    pub(crate) fn new(app: &'a str) -> Self {
+        SOCKET_CONNECTED_GAUGE.with_label_values(&[app]).inc();
+        Self {
+            app,
+            start_time: Instant::now(),
+            received_bytes: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn get_bytes_counter(&self, direction: Direction) -> &AtomicU64 {
+        match direction {
+            Direction::Received => &self.received_bytes,
+            Direction::Sent => &self.sent_bytes,
+        }
+    }
+
+    /// Bytes which went through the socket in `direction` so far.
+    pub(crate) fn get_bytes(&self, direction: Direction) -> u64 {
+        self.get_bytes_counter(direction).load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    pub(crate) fn commit_message(&self, direction: Direction, message: &Message) {
+        let size = message.len();
+        let message_labels = [self.app, direction.as_str(), get_message_type(message)];
+        self.get_bytes_counter(direction)
+            .fetch_add(size as u64, Ordering::Relaxed);
+
+        SOCKET_MESSAGE_COUNTER
+            .with_label_values(&message_labels)
+            .inc();
+
+        SOCKET_MESSAGE_SIZE_HISTOGRAM
+            .with_label_values(&message_labels)
+            .observe(size as f64);
+
+        SOCKET_BYTES_COUNTER
+            .with_label_values(&[self.app, direction.as_str()])
+            .inc_by(size as f64);
+    }
+
+    /// Count bytes going through a socket whose messages are not seen.
+    pub(crate) fn commit_bytes(&self, direction: Direction, size: u64) {
+        self.get_bytes_counter(direction)
+            .fetch_add(size, Ordering::Relaxed);
+        SOCKET_BYTES_COUNTER
+            .with_label_values(&[self.app, direction.as_str()])
+            .inc_by(size as f64);
+    }
+}
+
+impl<'a> Drop for SocketMetricsGuard<'a> {
+    fn drop(&mut self) {
+        SOCKET_CONNECTED_GAUGE.with_label_values(&[self.app]).dec();
+
+        SOCKET_SESSION_DURATION_HISTOGRAM
+            .with_label_values(&[self.app])
+            .observe(self.start_time.elapsed().as_secs_f64());
+    }
+}
+
+fn get_metric_name(name: &str, protocol: Protocol) -> String {
+    let metrics_prefix = &runtime_config().metrics_prefix;
+    if metrics_prefix.is_empty() {
+        format!("gateway_{protocol}_{name}")
+    } else {
+        format!("gateway_{metrics_prefix}_{protocol}_{name}")
+    }
+}
+
+/// Get the buckets of an histogram, `histogram_buckets` from the runtime config takes precedence
+/// over the provided default.
+fn get_buckets(name: &str, protocol: Protocol, default: Vec<f64>) -> Vec<f64> {
+    runtime_config()
+        .histogram_buckets
+        .get(&format!("{protocol}_{name}"))
+        .cloned()
+        .unwrap_or(default)
+}
+
+static HTTP_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        opts!(
+            get_metric_name("requests_total", Protocol::Http),
+            "Number of HTTP requests made."
+        ),
+        &HTTP_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static HTTP_REQ_LAT_NAME: LazyLock<String> =
+    LazyLock::new(|| get_metric_name("request_duration_seconds", Protocol::Http));
+
+static HTTP_REQ_LAT_BUCKETS: LazyLock<Vec<f64>> = LazyLock::new(|| {
+    get_buckets(
+        "request_duration_seconds",
+        Protocol::Http,
+        prometheus::DEFAULT_BUCKETS.to_vec(),
+    )
+});
+
+static HTTP_REQ_LAT_HISTOGRAM: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        HTTP_REQ_LAT_NAME.to_string(),
+        "The HTTP request latencies in seconds.",
+        &HTTP_LABEL_NAMES,
+        HTTP_REQ_LAT_BUCKETS.clone()
+    )
+    .unwrap()
+});
+
+static HTTP_UPSTREAM_LAT_HISTOGRAM: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        get_metric_name("upstream_duration_seconds", Protocol::Http),
+        "The latencies of the round trip to the upstream server in seconds.",
+        &HTTP_LABEL_NAMES,
+        get_buckets(
+            "upstream_duration_seconds",
+            Protocol::Http,
+            prometheus::DEFAULT_BUCKETS.to_vec()
+        )
+    )
+    .unwrap()
+});
+
+static HTTP_REQ_SIZE_HISTOGRAM_LOW: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        get_metric_name("request_size_low_bytes", Protocol::Http),
+        "The HTTP request size in bytes (lower bound).",
+        &HTTP_LABEL_NAMES,
+        get_buckets(
+            "request_size_low_bytes",
+            Protocol::Http,
+            exponential_buckets(1.0, 2.0, 35).unwrap()
+        )
+    )
+    .unwrap()
+});
+
+static HTTP_REQ_SIZE_HISTOGRAM_HIGH: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        get_metric_name("request_size_high_bytes", Protocol::Http),
+        "The HTTP request size in bytes (upper bound).",
+        &HTTP_LABEL_NAMES,
+        get_buckets(
+            "request_size_high_bytes",
+            Protocol::Http,
+            exponential_buckets(1.0, 2.0, 35).unwrap()
+        )
+    )
+    .unwrap()
+});
+
+static HTTP_RES_SIZE_HISTOGRAM_LOW: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        get_metric_name("response_size_low_bytes", Protocol::Http),
+        "The HTTP response size in bytes (lower bound).",
+        &HTTP_LABEL_NAMES,
+        get_buckets(
+            "response_size_low_bytes",
+            Protocol::Http,
+            exponential_buckets(1.0, 2.0, 35).unwrap()
+        )
+    )
+    .unwrap()
+});
+
+static HTTP_RES_SIZE_HISTOGRAM_HIGH: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        get_metric_name("response_size_high_bytes", Protocol::Http),
+        "The HTTP response size in bytes (upper bound).",
+        &HTTP_LABEL_NAMES,
+        get_buckets(
+            "response_size_high_bytes",
+            Protocol::Http,
+            exponential_buckets(1.0, 2.0, 35).unwrap()
+        )
+    )
+    .unwrap()
+});
+
+static UPSTREAM_HEALTH_GAUGE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        get_metric_name("upstream_health", Protocol::Http),
+        "Reachability of the upstream server: 2 healthy, 1 degraded, 0 down.",
+        &APP_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static AUTH_SUCCESS_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("auth_success_total", Protocol::Http),
+        "Number of tokens successfully decoded.",
+        &AUTH_SUCCESS_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static AUTH_FAILURE_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("auth_failures_total", Protocol::Http),
+        "Number of tokens rejected by each auth source.",
+        &AUTH_FAILURE_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static PERMISSION_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("permission_checks_total", Protocol::Http),
+        "Number of permission checks allowed or denied, enforced or not (dry run).",
+        &PERMISSION_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static EXT_AUTHZ_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("ext_authz_decisions_total", Protocol::Http),
+        "Number of decisions of external authorization services, or errors getting them.",
+        &EXT_AUTHZ_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static QUOTA_REJECTION_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("quota_rejections_total", Protocol::Http),
+        "Number of requests rejected by the quota of their app or the global one.",
+        &QUOTA_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static SMUGGLING_REJECTION_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("smuggling_rejections_total", Protocol::Http),
+        "Number of connections whose requests were rejected for an ambiguous framing.",
+        &SMUGGLING_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static CLIENT_BLOCK_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("client_blocks_total", Protocol::Http),
+        "Number of tokens or IPs blocked for their ratio of errors.",
+        &CLIENT_BLOCK_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static SLO_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("slo_requests_total", Protocol::Http),
+        "Number of requests meeting (good) or not (bad) each SLO.",
+        &SLO_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static USER_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("user_requests_total", Protocol::Http),
+        "Number of requests made by the most active users.",
+        &USER_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static TENANT_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("tenant_requests_total", Protocol::Http),
+        "Number of requests of each tenant.",
+        &TENANT_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static TENANT_LAT_HISTOGRAM: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        get_metric_name("tenant_request_duration_seconds", Protocol::Http),
+        "The HTTP request latencies of each tenant in seconds.",
+        &TENANT_DURATION_LABEL_NAMES,
+        get_buckets(
+            "tenant_request_duration_seconds",
+            Protocol::Http,
+            prometheus::DEFAULT_BUCKETS.to_vec()
+        )
+    )
+    .unwrap()
+});
+
+static TENANT_IN_FLIGHT_GAUGE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        get_metric_name("tenant_requests_in_flight", Protocol::Http),
+        "Number of requests of each tenant being served.",
+        &TENANT_IN_FLIGHT_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static CONNECTION_ACCEPTED_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("connections_accepted_total", Protocol::Http),
+        "Number of accepted connections.",
+        &LISTENER_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static CONNECTION_ACTIVE_GAUGE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        get_metric_name("connections_active", Protocol::Http),
+        "Number of currently open connections.",
+        &LISTENER_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static CONNECTION_CLOSED_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("connections_closed_total", Protocol::Http),
+        "Number of closed connections by reason.",
+        &CONNECTION_CLOSED_LABEL_NAMES
+    )
+    .unwrap()
+});
+
+static SOCKET_CONNECTED_GAUGE: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        get_metric_name("clients", Protocol::Socket),
+        "Number simultaneously open sockets",
+        &SOCKET_LABEL_NAMES,
+    )
+    .unwrap()
+});
+
+static SOCKET_MESSAGE_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("messages_total", Protocol::Socket),
+        "Total number of messages going through sockets",
+        &SOCKET_MESSAGE_LABEL_NAMES,
+    )
+    .unwrap()
+});
+
+static SOCKET_MESSAGE_SIZE_HISTOGRAM: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        get_metric_name("message_size_bytes", Protocol::Socket),
+        "Size of messages going through sockets in bytes",
+        &SOCKET_MESSAGE_LABEL_NAMES,
+        get_buckets(
+            "message_size_bytes",
+            Protocol::Socket,
+            exponential_buckets(1.0, 2.0, 35).unwrap()
+        )
+    )
+    .unwrap()
+});
+
+static SOCKET_BYTES_COUNTER: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        get_metric_name("bytes_total", Protocol::Socket),
+        "Total number of bytes going through sockets",
+        &SOCKET_DIRECTION_LABEL_NAMES,
+    )
+    .unwrap()
+});
+
+static SOCKET_SESSION_DURATION_HISTOGRAM: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        get_metric_name("session_duration_seconds", Protocol::Socket),
+        "Lifetime of sockets in seconds",
+        &SOCKET_LABEL_NAMES,
+        get_buckets(
+            "session_duration_seconds",
+            Protocol::Socket,
+            exponential_buckets(1.0, 2.0, 20).unwrap()
+        )
+    )
+    .unwrap()
+});
@@ -0,0 +1,1287 @@
+use std::any::type_name;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Body;
+use hyper::header::{
+    HeaderValue, ALT_SVC, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE,
+};
+use hyper::http::uri::PathAndQuery;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use opentelemetry::context::FutureExt;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::{Context, KeyValue};
+use tower::util::BoxCloneService;
+use tower::{service_fn, Layer, Service, ServiceBuilder, ServiceExt};
+use url::Url;
+
+use crate::access_log::{AccessLog, SharedAccessLog};
+use crate::admin::internal_response;
+use crate::anomaly::{blocked_for, track_response, Client};
+use crate::api::{ApiDefinition, ApiMode};
+use crate::audit::audit_denial;
+use crate::auth::{get_claims, Claims};
+use crate::body_capture::is_capture_requested;
+use crate::client_ip::{get_client_ip, inject_forwarded_headers};
+use crate::cors::{inject_cors, CorsRequest};
+use crate::endpoint::Endpoint;
+use crate::ext_authz::{check, CheckRequest, Decision};
+use crate::fetch_crd::ApiLock;
+use crate::metrics::{
+    commit_ext_authz_decision, commit_http_metrics, commit_permission_check,
+    commit_quota_rejection, commit_tenant_request, commit_user_request, tenant_label,
+};
+use crate::openapi::{get_document, has_body, reject};
+use crate::permission::{has_perm, PermLock, PermissionId, RoleLock};
+use crate::predicate::eval_predicate;
+use crate::quota::{count_request, Quota};
+use crate::route::Node;
+use crate::runtime_config::runtime_config;
+use crate::script::{run_script, ScriptAction};
+use crate::security_headers::{SecurityHeadersInjected, SecurityHeadersSpec};
+use crate::telemetry::{end_span, start_server_span};
+use crate::tenancy::{resolve_tenant, TenantPermit};
+use crate::token_binding::check_binding;
+use crate::{
+    get_response, inject_identity, into_boxed_response, proxy, BoxResponse, HttpClient,
+    RequestBody, BAD_REQUEST, FORBIDDEN, NOT_FOUND, NO_CONTENT, OK, TOO_MANY_REQUESTS,
+};
+
+const URI_TOO_LONG: &[u8] = b"URI Too Long";
+const HEADERS_TOO_LARGE: &[u8] = b"Request Header Fields Too Large";
+const UNSUPPORTED_MEDIA_TYPE: &[u8] = b"Unsupported Media Type";
+const UNAUTHORIZED: &[u8] = b"Unauthorized";
+
+/// Headers of the original method and URI of forward-auth requests, as set by nginx
+/// (`proxy_set_header`) or Traefik.
+const ORIGINAL_METHOD_HEADERS: [&str; 2] = ["x-original-method", "x-forwarded-method"];
+const ORIGINAL_URI_HEADERS: [&str; 2] = ["x-original-uri", "x-forwarded-uri"];
+
+/// The rest of the pipeline, which a middleware calls to pass the request on.
+pub type Next = BoxCloneService<Request<RequestBody>, BoxResponse<Bytes>, anyhow::Error>;
+
+/// State shared by the requests of the main listener.
+#[derive(Clone)]
+pub struct GatewayState {
+    pub client: HttpClient,
+    pub perm_lock: PermLock,
+    pub role_lock: RoleLock,
+    pub api_lock: ApiLock,
+}
+
+/// Address of the peer of the connection, inserted before the request enters the pipeline.
+#[derive(Clone, Copy)]
+pub struct RemoteAddr(pub SocketAddr);
+
+/// Address of the client, which may be behind trusted proxies.
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// Whether the client asked for the bodies of the request to be captured.
+#[derive(Clone, Copy)]
+pub struct CaptureRequested(pub bool);
+
+/// Name of the app targeted by the request, with its leading `/`.
+#[derive(Clone)]
+pub struct App(pub String);
+
+/// The authenticated user.
+#[derive(Clone)]
+pub struct Identity {
+    pub claims: Claims,
+    pub token_type: String,
+}
+
+/// Marks a forward-auth request, answered once authorized instead of being forwarded.
+#[derive(Clone, Copy)]
+pub struct ForwardAuth;
+
+/// The tenant of the authenticated user, from `tenancy.tenant`.
+#[derive(Clone)]
+pub struct Tenant(pub String);
+
+/// The api and endpoint the request is forwarded to.
+#[derive(Clone)]
+pub struct Route {
+    pub api: Arc<ApiDefinition>,
+    pub endpoint: Arc<Endpoint>,
+    /// Path and query forwarded to the api.
+    pub forwarded_uri: String,
+    /// Upstream URI of `forwarded_uri`, the websocket one being only built for upgrades.
+    pub http_uri: String,
+    /// Routing tree of the api, resolving the endpoint of rewritten paths.
+    node: Arc<Node>,
+}
+
+impl Route {
+    /// Forward the request to another path and query of the api.
+    fn set_forwarded_uri(&mut self, forwarded_uri: String) {
+        self.http_uri = self.api.http_uri(&forwarded_uri);
+        self.forwarded_uri = forwarded_uri;
+    }
+
+    /// Forward the request to another path and query of the api, resolving its endpoint again.
+    /// Returns `false` when no endpoint of the api matches the new path.
+    fn reroute(&mut self, method: &str, forwarded_uri: String) -> bool {
+        if let ApiMode::ForwardStrict(_) = self.api.spec.mode {
+            let path = match forwarded_uri.split_once('?') {
+                Some((path, _)) => path,
+                None => &forwarded_uri,
+            };
+            match self.node.match_path(path, method) {
+                Some(endpoint) => self.endpoint = endpoint.clone(),
+                None => return false,
+            }
+        }
+        self.set_forwarded_uri(forwarded_uri);
+        true
+    }
+}
+
+/// The permission enforced on the request, which websocket tunnels keep checking.
+#[derive(Clone)]
+pub struct EnforcedPermission(pub Option<PermissionId>);
+
+/// Layer running `f` with each request and the rest of the pipeline, which `f` calls to pass the
+/// request on or skips to answer it itself.
+pub fn middleware<F>(f: F) -> MiddlewareLayer<F> {
+    MiddlewareLayer { f }
+}
+
+#[derive(Clone)]
+pub struct MiddlewareLayer<F> {
+    f: F,
+}
+
+impl<F, S> Layer<S> for MiddlewareLayer<F>
+where
+    F: Clone,
+    S: Service<Request<RequestBody>, Response = BoxResponse<Bytes>, Error = anyhow::Error>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Service = Middleware<F>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Middleware {
+            f: self.f.clone(),
+            next: BoxCloneService::new(inner),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Middleware<F> {
+    f: F,
+    next: Next,
+}
+
+impl<F, Fut> Service<Request<RequestBody>> for Middleware<F>
+where
+    F: Fn(Request<RequestBody>, Next) -> Fut,
+    Fut: Future<Output = Result<BoxResponse<Bytes>>> + Send + 'static,
+{
+    type Response = BoxResponse<Bytes>;
+    type Error = anyhow::Error;
+    type Future = BoxFuture<'static, Result<BoxResponse<Bytes>>>;
+
+    // The rest of the pipeline is made ready by `oneshot` when the request is passed on.
+    fn poll_ready(&mut self, _: &mut TaskContext<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<RequestBody>) -> Self::Future {
+        Box::pin((self.f)(req, self.next.clone()))
+    }
+}
+
+/// Build the pipeline of the main listener, from the outermost layer to the proxy.
+pub fn gateway_service(state: GatewayState) -> Next {
+    let api_lock = state.api_lock.clone();
+    let headers_api_lock = state.api_lock.clone();
+    let filter_api_lock = state.api_lock.clone();
+    let perm_lock = state.perm_lock.clone();
+    let transform_perm_lock = state.perm_lock.clone();
+    let script_perm_lock = state.perm_lock.clone();
+    let role_lock = state.role_lock.clone();
+    let client = state.client.clone();
+
+    let service = ServiceBuilder::new()
+        .layer(middleware(resolve_client_ip))
+        .layer(middleware(inject_security_headers))
+        .layer(middleware(advertise_http3))
+        .layer(middleware(serve_internal))
+        .layer(middleware(observe))
+        .layer(middleware(cors))
+        .layer(middleware(enforce_request_limits))
+        .layer(middleware(detect_body_capture))
+        .layer(middleware(answer_preflight))
+        .layer(middleware(reject_blocked_source))
+        .layer(middleware(forward_auth))
+        .layer(middleware(resolve_app))
+        .layer(middleware(move |req, next| {
+            inject_api_security_headers(req, next, headers_api_lock.clone())
+        }))
+        .layer(middleware(move |req, next| {
+            filter_source(req, next, filter_api_lock.clone())
+        }))
+        .layer(middleware(authenticate))
+        .layer(middleware(reject_blocked_token))
+        .layer(middleware(scope_tenant))
+        .layer(middleware(move |req, next| {
+            resolve_route(req, next, api_lock.clone())
+        }))
+        .layer(middleware(check_token_binding))
+        .layer(middleware(check_content_type))
+        .layer(middleware(move |req, next| {
+            authorize(req, next, perm_lock.clone())
+        }))
+        .layer(middleware(enforce_quota))
+        .layer(middleware(validate_openapi))
+        .layer(middleware(move |req, next| {
+            external_authorize(req, next, client.clone())
+        }))
+        .layer(middleware(move |req, next| {
+            answer_forward_auth(req, next, role_lock.clone())
+        }))
+        .layer(middleware(move |req, next| {
+            transform_request(req, next, transform_perm_lock.clone())
+        }))
+        .layer(middleware(move |req, next| {
+            run_api_script(req, next, script_perm_lock.clone())
+        }))
+        .service(service_fn(move |req| proxy(req, state.clone())));
+
+    BoxCloneService::new(service)
+}
+
+/// Get an extension inserted by an outer layer.
+pub fn extension<T, B>(req: &Request<B>) -> Result<&T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    req.extensions()
+        .get()
+        .ok_or_else(|| anyhow!("Missing request extension {}", type_name::<T>()))
+}
+
+/// Get the access log of the request, a detached one if it is not observed.
+pub fn access_log<B>(req: &Request<B>) -> SharedAccessLog {
+    req.extensions().get().cloned().unwrap_or_default()
+}
+
+fn status_response(status_code: StatusCode, content: &'static [u8]) -> Result<BoxResponse<Bytes>> {
+    get_response(status_code, content).map(into_boxed_response)
+}
+
+async fn resolve_client_ip(
+    mut req: Request<RequestBody>,
+    next: Next,
+) -> Result<BoxResponse<Bytes>> {
+    let RemoteAddr(remote_addr) = *extension(&req)?;
+    let client_ip = get_client_ip(req.headers(), remote_addr.ip());
+    inject_forwarded_headers(req.headers_mut(), client_ip, remote_addr.ip());
+    req.extensions_mut().insert(ClientIp(client_ip));
+
+    next.oneshot(req).await
+}
+
+/// Add the global `security_headers` to the responses not handled by
+/// `inject_api_security_headers`, including those of the gateway itself.
+async fn inject_security_headers(
+    req: Request<RequestBody>,
+    next: Next,
+) -> Result<BoxResponse<Bytes>> {
+    let mut response = next.oneshot(req).await?;
+    if response
+        .extensions()
+        .get::<SecurityHeadersInjected>()
+        .is_none()
+    {
+        if let Some(security_headers) = &runtime_config().security_headers {
+            security_headers.inject(response.headers_mut());
+        }
+    }
+
+    Ok(response)
+}
+
+/// Advertise the `http3` listener, to which clients switch for their next requests.
+async fn advertise_http3(req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let mut response = next.oneshot(req).await?;
+    if let Some(http3) = &runtime_config().http3 {
+        if let Ok(alt_svc) = HeaderValue::from_str(&http3.alt_svc()) {
+            response.headers_mut().insert(ALT_SVC, alt_svc);
+        }
+    }
+
+    Ok(response)
+}
+
+async fn serve_internal(req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let ClientIp(client_ip) = *extension(&req)?;
+    if let Some(response) = internal_response(&req, client_ip, false).await {
+        return response;
+    }
+
+    next.oneshot(req).await
+}
+
+/// Trace, log, audit and count the request once its response is known.
+async fn observe(mut req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let start_time = Instant::now();
+    let method = req.method().clone();
+    let req_size = req.size_hint();
+    let ClientIp(client_ip) = *extension(&req)?;
+
+    let mut access_log = AccessLog::new(&req);
+    access_log.client_ip = Some(client_ip);
+    let access_log = SharedAccessLog::new(access_log);
+    req.extensions_mut().insert(access_log.clone());
+
+    let cx = start_server_span(&req);
+    // The context is attached so that inner layers can start child spans of the request.
+    let response = next.oneshot(req).with_context(cx.clone()).await;
+    let status_code = response.as_ref().ok().map(Response::status);
+    end_span(&cx, status_code);
+
+    let mut access_log = access_log.lock();
+    if let Ok(response) = &response {
+        // The context is attached so that metrics can reference the trace as an exemplar.
+        let _guard = cx.attach();
+        commit_http_metrics(
+            access_log.app.as_deref().unwrap_or(""),
+            &method,
+            &start_time,
+            response.status(),
+            &req_size,
+            &response.size_hint(),
+        );
+    }
+
+    access_log.status_code = status_code
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+        .as_u16();
+    access_log.duration_ms = start_time.elapsed().as_millis();
+    access_log.emit();
+    audit_denial(&access_log, client_ip);
+    track_response(&access_log, client_ip);
+    if let (Some(app), Some(token_id)) = (&access_log.app, &access_log.token_id) {
+        commit_user_request(app, token_id);
+    }
+    if let (Some(app), Some(tenant), Some(status_code)) =
+        (&access_log.app, &access_log.tenant, status_code)
+    {
+        commit_tenant_request(app, &tenant_label(tenant), status_code, &start_time);
+    }
+
+    response
+}
+
+async fn cors(req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let cors_request = CorsRequest::new(&req);
+    let mut response = next.oneshot(req).await;
+    if let Ok(response) = &mut response {
+        inject_cors(&cors_request, response.headers_mut());
+    }
+
+    response
+}
+
+/// Check the `request_limits`, returning the status and content of the response to reject the
+/// request with if it is too large.
+fn check_request_limits<B>(req: &Request<B>) -> Option<(StatusCode, &'static [u8], String)> {
+    let runtime_config = runtime_config();
+    let limits = &runtime_config.request_limits;
+
+    let uri_length = req.uri().authority().map_or(0, |a| a.as_str().len())
+        + req.uri().path_and_query().map_or(0, |p| p.as_str().len());
+    if uri_length > limits.max_uri_length {
+        return Some((
+            StatusCode::URI_TOO_LONG,
+            URI_TOO_LONG,
+            format!("URI of {uri_length} bytes is too long"),
+        ));
+    }
+
+    let mut header_bytes = 0;
+    for (name, value) in req.headers() {
+        let header_size = name.as_str().len() + value.len();
+        if header_size > limits.max_header_size {
+            return Some((
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                HEADERS_TOO_LARGE,
+                format!("Header {name} of {header_size} bytes is too large"),
+            ));
+        }
+        header_bytes += header_size;
+    }
+    if header_bytes > limits.max_header_bytes {
+        return Some((
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            HEADERS_TOO_LARGE,
+            format!("Headers of {header_bytes} bytes are too large"),
+        ));
+    }
+
+    None
+}
+
+async fn enforce_request_limits(
+    req: Request<RequestBody>,
+    next: Next,
+) -> Result<BoxResponse<Bytes>> {
+    if let Some((status_code, content, error)) = check_request_limits(&req) {
+        access_log(&req).lock().set_error(error);
+        return status_response(status_code, content);
+    }
+
+    next.oneshot(req).await
+}
+
+async fn detect_body_capture(
+    mut req: Request<RequestBody>,
+    next: Next,
+) -> Result<BoxResponse<Bytes>> {
+    let ClientIp(client_ip) = *extension(&req)?;
+    let capture_requested = is_capture_requested(req.headers(), client_ip);
+    req.headers_mut()
+        .remove(&runtime_config().body_capture.trigger_header);
+    req.extensions_mut()
+        .insert(CaptureRequested(capture_requested));
+
+    next.oneshot(req).await
+}
+
+/// Answer CORS preflights, their headers being added by the `cors` layer.
+async fn answer_preflight(req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    if req.method() == Method::OPTIONS {
+        return status_response(StatusCode::NO_CONTENT, NO_CONTENT);
+    }
+
+    next.oneshot(req).await
+}
+
+/// Answer the requests of a blocked client with `429`.
+fn reject_blocked(
+    req: &Request<RequestBody>,
+    client: &Client,
+) -> Result<Option<BoxResponse<Bytes>>> {
+    let Some(blocked_for) = blocked_for(client) else {
+        return Ok(None);
+    };
+    access_log(req)
+        .lock()
+        .set_error(format!("Blocked {client} for {}s", blocked_for.as_secs()));
+    let mut response = status_response(StatusCode::TOO_MANY_REQUESTS, TOO_MANY_REQUESTS)?;
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(blocked_for.as_secs() + 1));
+    Ok(Some(response))
+}
+
+fn original_header<'a, B>(req: &'a Request<B>, names: &[&str]) -> Option<&'a str> {
+    names
+        .iter()
+        .find_map(|name| req.headers().get(*name)?.to_str().ok())
+}
+
+/// Turn a forward-auth request of another proxy into the original request it describes, to be
+/// authenticated and authorized as if it was forwarded, then answered by `answer_forward_auth`.
+async fn forward_auth(mut req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let is_forward_auth = runtime_config()
+        .forward_auth
+        .as_ref()
+        .is_some_and(|forward_auth| forward_auth.path == req.uri().path());
+    if !is_forward_auth {
+        return next.oneshot(req).await;
+    }
+
+    let method = original_header(&req, &ORIGINAL_METHOD_HEADERS)
+        .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+    let path_and_query = original_header(&req, &ORIGINAL_URI_HEADERS)
+        .and_then(|uri| uri.parse::<Uri>().ok()?.path_and_query().cloned());
+    let (Some(method), Some(path_and_query)) = (method, path_and_query) else {
+        access_log(&req)
+            .lock()
+            .set_error("Missing original method or URI of forward-auth request");
+        return status_response(StatusCode::BAD_REQUEST, BAD_REQUEST);
+    };
+
+    *req.method_mut() = method;
+    *req.uri_mut() = Uri::from(path_and_query);
+    req.extensions_mut().insert(ForwardAuth);
+    access_log(&req).lock().set_request(&req);
+
+    next.oneshot(req).await
+}
+
+/// Answer an authorized forward-auth request with `200` and the identity headers, which the proxy
+/// copies to the request it forwards.
+async fn answer_forward_auth(
+    req: Request<RequestBody>,
+    next: Next,
+    role_lock: RoleLock,
+) -> Result<BoxResponse<Bytes>> {
+    if req.extensions().get::<ForwardAuth>().is_none() {
+        return next.oneshot(req).await;
+    }
+
+    let Route { api, .. } = extension(&req)?;
+    let Identity { claims, token_type } = extension(&req)?;
+    let mut response = get_response(StatusCode::OK, OK)?;
+    inject_identity(response.headers_mut(), claims, token_type, api, &role_lock);
+
+    Ok(into_boxed_response(response))
+}
+
+/// Reject a request without a valid token, with `401` if it is a forward-auth request as
+/// expected by the proxies.
+fn reject_unauthenticated<B>(req: &Request<B>) -> Result<BoxResponse<Bytes>> {
+    if req.extensions().get::<ForwardAuth>().is_none() {
+        return status_response(StatusCode::FORBIDDEN, FORBIDDEN);
+    }
+
+    let mut response = status_response(StatusCode::UNAUTHORIZED, UNAUTHORIZED)?;
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    Ok(response)
+}
+
+/// Reject the requests of a source IP blocked by `anomaly_detection`, before authenticating them.
+async fn reject_blocked_source(
+    req: Request<RequestBody>,
+    next: Next,
+) -> Result<BoxResponse<Bytes>> {
+    let ClientIp(client_ip) = *extension(&req)?;
+    if let Some(response) = reject_blocked(&req, &Client::Ip(client_ip))? {
+        return Ok(response);
+    }
+
+    next.oneshot(req).await
+}
+
+/// Reject the requests of a token blocked by `anomaly_detection`.
+async fn reject_blocked_token(req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let Identity { claims, .. } = extension(&req)?;
+    if let Some(response) = reject_blocked(&req, &Client::Token(claims.token_id.clone()))? {
+        return Ok(response);
+    }
+
+    next.oneshot(req).await
+}
+
+/// Resolve the tenant of the user with `tenancy.tenant`, holding one of its concurrent requests
+/// until the response is sent.
+async fn scope_tenant(mut req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let runtime_config = runtime_config();
+    let Some(tenancy) = &runtime_config.tenancy else {
+        return next.oneshot(req).await;
+    };
+    let Identity { claims, .. } = extension(&req)?;
+    let Some(tenant) = resolve_tenant(claims, tenancy) else {
+        return next.oneshot(req).await;
+    };
+
+    access_log(&req).lock().tenant = Some(tenant.clone());
+    let (_, max_concurrent_requests) = tenancy.limits(&tenant);
+    let Some(permit) = TenantPermit::acquire(&tenant, max_concurrent_requests) else {
+        let App(app) = extension(&req)?;
+        commit_quota_rejection(app, "tenant_concurrency");
+        access_log(&req)
+            .lock()
+            .set_error("Over the concurrent requests of the tenant");
+        return status_response(StatusCode::TOO_MANY_REQUESTS, TOO_MANY_REQUESTS);
+    };
+    req.extensions_mut().insert(Tenant(tenant));
+
+    let response = next.oneshot(req).await?;
+    // The permit is released once the body is sent, or dropped.
+    Ok(response.map(|body| {
+        body.map_frame(move |frame| {
+            let _ = &permit;
+            frame
+        })
+        .boxed()
+    }))
+}
+
+async fn resolve_app(mut req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let path = req.uri().path();
+    let Some(slash_index) = path[1..].find('/') else {
+        access_log(&req).lock().set_error("No / found");
+        return status_response(StatusCode::NOT_FOUND, NOT_FOUND);
+    };
+    let app = path[..slash_index + 1].to_string();
+
+    access_log(&req).lock().app = Some(app.clone());
+    req.extensions_mut().insert(App(app));
+
+    next.oneshot(req).await
+}
+
+/// Add the `security_headers` of the api, merged with the global ones, to its responses.
+async fn inject_api_security_headers(
+    req: Request<RequestBody>,
+    next: Next,
+    api_lock: ApiLock,
+) -> Result<BoxResponse<Bytes>> {
+    let App(app) = extension(&req)?;
+    let security_headers = match api_lock.load().get(app) {
+        Some((api, _)) => api.spec.security_headers.as_ref().map(|security_headers| {
+            SecurityHeadersSpec::merge(runtime_config().security_headers.as_ref(), security_headers)
+        }),
+        None => None,
+    };
+    let Some(security_headers) = security_headers else {
+        return next.oneshot(req).await;
+    };
+
+    let mut response = next.oneshot(req).await?;
+    security_headers.inject(response.headers_mut());
+    response.extensions_mut().insert(SecurityHeadersInjected);
+    Ok(response)
+}
+
+/// Reject the sources not allowed by the `ip_filter` of the api, before authenticating them.
+async fn filter_source(
+    req: Request<RequestBody>,
+    next: Next,
+    api_lock: ApiLock,
+) -> Result<BoxResponse<Bytes>> {
+    let App(app) = extension(&req)?;
+    let ClientIp(client_ip) = *extension(&req)?;
+
+    let allowed = match api_lock.load().get(app) {
+        Some((api, _)) => api
+            .spec
+            .ip_filter
+            .as_ref()
+            .is_none_or(|ip_filter| ip_filter.is_allowed(client_ip)),
+        None => true,
+    };
+    if !allowed {
+        access_log(&req)
+            .lock()
+            .set_error(format!("Source {client_ip} not allowed"));
+        return status_response(StatusCode::FORBIDDEN, FORBIDDEN);
+    }
+
+    next.oneshot(req).await
+}
+
+fn get_auth_from_url(uri: &Uri) -> Option<String> {
+    let url = Url::parse(&format!("http://localhost{}", uri.path_and_query()?)).ok()?;
+    for (key, value) in url.query_pairs() {
+        if key != "_auth_token" {
+            continue;
+        }
+        return Some(format!("Bearer {}", value));
+    }
+    warn!("event='No authorization header found'");
+    None
+}
+
+async fn authenticate(mut req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let access_log = access_log(&req);
+
+    let authorization = match req.headers().get(AUTHORIZATION) {
+        None => match get_auth_from_url(req.uri()) {
+            None => {
+                access_log.lock().set_error("No authorization header");
+                return reject_unauthenticated(&req);
+            }
+            Some(authorization) => authorization,
+        },
+        Some(authorization) => match authorization.to_str() {
+            Err(e) => {
+                access_log
+                    .lock()
+                    .set_error(format!("Error in authorization: {e:#?}"));
+                return reject_unauthenticated(&req);
+            }
+            Ok(authorization) => authorization.to_string(),
+        },
+    };
+    let Some((claims, token_type)) = get_claims(&authorization).await else {
+        access_log.lock().set_error("Invalid or no claim");
+        return reject_unauthenticated(&req);
+    };
+
+    {
+        let mut access_log = access_log.lock();
+        access_log.user_sub = Some(claims.sub.clone());
+        access_log.token_id = Some(claims.token_id.clone());
+    }
+    req.extensions_mut().insert(Identity { claims, token_type });
+
+    next.oneshot(req).await
+}
+
+async fn resolve_route(
+    mut req: Request<RequestBody>,
+    next: Next,
+    api_lock: ApiLock,
+) -> Result<BoxResponse<Bytes>> {
+    let access_log = access_log(&req);
+    let App(app) = extension(&req)?;
+
+    let Some(forwarded_uri) = req.uri().path_and_query().map(|x| &x.as_str()[app.len()..]) else {
+        access_log.lock().set_error("Forward api not found");
+        return status_response(StatusCode::NOT_FOUND, NOT_FOUND);
+    };
+    let forwarded_path = &req.uri().path()[app.len()..];
+
+    let route = match api_lock.load().get(app) {
+        None => {
+            access_log.lock().set_error("Forward api not found");
+            return status_response(StatusCode::NOT_FOUND, NOT_FOUND);
+        }
+        Some((api, _)) if !api.spec.enabled => {
+            access_log.lock().set_error("Api disabled");
+            let status_code =
+                StatusCode::from_u16(api.spec.disabled_status).unwrap_or(StatusCode::NOT_FOUND);
+            return status_response(
+                status_code,
+                status_code
+                    .canonical_reason()
+                    .unwrap_or_default()
+                    .as_bytes(),
+            );
+        }
+        Some((api, _)) if api.in_maintenance() => {
+            access_log.lock().set_error("Api in maintenance");
+            return status_response(StatusCode::SERVICE_UNAVAILABLE, b"Service Unavailable");
+        }
+        Some((api, node)) => {
+            let endpoint = match api.spec.mode {
+                ApiMode::ForwardAll => node.forward_all(req.method().as_str(), app),
+                ApiMode::ForwardStrict(_) => {
+                    match node.match_path(forwarded_path, req.method().as_str()) {
+                        Some(endpoint) => endpoint.clone(),
+                        None => {
+                            access_log.lock().set_error("Endpoint not found in service");
+                            return status_response(StatusCode::NOT_FOUND, NOT_FOUND);
+                        }
+                    }
+                }
+            };
+            Route {
+                api: api.clone(),
+                endpoint,
+                forwarded_uri: forwarded_uri.to_string(),
+                http_uri: api.http_uri(forwarded_uri),
+                node: node.clone(),
+            }
+        }
+    };
+
+    if let Some(predicate) = &route.api.spec.predicate {
+        let ClientIp(client_ip) = *extension(&req)?;
+        let Identity { claims, token_type } = extension(&req)?;
+        match eval_predicate(app, predicate, req.headers(), client_ip, claims, token_type) {
+            Ok(true) => (),
+            Ok(false) => {
+                access_log.lock().set_error("Predicate of the api not met");
+                return status_response(StatusCode::NOT_FOUND, NOT_FOUND);
+            }
+            Err(e) => {
+                access_log.lock().set_error(format!("Predicate: {e}"));
+                return status_response(StatusCode::NOT_FOUND, NOT_FOUND);
+            }
+        }
+    }
+
+    {
+        let mut access_log = access_log.lock();
+        access_log.perm = Some(route.endpoint.permission.clone());
+        access_log.set_upstream_uri(&route.http_uri);
+    }
+    Context::current()
+        .span()
+        .set_attribute(KeyValue::new("gateway.app", app.to_string()));
+    req.extensions_mut().insert(route);
+
+    next.oneshot(req).await
+}
+
+/// Reject the tokens used from another client than the one they were bound to by the
+/// `token_binding` of their api.
+async fn check_token_binding(req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let Route { api, .. } = extension(&req)?;
+    if let Some(token_binding) = &api.spec.token_binding {
+        let App(app) = extension(&req)?;
+        let ClientIp(client_ip) = *extension(&req)?;
+        let Identity { claims, .. } = extension(&req)?;
+        if !check_binding(app, token_binding, claims, client_ip, req.headers()) {
+            warn!(
+                "event='Token {} of {app} used from another client'",
+                claims.token_id
+            );
+            access_log(&req)
+                .lock()
+                .set_error("Token bound to another client");
+            return status_response(StatusCode::FORBIDDEN, FORBIDDEN);
+        }
+    }
+
+    next.oneshot(req).await
+}
+
+/// Reject the requests whose body is not of one of the `content_types` of their endpoint with
+/// `415`.
+async fn check_content_type(req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let Route { endpoint, .. } = extension(&req)?;
+    if has_body(req.headers()) {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok());
+        if !endpoint.accepts_content_type(content_type) {
+            access_log(&req).lock().set_error(format!(
+                "Content type {} not accepted",
+                content_type.unwrap_or("missing")
+            ));
+            return status_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, UNSUPPORTED_MEDIA_TYPE);
+        }
+    }
+
+    next.oneshot(req).await
+}
+
+/// Count the request against the quota of its app and the global one, answering it with `429`
+/// once either is exceeded.
+async fn enforce_quota(req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let App(app) = extension(&req)?;
+    let Route { api, .. } = extension(&req)?;
+    let runtime_config = runtime_config();
+    let app_quota = api
+        .spec
+        .quota
+        .as_ref()
+        .map(Quota::from)
+        .or_else(|| runtime_config.quotas.apps.get(app).map(Quota::from));
+    let tenant_quota = req.extensions().get().and_then(|Tenant(tenant)| {
+        let (quota, _) = runtime_config.tenancy.as_ref()?.limits(tenant);
+        Some((tenant.as_str(), Quota::from(quota?)))
+    });
+    let global_quota = runtime_config.quotas.global.as_ref().map(Quota::from);
+
+    match count_request(app, app_quota, tenant_quota, global_quota).await {
+        Ok(None) => next.oneshot(req).await,
+        Ok(Some(state)) => {
+            let mut response = next.oneshot(req).await?;
+            state.inject_headers(response.headers_mut());
+            Ok(response)
+        }
+        Err(exceeded) => {
+            commit_quota_rejection(app, exceeded.scope);
+            access_log(&req)
+                .lock()
+                .set_error(format!("Over the {} quota", exceeded.scope));
+            let mut response = status_response(StatusCode::TOO_MANY_REQUESTS, TOO_MANY_REQUESTS)?;
+            exceeded.inject_headers(response.headers_mut());
+            Ok(response)
+        }
+    }
+}
+
+async fn authorize(
+    mut req: Request<RequestBody>,
+    next: Next,
+    perm_lock: PermLock,
+) -> Result<BoxResponse<Bytes>> {
+    if let Some(response) = check_permission(&mut req, &perm_lock)? {
+        return Ok(response);
+    }
+
+    next.oneshot(req).await
+}
+
+/// Check the permission of the endpoint of the request, returning the response of a denied one.
+fn check_permission<B>(
+    req: &mut Request<B>,
+    perm_lock: &PermLock,
+) -> Result<Option<BoxResponse<Bytes>>> {
+    let App(app) = extension(req)?;
+    let Route { api, endpoint, .. } = extension(req)?;
+    let Identity { claims, .. } = extension(req)?;
+
+    // Permissions are checked but not enforced by dry runs.
+    let dry_run = api.spec.dry_run_permissions || runtime_config().dry_run_permissions;
+    if endpoint.check_permission {
+        let allowed = has_perm(perm_lock, endpoint.permission_id, &claims.token_id);
+        commit_permission_check(app, allowed, dry_run);
+
+        if !allowed && dry_run {
+            warn!(
+                "event='Permission {} missing for {}, forwarded by dry run'",
+                endpoint.permission, claims.token_id
+            );
+            access_log(req).lock().dry_run_denied = Some(true);
+        } else if !allowed {
+            access_log(req)
+                .lock()
+                .set_error("Does not have the permission");
+            return status_response(StatusCode::FORBIDDEN, FORBIDDEN).map(Some);
+        }
+    }
+
+    let permission = (endpoint.check_permission && !dry_run).then_some(endpoint.permission_id);
+    req.extensions_mut().insert(EnforcedPermission(permission));
+
+    Ok(None)
+}
+
+/// Forward the request to `forwarded_uri`, rewritten by the api, checking the permission of its
+/// endpoint again so that rewrites cannot reach endpoints the user is not allowed to.
+fn reroute<B>(
+    req: &mut Request<B>,
+    forwarded_uri: String,
+    perm_lock: &PermLock,
+) -> Result<Option<BoxResponse<Bytes>>> {
+    let access_log = access_log(req);
+    let method = req.method().clone();
+    let Some(route) = req.extensions_mut().get_mut::<Route>() else {
+        return Err(anyhow!(
+            "Missing request extension {}",
+            type_name::<Route>()
+        ));
+    };
+    if !route.reroute(method.as_str(), forwarded_uri) {
+        access_log
+            .lock()
+            .set_error("Rewritten endpoint not found in service");
+        return status_response(StatusCode::NOT_FOUND, NOT_FOUND).map(Some);
+    }
+    access_log.lock().set_upstream_uri(&route.http_uri);
+
+    check_permission(req, perm_lock)
+}
+
+/// Validate the request against the `openapi` document of the api, its JSON body being validated
+/// once buffered by `proxy`.
+async fn validate_openapi(mut req: Request<RequestBody>, next: Next) -> Result<BoxResponse<Bytes>> {
+    let access_log = access_log(&req);
+    let Route {
+        api, forwarded_uri, ..
+    } = extension(&req)?;
+    let Some(openapi) = &api.spec.openapi else {
+        return next.oneshot(req).await;
+    };
+
+    let document = match get_document(openapi) {
+        Ok(document) => document,
+        Err(e) => {
+            access_log.lock().set_error(format!("OpenAPI: {e}"));
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR, b"Internal Server Error");
+        }
+    };
+    let (path, query) = match forwarded_uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (forwarded_uri.as_str(), None),
+    };
+    match document.validate_request(openapi, req.method(), path, query, req.headers()) {
+        Ok(Some(body_schema)) => {
+            req.extensions_mut().insert(body_schema);
+        }
+        Ok(None) => (),
+        Err(e) => return reject(StatusCode::BAD_REQUEST, e, &access_log),
+    }
+
+    next.oneshot(req).await
+}
+
+/// Ask the external authorization service of the api whether to forward the request.
+async fn external_authorize(
+    mut req: Request<RequestBody>,
+    next: Next,
+    client: HttpClient,
+) -> Result<BoxResponse<Bytes>> {
+    let access_log = access_log(&req);
+    let Route {
+        api, forwarded_uri, ..
+    } = extension(&req)?;
+    let Some(ext_authz) = api.spec.ext_authz.clone() else {
+        return next.oneshot(req).await;
+    };
+    let App(app) = extension(&req)?;
+    let app = app.clone();
+    let Identity { claims, .. } = extension(&req)?;
+    let ClientIp(client_ip) = *extension(&req)?;
+
+    let check_request = CheckRequest {
+        app: &app,
+        method: req.method(),
+        path: forwarded_uri,
+        headers: req.headers(),
+        claims,
+        client_ip,
+    };
+    match check(&client, &ext_authz, &check_request).await {
+        Ok(Decision::Allow(headers)) => {
+            commit_ext_authz_decision(&app, "allowed");
+            req.headers_mut().extend(headers);
+            next.oneshot(req).await
+        }
+        Ok(Decision::Deny(response)) => {
+            commit_ext_authz_decision(&app, "denied");
+            access_log.lock().set_error("Denied by ext_authz");
+            Ok(into_boxed_response(response))
+        }
+        Err(e) if ext_authz.allow_on_error => {
+            commit_ext_authz_decision(&app, "error");
+            warn!("event='Ext authz of {app} failed, request allowed: {e}'");
+            next.oneshot(req).await
+        }
+        Err(e) => {
+            commit_ext_authz_decision(&app, "error");
+            access_log.lock().set_error(format!("Ext authz: {e}"));
+            status_response(StatusCode::FORBIDDEN, FORBIDDEN)
+        }
+    }
+}
+
+/// Apply the `request_transform` of the api.
+async fn transform_request(
+    mut req: Request<RequestBody>,
+    next: Next,
+    perm_lock: PermLock,
+) -> Result<BoxResponse<Bytes>> {
+    let Route {
+        api, forwarded_uri, ..
+    } = extension(&req)?;
+    let (api, forwarded_uri) = (api.clone(), forwarded_uri.clone());
+    let Some(request_transform) = &api.spec.request_transform else {
+        return next.oneshot(req).await;
+    };
+
+    let transformed_uri = request_transform.apply(&forwarded_uri, req.headers_mut());
+    if transformed_uri != forwarded_uri {
+        if let Some(response) = reroute(&mut req, transformed_uri, &perm_lock)? {
+            return Ok(response);
+        }
+    }
+
+    next.oneshot(req).await
+}
+
+/// Run the script of the api, which can rewrite the forwarded path and the headers or answer the
+/// request itself.
+async fn run_api_script(
+    mut req: Request<RequestBody>,
+    next: Next,
+    perm_lock: PermLock,
+) -> Result<BoxResponse<Bytes>> {
+    let access_log = access_log(&req);
+    let Route {
+        api, forwarded_uri, ..
+    } = extension(&req)?;
+    let Some(script) = &api.spec.script else {
+        return next.oneshot(req).await;
+    };
+    let App(app) = extension(&req)?;
+    let Identity { claims, .. } = extension(&req)?;
+
+    let (app, script, forwarded_uri, user) = (
+        app.clone(),
+        script.clone(),
+        forwarded_uri.clone(),
+        claims.token_id.clone(),
+    );
+    let method = req.method().clone();
+    let action = run_script(
+        &app,
+        &script,
+        &method,
+        &forwarded_uri,
+        &user,
+        req.headers_mut(),
+    );
+
+    match action {
+        Err(e) => {
+            access_log.lock().set_error(format!("Script: {e}"));
+            status_response(StatusCode::INTERNAL_SERVER_ERROR, b"Internal Server Error")
+        }
+        Ok(ScriptAction::Respond(status_code, body)) => Ok(into_boxed_response(
+            Response::builder()
+                .status(status_code)
+                .body(Full::from(body))?,
+        )),
+        Ok(ScriptAction::Forward(path)) if path == forwarded_uri => next.oneshot(req).await,
+        Ok(ScriptAction::Forward(path)) => {
+            if path.parse::<PathAndQuery>().is_err() || !path.starts_with('/') {
+                access_log
+                    .lock()
+                    .set_error(format!("Script: invalid path {path}"));
+                return status_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    b"Internal Server Error",
+                );
+            }
+            if let Some(response) = reroute(&mut req, path, &perm_lock)? {
+                return Ok(response);
+            }
+            next.oneshot(req).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::path::PathBuf;
+
+    use arc_swap::ArcSwap;
+    use hyper::HeaderMap;
+    use serde_json::json;
+
+    use super::*;
+    use crate::api::ApiDefinitionSpec;
+    use crate::fetch_crd::build_api;
+    use crate::permission::PermissionIndex;
+    use crate::runtime_config::set_config_path;
+
+    /// A request to `/shop/public`, routed to a `forward_strict` api whose `spec` is merged with
+    /// `extra`, and a user allowed to `GET /public` but not `GET /admin`.
+    fn routed_request(extra: serde_json::Value) -> (Request<()>, PermLock) {
+        set_config_path(PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/bench/config.yaml"
+        )));
+
+        let mut spec = json!({
+            "app_name": "/shop",
+            "host": "shop:8080",
+            "mode": {
+                "kind": "forward_strict",
+                "endpoints": [
+                    { "path": "/public", "method": "GET" },
+                    { "path": "/admin", "method": "GET" },
+                ],
+            },
+        });
+        spec.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        let spec: ApiDefinitionSpec = serde_json::from_value(spec).unwrap();
+        let (api, node) = build_api(ApiDefinition::new("shop", spec)).unwrap();
+        let claims: Claims = serde_json::from_value(json!({
+            "sub": "sub",
+            "iss": "iss",
+            "exp": 0,
+            "preferred_username": "jdoe",
+            "given_name": "John",
+            "family_name": "Doe",
+            "email": "jdoe@example.com",
+            "token_id": "jdoe",
+        }))
+        .unwrap();
+
+        let mut req = Request::get("/shop/public").body(()).unwrap();
+        req.extensions_mut().insert(App("/shop".to_string()));
+        req.extensions_mut().insert(Identity {
+            claims,
+            token_type: "Bearer".to_string(),
+        });
+        req.extensions_mut().insert(Route {
+            endpoint: node.match_path("/public", "GET").unwrap().clone(),
+            forwarded_uri: "/public".to_string(),
+            http_uri: api.http_uri("/public"),
+            api,
+            node,
+        });
+
+        let permissions = HashMap::from([(
+            "shop::GET::/public".to_string(),
+            HashSet::from(["jdoe".to_string()]),
+        )]);
+        let perm_lock = Arc::new(ArcSwap::from_pointee(PermissionIndex::new(permissions)));
+        (req, perm_lock)
+    }
+
+    #[test]
+    fn script_rewrites_are_authorized_again() {
+        let (mut req, perm_lock) = routed_request(json!({
+            "script": {
+                "source": r#"request.path = request.headers["x-path"];"#,
+            },
+        }));
+        assert!(check_permission(&mut req, &perm_lock).unwrap().is_none());
+
+        let Route { api, .. } = extension(&req).unwrap();
+        let script = api.spec.script.clone().unwrap();
+        let rewrite = |req: &mut Request<()>, path: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-path", HeaderValue::from_static(path));
+            let action = run_script(
+                "/shop",
+                &script,
+                &Method::GET,
+                "/public",
+                "jdoe",
+                &mut headers,
+            );
+            let Ok(ScriptAction::Forward(path)) = action else {
+                panic!("The script did not forward the request");
+            };
+            reroute(req, path, &perm_lock).unwrap()
+        };
+
+        let response = rewrite(&mut req, "/admin").unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = rewrite(&mut req, "/missing").unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        assert!(rewrite(&mut req, "/public?page=2").is_none());
+        let Route {
+            endpoint,
+            forwarded_uri,
+            http_uri,
+            ..
+        } = extension(&req).unwrap();
+        assert_eq!(endpoint.path, "/public");
+        assert_eq!(forwarded_uri, "/public?page=2");
+        assert_eq!(http_uri, "http://shop:8080/public?page=2");
+    }
+
+    #[test]
+    fn transform_rewrites_are_authorized_again() {
+        let (mut req, perm_lock) = routed_request(json!({
+            "request_transform": {
+                "rewrite_path": [{ "from": "/pages/{name}", "to": "/{name}" }],
+            },
+        }));
+        assert!(check_permission(&mut req, &perm_lock).unwrap().is_none());
+
+        let Route { api, .. } = extension(&req).unwrap();
+        let request_transform = api.spec.request_transform.clone().unwrap();
+        let rewrite = |req: &mut Request<()>, forwarded_uri: &str| {
+            let transformed_uri = request_transform.apply(forwarded_uri, &mut HeaderMap::new());
+            reroute(req, transformed_uri, &perm_lock).unwrap()
+        };
+
+        let response = rewrite(&mut req, "/pages/admin").unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        assert!(rewrite(&mut req, "/pages/public?page=2").is_none());
+        let Route {
+            endpoint,
+            forwarded_uri,
+            ..
+        } = extension(&req).unwrap();
+        assert_eq!(endpoint.path, "/public");
+        assert_eq!(forwarded_uri, "/public?page=2");
+    }
+}
@@ -0,0 +1,1024 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Body;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING};
+use hyper::{HeaderMap, Method, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+use url::form_urlencoded;
+
+use crate::access_log::SharedAccessLog;
+use crate::endpoint::Endpoint;
+use crate::permission::PermissionId;
+use crate::{into_boxed_response, BoxResponse};
+
+/// An OpenAPI 3 document describing the operations of an API, against which its requests are
+/// validated before being forwarded.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct OpenApiSpec {
+    /// YAML or JSON document on the filesystem of the gateway, whose paths are relative to the
+    /// app.
+    pub path: String,
+    /// Validate JSON request bodies against their schema, which buffers them.
+    #[serde(default)]
+    pub validate_body: bool,
+    /// Bytes of a body buffered to validate it, larger bodies being answered with `413`.
+    #[serde(default = "max_body_size_default")]
+    pub max_body_size: usize,
+    /// Add the operations of the document to the endpoints of `forward_strict` mode, those
+    /// already listed being kept as they are.
+    #[serde(default)]
+    pub import_endpoints: bool,
+}
+
+fn max_body_size_default() -> usize {
+    1024 * 1024
+}
+
+/// Nested references and schemas deeper than this, such as recursive ones, are not followed.
+const MAX_DEPTH: usize = 64;
+
+const METHODS: [&str; 8] = [
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+
+#[derive(Clone)]
+struct Parameter {
+    name: String,
+    location: String,
+    required: bool,
+    schema: Value,
+}
+
+struct RequestBody {
+    required: bool,
+    /// Schema of each media range, lowercased.
+    content: Vec<(String, Option<Value>)>,
+}
+
+struct Operation {
+    method: Method,
+    path: String,
+    /// Literal segments, or the names of the parameters of the others.
+    segments: Vec<Result<String, String>>,
+    parameters: Vec<Parameter>,
+    body: Option<RequestBody>,
+    /// Settings of the imported endpoint, from the `x-gateway-*` extensions of the operation.
+    is_websocket: bool,
+    check_permission: bool,
+    capture_bodies: bool,
+}
+
+pub struct OpenApiDocument {
+    root: Value,
+    operations: Vec<Operation>,
+}
+
+/// Parsed documents by path, parsed again when an api referring to them is applied.
+static DOCUMENTS: LazyLock<RwLock<HashMap<String, Arc<OpenApiDocument>>>> =
+    LazyLock::new(Default::default);
+
+/// Compiled `pattern`s of schemas, `None` standing for those which are not valid.
+static PATTERNS: LazyLock<RwLock<HashMap<String, Option<Regex>>>> = LazyLock::new(Default::default);
+
+/// Convert a YAML value to JSON, keys such as response codes being turned into strings.
+fn to_json(value: serde_yaml::Value) -> Value {
+    match value {
+        serde_yaml::Value::Null => Value::Null,
+        serde_yaml::Value::Bool(bool) => Value::Bool(bool),
+        serde_yaml::Value::Number(number) => serde_json::to_value(number).unwrap_or(Value::Null),
+        serde_yaml::Value::String(string) => Value::String(string),
+        serde_yaml::Value::Sequence(items) => {
+            Value::Array(items.into_iter().map(to_json).collect())
+        }
+        serde_yaml::Value::Mapping(mapping) => Value::Object(
+            mapping
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        serde_yaml::Value::String(key) => key,
+                        key => serde_yaml::to_string(&key)
+                            .unwrap_or_default()
+                            .trim_end()
+                            .to_string(),
+                    };
+                    (key, to_json(value))
+                })
+                .collect(),
+        ),
+        serde_yaml::Value::Tagged(tagged) => to_json(tagged.value),
+    }
+}
+
+/// Follow the local `$ref` of `value`, if any.
+fn resolve<'a>(root: &'a Value, mut value: &'a Value) -> Result<&'a Value, String> {
+    for _ in 0..MAX_DEPTH {
+        let Some(reference) = value.get("$ref").and_then(Value::as_str) else {
+            return Ok(value);
+        };
+        value = reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+            .ok_or_else(|| format!("unresolved reference {reference}"))?;
+    }
+    Err("too many nested references".to_string())
+}
+
+/// Check that every `$ref` of the document is local and resolved.
+fn check_refs(root: &Value, value: &Value) -> Result<()> {
+    match value {
+        Value::Object(object) => {
+            if object.get("$ref").is_some_and(Value::is_string) {
+                resolve(root, value).map_err(|e| anyhow!("{e}"))?;
+            }
+            object
+                .values()
+                .try_for_each(|value| check_refs(root, value))
+        }
+        Value::Array(items) => items.iter().try_for_each(|value| check_refs(root, value)),
+        _ => Ok(()),
+    }
+}
+
+fn parse_parameters(root: &Value, item: &Value) -> Result<Vec<Parameter>> {
+    let mut parameters = Vec::new();
+    for parameter in item
+        .get("parameters")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        let parameter = resolve(root, parameter).map_err(|e| anyhow!("{e}"))?;
+        let (Some(name), Some(location)) = (
+            parameter.get("name").and_then(Value::as_str),
+            parameter.get("in").and_then(Value::as_str),
+        ) else {
+            bail!("Parameters need a `name` and an `in`");
+        };
+        parameters.push(Parameter {
+            name: name.to_string(),
+            location: location.to_string(),
+            required: location == "path"
+                || parameter
+                    .get("required")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+            schema: parameter.get("schema").cloned().unwrap_or_default(),
+        });
+    }
+
+    Ok(parameters)
+}
+
+fn parse_request_body(root: &Value, body: &Value) -> Result<RequestBody> {
+    let body = resolve(root, body).map_err(|e| anyhow!("{e}"))?;
+    let content = body
+        .get("content")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .map(|(media_range, media_type)| {
+            (
+                media_range.to_lowercase(),
+                media_type.get("schema").cloned(),
+            )
+        })
+        .collect();
+
+    Ok(RequestBody {
+        required: body
+            .get("required")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+        content,
+    })
+}
+
+fn extension(operation: &Value, name: &str) -> Option<bool> {
+    operation.get(name).and_then(Value::as_bool)
+}
+
+fn parse_document(root: Value) -> Result<OpenApiDocument> {
+    let version = root.get("openapi").and_then(Value::as_str).unwrap_or("");
+    if !version.starts_with("3.") {
+        bail!("Only OpenAPI 3 documents are supported");
+    }
+    check_refs(&root, &root)?;
+
+    let paths = root
+        .get("paths")
+        .and_then(Value::as_object)
+        .ok_or_else(|| anyhow!("Missing `paths`"))?;
+    let mut operations = Vec::new();
+    for (path, item) in paths {
+        let item = resolve(&root, item).map_err(|e| anyhow!("{e}"))?;
+        let common = parse_parameters(&root, item)?;
+        for method in METHODS {
+            let Some(operation) = item.get(method) else {
+                continue;
+            };
+
+            let mut parameters = parse_parameters(&root, operation)?;
+            for parameter in &common {
+                if !parameters.iter().any(|overriding| {
+                    overriding.name == parameter.name && overriding.location == parameter.location
+                }) {
+                    parameters.push(parameter.clone());
+                }
+            }
+
+            operations.push(Operation {
+                method: Method::from_bytes(method.to_uppercase().as_bytes())?,
+                path: path.clone(),
+                segments: path
+                    .split('/')
+                    .map(|segment| {
+                        match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                            Some(name) => Err(name.to_string()),
+                            None => Ok(segment.to_string()),
+                        }
+                    })
+                    .collect(),
+                parameters,
+                body: operation
+                    .get("requestBody")
+                    .map(|body| parse_request_body(&root, body))
+                    .transpose()?,
+                is_websocket: extension(operation, "x-gateway-websocket").unwrap_or(false),
+                check_permission: extension(operation, "x-gateway-check-permission")
+                    .unwrap_or(true),
+                capture_bodies: extension(operation, "x-gateway-capture-bodies").unwrap_or(false),
+            });
+        }
+    }
+
+    Ok(OpenApiDocument { root, operations })
+}
+
+fn read_document(path: &str) -> Result<OpenApiDocument> {
+    let content = fs::read_to_string(path).map_err(|e| anyhow!("Could not read {path}: {e}"))?;
+    parse_document(to_json(serde_yaml::from_str(&content)?))
+        .map_err(|e| anyhow!("Invalid document {path}: {e}"))
+}
+
+/// Parse the document of `spec`, which is only done by the server as the path refers to its
+/// filesystem.
+pub fn load_openapi(spec: &OpenApiSpec) -> Result<Arc<OpenApiDocument>> {
+    let document = Arc::new(read_document(&spec.path)?);
+    DOCUMENTS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(spec.path.clone(), document.clone());
+    Ok(document)
+}
+
+/// List the endpoints of the operations of the document at `path`, as `forward_strict` mode
+/// expects them.
+pub fn import_endpoints(path: &str) -> Result<Vec<Endpoint>> {
+    read_document(path)?.endpoints()
+}
+
+pub fn get_document(spec: &OpenApiSpec) -> Result<Arc<OpenApiDocument>> {
+    let cached = DOCUMENTS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(&spec.path)
+        .cloned();
+    match cached {
+        Some(document) => Ok(document),
+        None => load_openapi(spec),
+    }
+}
+
+fn is_match(pattern: &str, value: &str) -> bool {
+    if let Some(regex) = PATTERNS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(pattern)
+    {
+        return regex.as_ref().is_none_or(|regex| regex.is_match(value));
+    }
+
+    let regex = Regex::new(pattern).ok();
+    let matched = regex.as_ref().is_none_or(|regex| regex.is_match(value));
+    PATTERNS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(pattern.to_string(), regex);
+    matched
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        "string" => value.is_string(),
+        _ => true,
+    }
+}
+
+/// Types allowed by `schema`, with `nullable`, if it restricts them.
+fn schema_types(schema: &Value) -> Option<Vec<&str>> {
+    let mut types: Vec<&str> = match schema.get("type")? {
+        Value::String(kind) => vec![kind],
+        Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+        _ => return None,
+    };
+    if schema.get("nullable").and_then(Value::as_bool) == Some(true) {
+        types.push("null");
+    }
+    Some(types)
+}
+
+fn check_bound(schema: &Value, value: f64, at: &str, is_minimum: bool) -> Result<(), String> {
+    let (bound, exclusive) = match is_minimum {
+        true => ("minimum", "exclusiveMinimum"),
+        false => ("maximum", "exclusiveMaximum"),
+    };
+    // `exclusiveMinimum` is a flag in OpenAPI 3.0 and a bound in 3.1.
+    let (limit, is_exclusive) = match (
+        schema.get(bound).and_then(Value::as_f64),
+        schema.get(exclusive),
+    ) {
+        (_, Some(Value::Number(limit))) => (limit.as_f64(), true),
+        (limit, Some(Value::Bool(is_exclusive))) => (limit, *is_exclusive),
+        (limit, _) => (limit, false),
+    };
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+
+    let expected = match (is_minimum, is_exclusive) {
+        (true, false) if value < limit => "at least",
+        (true, true) if value <= limit => "greater than",
+        (false, false) if value > limit => "at most",
+        (false, true) if value >= limit => "less than",
+        _ => return Ok(()),
+    };
+    Err(format!("{at}: {value} should be {expected} {limit}"))
+}
+
+/// Validate `value` against `schema`, a subset of JSON Schema: `type`, `nullable`, `enum`,
+/// `const`, `allOf`, `anyOf`, `oneOf`, `not`, the lengths, bounds and `pattern` of values, the
+/// `items` of arrays and the `properties`, `required` and `additionalProperties` of objects.
+fn validate(
+    root: &Value,
+    schema: &Value,
+    value: &Value,
+    at: &str,
+    depth: usize,
+) -> Result<(), String> {
+    if depth > MAX_DEPTH {
+        return Ok(());
+    }
+    let schema = resolve(root, schema).map_err(|e| format!("{at}: {e}"))?;
+    let Some(object) = schema.as_object() else {
+        return Ok(());
+    };
+
+    if let Some(types) = schema_types(schema) {
+        if !types.iter().any(|expected| has_type(value, expected)) {
+            return Err(format!("{at}: expected {}", types.join(" or ")));
+        }
+    }
+    if value.is_null() && schema.get("nullable").and_then(Value::as_bool) == Some(true) {
+        return Ok(());
+    }
+    if let Some(allowed) = object.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            return Err(format!("{at}: {value} is not one of the allowed values"));
+        }
+    }
+    if let Some(constant) = object.get("const") {
+        if constant != value {
+            return Err(format!("{at}: expected {constant}"));
+        }
+    }
+
+    for schema in object
+        .get("allOf")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        validate(root, schema, value, at, depth + 1)?;
+    }
+    if let Some(schemas) = object.get("anyOf").and_then(Value::as_array) {
+        if !schemas
+            .iter()
+            .any(|schema| validate(root, schema, value, at, depth + 1).is_ok())
+        {
+            return Err(format!("{at}: does not match any of `anyOf`"));
+        }
+    }
+    if let Some(schemas) = object.get("oneOf").and_then(Value::as_array) {
+        let matching = schemas
+            .iter()
+            .filter(|schema| validate(root, schema, value, at, depth + 1).is_ok())
+            .count();
+        if matching != 1 {
+            return Err(format!("{at}: matches {matching} of `oneOf` instead of 1"));
+        }
+    }
+    if let Some(schema) = object.get("not") {
+        if validate(root, schema, value, at, depth + 1).is_ok() {
+            return Err(format!("{at}: matches `not`"));
+        }
+    }
+
+    match value {
+        Value::String(string) => {
+            let length = string.chars().count() as u64;
+            if let Some(min) = object.get("minLength").and_then(Value::as_u64) {
+                if length < min {
+                    return Err(format!("{at}: should be at least {min} characters long"));
+                }
+            }
+            if let Some(max) = object.get("maxLength").and_then(Value::as_u64) {
+                if length > max {
+                    return Err(format!("{at}: should be at most {max} characters long"));
+                }
+            }
+            if let Some(pattern) = object.get("pattern").and_then(Value::as_str) {
+                if !is_match(pattern, string) {
+                    return Err(format!("{at}: does not match {pattern}"));
+                }
+            }
+        }
+        Value::Number(number) => {
+            let number = number.as_f64().unwrap_or_default();
+            check_bound(schema, number, at, true)?;
+            check_bound(schema, number, at, false)?;
+        }
+        Value::Array(items) => {
+            let length = items.len() as u64;
+            if let Some(min) = object.get("minItems").and_then(Value::as_u64) {
+                if length < min {
+                    return Err(format!("{at}: should have at least {min} items"));
+                }
+            }
+            if let Some(max) = object.get("maxItems").and_then(Value::as_u64) {
+                if length > max {
+                    return Err(format!("{at}: should have at most {max} items"));
+                }
+            }
+            if let Some(schema) = object.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    validate(root, schema, item, &format!("{at}[{index}]"), depth + 1)?;
+                }
+            }
+        }
+        Value::Object(fields) => {
+            for name in object
+                .get("required")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+            {
+                if !fields.contains_key(name) {
+                    return Err(format!("{at}: missing property `{name}`"));
+                }
+            }
+            let properties = object.get("properties").and_then(Value::as_object);
+            for (name, field) in fields {
+                let field_at = format!("{at}.{name}");
+                match (
+                    properties.and_then(|properties| properties.get(name)),
+                    object.get("additionalProperties"),
+                ) {
+                    (Some(schema), _) => validate(root, schema, field, &field_at, depth + 1)?,
+                    (None, Some(Value::Bool(false))) => {
+                        return Err(format!("{at}: unknown property `{name}`"));
+                    }
+                    (None, Some(schema)) => validate(root, schema, field, &field_at, depth + 1)?,
+                    (None, None) => (),
+                }
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Parse a single raw value as the type of `schema`, keeping it as a string if it cannot be.
+fn parse_value(kind: Option<&str>, raw: &str) -> Value {
+    let parsed = match kind {
+        Some("integer") => raw.parse::<i64>().ok().map(Value::from),
+        Some("number") => raw
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        Some("boolean") => raw.parse::<bool>().ok().map(Value::from),
+        _ => None,
+    };
+    parsed.unwrap_or_else(|| Value::from(raw))
+}
+
+fn first_type<'a>(root: &'a Value, schema: &'a Value) -> Option<&'a str> {
+    let schema = resolve(root, schema).ok()?;
+    schema_types(schema)?
+        .into_iter()
+        .find(|kind| *kind != "null")
+}
+
+/// Whether a request has a non-empty body, from its headers.
+pub(crate) fn has_body(headers: &HeaderMap) -> bool {
+    headers.contains_key(TRANSFER_ENCODING)
+        || headers
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .is_some_and(|length| length != "0")
+}
+
+impl OpenApiDocument {
+    /// Endpoints of the operations, configured by their `x-gateway-websocket`,
+    /// `x-gateway-check-permission` and `x-gateway-capture-bodies` extensions.
+    pub fn endpoints(&self) -> Result<Vec<Endpoint>> {
+        self.operations
+            .iter()
+            .map(|operation| {
+                let endpoint = Endpoint {
+                    path: operation.path.clone(),
+                    method: operation.method.to_string(),
+                    is_websocket: operation.is_websocket,
+                    permission: String::new(),
+                    permission_id: PermissionId::default(),
+                    check_permission: operation.check_permission,
+                    capture_bodies: operation.capture_bodies,
+                    content_types: Vec::new(),
+                };
+                endpoint
+                    .check_fields()
+                    .map_err(|e| anyhow!("{} {}: {e}", operation.method, operation.path))?;
+                Ok(endpoint)
+            })
+            .collect()
+    }
+
+    /// Parse the raw values of a parameter as its schema, arrays being either repeated or comma
+    /// separated. `None` stands for objects, which are not validated.
+    fn parse_parameter(&self, schema: &Value, raw: &[String]) -> Option<Value> {
+        match first_type(&self.root, schema) {
+            Some("object") => None,
+            Some("array") => {
+                let items = resolve(&self.root, schema)
+                    .ok()
+                    .and_then(|schema| schema.get("items"));
+                let kind = items.and_then(|items| first_type(&self.root, items));
+                let values: Vec<&str> = match raw {
+                    [value] => value.split(',').collect(),
+                    values => values.iter().map(String::as_str).collect(),
+                };
+                Some(Value::Array(
+                    values
+                        .into_iter()
+                        .map(|value| parse_value(kind, value))
+                        .collect(),
+                ))
+            }
+            kind => Some(parse_value(kind, &raw[0])),
+        }
+    }
+
+    fn find_operation(&self, method: &Method, path: &str) -> Option<(&Operation, Vec<String>)> {
+        let segments: Vec<&str> = path.split('/').collect();
+        self.operations
+            .iter()
+            .filter(|operation| operation.method == *method)
+            .filter(|operation| operation.segments.len() == segments.len())
+            .filter_map(|operation| {
+                let mut captures = Vec::new();
+                for (expected, segment) in operation.segments.iter().zip(&segments) {
+                    match expected {
+                        Ok(literal) if literal != segment => return None,
+                        Ok(_) => (),
+                        Err(_) => captures.push(percent_decode_str(segment).decode_utf8_lossy()),
+                    }
+                }
+                Some((operation, captures))
+            })
+            // Literal segments take precedence over parameters.
+            .min_by_key(|(_, captures)| captures.len())
+            .map(|(operation, captures)| {
+                (
+                    operation,
+                    captures.into_iter().map(|capture| capture.into()).collect(),
+                )
+            })
+    }
+
+    /// Validate the path, query and headers of a request and the presence and type of its body,
+    /// returning the schema of its JSON body if it should be validated too.
+    pub fn validate_request(
+        self: &Arc<Self>,
+        spec: &OpenApiSpec,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        headers: &HeaderMap,
+    ) -> Result<Option<BodySchema>, String> {
+        let Some((operation, captures)) = self.find_operation(method, path) else {
+            return Err(format!("no operation for {method} {path}"));
+        };
+
+        let path_values: HashMap<&str, &String> = operation
+            .segments
+            .iter()
+            .filter_map(|segment| segment.as_ref().err())
+            .map(String::as_str)
+            .zip(&captures)
+            .collect();
+        let query: Vec<(String, String)> = form_urlencoded::parse(query.unwrap_or("").as_bytes())
+            .into_owned()
+            .collect();
+
+        for parameter in &operation.parameters {
+            let raw: Vec<String> = match parameter.location.as_str() {
+                "path" => path_values
+                    .get(parameter.name.as_str())
+                    .map(|value| value.to_string())
+                    .into_iter()
+                    .collect(),
+                "query" => query
+                    .iter()
+                    .filter(|(name, _)| *name == parameter.name)
+                    .map(|(_, value)| value.clone())
+                    .collect(),
+                "header" => headers
+                    .get_all(parameter.name.as_str())
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .map(str::to_string)
+                    .collect(),
+                _ => continue,
+            };
+            let at = format!("{} parameter `{}`", parameter.location, parameter.name);
+            if raw.is_empty() {
+                if parameter.required {
+                    return Err(format!("missing {at}"));
+                }
+                continue;
+            }
+            if let Some(value) = self.parse_parameter(&parameter.schema, &raw) {
+                validate(&self.root, &parameter.schema, &value, &at, 0)?;
+            }
+        }
+
+        let Some(body) = &operation.body else {
+            return Ok(None);
+        };
+        if !has_body(headers) {
+            return match body.required {
+                true => Err(format!("missing body of {method} {}", operation.path)),
+                false => Ok(None),
+            };
+        }
+
+        let Some(content_type) = headers
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+        else {
+            return Err("missing content type".to_string());
+        };
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        let main_type = media_type.split('/').next().unwrap_or_default();
+        // The most specific media range is used, as in `application/json`, `application/*` then
+        // `*/*`.
+        let Some((_, schema)) = body
+            .content
+            .iter()
+            .filter(|(media_range, _)| {
+                *media_range == media_type
+                    || *media_range == format!("{main_type}/*")
+                    || media_range == "*/*"
+            })
+            .min_by_key(|(media_range, _)| media_range.matches('*').count())
+        else {
+            return Err(format!("unsupported content type {media_type}"));
+        };
+
+        let is_json = media_type == "application/json" || media_type.ends_with("+json");
+        Ok(match schema {
+            Some(schema) if spec.validate_body && is_json => Some(BodySchema {
+                document: self.clone(),
+                schema: schema.clone(),
+                max_body_size: spec.max_body_size,
+            }),
+            _ => None,
+        })
+    }
+}
+
+/// Schema of the JSON body of a request, which is validated once buffered.
+#[derive(Clone)]
+pub struct BodySchema {
+    document: Arc<OpenApiDocument>,
+    schema: Value,
+    max_body_size: usize,
+}
+
+impl BodySchema {
+    /// Buffer and validate the body, returning it or the status answering the request.
+    pub async fn validate<B>(&self, body: B) -> Result<Bytes, (StatusCode, String)>
+    where
+        B: Body<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let body = Limited::new(body, self.max_body_size)
+            .collect()
+            .await
+            .map(|collected| collected.to_bytes())
+            .map_err(|e| {
+                (
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("could not buffer the body: {e}"),
+                )
+            })?;
+        let value: Value = serde_json::from_slice(&body).map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("body is not valid JSON: {e}"),
+            )
+        })?;
+        validate(&self.document.root, &self.schema, &value, "body", 0)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+        Ok(body)
+    }
+}
+
+/// Answer a request which does not match the document with `status_code` and the reason, which is
+/// also recorded in its access log.
+pub fn reject(
+    status_code: StatusCode,
+    error: String,
+    access_log: &SharedAccessLog,
+) -> Result<BoxResponse<Bytes>> {
+    let body = format!(
+        "{}: {error}\n",
+        status_code.canonical_reason().unwrap_or_default()
+    );
+    access_log.lock().set_error(format!("OpenAPI: {error}"));
+
+    Ok(into_boxed_response(
+        Response::builder()
+            .status(status_code)
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Full::from(body))?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    const DOCUMENT: &str = r##"
+openapi: 3.0.3
+paths:
+  /items/{id}:
+    parameters:
+      - name: id
+        in: path
+        schema: { type: integer, minimum: 1 }
+    get:
+      parameters:
+        - name: fields
+          in: query
+          schema:
+            type: array
+            maxItems: 2
+            items: { type: string, enum: [name, price] }
+        - name: x-version
+          in: header
+          required: true
+          schema: { type: string, pattern: "^v[0-9]+$" }
+  /items/latest:
+    get: {}
+  /items:
+    post:
+      requestBody:
+        required: true
+        content:
+          application/json:
+            schema: { $ref: "#/components/schemas/Item" }
+          text/*: {}
+components:
+  schemas:
+    Item:
+      type: object
+      required: [name]
+      additionalProperties: false
+      properties:
+        name: { type: string, minLength: 1 }
+        price: { type: number, exclusiveMinimum: true, minimum: 0 }
+        tags:
+          type: array
+          items: { type: string }
+        discount:
+          nullable: true
+          oneOf:
+            - { type: integer }
+            - { type: string, enum: [half] }
+"##;
+
+    fn document() -> Arc<OpenApiDocument> {
+        Arc::new(parse_document(to_json(serde_yaml::from_str(DOCUMENT).unwrap())).unwrap())
+    }
+
+    fn spec(validate_body: bool) -> OpenApiSpec {
+        OpenApiSpec {
+            path: String::new(),
+            validate_body,
+            max_body_size: 64,
+            import_endpoints: false,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    fn get_item(path: &str, query: Option<&str>, headers: &HeaderMap) -> Result<(), String> {
+        document()
+            .validate_request(&spec(false), &Method::GET, path, query, headers)
+            .map(|_| ())
+    }
+
+    fn post_item(headers: &HeaderMap) -> Result<Option<BodySchema>, String> {
+        document().validate_request(&spec(true), &Method::POST, "/items", None, headers)
+    }
+
+    #[test]
+    fn documents_are_checked() {
+        let parse =
+            |document: &str| parse_document(to_json(serde_yaml::from_str(document).unwrap()));
+        assert!(parse("swagger: '2.0'\npaths: {}").is_err());
+        assert!(parse("openapi: 3.1.0").is_err());
+        assert!(parse("openapi: 3.1.0\npaths:\n  /a:\n    $ref: '#/missing'").is_err());
+        assert!(parse("openapi: 3.1.0\npaths:\n  /a:\n    get:\n      parameters: [{}]").is_err());
+    }
+
+    #[test]
+    fn parameters_are_validated() {
+        let version = headers(&[("x-version", "v2")]);
+        assert_eq!(
+            get_item("/items/3", Some("fields=name,price"), &version),
+            Ok(())
+        );
+        assert_eq!(
+            get_item("/items/3", Some("fields=name&fields=price"), &version),
+            Ok(())
+        );
+        // Literal segments take precedence over parameters.
+        assert_eq!(get_item("/items/latest", None, &HeaderMap::new()), Ok(()));
+
+        assert_eq!(
+            get_item("/items/0", None, &version),
+            Err("path parameter `id`: 0 should be at least 1".to_string())
+        );
+        assert_eq!(
+            get_item("/items/abc", None, &version),
+            Err("path parameter `id`: expected integer".to_string())
+        );
+        assert_eq!(
+            get_item("/items/3", Some("fields=name,price,name"), &version),
+            Err("query parameter `fields`: should have at most 2 items".to_string())
+        );
+        assert_eq!(
+            get_item("/items/3", Some("fields=color"), &version),
+            Err(
+                "query parameter `fields`[0]: \"color\" is not one of the allowed values"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            get_item("/items/3", None, &HeaderMap::new()),
+            Err("missing header parameter `x-version`".to_string())
+        );
+        assert_eq!(
+            get_item("/items/3", None, &headers(&[("x-version", "2")])),
+            Err("header parameter `x-version`: does not match ^v[0-9]+$".to_string())
+        );
+        assert_eq!(
+            get_item("/items/3/parts", None, &version),
+            Err("no operation for GET /items/3/parts".to_string())
+        );
+    }
+
+    #[test]
+    fn content_types_are_validated() {
+        assert_eq!(
+            post_item(&HeaderMap::new()).err(),
+            Some("missing body of POST /items".to_string())
+        );
+        assert_eq!(
+            post_item(&headers(&[("content-length", "2")])).err(),
+            Some("missing content type".to_string())
+        );
+        assert_eq!(
+            post_item(&headers(&[
+                ("content-length", "2"),
+                ("content-type", "image/png")
+            ]))
+            .err(),
+            Some("unsupported content type image/png".to_string())
+        );
+
+        let text = headers(&[("content-length", "2"), ("content-type", "text/plain")]);
+        assert!(post_item(&text).unwrap().is_none());
+        let json = headers(&[
+            ("transfer-encoding", "chunked"),
+            ("content-type", "Application/JSON; charset=utf-8"),
+        ]);
+        assert!(post_item(&json).unwrap().is_some());
+        // Bodies are only validated when asked to.
+        assert!(document()
+            .validate_request(&spec(false), &Method::POST, "/items", None, &json)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn bodies_are_validated_against_their_schema() {
+        let json = headers(&[
+            ("content-length", "2"),
+            ("content-type", "application/json"),
+        ]);
+        let schema = post_item(&json).unwrap().unwrap();
+        let validate =
+            |body: &'static str| schema.validate(Full::new(Bytes::from_static(body.as_bytes())));
+
+        assert!(
+            validate(r#"{"name": "pen", "price": 1.5, "tags": ["blue"]}"#)
+                .await
+                .is_ok()
+        );
+        assert!(validate(r#"{"name": "pen", "discount": null}"#)
+            .await
+            .is_ok());
+        assert!(validate(r#"{"name": "pen", "discount": "half"}"#)
+            .await
+            .is_ok());
+
+        for (body, error) in [
+            ("{", "body is not valid JSON"),
+            ("[]", "body: expected object"),
+            (r#"{"price": 1}"#, "body: missing property `name`"),
+            (
+                r#"{"name": ""}"#,
+                "body.name: should be at least 1 characters long",
+            ),
+            (
+                r#"{"name": "pen", "price": 0}"#,
+                "body.price: 0 should be greater than 0",
+            ),
+            (
+                r#"{"name": "pen", "tags": [1]}"#,
+                "body.tags[0]: expected string",
+            ),
+            (
+                r#"{"name": "pen", "color": "blue"}"#,
+                "body: unknown property `color`",
+            ),
+            (
+                r#"{"name": "pen", "discount": "full"}"#,
+                "body.discount: matches 0 of `oneOf` instead of 1",
+            ),
+        ] {
+            let (status_code, reason) = validate(body).await.unwrap_err();
+            assert_eq!(status_code, StatusCode::BAD_REQUEST);
+            assert!(reason.starts_with(error), "{body}: {reason}");
+        }
+
+        let large = r#"{"name": "a pen whose name is really long enough to go over 64 bytes"}"#;
+        let (status_code, _) = validate(large).await.unwrap_err();
+        assert_eq!(status_code, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
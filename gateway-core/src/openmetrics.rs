@@ -0,0 +1,143 @@
+use std::io::Write;
+use std::time::UNIX_EPOCH;
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::Encoder;
+
+use crate::metrics::get_exemplars;
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Encode metrics in the OpenMetrics text format, with the exemplars of histogram buckets.
+pub struct OpenMetricsEncoder;
+
+fn format_value(value: f64) -> String {
+    if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+fn format_labels(labels: &[LabelPair], extra: Option<(&str, &str)>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|label| format!(r#"{}="{}""#, label.get_name(), escape(label.get_value())))
+        .collect();
+    if let Some((name, value)) = extra {
+        pairs.push(format!(r#"{name}="{}""#, escape(value)));
+    }
+
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn write_histogram<W: Write>(
+    writer: &mut W,
+    name: &str,
+    family_name: &str,
+    metric: &Metric,
+) -> prometheus::Result<()> {
+    let histogram = metric.get_histogram();
+    let exemplars = get_exemplars(family_name, metric);
+
+    let upper_bounds = histogram
+        .get_bucket()
+        .iter()
+        .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+        .chain([(f64::INFINITY, histogram.get_sample_count())]);
+    for (index, (upper_bound, count)) in upper_bounds.enumerate() {
+        let labels = format_labels(metric.get_label(), Some(("le", &format_value(upper_bound))));
+        write!(writer, "{name}_bucket{labels} {count}")?;
+        if let Some(Some(exemplar)) = exemplars.get(index) {
+            let timestamp = exemplar
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            write!(
+                writer,
+                r#" # {{trace_id="{}",span_id="{}"}} {} {timestamp:.3}"#,
+                exemplar.trace_id,
+                exemplar.span_id,
+                format_value(exemplar.value),
+            )?;
+        }
+        writeln!(writer)?;
+    }
+
+    let labels = format_labels(metric.get_label(), None);
+    writeln!(
+        writer,
+        "{name}_sum{labels} {}",
+        format_value(histogram.get_sample_sum())
+    )?;
+    writeln!(
+        writer,
+        "{name}_count{labels} {}",
+        histogram.get_sample_count()
+    )?;
+
+    Ok(())
+}
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(
+        &self,
+        families: &[MetricFamily],
+        writer: &mut W,
+    ) -> prometheus::Result<()> {
+        for family in families {
+            let family_name = family.get_name();
+            // Counter samples get the `_total` suffix which is not part of the family name.
+            let (name, metric_type) = match family.get_field_type() {
+                MetricType::COUNTER => (
+                    family_name.strip_suffix("_total").unwrap_or(family_name),
+                    "counter",
+                ),
+                MetricType::GAUGE => (family_name, "gauge"),
+                MetricType::HISTOGRAM => (family_name, "histogram"),
+                MetricType::SUMMARY | MetricType::UNTYPED => continue,
+            };
+
+            writeln!(writer, "# TYPE {name} {metric_type}")?;
+            writeln!(writer, "# HELP {name} {}", escape(family.get_help()))?;
+
+            for metric in family.get_metric() {
+                let labels = format_labels(metric.get_label(), None);
+                match family.get_field_type() {
+                    MetricType::COUNTER => writeln!(
+                        writer,
+                        "{name}_total{labels} {}",
+                        format_value(metric.get_counter().get_value())
+                    )?,
+                    MetricType::GAUGE => writeln!(
+                        writer,
+                        "{name}{labels} {}",
+                        format_value(metric.get_gauge().get_value())
+                    )?,
+                    _ => write_histogram(writer, name, family_name, metric)?,
+                }
+            }
+        }
+
+        writeln!(writer, "# EOF")?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
@@ -0,0 +1,220 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::{
+    exemplar, metric, number_data_point, AggregationTemporality, Exemplar, Gauge, Histogram,
+    HistogramDataPoint, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics, Sum,
+};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use prometheus::proto::{MetricFamily, MetricType};
+use prost::Message;
+use tokio::time::interval;
+
+use crate::metrics::get_exemplars;
+use crate::runtime_config::{runtime_config, OtlpMetricsConfig};
+
+fn now_unix_nano() -> u64 {
+    to_unix_nano(SystemTime::now())
+}
+
+fn to_unix_nano(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+fn string_attribute(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+fn get_attributes(metric: &prometheus::proto::Metric) -> Vec<KeyValue> {
+    metric
+        .get_label()
+        .iter()
+        .map(|label| string_attribute(label.get_name(), label.get_value()))
+        .collect()
+}
+
+fn number_data_point(
+    metric: &prometheus::proto::Metric,
+    value: f64,
+    start_time: u64,
+    time: u64,
+) -> NumberDataPoint {
+    NumberDataPoint {
+        attributes: get_attributes(metric),
+        start_time_unix_nano: start_time,
+        time_unix_nano: time,
+        value: Some(number_data_point::Value::AsDouble(value)),
+        ..Default::default()
+    }
+}
+
+fn histogram_data_point(
+    name: &str,
+    metric: &prometheus::proto::Metric,
+    start_time: u64,
+    time: u64,
+) -> HistogramDataPoint {
+    let histogram = metric.get_histogram();
+
+    // Prometheus buckets are cumulative and do not include `+Inf` while OTLP buckets are not
+    // cumulative and have one more bucket than bounds.
+    let mut explicit_bounds = Vec::with_capacity(histogram.get_bucket().len());
+    let mut bucket_counts = Vec::with_capacity(histogram.get_bucket().len() + 1);
+    let mut previous_count = 0;
+    for bucket in histogram.get_bucket() {
+        explicit_bounds.push(bucket.get_upper_bound());
+        bucket_counts.push(bucket.get_cumulative_count() - previous_count);
+        previous_count = bucket.get_cumulative_count();
+    }
+    bucket_counts.push(histogram.get_sample_count() - previous_count);
+
+    let exemplars = get_exemplars(name, metric)
+        .into_iter()
+        .flatten()
+        .map(|exemplar| Exemplar {
+            time_unix_nano: to_unix_nano(exemplar.timestamp),
+            span_id: exemplar.span_id.to_bytes().to_vec(),
+            trace_id: exemplar.trace_id.to_bytes().to_vec(),
+            value: Some(exemplar::Value::AsDouble(exemplar.value)),
+            ..Default::default()
+        })
+        .collect();
+
+    HistogramDataPoint {
+        attributes: get_attributes(metric),
+        start_time_unix_nano: start_time,
+        time_unix_nano: time,
+        count: histogram.get_sample_count(),
+        sum: Some(histogram.get_sample_sum()),
+        bucket_counts,
+        explicit_bounds,
+        exemplars,
+        ..Default::default()
+    }
+}
+
+/// Convert a metric family gathered from the prometheus registry into its OTLP equivalent.
+fn convert_family(family: &MetricFamily, start_time: u64, time: u64) -> Option<Metric> {
+    let data = match family.get_field_type() {
+        MetricType::COUNTER => metric::Data::Sum(Sum {
+            data_points: family
+                .get_metric()
+                .iter()
+                .map(|m| number_data_point(m, m.get_counter().get_value(), start_time, time))
+                .collect(),
+            aggregation_temporality: AggregationTemporality::Cumulative as i32,
+            is_monotonic: true,
+        }),
+        MetricType::GAUGE => metric::Data::Gauge(Gauge {
+            data_points: family
+                .get_metric()
+                .iter()
+                .map(|m| number_data_point(m, m.get_gauge().get_value(), start_time, time))
+                .collect(),
+        }),
+        MetricType::HISTOGRAM => metric::Data::Histogram(Histogram {
+            data_points: family
+                .get_metric()
+                .iter()
+                .map(|m| histogram_data_point(family.get_name(), m, start_time, time))
+                .collect(),
+            aggregation_temporality: AggregationTemporality::Cumulative as i32,
+        }),
+        MetricType::SUMMARY | MetricType::UNTYPED => return None,
+    };
+
+    Some(Metric {
+        name: family.get_name().to_string(),
+        description: family.get_help().to_string(),
+        data: Some(data),
+        ..Default::default()
+    })
+}
+
+fn build_request(config: &OtlpMetricsConfig, start_time: u64) -> ExportMetricsServiceRequest {
+    let time = now_unix_nano();
+    let metrics = prometheus::gather()
+        .iter()
+        .filter_map(|family| convert_family(family, start_time, time))
+        .collect();
+
+    ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: Some(Resource {
+                attributes: vec![string_attribute("service.name", &config.service_name)],
+                ..Default::default()
+            }),
+            scope_metrics: vec![ScopeMetrics {
+                scope: Some(InstrumentationScope {
+                    name: "gateway".to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    ..Default::default()
+                }),
+                metrics,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+    }
+}
+
+async fn push_metrics(
+    client: &Client<HttpConnector, Full<Bytes>>,
+    config: &OtlpMetricsConfig,
+    start_time: u64,
+) -> Result<()> {
+    let body = build_request(config, start_time).encode_to_vec();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(config.endpoint.clone())
+        .header(CONTENT_TYPE, "application/x-protobuf")
+        .body(Full::new(Bytes::from(body)))?;
+
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        bail!("Unexpected status {}", response.status());
+    }
+
+    Ok(())
+}
+
+/// Periodically push the content of the prometheus registry to an OTLP/HTTP collector if
+/// `otlp_metrics` is configured. The `/metrics` endpoint is still served.
+pub async fn export_metrics() -> Result<()> {
+    let runtime_config = runtime_config();
+    let Some(config) = &runtime_config.otlp_metrics else {
+        return Ok(());
+    };
+
+    info!("event='Exporting metrics toward {}'", &config.endpoint);
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+    let start_time = now_unix_nano();
+    let mut ticker = interval(config.export_interval);
+
+    loop {
+        ticker.tick().await;
+        if let Err(e) = push_metrics(&client, config, start_time).await {
+            warn!(
+                "event='Fail to export metrics to {}: {e}'",
+                &config.endpoint
+            );
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, RwLock};
+
+use anyhow::{bail, Result};
+use arc_swap::ArcSwap;
+use bytes::{Bytes, BytesMut};
+use futures::TryStreamExt;
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use regex::Regex;
+use serde::Deserialize;
+use tokio::time::sleep;
+
+use crate::change_events::notify_permissions_changed;
+use crate::permission_audit::record_snapshot;
+use crate::redact::redact;
+use crate::runtime_config::{runtime_config, PermUri};
+
+#[derive(Deserialize, Debug)]
+struct Perm {
+    role_name: String,
+    user_id: HashSet<String>,
+}
+
+type PermList = Vec<Perm>;
+
+/// The users granted each permission.
+pub type Permissions = HashMap<String, HashSet<String>>;
+/// The comma-separated roles of each user, by app.
+pub type Roles = HashMap<String, HashMap<String, String>>;
+/// Snapshot of the permissions, read without locking by requests and replaced once fetched.
+pub type PermLock = Arc<ArcSwap<PermissionIndex>>;
+/// Snapshot of the roles, replaced along with the permissions.
+pub type RoleLock = Arc<ArcSwap<Roles>>;
+
+/// Interned permission name, resolved when apis and permissions are loaded so that requests
+/// check permissions without building their names. The default is the empty permission.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PermissionId(u32);
+
+/// Names of the permissions interned so far, which are never removed: they only come from the
+/// `ApiDefinition`s and the `perm_uris`, not from requests.
+struct Interner {
+    ids: HashMap<Arc<str>, PermissionId>,
+    names: Vec<Arc<str>>,
+}
+
+static INTERNER: LazyLock<RwLock<Interner>> = LazyLock::new(|| {
+    let empty: Arc<str> = Arc::from("");
+    RwLock::new(Interner {
+        ids: HashMap::from([(empty.clone(), PermissionId::default())]),
+        names: vec![empty],
+    })
+});
+
+/// Get the id of a permission, interning it if it is new.
+pub fn intern_permission(name: &str) -> PermissionId {
+    if let Some(id) = find_permission(name) {
+        return id;
+    }
+
+    let mut interner = INTERNER.write().unwrap_or_else(|e| e.into_inner());
+    if let Some(id) = interner.ids.get(name) {
+        return *id;
+    }
+    let id = PermissionId(interner.names.len() as u32);
+    let name: Arc<str> = Arc::from(name);
+    interner.ids.insert(name.clone(), id);
+    interner.names.push(name);
+    id
+}
+
+/// Get the id of a permission without interning it, for names built from requests.
+pub(crate) fn find_permission(name: &str) -> Option<PermissionId> {
+    INTERNER
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .ids
+        .get(name)
+        .copied()
+}
+
+/// The sorted ids of the permissions granted to each user, built when the permissions are
+/// fetched so that each user is stored once and each of its permissions in 4 bytes.
+#[derive(Debug, Default)]
+pub struct PermissionIndex(HashMap<Box<str>, Box<[PermissionId]>>);
+
+impl PermissionIndex {
+    pub fn new(permissions: Permissions) -> Self {
+        let mut granted: HashMap<Box<str>, Vec<PermissionId>> = HashMap::new();
+        for (name, users) in permissions {
+            let id = intern_permission(&name);
+            for user in users {
+                granted.entry(user.into_boxed_str()).or_default().push(id);
+            }
+        }
+
+        Self(
+            granted
+                .into_iter()
+                .map(|(user, mut ids)| {
+                    ids.sort_unstable();
+                    ids.dedup();
+                    (user, ids.into_boxed_slice())
+                })
+                .collect(),
+        )
+    }
+
+    fn is_granted(&self, permission: PermissionId, user: &str) -> bool {
+        self.0
+            .get(user)
+            .is_some_and(|ids| ids.binary_search(&permission).is_ok())
+    }
+
+    /// The names of the permissions granted to `user`, sorted.
+    pub(crate) fn permissions_of(&self, user: &str) -> Vec<String> {
+        let interner = INTERNER.read().unwrap_or_else(|e| e.into_inner());
+        let mut names: Vec<String> = self
+            .0
+            .get(user)
+            .into_iter()
+            .flatten()
+            .map(|id| interner.names[id.0 as usize].to_string())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// The permissions of the index by name, without those granted to no one.
+    pub fn to_permissions(&self) -> Permissions {
+        let interner = INTERNER.read().unwrap_or_else(|e| e.into_inner());
+        let mut permissions = Permissions::new();
+        for (user, ids) in &self.0 {
+            for id in ids.iter() {
+                permissions
+                    .entry(interner.names[id.0 as usize].to_string())
+                    .or_default()
+                    .insert(user.to_string());
+            }
+        }
+        permissions
+    }
+}
+
+static IS_ROLE_PERM: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new("([^:]+)::roles::(.*)").unwrap());
+
+async fn try_fetch_perm(perm_uri: &PermUri) -> Result<PermList> {
+    let client = Client::builder(TokioExecutor::new()).build_http::<Full<Bytes>>();
+
+    let res = client.get(perm_uri.uri.clone()).await?;
+    let body: BytesMut = res.into_data_stream().try_collect().await?;
+
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn fetch_perm(perm_uri: &PermUri) -> Option<PermList> {
+    try_fetch_perm(perm_uri)
+        .await
+        .inspect_err(|e| error!("fail to fetch {}: {e}", redact(&perm_uri.uri.to_string())))
+        .ok()
+}
+
+/// Check that the permissions of `perm_uri` can be fetched and parsed.
+pub async fn check_perm_uri(perm_uri: &PermUri) -> Result<()> {
+    try_fetch_perm(perm_uri).await.map(|_| ())
+}
+
+pub async fn get_perm() -> Result<(Permissions, Roles)> {
+    let mut perm_hm: HashMap<String, HashSet<String>> = HashMap::new();
+    let mut user_role = HashMap::new();
+
+    for perm_uri in runtime_config().perm_uris.iter() {
+        match fetch_perm(perm_uri).await {
+            Some(perm_vec) => {
+                for perm in perm_vec.iter() {
+                    if let Some(captures) = IS_ROLE_PERM.captures(&perm.role_name) {
+                        let app_name = captures.get(1).unwrap().as_str();
+                        let role_name = captures.get(2).unwrap().as_str();
+                        for user_id in perm.user_id.iter() {
+                            user_role
+                                .entry(user_id.to_string())
+                                .or_insert_with(HashMap::new)
+                                .entry(app_name.to_string())
+                                .or_insert_with(Vec::new)
+                                .push(role_name.to_string());
+                        }
+                    }
+                    if perm_hm.contains_key(&perm.role_name) {
+                        let old_value = perm_hm.get(&perm.role_name).unwrap();
+                        let new_value: HashSet<String> = old_value
+                            .union(&perm.user_id)
+                            .map(|s| s.to_string())
+                            .collect();
+                        perm_hm.insert(perm.role_name.to_string(), new_value);
+                    } else {
+                        perm_hm.insert(perm.role_name.to_string(), perm.user_id.clone());
+                    }
+                }
+            }
+            None => {
+                bail!("Fail to fetch permissions");
+            }
+        }
+    }
+
+    let mut user_role_final = HashMap::new();
+    for (user_sub, apps) in &user_role {
+        for (app_name, perms) in apps {
+            let perm_str = perms
+                .iter()
+                .fold(String::new(), |acc, perm| acc + "," + perm.as_str());
+            user_role_final
+                .entry(user_sub.to_string())
+                .or_insert_with(HashMap::new)
+                .insert(app_name.to_string(), perm_str[1..].to_string());
+        }
+    }
+    record_snapshot(&perm_hm);
+    Ok((perm_hm, user_role_final))
+}
+
+/// Fetch the permissions and roles and replace the current ones, keeping them on failure.
+pub async fn refresh_perm(perm_lock: &PermLock, role_lock: &RoleLock) -> Result<()> {
+    let (perm, role) = get_perm().await?;
+    notify_permissions_changed(&perm_lock.load().to_permissions(), &perm);
+    perm_lock.store(Arc::new(PermissionIndex::new(perm)));
+    role_lock.store(Arc::new(role));
+    Ok(())
+}
+
+pub async fn update_perm(perm_lock: PermLock, role_lock: RoleLock) -> Result<()> {
+    let mut error_count = 0;
+
+    loop {
+        sleep(runtime_config().perm_update_delay).await;
+        let perm_update = refresh_perm(&perm_lock, &role_lock).await;
+        if perm_update.is_err() {
+            error_count += 1;
+            error!(
+                "Failed to fetch/update permissions for the {} times",
+                error_count
+            );
+
+            if error_count >= runtime_config().max_fetch_error_count {
+                bail!("Failed to fetch/update permissions")
+            }
+        } else {
+            error_count = 0;
+            debug!("perm updated");
+        }
+    }
+}
+
+pub fn has_perm(perm_lock: &PermLock, perm: PermissionId, token_id: &str) -> bool {
+    perm_lock.load().is_granted(perm, token_id)
+}
@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+
+use crate::permission::Permissions;
+use crate::redact::redact;
+use crate::runtime_config::runtime_config;
+
+/// An entry of the permission audit file, chained to the previous one by `previous_hash`.
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotRecord {
+    timestamp: String,
+    /// The `perm_uris` the snapshot was fetched from, separated by spaces.
+    source: String,
+    permissions_hash: String,
+    previous_hash: Option<String>,
+    hash: String,
+}
+
+impl SnapshotRecord {
+    /// Hash of the record, covering the hash of the previous one.
+    fn compute_hash(&self) -> String {
+        let chained = format!(
+            "{}\n{}\n{}\n{}",
+            self.previous_hash.as_deref().unwrap_or_default(),
+            self.timestamp,
+            self.source,
+            self.permissions_hash
+        );
+        to_hex(digest(&SHA256, chained.as_bytes()).as_ref())
+    }
+}
+
+/// The last record of the audit file, read once from the file.
+struct Chain {
+    path: PathBuf,
+    last: Option<SnapshotRecord>,
+}
+
+static CHAIN: LazyLock<Mutex<Option<Chain>>> = LazyLock::new(Default::default);
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Hash of the permissions serialized as JSON with sorted permissions and users, so that the
+/// same permissions always have the same hash.
+fn hash_permissions(permissions: &Permissions) -> String {
+    let sorted: BTreeMap<_, BTreeSet<_>> = permissions
+        .iter()
+        .map(|(permission, users)| (permission, users.iter().collect()))
+        .collect();
+    let json = serde_json::to_vec(&sorted).unwrap_or_default();
+    to_hex(digest(&SHA256, &json).as_ref())
+}
+
+fn read_records(path: &Path) -> Result<Vec<SnapshotRecord>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    content
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+fn append(path: &Path, record: &SnapshotRecord) -> Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Append the permissions to the `permission_audit` file if they differ from those of its last
+/// record.
+pub(crate) fn record_snapshot(permissions: &Permissions) {
+    let runtime_config = runtime_config();
+    let Some(config) = &runtime_config.permission_audit else {
+        return;
+    };
+
+    let mut chain = CHAIN
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if chain.as_ref().is_none_or(|chain| chain.path != config.path) {
+        match read_records(&config.path) {
+            Ok(mut records) => {
+                *chain = Some(Chain {
+                    path: config.path.clone(),
+                    last: records.pop(),
+                })
+            }
+            Err(e) => {
+                error!(
+                    "event='Cannot read permission audit {}: {e}'",
+                    config.path.display()
+                );
+                return;
+            }
+        }
+    }
+    let Some(chain) = chain.as_mut() else {
+        return;
+    };
+
+    let permissions_hash = hash_permissions(permissions);
+    if chain
+        .last
+        .as_ref()
+        .is_some_and(|last| last.permissions_hash == permissions_hash)
+    {
+        return;
+    }
+    let mut record = SnapshotRecord {
+        timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        source: runtime_config
+            .perm_uris
+            .iter()
+            .map(|perm_uri| redact(&perm_uri.uri.to_string()).into_owned())
+            .collect::<Vec<_>>()
+            .join(" "),
+        permissions_hash,
+        previous_hash: chain.last.as_ref().map(|last| last.hash.clone()),
+        hash: String::new(),
+    };
+    record.hash = record.compute_hash();
+
+    match append(&config.path, &record) {
+        Ok(()) => chain.last = Some(record),
+        Err(e) => error!(
+            "event='Cannot write permission audit {}: {e}'",
+            config.path.display()
+        ),
+    }
+}
+
+/// Check the hashes of a permission audit file and their chaining, returning the number of
+/// records.
+pub fn verify_audit_file(path: &Path) -> Result<usize> {
+    let records = read_records(path)?;
+    let mut previous_hash = None;
+    for (index, record) in records.iter().enumerate() {
+        if record.previous_hash != previous_hash {
+            bail!("Record {} is not chained to the previous one", index + 1);
+        }
+        if record.hash != record.compute_hash() {
+            bail!("Record {} has an invalid hash", index + 1);
+        }
+        previous_hash = Some(record.hash.clone());
+    }
+    Ok(records.len())
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use anyhow::{anyhow, Result};
+use hyper::HeaderMap;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use serde_json::Value;
+
+use crate::auth::Claims;
+use crate::script::new_engine;
+
+/// Operations a predicate can run for a request, going over not matching the request.
+const MAX_OPERATIONS: u64 = 10_000;
+
+static ENGINE: LazyLock<Engine> = LazyLock::new(|| new_engine(MAX_OPERATIONS));
+
+struct Predicate {
+    source: String,
+    ast: AST,
+}
+
+/// Compiled predicates by app, compiled again when their source changes.
+static PREDICATES: LazyLock<RwLock<HashMap<String, Arc<Predicate>>>> =
+    LazyLock::new(Default::default);
+
+/// Check that `source` is a single Rhai expression.
+pub fn check_predicate(source: &str) -> Result<()> {
+    ENGINE.compile_expression(source)?;
+    Ok(())
+}
+
+fn get_predicate(app: &str, source: &str) -> Result<Arc<Predicate>> {
+    if let Some(predicate) = PREDICATES
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(app)
+        .filter(|predicate| predicate.source == source)
+    {
+        return Ok(predicate.clone());
+    }
+
+    let predicate = Arc::new(Predicate {
+        source: source.to_string(),
+        ast: ENGINE.compile_expression(source)?,
+    });
+    PREDICATES
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(app.to_string(), predicate.clone());
+    Ok(predicate)
+}
+
+fn to_dynamic(value: &Value) -> Dynamic {
+    match value {
+        Value::Null => Dynamic::UNIT,
+        Value::Bool(value) => (*value).into(),
+        Value::Number(value) => match value.as_i64() {
+            Some(value) => value.into(),
+            None => value.as_f64().unwrap_or_default().into(),
+        },
+        Value::String(value) => value.clone().into(),
+        Value::Array(values) => values.iter().map(to_dynamic).collect::<Vec<_>>().into(),
+        Value::Object(values) => Dynamic::from_map(
+            values
+                .iter()
+                .map(|(name, value)| (name.into(), to_dynamic(value)))
+                .collect::<Map>(),
+        ),
+    }
+}
+
+/// Evaluate the predicate of `app`, which sees the `headers` whose values are valid strings, the
+/// `client_ip`, the `token_type` and the `claims` of the request.
+pub fn eval_predicate(
+    app: &str,
+    source: &str,
+    headers: &HeaderMap,
+    client_ip: IpAddr,
+    claims: &Claims,
+    token_type: &str,
+) -> Result<bool> {
+    let predicate = get_predicate(app, source)?;
+
+    let headers: Map = headers
+        .keys()
+        .filter_map(|name| {
+            let value = headers.get(name)?.to_str().ok()?;
+            Some((name.as_str().into(), value.into()))
+        })
+        .collect();
+    let mut scope = Scope::new();
+    scope.push_constant("headers", headers);
+    scope.push_constant("client_ip", client_ip.to_string());
+    scope.push_constant("token_type", token_type.to_string());
+    scope.push_constant("claims", to_dynamic(&claims.to_value()));
+
+    ENGINE
+        .eval_ast_with_scope::<bool>(&mut scope, &predicate.ast)
+        .map_err(|e| anyhow!("{e}"))
+}
@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use hyper::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use hyper::HeaderMap;
+use redis::aio::MultiplexedConnection;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::time::timeout;
+
+use crate::runtime_config::{runtime_config, QuotaConfig, QuotaStoreConfig};
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// At most `requests` requests to the API every `window` seconds, further ones being answered
+/// with `429`.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct QuotaSpec {
+    pub requests: u64,
+    pub window: u64,
+}
+
+/// A quota of requests, from the `ApiDefinition` or the runtime config.
+#[derive(Clone, Copy)]
+pub(crate) struct Quota {
+    requests: u64,
+    window: Duration,
+}
+
+impl From<&QuotaSpec> for Quota {
+    fn from(spec: &QuotaSpec) -> Self {
+        Self {
+            requests: spec.requests,
+            window: Duration::from_secs(spec.window),
+        }
+    }
+}
+
+impl From<&QuotaConfig> for Quota {
+    fn from(config: &QuotaConfig) -> Self {
+        Self {
+            requests: config.requests,
+            window: config.window,
+        }
+    }
+}
+
+/// What a quota counts the requests of, naming its windows.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum QuotaKey {
+    Global,
+    App(String),
+    Tenant(String),
+}
+
+impl QuotaKey {
+    fn scope(&self) -> &'static str {
+        match self {
+            QuotaKey::Global => "global",
+            QuotaKey::App(_) => "app",
+            QuotaKey::Tenant(_) => "tenant",
+        }
+    }
+}
+
+/// Requests counted since the start of the current window of a quota.
+struct Window {
+    started_at: Instant,
+    count: u64,
+}
+
+/// Windows of the quota of each app and tenant, and of the global one.
+static WINDOWS: LazyLock<Mutex<HashMap<QuotaKey, Window>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// State of a quota once a request is counted, sent as `RateLimit-*` headers.
+pub(crate) struct QuotaState {
+    limit: u64,
+    remaining: u64,
+    reset: Duration,
+}
+
+impl QuotaState {
+    /// Seconds until the window ends, rounded up so that clients do not retry too early.
+    fn reset_seconds(&self) -> u64 {
+        self.reset.as_secs() + u64::from(self.reset.subsec_nanos() > 0)
+    }
+
+    pub(crate) fn inject_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(self.reset_seconds()));
+    }
+}
+
+/// A request over a quota, `scope` being `app`, `tenant` or `global`.
+pub(crate) struct QuotaExceeded {
+    pub(crate) scope: &'static str,
+    pub(crate) state: QuotaState,
+}
+
+impl QuotaExceeded {
+    pub(crate) fn inject_headers(&self, headers: &mut HeaderMap) {
+        self.state.inject_headers(headers);
+        headers.insert(RETRY_AFTER, HeaderValue::from(self.state.reset_seconds()));
+    }
+}
+
+/// Time waited before connecting again to the store once it failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The Redis server of `quotas.store`, whose windows are shared by the instances of the gateway.
+struct Store {
+    timeout: Duration,
+    key_prefix: String,
+    client: redis::Client,
+    connection: tokio::sync::Mutex<Option<MultiplexedConnection>>,
+    reconnect_at: Mutex<Option<Instant>>,
+    available: AtomicBool,
+}
+
+/// Only read at startup, the store being set up with the first request.
+static STORE: LazyLock<Option<Store>> = LazyLock::new(|| {
+    let runtime_config = runtime_config();
+    let config: &QuotaStoreConfig = runtime_config.quotas.store.as_ref()?;
+    match redis::Client::open(config.redis_url.as_str()) {
+        Ok(client) => Some(Store {
+            timeout: config.timeout,
+            key_prefix: config.key_prefix.clone(),
+            client,
+            connection: tokio::sync::Mutex::new(None),
+            reconnect_at: Mutex::new(None),
+            available: AtomicBool::new(true),
+        }),
+        Err(e) => {
+            error!("event='Invalid quota store, windows are kept locally: {e}'");
+            None
+        }
+    }
+});
+
+impl Store {
+    async fn get_connection(&self) -> Result<MultiplexedConnection> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = &*connection {
+            return Ok(connection.clone());
+        }
+
+        if let Some(reconnect_at) = *self.reconnect_at.lock().unwrap() {
+            if Instant::now() < reconnect_at {
+                bail!("Waiting to connect again");
+            }
+        }
+        match self.client.get_multiplexed_async_connection().await {
+            Ok(new_connection) => {
+                *connection = Some(new_connection.clone());
+                Ok(new_connection)
+            }
+            Err(e) => {
+                *self.reconnect_at.lock().unwrap() = Some(Instant::now() + RECONNECT_DELAY);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Count a request in the windows of the store, which start at multiples of their duration
+    /// since the epoch so that all the instances share them. Requests over a quota are counted
+    /// too.
+    async fn count(&self, quotas: &[(QuotaKey, Quota)]) -> Result<Vec<(u64, Duration)>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        let mut resets = Vec::new();
+        for (key, quota) in quotas {
+            let window = quota.window.as_millis().max(1);
+            let index = now.as_millis() / window;
+            let key = match key {
+                QuotaKey::Global => format!("{}global:{index}", self.key_prefix),
+                QuotaKey::App(app) => format!("{}app:{app}:{index}", self.key_prefix),
+                QuotaKey::Tenant(tenant) => format!("{}tenant:{tenant}:{index}", self.key_prefix),
+            };
+            pipe.incr(&key, 1)
+                .pexpire(&key, i64::try_from(window).unwrap_or(i64::MAX))
+                .ignore();
+            resets.push(Duration::from_millis(
+                u64::try_from((index + 1) * window - now.as_millis()).unwrap_or_default(),
+            ));
+        }
+
+        let mut connection = self.get_connection().await?;
+        let counts: Vec<u64> = match pipe.query_async(&mut connection).await {
+            Ok(counts) => counts,
+            Err(e) => {
+                // The connection is set up again for the next request.
+                *self.connection.lock().await = None;
+                return Err(e.into());
+            }
+        };
+        Ok(counts.into_iter().zip(resets).collect())
+    }
+
+    /// Remember a token for `ttl`, returning whether it was its first use.
+    async fn use_token(&self, source: &str, jti: &str, ttl: Duration) -> Result<bool> {
+        let key = format!("{}token:{source}:{jti}", self.key_prefix);
+        let mut connection = self.get_connection().await?;
+        let set: Option<String> = match redis::cmd("SET")
+            .arg(&key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl.as_secs().max(1))
+            .query_async(&mut connection)
+            .await
+        {
+            Ok(set) => set,
+            Err(e) => {
+                *self.connection.lock().await = None;
+                return Err(e.into());
+            }
+        };
+        Ok(set.is_some())
+    }
+
+    /// Log the first failure and the recovery of the store, not every request.
+    fn set_available(&self, available: bool, error: Option<String>) {
+        if self.available.swap(available, Ordering::Relaxed) == available {
+            return;
+        }
+        match error {
+            Some(e) => warn!("event='Quota store unavailable, windows are kept locally: {e}'"),
+            None => info!("event='Quota store available again'"),
+        }
+    }
+}
+
+/// Get the state of each quota from the count of its window.
+fn check_counts(
+    quotas: &[(QuotaKey, Quota)],
+    counts: Vec<(u64, Duration)>,
+) -> std::result::Result<Option<QuotaState>, QuotaExceeded> {
+    let mut states = Vec::new();
+    for ((key, quota), (count, reset)) in quotas.iter().zip(counts) {
+        if count > quota.requests {
+            return Err(QuotaExceeded {
+                scope: key.scope(),
+                state: QuotaState {
+                    limit: quota.requests,
+                    remaining: 0,
+                    reset,
+                },
+            });
+        }
+        states.push(QuotaState {
+            limit: quota.requests,
+            remaining: quota.requests - count,
+            reset,
+        });
+    }
+    Ok(states.into_iter().min_by_key(|state| state.remaining))
+}
+
+/// Count a request against the quota of `app`, the one of its tenant and the global one,
+/// returning the state of the quota with the fewest remaining requests. The windows of
+/// `quotas.store` are used if it answers in time, those of the instance otherwise.
+pub(crate) async fn count_request(
+    app: &str,
+    app_quota: Option<Quota>,
+    tenant_quota: Option<(&str, Quota)>,
+    global_quota: Option<Quota>,
+) -> std::result::Result<Option<QuotaState>, QuotaExceeded> {
+    let quotas: Vec<_> = [
+        (QuotaKey::App(app.to_string()), app_quota),
+        (QuotaKey::Global, global_quota),
+    ]
+    .into_iter()
+    .filter_map(|(key, quota)| Some((key, quota?)))
+    .chain(tenant_quota.map(|(tenant, quota)| (QuotaKey::Tenant(tenant.to_string()), quota)))
+    .collect();
+    if quotas.is_empty() {
+        return Ok(None);
+    }
+
+    if let Some(store) = &*STORE {
+        match timeout(store.timeout, store.count(&quotas)).await {
+            Ok(Ok(counts)) => {
+                store.set_available(true, None);
+                return check_counts(&quotas, counts);
+            }
+            Ok(Err(e)) => store.set_available(false, Some(e.to_string())),
+            Err(_) => store.set_available(false, Some("timeout".to_string())),
+        }
+    }
+    count_locally(&quotas)
+}
+
+/// Record the use of a token of a `single_use` auth source in `quotas.store`, until it expires in
+/// `ttl`, so that it is used once across the instances. Returns whether it was its first use, or
+/// `None` without a store or if it does not answer in time.
+pub(crate) async fn use_token_in_store(source: &str, jti: &str, ttl: Duration) -> Option<bool> {
+    let store = STORE.as_ref()?;
+    match timeout(store.timeout, store.use_token(source, jti, ttl)).await {
+        Ok(Ok(first_use)) => {
+            store.set_available(true, None);
+            Some(first_use)
+        }
+        Ok(Err(e)) => {
+            store.set_available(false, Some(e.to_string()));
+            None
+        }
+        Err(_) => {
+            store.set_available(false, Some("timeout".to_string()));
+            None
+        }
+    }
+}
+
+/// Count a request in the windows of the instance, a request over either quota not being
+/// counted.
+fn count_locally(
+    quotas: &[(QuotaKey, Quota)],
+) -> std::result::Result<Option<QuotaState>, QuotaExceeded> {
+    let now = Instant::now();
+    let mut windows = WINDOWS.lock().unwrap();
+    let mut states = Vec::new();
+    for (key, quota) in quotas {
+        let window = windows.entry(key.clone()).or_insert(Window {
+            started_at: now,
+            count: 0,
+        });
+        if now.duration_since(window.started_at) >= quota.window {
+            window.started_at = now;
+            window.count = 0;
+        }
+
+        let reset = quota
+            .window
+            .saturating_sub(now.duration_since(window.started_at));
+        if window.count >= quota.requests {
+            return Err(QuotaExceeded {
+                scope: key.scope(),
+                state: QuotaState {
+                    limit: quota.requests,
+                    remaining: 0,
+                    reset,
+                },
+            });
+        }
+        states.push(QuotaState {
+            limit: quota.requests,
+            remaining: quota.requests - window.count - 1,
+            reset,
+        });
+    }
+
+    for (key, _) in quotas {
+        if let Some(window) = windows.get_mut(key) {
+            window.count += 1;
+        }
+    }
+    Ok(states.into_iter().min_by_key(|state| state.remaining))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn quota(requests: u64, window: Duration) -> Quota {
+        Quota { requests, window }
+    }
+
+    fn header(headers: &HeaderMap, name: &str) -> u64 {
+        headers[name].to_str().unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn windows_reset_once_over() {
+        let quotas = [(
+            QuotaKey::App("/windows".to_string()),
+            quota(2, Duration::from_millis(100)),
+        )];
+        assert_eq!(count_locally(&quotas).ok().flatten().unwrap().remaining, 1);
+        assert_eq!(count_locally(&quotas).ok().flatten().unwrap().remaining, 0);
+        let exceeded = count_locally(&quotas).err().unwrap();
+        assert_eq!(exceeded.scope, "app");
+        assert!(exceeded.state.reset <= Duration::from_millis(100));
+
+        sleep(Duration::from_millis(100));
+        let state = count_locally(&quotas).ok().flatten().unwrap();
+        assert_eq!(state.remaining, 1);
+        assert!(state.reset > Duration::from_millis(50));
+    }
+
+    #[test]
+    fn exceeded_quotas_send_retry_after() {
+        let quotas = [(
+            QuotaKey::App("/retry".to_string()),
+            quota(1, Duration::from_secs(60)),
+        )];
+        count_locally(&quotas).ok().unwrap();
+        let exceeded = count_locally(&quotas).err().unwrap();
+
+        let mut headers = HeaderMap::new();
+        exceeded.inject_headers(&mut headers);
+        assert_eq!(header(&headers, "ratelimit-limit"), 1);
+        assert_eq!(header(&headers, "ratelimit-remaining"), 0);
+        // Rounded up, so that clients do not retry before the window ends.
+        let retry_after = header(&headers, "retry-after");
+        assert!((59..=60).contains(&retry_after));
+        assert_eq!(header(&headers, "ratelimit-reset"), retry_after);
+    }
+
+    #[test]
+    fn the_quota_with_the_fewest_remaining_requests_is_sent() {
+        let quotas = [
+            (
+                QuotaKey::App("/headers".to_string()),
+                quota(10, Duration::from_secs(60)),
+            ),
+            (
+                QuotaKey::Tenant("headers".to_string()),
+                quota(2, Duration::from_secs(30)),
+            ),
+        ];
+        let state = count_locally(&quotas).ok().flatten().unwrap();
+        let mut headers = HeaderMap::new();
+        state.inject_headers(&mut headers);
+        assert_eq!(header(&headers, "ratelimit-limit"), 2);
+        assert_eq!(header(&headers, "ratelimit-remaining"), 1);
+        assert_eq!(header(&headers, "ratelimit-reset"), 30);
+        assert!(headers.get(RETRY_AFTER).is_none());
+
+        count_locally(&quotas).ok().unwrap();
+        let exceeded = count_locally(&quotas).err().unwrap();
+        assert_eq!(exceeded.scope, "tenant");
+        // Requests over a quota are not counted in the others.
+        assert_eq!(WINDOWS.lock().unwrap()[&quotas[0].0].count, 2);
+    }
+
+    #[test]
+    fn store_counts_include_the_request() {
+        let quotas = [(QuotaKey::Global, quota(3, Duration::from_secs(60)))];
+        let reset = Duration::from_millis(1500);
+
+        let state = check_counts(&quotas, vec![(3, reset)])
+            .ok()
+            .flatten()
+            .unwrap();
+        assert_eq!(state.remaining, 0);
+        assert_eq!(state.reset_seconds(), 2);
+
+        let exceeded = check_counts(&quotas, vec![(4, reset)]).err().unwrap();
+        assert_eq!(exceeded.scope, "global");
+        assert_eq!(exceeded.state.remaining, 0);
+    }
+}
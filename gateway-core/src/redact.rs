@@ -0,0 +1,98 @@
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+use regex::{Captures, Regex};
+use serde_json::Value;
+use url::Url;
+
+use crate::runtime_config::runtime_config;
+
+/// Replacement of the redacted values, as in captured bodies.
+const REDACTED: &str = "[REDACTED]";
+
+/// Matches the values of the `log_redaction` query parameters (`_auth_token=<JWT>`) and headers
+/// (`authorization: Bearer <JWT>`, or as in a debug header map), the kept prefix being in the
+/// first or second group, and JWTs found anywhere else.
+static SECRETS: LazyLock<Regex> = LazyLock::new(|| {
+    let runtime_config = runtime_config();
+    let config = &runtime_config.log_redaction;
+    let join = |names: &[String]| {
+        names
+            .iter()
+            .map(|name| regex::escape(name))
+            .collect::<Vec<_>>()
+            .join("|")
+    };
+    let mut alternatives = vec![r"\beyJ[\w-]*\.[\w-]+\.[\w-]*".to_string()];
+    if !config.query_params.is_empty() {
+        alternatives.push(format!(
+            r"([?&;](?:{})=)[^&#\s]*",
+            join(&config.query_params)
+        ));
+    }
+    if !config.headers.is_empty() {
+        alternatives.push(format!(
+            r#"(\b(?:{})"?\s*:\s*"?)[^"\r\n]*"#,
+            join(&config.headers)
+        ));
+    }
+    Regex::new(&format!("(?i){}", alternatives.join("|"))).unwrap()
+});
+
+/// Redact the secrets of a URI or any text bound to be logged, such as an error.
+pub(crate) fn redact(text: &str) -> Cow<'_, str> {
+    SECRETS.replace_all(text, |captures: &Captures| {
+        let prefix = captures
+            .get(1)
+            .or_else(|| captures.get(2))
+            .map_or("", |prefix| prefix.as_str());
+        format!("{prefix}{REDACTED}")
+    })
+}
+
+/// Settings of the runtime config holding a secret.
+const SECRET_SETTINGS: [&str; 3] = ["bearer_token", "secret", "key"];
+/// Settings of the runtime config holding a URL, whose password and query may be secrets.
+const URL_SETTINGS: [&str; 5] = ["uri", "webhook", "endpoint", "otlp_endpoint", "redis_url"];
+
+fn redact_url(url: &str) -> Option<String> {
+    let mut url = Url::parse(url).ok()?;
+    if url.password().is_some() {
+        url.set_password(Some(REDACTED)).ok()?;
+    }
+    if url.query().is_some() {
+        url.set_query(Some(REDACTED));
+    }
+    Some(url.into())
+}
+
+/// Redact the secrets of the settings of a runtime config: keys, tokens, the values of the
+/// `headers` added to requests, and the passwords and queries of URLs.
+pub(crate) fn redact_settings(value: &mut Value) {
+    match value {
+        Value::Object(settings) => {
+            for (name, setting) in settings.iter_mut() {
+                match setting {
+                    Value::String(_) | Value::Number(_)
+                        if SECRET_SETTINGS.contains(&name.as_str()) =>
+                    {
+                        *setting = REDACTED.into();
+                    }
+                    Value::String(url) if URL_SETTINGS.contains(&name.as_str()) => {
+                        if let Some(redacted) = redact_url(url) {
+                            *url = redacted;
+                        }
+                    }
+                    Value::Object(headers) if name == "headers" => {
+                        headers
+                            .values_mut()
+                            .for_each(|value| *value = REDACTED.into());
+                    }
+                    _ => redact_settings(setting),
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_settings),
+        _ => (),
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock};
 
+use hyper::Method;
 use regex::Regex;
 
 use crate::api::{ApiDefinition, ApiMode};
@@ -8,9 +9,24 @@ use crate::endpoint::Endpoint;
 
 static IS_PARAM: LazyLock<Regex> = LazyLock::new(|| Regex::new("\\{[^/]*\\}").unwrap());
 
+/// Methods whose `forward_all` endpoints are built with the api, others being built by each
+/// request.
+const FORWARD_ALL_METHODS: [Method; 9] = [
+    Method::GET,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::PATCH,
+    Method::HEAD,
+    Method::OPTIONS,
+    Method::CONNECT,
+    Method::TRACE,
+];
+
 #[derive(Debug)]
 pub struct Node {
-    endpoint_set: HashMap<String, Endpoint>,
+    /// Endpoints of the node by method, or those of any path for a `forward_all` api.
+    endpoint_set: HashMap<String, Arc<Endpoint>>,
     sub_route: HashMap<String, Self>,
     param: Option<Box<Self>>,
 }
@@ -37,7 +53,8 @@ impl Node {
     fn insert<'a>(&mut self, split_path: &mut impl Iterator<Item = &'a str>, endpoint: Endpoint) {
         match split_path.next() {
             None => {
-                self.endpoint_set.insert(endpoint.method.clone(), endpoint);
+                self.endpoint_set
+                    .insert(endpoint.method.clone(), Arc::new(endpoint));
             }
             Some(current_path) => {
                 match IS_PARAM.is_match(current_path) {
@@ -70,7 +87,14 @@ impl Node {
         let mut node = Node::empty();
 
         match &api.spec.mode {
-            ApiMode::ForwardAll => (),
+            ApiMode::ForwardAll => {
+                for method in FORWARD_ALL_METHODS {
+                    let endpoint =
+                        Endpoint::from_forward_all(method.as_str(), &api.spec.app_name, true);
+                    node.endpoint_set
+                        .insert(endpoint.method.clone(), Arc::new(endpoint));
+                }
+            }
             ApiMode::ForwardStrict(endpoints) => {
                 for endpoint in endpoints {
                     let mut built_endpoint = endpoint.clone();
@@ -86,7 +110,15 @@ impl Node {
         node
     }
 
-    pub fn match_path(&self, path: &str, method: &str) -> Option<&Endpoint> {
+    /// Endpoint of a request to a `forward_all` api.
+    pub fn forward_all(&self, method: &str, app: &str) -> Arc<Endpoint> {
+        match self.endpoint_set.get(method) {
+            Some(endpoint) => endpoint.clone(),
+            None => Arc::new(Endpoint::from_forward_all(method, app, false)),
+        }
+    }
+
+    pub fn match_path(&self, path: &str, method: &str) -> Option<&Arc<Endpoint>> {
         let mut split_path = strip_path(path).split('/');
         let mut node = self;
         loop {
@@ -0,0 +1,1645 @@
+use std::collections::HashMap;
+use std::env;
+use std::error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::{Arc, LazyLock, OnceLock, RwLock};
+use std::time::Duration;
+
+use hyper::header::HeaderName;
+use hyper::http::Uri;
+use ipnet::IpNet;
+use jsonwebtoken::DecodingKey;
+use regex::Regex;
+use rustls::RootCertStore;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{RootSchema, Schema};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer};
+use serde_yaml::{Mapping, Value};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use url::Url;
+
+use crate::access_log::ACCESS_LOG_FIELDS;
+use crate::auth::check_claim_template;
+use crate::security_headers::SecurityHeadersSpec;
+use crate::tls::client_config_builder;
+
+/// A duration such as `30s` or `5m`, or a deprecated number of seconds.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum DurationValue {
+    Seconds(f64),
+    Human(String),
+}
+
+impl TryFrom<DurationValue> for Duration {
+    type Error = String;
+
+    fn try_from(value: DurationValue) -> std::result::Result<Self, Self::Error> {
+        match value {
+            DurationValue::Seconds(seconds) => {
+                warn!(
+                    "event='Duration {seconds} given as a number of seconds, which is deprecated: \
+                     use a string such as \"{seconds}s\"'"
+                );
+                Duration::try_from_secs_f64(seconds)
+                    .map_err(|e| format!("invalid duration {seconds}: {e}"))
+            }
+            DurationValue::Human(duration) => humantime::parse_duration(&duration)
+                .map_err(|e| format!("invalid duration `{duration}`: {e}")),
+        }
+    }
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Duration, D::Error> {
+    DurationValue::deserialize(deserializer)?
+        .try_into()
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_option_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Duration>, D::Error> {
+    Option::<DurationValue>::deserialize(deserializer)?
+        .map(Duration::try_from)
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PermUri {
+    #[serde(with = "http_serde::uri")]
+    #[schemars(with = "String")]
+    pub uri: Uri,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AuthSource {
+    pub name: String,
+    pub token_type: String,
+    pub issuer: String,
+    pub audience: String,
+    /// Inlined PEM public key.
+    pub public_key: Option<String>,
+    /// File of the PEM public key, read at startup and on reload.
+    pub public_key_file: Option<PathBuf>,
+    /// Kubernetes Secret key holding the PEM public key, fetched at startup and on reload.
+    pub public_key_secret: Option<SecretKeyRef>,
+    /// Accept each token once, rejecting a second use of its `jti` claim until it expires, across
+    /// the instances sharing `quotas.store`.
+    #[serde(default)]
+    pub single_use: bool,
+}
+
+/// A key of a Kubernetes Secret.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SecretKeyRef {
+    /// Defaults to the namespace of the gateway.
+    pub namespace: Option<String>,
+    pub name: String,
+    pub key: String,
+}
+
+/// Settings of the websocket library, each defaulting to its own default.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+struct WebSocketConfigInternal {
+    write_buffer_size: usize,
+    max_write_buffer_size: usize,
+    max_message_size: usize,
+    max_frame_size: usize,
+    accept_unmasked_frames: bool,
+}
+
+impl Default for WebSocketConfigInternal {
+    fn default() -> Self {
+        let config = WebSocketConfig::default();
+        Self {
+            write_buffer_size: config.write_buffer_size,
+            max_write_buffer_size: config.max_write_buffer_size,
+            max_message_size: config.max_message_size.unwrap_or(usize::MAX),
+            max_frame_size: config.max_frame_size.unwrap_or(usize::MAX),
+            accept_unmasked_frames: config.accept_unmasked_frames,
+        }
+    }
+}
+
+/// Trust store used to connect to `wss://` backends.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct WebsocketTlsConfig {
+    /// PEM files of additional trusted CAs.
+    #[serde(default)]
+    pub ca_files: Vec<PathBuf>,
+    /// Trust the CAs of the system.
+    #[serde(default = "native_roots_default")]
+    pub native_roots: bool,
+}
+
+impl Default for WebsocketTlsConfig {
+    fn default() -> Self {
+        Self {
+            ca_files: Vec::new(),
+            native_roots: native_roots_default(),
+        }
+    }
+}
+
+fn native_roots_default() -> bool {
+    true
+}
+
+/// TLS versions, cipher suites and curves allowed for the TLS connections of the gateway.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TlsPolicyConfig {
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// Allowed cipher suites by order of preference, all those supported when empty.
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// Allowed key exchange groups by order of preference, all those supported when empty.
+    #[serde(default)]
+    pub curves: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "kind")]
+pub enum LogSinkConfig {
+    /// Records go through a dedicated log target, along with other logs.
+    #[default]
+    Log,
+    Stdout,
+    File {
+        path: PathBuf,
+        /// Maximum size of the file in bytes before rotation.
+        max_size: Option<u64>,
+        /// Maximum age of the file before rotation.
+        #[serde(default, deserialize_with = "deserialize_option_duration")]
+        #[schemars(with = "Option<DurationValue>")]
+        rotate_every: Option<Duration>,
+        /// Number of rotated files kept.
+        #[serde(default = "max_files_default")]
+        max_files: usize,
+    },
+    Syslog {
+        /// `udp://host:port` or the path of a unix datagram socket such as `/dev/log`.
+        address: String,
+    },
+}
+
+fn max_files_default() -> usize {
+    5
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// Fields of each record, all of them are logged if unset.
+    pub fields: Option<Vec<String>>,
+    #[serde(default)]
+    pub sink: LogSinkConfig,
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AuditLogConfig {
+    #[serde(default)]
+    pub sink: LogSinkConfig,
+}
+
+/// Secrets redacted from the URIs and errors of the access and audit logs, and from the logged
+/// URIs, JWTs being always redacted.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LogRedactionConfig {
+    #[serde(default = "redacted_query_params_default")]
+    pub query_params: Vec<String>,
+    #[serde(default = "redacted_headers_default")]
+    pub headers: Vec<String>,
+}
+
+fn redacted_query_params_default() -> Vec<String> {
+    [
+        "_auth_token",
+        "access_token",
+        "id_token",
+        "token",
+        "api_key",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn redacted_headers_default() -> Vec<String> {
+    [
+        "authorization",
+        "proxy-authorization",
+        "cookie",
+        "set-cookie",
+        "x-api-key",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+impl Default for LogRedactionConfig {
+    fn default() -> Self {
+        Self {
+            query_params: redacted_query_params_default(),
+            headers: redacted_headers_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BodyCaptureConfig {
+    /// Number of bytes logged from the start of each body.
+    #[serde(default = "max_bytes_default")]
+    pub max_bytes: usize,
+    /// Keys whose values are redacted from JSON and form bodies.
+    #[serde(default = "redacted_keys_default")]
+    pub redacted_keys: Vec<String>,
+    /// Header enabling the capture of a single request, only honored for trusted sources.
+    #[serde(default = "trigger_header_default")]
+    pub trigger_header: String,
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub trusted_sources: Vec<IpNet>,
+}
+
+fn max_bytes_default() -> usize {
+    4096
+}
+
+fn redacted_keys_default() -> Vec<String> {
+    [
+        "password",
+        "secret",
+        "token",
+        "access_token",
+        "refresh_token",
+        "client_secret",
+        "authorization",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn trigger_header_default() -> String {
+    "X-Gateway-Capture-Body".to_string()
+}
+
+impl Default for BodyCaptureConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: max_bytes_default(),
+            redacted_keys: redacted_keys_default(),
+            trigger_header: trigger_header_default(),
+            trusted_sources: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsAuthConfig {
+    /// Token expected in the `Authorization: Bearer <token>` header.
+    pub bearer_token: Option<String>,
+    /// Sources allowed to scrape metrics, any source if empty.
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub allowed_sources: Vec<IpNet>,
+    /// Only serve `/metrics` on the admin listener.
+    #[serde(default)]
+    pub admin_listener_only: bool,
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AdminAuthConfig {
+    /// Token expected in the `Authorization: Bearer <token>` header.
+    pub bearer_token: Option<String>,
+    /// Sources allowed to call the `/admin` endpoints, any source if empty.
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub allowed_sources: Vec<IpNet>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    /// OTLP/HTTP endpoint receiving the spans, for example
+    /// `http://otel-collector:4318/v1/traces`.
+    pub otlp_endpoint: String,
+    #[serde(default = "sampling_ratio_default")]
+    pub sampling_ratio: f64,
+    #[serde(default = "service_name_default")]
+    pub service_name: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpMetricsConfig {
+    /// OTLP/HTTP endpoint receiving the metrics, for example
+    /// `http://otel-collector:4318/v1/metrics`.
+    #[serde(with = "http_serde::uri")]
+    #[schemars(with = "String")]
+    pub endpoint: Uri,
+    /// Delay between each export.
+    #[serde(
+        default = "export_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub export_interval: Duration,
+    #[serde(default = "service_name_default")]
+    pub service_name: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorReportingConfig {
+    /// Endpoint receiving each panic report as a JSON `POST`.
+    #[serde(with = "http_serde::uri")]
+    #[schemars(with = "String")]
+    pub webhook: Uri,
+    /// Headers added to the requests, for example to authenticate them.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ChangeWebhookConfig {
+    /// Endpoint receiving each change of the served apis and of the permissions as a CloudEvent
+    /// `POST`.
+    #[serde(with = "http_serde::uri")]
+    #[schemars(with = "String")]
+    pub webhook: Uri,
+    /// Headers added to the requests, for example to authenticate them.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Key of the HMAC-SHA256 of the bodies, sent as `X-Gateway-Signature: sha256=<hex>`.
+    pub secret: Option<String>,
+    /// `source` of the CloudEvents, to tell gateways apart.
+    #[serde(default = "change_source_default")]
+    pub source: String,
+}
+
+fn change_source_default() -> String {
+    "gateway".to_string()
+}
+
+/// Block the IPs and tokens getting too many errors, such as those scanning for paths or
+/// permissions.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AnomalyDetectionConfig {
+    /// Duration over which the responses of each client are counted.
+    #[serde(
+        default = "anomaly_window_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub window: Duration,
+    /// Requests of a window below which a client is not blocked.
+    #[serde(default = "min_requests_default")]
+    pub min_requests: u64,
+    /// Ratio of errors in a window from which a client is blocked.
+    #[serde(default = "max_error_ratio_default")]
+    pub max_error_ratio: f64,
+    /// Status codes counted as errors.
+    #[serde(default = "error_statuses_default")]
+    pub error_statuses: Vec<u16>,
+    #[serde(
+        default = "block_duration_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub block_duration: Duration,
+    /// Sources which are never blocked, such as monitoring probes.
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub exempt_sources: Vec<IpNet>,
+    /// Clients tracked at once, new ones being ignored once reached.
+    #[serde(default = "max_clients_default")]
+    pub max_clients: usize,
+}
+
+fn anomaly_window_default() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn min_requests_default() -> u64 {
+    20
+}
+
+fn max_error_ratio_default() -> f64 {
+    0.5
+}
+
+fn error_statuses_default() -> Vec<u16> {
+    vec![401, 403, 404]
+}
+
+fn block_duration_default() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn max_clients_default() -> usize {
+    100_000
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct IdentitySignatureConfig {
+    /// Key of the HMAC-SHA256 of the `X-Forwarded-User*` headers, shared with the upstream
+    /// servers verifying them.
+    pub key: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Http3Config {
+    /// UDP address of the QUIC listener.
+    pub bind_to: String,
+    /// PEM files of the certificate chain and private key presented to the clients.
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// Port advertised in `Alt-Svc`, as reached by the clients, the one of `bind_to` by default.
+    pub alt_svc_port: Option<u16>,
+    /// Duration clients remember the advertisement for.
+    #[serde(
+        default = "alt_svc_max_age_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub alt_svc_max_age: Duration,
+}
+
+fn alt_svc_max_age_default() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+impl Http3Config {
+    /// `Alt-Svc` value advertising the listener on the same host.
+    pub fn alt_svc(&self) -> String {
+        let port = self.alt_svc_port.unwrap_or_else(|| {
+            self.bind_to
+                .parse::<SocketAddr>()
+                .map_or(0, |addr| addr.port())
+        });
+        format!("h3=\":{port}\"; ma={}", self.alt_svc_max_age.as_secs())
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardAuthConfig {
+    /// Path of the endpoint on `bind_to`, shadowing the same path of the apis.
+    #[serde(default = "forward_auth_path_default")]
+    pub path: String,
+}
+
+fn forward_auth_path_default() -> String {
+    "/auth/forward".to_string()
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PermissionAuditConfig {
+    /// File to which each new permission snapshot is appended as a hash-chained JSON line.
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UserMetricsConfig {
+    /// Maximum number of users with their own label value, other users are counted as `other`.
+    #[serde(default = "top_n_default")]
+    pub top_n: usize,
+    /// Delay between each election of the most active users.
+    #[serde(
+        default = "election_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub election_interval: Duration,
+    /// Label users with a hash of their `token_id` instead of the `token_id` itself.
+    #[serde(default)]
+    pub hash_token_id: bool,
+}
+
+/// Thresholds of the ratio of failed upstream requests (502, 503 and 504) over the last
+/// `window` from which an upstream is considered degraded or down.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamHealthConfig {
+    #[serde(
+        default = "upstream_health_window_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub window: Duration,
+    #[serde(default = "degraded_ratio_default")]
+    pub degraded_ratio: f64,
+    #[serde(default = "down_ratio_default")]
+    pub down_ratio: f64,
+}
+
+impl Default for UpstreamHealthConfig {
+    fn default() -> Self {
+        Self {
+            window: upstream_health_window_default(),
+            degraded_ratio: degraded_ratio_default(),
+            down_ratio: down_ratio_default(),
+        }
+    }
+}
+
+fn upstream_health_window_default() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn degraded_ratio_default() -> f64 {
+    0.05
+}
+
+fn down_ratio_default() -> f64 {
+    0.5
+}
+
+/// At most `requests` requests every `window`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub requests: u64,
+    #[serde(deserialize_with = "deserialize_duration")]
+    #[schemars(with = "DurationValue")]
+    pub window: Duration,
+}
+
+/// Redis server sharing the quota windows between the instances of the gateway.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaStoreConfig {
+    /// `redis://[<user>:<password>@]<host>[:<port>][/<db>]` URL.
+    pub redis_url: String,
+    /// Time given to the store to count a request, the windows of the instance being used
+    /// otherwise.
+    #[serde(
+        default = "quota_store_timeout_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub timeout: Duration,
+    #[serde(default = "quota_key_prefix_default")]
+    pub key_prefix: String,
+}
+
+fn quota_store_timeout_default() -> Duration {
+    Duration::from_millis(50)
+}
+
+fn quota_key_prefix_default() -> String {
+    "gateway:quota:".to_string()
+}
+
+/// Quotas protecting the upstream servers as a whole, requests over them being answered with
+/// `429`.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct QuotasConfig {
+    /// Quota of all the apps together.
+    pub global: Option<QuotaConfig>,
+    /// Quota of each app by name, the `quota` of its `ApiDefinition` taking precedence.
+    #[serde(default)]
+    pub apps: HashMap<String, QuotaConfig>,
+    /// Only read at startup.
+    pub store: Option<QuotaStoreConfig>,
+}
+
+/// Tenant of each request, derived from the claims of its token, with its limits and metrics.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TenancyConfig {
+    /// Template such as `{claims.org_id}`, requests missing one of its claims having no tenant.
+    pub tenant: String,
+    /// Quota of each tenant, over all the apps.
+    pub quota: Option<QuotaConfig>,
+    /// Requests of each tenant served at once, further ones being answered with `429`.
+    pub max_concurrent_requests: Option<usize>,
+    /// Limits of some tenants by name, taking precedence over the ones above.
+    #[serde(default)]
+    pub tenants: HashMap<String, TenantLimitsConfig>,
+    /// Tenants with their own label value in metrics, in order of arrival, further ones being
+    /// counted as `other`.
+    #[serde(default = "max_tenants_default")]
+    pub max_tenants: usize,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TenantLimitsConfig {
+    pub quota: Option<QuotaConfig>,
+    pub max_concurrent_requests: Option<usize>,
+}
+
+fn max_tenants_default() -> usize {
+    100
+}
+
+impl TenancyConfig {
+    /// Quota and concurrency limit of `tenant`.
+    pub fn limits(&self, tenant: &str) -> (Option<&QuotaConfig>, Option<usize>) {
+        let limits = self.tenants.get(tenant);
+        (
+            limits
+                .and_then(|limits| limits.quota.as_ref())
+                .or(self.quota.as_ref()),
+            limits
+                .and_then(|limits| limits.max_concurrent_requests)
+                .or(self.max_concurrent_requests),
+        )
+    }
+}
+
+/// Limits of the metrics registered by extensions (scripts, WASM filters or embedding programs).
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CustomMetricsConfig {
+    /// Maximum number of metrics, registering more fails.
+    #[serde(default = "max_metrics_default")]
+    pub max_metrics: usize,
+    /// Maximum number of series of each metric, the label values of further series being all
+    /// `other`.
+    #[serde(default = "max_series_default")]
+    pub max_series: usize,
+}
+
+impl Default for CustomMetricsConfig {
+    fn default() -> Self {
+        Self {
+            max_metrics: max_metrics_default(),
+            max_series: max_series_default(),
+        }
+    }
+}
+
+fn max_metrics_default() -> usize {
+    50
+}
+
+fn max_series_default() -> usize {
+    100
+}
+
+/// A request is good for an SLO if its status code is at most `max_status_code` and, if set, it
+/// was handled within `latency_threshold`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SloConfig {
+    pub name: String,
+    /// Apps the SLO applies to, all of them if empty.
+    #[serde(default)]
+    pub apps: Vec<String>,
+    #[serde(default = "max_status_code_default")]
+    pub max_status_code: u16,
+    #[serde(default, deserialize_with = "deserialize_option_duration")]
+    #[schemars(with = "Option<DurationValue>")]
+    pub latency_threshold: Option<Duration>,
+}
+
+/// Limits of the request line and headers, larger requests being rejected with `414` or `431`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RequestLimitsConfig {
+    #[serde(default = "max_uri_length_default")]
+    pub max_uri_length: usize,
+    /// Maximum size of a header, name and value included.
+    #[serde(default = "max_header_size_default")]
+    pub max_header_size: usize,
+    /// Maximum size of all headers.
+    #[serde(default = "max_header_bytes_default")]
+    pub max_header_bytes: usize,
+    #[serde(default = "max_headers_default")]
+    pub max_headers: usize,
+}
+
+impl Default for RequestLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_uri_length: max_uri_length_default(),
+            max_header_size: max_header_size_default(),
+            max_header_bytes: max_header_bytes_default(),
+            max_headers: max_headers_default(),
+        }
+    }
+}
+
+impl RequestLimitsConfig {
+    /// Size of the read buffer of connections, holding at least the request line and headers.
+    pub fn max_buf_size(&self) -> usize {
+        // Hyper requires at least 8 KiB, the margin leaving room for the method, version and
+        // delimiters.
+        (self.max_uri_length + self.max_header_bytes + 1024).max(8192)
+    }
+}
+
+fn max_uri_length_default() -> usize {
+    16384
+}
+
+fn max_header_size_default() -> usize {
+    16384
+}
+
+fn max_header_bytes_default() -> usize {
+    65536
+}
+
+fn max_headers_default() -> usize {
+    100
+}
+
+/// `ApiDefinition`s read from the YAML files of a directory instead of being watched in the
+/// cluster.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ApiDirConfig {
+    /// Directory of the files, relative to the one of the runtime config file.
+    pub path: PathBuf,
+    /// Delay between each check of the files for changes.
+    #[serde(
+        default = "poll_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub poll_interval: Duration,
+}
+
+fn poll_interval_default() -> Duration {
+    Duration::from_secs(2)
+}
+
+/// Changes to the headers of all upstream responses, before the `response_transform` of their
+/// api, to hide details of the upstream servers.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseScrubbingConfig {
+    /// Headers removed, such as `server` or `x-powered-by`.
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+    #[serde(default)]
+    pub rewrite_headers: Vec<HeaderRewriteConfig>,
+}
+
+/// Replace the matches of `pattern` in each value of `header` by `replacement`, which may refer
+/// to groups as `$1`. Values left empty are removed.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRewriteConfig {
+    pub header: String,
+    pub pattern: String,
+    #[serde(default)]
+    pub replacement: String,
+}
+
+/// CORS headers of the responses to requests whose `Origin` is allowed.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Allowed origins, where `*` matches any characters as in `https://*.example.com`.
+    #[serde(default = "cors_any_default")]
+    pub allowed_origins: Vec<String>,
+    /// Methods allowed by preflights, `*` allowing the requested one.
+    #[serde(default = "cors_any_default")]
+    pub allowed_methods: Vec<String>,
+    /// Headers allowed by preflights, `*` allowing the requested ones.
+    #[serde(default = "cors_any_default")]
+    pub allowed_headers: Vec<String>,
+    #[serde(default = "expose_headers_default")]
+    pub expose_headers: Vec<String>,
+    /// Allow requests with cookies or HTTP authentication, the `Origin` being reflected.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Duration preflights are cached by browsers.
+    #[serde(
+        default = "cors_max_age_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub max_age: Duration,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: cors_any_default(),
+            allowed_methods: cors_any_default(),
+            allowed_headers: cors_any_default(),
+            expose_headers: expose_headers_default(),
+            allow_credentials: false,
+            max_age: cors_max_age_default(),
+        }
+    }
+}
+
+fn cors_any_default() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn expose_headers_default() -> Vec<String> {
+    vec!["location".to_string(), "retry-after".to_string()]
+}
+
+fn cors_max_age_default() -> Duration {
+    Duration::from_secs(86400)
+}
+
+fn max_status_code_default() -> u16 {
+    499
+}
+
+fn top_n_default() -> usize {
+    100
+}
+
+fn election_interval_default() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn export_interval_default() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn sampling_ratio_default() -> f64 {
+    1.0
+}
+
+fn perm_update_delay_default() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn max_fetch_error_count_default() -> u64 {
+    5
+}
+
+fn workers_default() -> usize {
+    1
+}
+
+fn shutdown_grace_period_default() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn service_name_default() -> String {
+    "gateway".to_string()
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub bind_to: String,
+    /// Address of the listener serving only the internal endpoints.
+    pub admin_bind_to: Option<String>,
+    /// HTTP/3 listener, advertised in the `Alt-Svc` header of the responses.
+    pub http3: Option<Http3Config>,
+    /// Label selector of the watched `ApiDefinition`s, all of them by default.
+    #[serde(default)]
+    pub crd_label: String,
+    /// Read the `ApiDefinition`s from files instead of watching them in the cluster.
+    pub api_dir: Option<ApiDirConfig>,
+    /// Inserted in metric names as `gateway_<metrics_prefix>_`, if not empty.
+    #[serde(default)]
+    pub metrics_prefix: String,
+    pub perm_uris: Vec<PermUri>,
+    #[serde(
+        default = "perm_update_delay_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub perm_update_delay: Duration,
+    pub auth_sources: Vec<AuthSource>,
+    #[serde(default = "max_fetch_error_count_default")]
+    pub max_fetch_error_count: u64,
+    #[serde(default)]
+    websocket_config: WebSocketConfigInternal,
+    #[serde(default)]
+    pub websocket_tls: WebsocketTlsConfig,
+    #[serde(default)]
+    pub tls_policy: TlsPolicyConfig,
+    /// Threads accepting and serving connections, each with its own listener bound with
+    /// `SO_REUSEPORT`, runtime and client. `1` serves them on the shared runtime.
+    #[serde(default = "workers_default")]
+    pub workers: usize,
+    /// Time given to websocket tunnels to close on shutdown.
+    #[serde(
+        default = "shutdown_grace_period_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    #[schemars(with = "DurationValue")]
+    pub shutdown_grace_period: Duration,
+    pub crds_namespaces: Option<Vec<String>>,
+    /// Custom buckets of histograms, indexed by metric name without the prefix (for example
+    /// `http_request_duration_seconds`).
+    #[serde(default)]
+    pub histogram_buckets: HashMap<String, Vec<f64>>,
+    pub tracing: Option<TracingConfig>,
+    pub otlp_metrics: Option<OtlpMetricsConfig>,
+    pub user_metrics: Option<UserMetricsConfig>,
+    pub error_reporting: Option<ErrorReportingConfig>,
+    pub change_webhook: Option<ChangeWebhookConfig>,
+    pub identity_signature: Option<IdentitySignatureConfig>,
+    pub anomaly_detection: Option<AnomalyDetectionConfig>,
+    pub permission_audit: Option<PermissionAuditConfig>,
+    #[serde(default)]
+    pub slos: Vec<SloConfig>,
+    #[serde(default)]
+    pub upstream_health: UpstreamHealthConfig,
+    #[serde(default)]
+    pub custom_metrics: CustomMetricsConfig,
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+    #[serde(default)]
+    pub audit_log: AuditLogConfig,
+    #[serde(default)]
+    pub body_capture: BodyCaptureConfig,
+    #[serde(default)]
+    pub log_redaction: LogRedactionConfig,
+    #[serde(default)]
+    pub metrics_auth: MetricsAuthConfig,
+    #[serde(default)]
+    pub admin_auth: AdminAuthConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub response_scrubbing: ResponseScrubbingConfig,
+    /// Proxies whose `X-Forwarded-For` and `X-Real-IP` headers are trusted to get client IPs.
+    #[serde(default)]
+    #[schemars(with = "Vec<String>")]
+    pub trusted_proxies: Vec<IpNet>,
+    #[serde(default)]
+    pub request_limits: RequestLimitsConfig,
+    #[serde(default)]
+    pub quotas: QuotasConfig,
+    pub tenancy: Option<TenancyConfig>,
+    /// Added to all responses, the `security_headers` of the `ApiDefinition`s taking precedence.
+    pub security_headers: Option<SecurityHeadersSpec>,
+    /// Check permissions of all APIs without enforcing them.
+    #[serde(default)]
+    pub dry_run_permissions: bool,
+    /// Endpoint answering the forward-auth requests of other proxies.
+    pub forward_auth: Option<ForwardAuthConfig>,
+    /// The settings as read, after includes, profile and environment overrides, without the
+    /// defaults of the missing ones.
+    #[serde(skip)]
+    pub settings: Value,
+}
+
+type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+
+static RUNTIME_CONFIG: LazyLock<RwLock<Arc<RuntimeConfig>>> =
+    LazyLock::new(|| match get_runtime_config(get_config_path()) {
+        Ok(x) => RwLock::new(Arc::new(x)),
+        Err(e) => {
+            error!("event='Runtime config is not valid: {e}'");
+            exit(1);
+        }
+    });
+
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set the path of the runtime config file given on the command line, before the config is read.
+pub fn set_config_path(path: PathBuf) {
+    // Only set once at startup.
+    let _ = CONFIG_PATH.set(path);
+}
+
+pub(crate) fn get_config_path() -> &'static Path {
+    CONFIG_PATH
+        .get()
+        .expect("The runtime config is read before its path is set")
+}
+
+/// Environment variable selecting the profile when none is given on the command line.
+const PROFILE_ENV: &str = "GATEWAY_PROFILE";
+
+static PROFILE: OnceLock<Option<String>> = OnceLock::new();
+
+/// Set the profile given on the command line, before the config is read.
+pub fn set_profile(profile: Option<String>) {
+    // Only set once at startup.
+    let _ = PROFILE.set(profile);
+}
+
+pub(crate) fn get_profile() -> Option<String> {
+    PROFILE
+        .get()
+        .cloned()
+        .flatten()
+        .or_else(|| env::var(PROFILE_ENV).ok())
+}
+
+/// Load and validate the runtime config file, without replacing the current config.
+pub fn load_runtime_config() -> Result<RuntimeConfig> {
+    get_runtime_config(get_config_path())
+}
+
+/// Get the current runtime config. Hold it rather than calling this again to read consistent
+/// settings across a reload.
+pub fn runtime_config() -> Arc<RuntimeConfig> {
+    RUNTIME_CONFIG.read().unwrap().clone()
+}
+
+/// Replace the current config with a reloaded one. Settings only read at startup are not applied
+/// until a restart.
+pub fn set_runtime_config(runtime_config: RuntimeConfig) {
+    *RUNTIME_CONFIG.write().unwrap() = Arc::new(runtime_config);
+}
+
+/// Prefix of the environment variables overriding settings of the runtime config file.
+const ENV_PREFIX: &str = "GATEWAY_";
+
+/// Override settings with the `GATEWAY_*` environment variables, whose values are parsed as YAML.
+/// Nested settings are separated by `__`, for example `GATEWAY_METRICS_AUTH__BEARER_TOKEN`
+/// overrides `metrics_auth.bearer_token`. Variables not naming a setting, such as the
+/// `GATEWAY_*_SERVICE_HOST` ones set by Kubernetes, are skipped.
+fn apply_env_overrides(
+    config: &mut Value,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<()> {
+    let schema = SchemaGenerator::default().root_schema_for::<RuntimeConfig>();
+    let fields = schema
+        .schema
+        .object
+        .map(|object| object.properties)
+        .unwrap_or_default();
+
+    for (name, raw_value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        if name == PROFILE_ENV {
+            continue;
+        }
+        let key = key.to_lowercase();
+        let field = key.split("__").next().unwrap_or_default();
+        if !fields.contains_key(field) {
+            warn!("event='Environment variable {name} skipped: `{field}` is not a setting'");
+            continue;
+        }
+        let value: Value = serde_yaml::from_str(&raw_value)
+            .map_err(|e| format!("Invalid value of environment variable `{name}`: {e}"))?;
+
+        let mut path = key.split("__").peekable();
+        let mut node = &mut *config;
+        while let Some(field) = path.next() {
+            let Value::Mapping(mapping) = node else {
+                return Err(format!(
+                    "Invalid environment variable `{name}`: `{field}` is not in a mapping"
+                )
+                .into());
+            };
+            if path.peek().is_none() {
+                mapping.insert(Value::from(field), value);
+                break;
+            }
+            node = mapping
+                .entry(Value::from(field))
+                .or_insert_with(|| Value::Mapping(Mapping::new()));
+        }
+
+        info!("event='Setting {key} overridden by {name}'");
+    }
+
+    Ok(())
+}
+
+/// Read the runtime config file as YAML, or as TOML or JSON depending on its extension.
+fn read_config_file(path: &Path) -> Result<Value> {
+    let value = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(&fs::read_to_string(path)?)?,
+        Some("json") => serde_json::from_reader(BufReader::new(File::open(path)?))?,
+        _ => serde_yaml::from_reader(BufReader::new(File::open(path)?))?,
+    };
+
+    Ok(value)
+}
+
+/// Settings which can be split into included files, whose lists are appended to the main ones.
+const INCLUDABLE_SETTINGS: [&str; 2] = ["perm_uris", "auth_sources"];
+
+/// Extensions of the files read from included directories.
+const CONFIG_EXTENSIONS: [&str; 4] = ["yaml", "yml", "toml", "json"];
+
+/// List the files of `include` entries, directories standing for their config files in name
+/// order. Relative paths are relative to the directory of the main config file.
+fn get_included_files(includes: Vec<PathBuf>, config_path: &Path) -> Result<Vec<PathBuf>> {
+    let config_dir = config_path.parent().unwrap_or(Path::new(""));
+    let mut files = Vec::new();
+
+    for include in includes {
+        let path = config_dir.join(include);
+        if !path.is_dir() {
+            files.push(path);
+            continue;
+        }
+
+        let mut dir_files = fs::read_dir(&path)
+            .map_err(|e| format!("Cannot read {}: {e}", path.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        dir_files.retain(|file| {
+            file.extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| CONFIG_EXTENSIONS.contains(&extension))
+        });
+        dir_files.sort();
+        files.extend(dir_files);
+    }
+
+    Ok(files)
+}
+
+/// Append the `perm_uris` and `auth_sources` of the files listed in `include` to those of the
+/// main config file.
+fn apply_includes(config: &mut Value, config_path: &Path) -> Result<()> {
+    let Value::Mapping(config) = config else {
+        return Ok(());
+    };
+    let Some(includes) = config.remove("include") else {
+        return Ok(());
+    };
+    let includes: Vec<PathBuf> =
+        serde_yaml::from_value(includes).map_err(|e| format!("Invalid `include`: {e}"))?;
+
+    for file in get_included_files(includes, config_path)? {
+        let included = read_config_file(&file)
+            .map_err(|e| format!("Invalid included file {}: {e}", file.display()))?;
+        let Value::Mapping(included) = included else {
+            return Err(format!("Invalid included file {}: not a mapping", file.display()).into());
+        };
+
+        for (key, value) in included {
+            let (Some(setting), Value::Sequence(items)) = (key.as_str().map(str::to_string), value)
+            else {
+                return Err(format!(
+                    "Invalid included file {}: settings must be lists",
+                    file.display()
+                )
+                .into());
+            };
+            if !INCLUDABLE_SETTINGS.contains(&setting.as_str()) {
+                return Err(format!(
+                    "Invalid included file {}: only {INCLUDABLE_SETTINGS:?} can be included",
+                    file.display()
+                )
+                .into());
+            }
+
+            match config
+                .entry(key)
+                .or_insert_with(|| Value::Sequence(Vec::new()))
+            {
+                Value::Sequence(main_items) => main_items.extend(items),
+                _ => return Err(format!("Invalid `{setting}`: it must be a list").into()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// JSON Schema of the runtime config file, including the `include` and `profiles` settings which
+/// are resolved before it is deserialized.
+pub fn config_schema() -> RootSchema {
+    let mut generator = SchemaGenerator::default();
+    let mut schema = generator.root_schema_for::<RuntimeConfig>();
+    let extra_properties = [
+        ("include", generator.subschema_for::<Vec<PathBuf>>()),
+        (
+            "profiles",
+            generator.subschema_for::<HashMap<String, serde_json::Value>>(),
+        ),
+    ];
+    schema
+        .schema
+        .object()
+        .properties
+        .extend(extra_properties.map(|(name, schema): (&str, Schema)| (name.to_string(), schema)));
+
+    schema
+}
+
+/// Merge `overrides` into `config`, mappings being merged key by key and other values replaced.
+fn merge_values(config: &mut Value, overrides: Value) {
+    match (config, overrides) {
+        (Value::Mapping(config), Value::Mapping(overrides)) => {
+            for (key, value) in overrides {
+                match config.get_mut(&key) {
+                    Some(current) => merge_values(current, value),
+                    None => {
+                        config.insert(key, value);
+                    }
+                }
+            }
+        }
+        (config, overrides) => *config = overrides,
+    }
+}
+
+/// Remove the `profiles` of the runtime config and merge the selected one into it.
+fn apply_profile(config: &mut Value, profile: Option<&str>) -> Result<()> {
+    let profiles = match config {
+        Value::Mapping(mapping) => mapping.remove("profiles"),
+        _ => None,
+    };
+    let Some(profile) = profile else {
+        return Ok(());
+    };
+
+    let overrides = match profiles {
+        Some(Value::Mapping(mut profiles)) => profiles.remove(profile),
+        Some(_) => return Err("Invalid `profiles`: it must be a mapping".into()),
+        None => None,
+    };
+    let Some(overrides) = overrides else {
+        return Err(format!("Unknown profile `{profile}`").into());
+    };
+    merge_values(config, overrides);
+
+    info!("event='Applied profile {profile}'");
+
+    Ok(())
+}
+
+fn get_runtime_config<P: AsRef<Path>>(path: P) -> Result<RuntimeConfig> {
+    let mut value = read_config_file(path.as_ref())?;
+    apply_includes(&mut value, path.as_ref())?;
+    apply_profile(&mut value, get_profile().as_deref())?;
+    apply_env_overrides(&mut value, env::vars())?;
+    let mut runtime_config: RuntimeConfig = serde_yaml::from_value(value.clone())?;
+    runtime_config.settings = value;
+
+    if let Some(api_dir) = &mut runtime_config.api_dir {
+        let config_dir = path.as_ref().parent().unwrap_or(Path::new(""));
+        api_dir.path = config_dir.join(&api_dir.path);
+    }
+
+    if runtime_config.bind_to.parse::<SocketAddr>().is_err() {
+        return Err(format!(
+            "Invalid `bind_to`: `{}` is not a socket address",
+            runtime_config.bind_to
+        )
+        .into());
+    }
+    if let Some(http3) = &mut runtime_config.http3 {
+        if http3.bind_to.parse::<SocketAddr>().is_err() {
+            return Err(format!(
+                "Invalid `http3.bind_to`: `{}` is not a socket address",
+                http3.bind_to
+            )
+            .into());
+        }
+        if http3.alt_svc_port == Some(0) {
+            return Err("Invalid `http3.alt_svc_port`: it must not be 0".into());
+        }
+        let config_dir = path.as_ref().parent().unwrap_or(Path::new(""));
+        http3.cert_file = config_dir.join(&http3.cert_file);
+        http3.key_file = config_dir.join(&http3.key_file);
+    }
+    if runtime_config.workers == 0 {
+        return Err("Invalid `workers`: it must be at least 1".into());
+    }
+    if let Some(admin_bind_to) = &runtime_config.admin_bind_to {
+        if admin_bind_to.parse::<SocketAddr>().is_err() {
+            return Err(format!(
+                "Invalid `admin_bind_to`: `{admin_bind_to}` is not a socket address"
+            )
+            .into());
+        }
+    }
+
+    for perm_uri in &runtime_config.perm_uris {
+        if perm_uri.uri.scheme_str() != Some("http") || perm_uri.uri.host().is_none() {
+            return Err(format!(
+                "Invalid `perm_uris`: `{}` must be an absolute `http://` URI",
+                perm_uri.uri
+            )
+            .into());
+        }
+    }
+
+    if runtime_config.websocket_config.max_write_buffer_size
+        <= runtime_config.websocket_config.write_buffer_size
+    {
+        runtime_config.websocket_config.max_write_buffer_size = usize::MAX;
+
+        log::error!(concat!(
+            "Invalid configuration value for `max_write_buffer_size` which should be at least ",
+            "`write_buffer_size` + 1. Its value is ignored.",
+        ))
+    }
+
+    for (name, buckets) in &runtime_config.histogram_buckets {
+        if buckets.is_empty() || buckets.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(format!(
+                "Invalid `histogram_buckets` for `{name}`: buckets must be non-empty and in \
+                 strictly increasing order"
+            )
+            .into());
+        }
+    }
+
+    if let Some(user_metrics) = &runtime_config.user_metrics {
+        if user_metrics.top_n == 0 {
+            return Err("Invalid `user_metrics.top_n`: it must be positive".into());
+        }
+    }
+
+    if runtime_config.perm_update_delay.is_zero() {
+        return Err("Invalid `perm_update_delay`: it must be positive".into());
+    }
+
+    if runtime_config.custom_metrics.max_series == 0 {
+        return Err("Invalid `custom_metrics.max_series`: it must be positive".into());
+    }
+
+    for (index, slo) in runtime_config.slos.iter().enumerate() {
+        if runtime_config.slos[..index]
+            .iter()
+            .any(|other| other.name == slo.name)
+        {
+            return Err(format!("Invalid `slos`: duplicated SLO `{}`", slo.name).into());
+        }
+        if slo
+            .latency_threshold
+            .is_some_and(|threshold| threshold.is_zero())
+        {
+            return Err(format!(
+                "Invalid `latency_threshold` for SLO `{}`: it must be positive",
+                slo.name
+            )
+            .into());
+        }
+    }
+
+    let upstream_health = &runtime_config.upstream_health;
+    if upstream_health.window.is_zero()
+        || !(0.0..=1.0).contains(&upstream_health.degraded_ratio)
+        || !(upstream_health.degraded_ratio..=1.0).contains(&upstream_health.down_ratio)
+    {
+        return Err(
+            "Invalid `upstream_health`: `window` must be positive and ratios must satisfy \
+             0 <= `degraded_ratio` <= `down_ratio` <= 1"
+                .into(),
+        );
+    }
+
+    let request_limits = &runtime_config.request_limits;
+    if request_limits.max_uri_length == 0
+        || request_limits.max_header_size == 0
+        || request_limits.max_header_bytes == 0
+        || request_limits.max_headers == 0
+    {
+        return Err("Invalid `request_limits`: limits must be positive".into());
+    }
+
+    for (name, quota) in runtime_config
+        .quotas
+        .global
+        .iter()
+        .map(|quota| ("global", quota))
+        .chain(
+            runtime_config
+                .quotas
+                .apps
+                .iter()
+                .map(|(app, quota)| (app.as_str(), quota)),
+        )
+    {
+        if quota.window.is_zero() {
+            return Err(format!("Invalid `quotas` of `{name}`: `window` must be positive").into());
+        }
+    }
+    if let Some(tenancy) = &runtime_config.tenancy {
+        check_claim_template(&tenancy.tenant)
+            .map_err(|e| format!("Invalid `tenancy.tenant`: {e}"))?;
+        let limits = tenancy
+            .tenants
+            .values()
+            .map(|limits| (limits.quota.as_ref(), limits.max_concurrent_requests))
+            .chain([(tenancy.quota.as_ref(), tenancy.max_concurrent_requests)]);
+        for (quota, max_concurrent_requests) in limits {
+            if quota.is_some_and(|quota| quota.window.is_zero())
+                || max_concurrent_requests == Some(0)
+            {
+                return Err(
+                    "Invalid `tenancy`: `window` and `max_concurrent_requests` must be \
+                    positive"
+                        .into(),
+                );
+            }
+        }
+    }
+    let scrubbing = &runtime_config.response_scrubbing;
+    let scrubbed_headers = scrubbing.remove_headers.iter().chain(
+        scrubbing
+            .rewrite_headers
+            .iter()
+            .map(|rewrite| &rewrite.header),
+    );
+    for name in scrubbed_headers {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(
+                format!("Invalid `response_scrubbing`: {name} isn't a valid header name").into(),
+            );
+        }
+    }
+    for rewrite in &scrubbing.rewrite_headers {
+        if let Err(e) = Regex::new(&rewrite.pattern) {
+            return Err(format!(
+                "Invalid `response_scrubbing` pattern of {}: {e}",
+                rewrite.header
+            )
+            .into());
+        }
+    }
+
+    if let Err(e) = client_config_builder(&runtime_config.tls_policy, RootCertStore::empty()) {
+        return Err(format!("Invalid `tls_policy`: {e}").into());
+    }
+
+    if let Some(anomaly_detection) = &runtime_config.anomaly_detection {
+        if anomaly_detection.window.is_zero()
+            || anomaly_detection.block_duration.is_zero()
+            || !(anomaly_detection.max_error_ratio > 0.0
+                && anomaly_detection.max_error_ratio <= 1.0)
+        {
+            return Err(
+                "Invalid `anomaly_detection`: `window` and `block_duration` must be \
+                positive and `max_error_ratio` between 0 and 1"
+                    .into(),
+            );
+        }
+    }
+
+    if runtime_config
+        .identity_signature
+        .as_ref()
+        .is_some_and(|identity_signature| identity_signature.key.is_empty())
+    {
+        return Err("Invalid `identity_signature`: `key` must not be empty".into());
+    }
+
+    if let Some(forward_auth) = &runtime_config.forward_auth {
+        if !forward_auth.path.starts_with('/') || forward_auth.path.contains('?') {
+            return Err(format!(
+                "Invalid `forward_auth.path`: `{}` is not a path",
+                forward_auth.path
+            )
+            .into());
+        }
+    }
+
+    if let Some(security_headers) = &runtime_config.security_headers {
+        security_headers
+            .check()
+            .map_err(|e| format!("Invalid `security_headers`: {e}"))?;
+    }
+
+    if let Some(store) = &runtime_config.quotas.store {
+        if !Url::parse(&store.redis_url).is_ok_and(|url| url.scheme() == "redis") {
+            return Err("Invalid `quotas.store.redis_url`: it must be a `redis://` URL".into());
+        }
+    }
+
+    if runtime_config.metrics_auth.admin_listener_only && runtime_config.admin_bind_to.is_none() {
+        return Err(
+            "Invalid `metrics_auth.admin_listener_only`: it requires `admin_bind_to`".into(),
+        );
+    }
+
+    for field in runtime_config.access_log.fields.iter().flatten() {
+        if !ACCESS_LOG_FIELDS.contains(&field.as_str()) {
+            return Err(format!(
+                "Invalid `access_log.fields`: unknown field `{field}`, expected one of {ACCESS_LOG_FIELDS:?}"
+            )
+            .into());
+        }
+    }
+
+    for auth_source in &runtime_config.auth_sources {
+        let key_count = [
+            auth_source.public_key.is_some(),
+            auth_source.public_key_file.is_some(),
+            auth_source.public_key_secret.is_some(),
+        ]
+        .into_iter()
+        .filter(|is_set| *is_set)
+        .count();
+        if key_count != 1 {
+            return Err(format!(
+                "Invalid auth source `{}`: exactly one of `public_key`, `public_key_file` and \
+                 `public_key_secret` must be set",
+                auth_source.name
+            )
+            .into());
+        }
+
+        // Keys from files and Secrets are checked once fetched.
+        if let Some(public_key) = &auth_source.public_key {
+            if let Err(e) = DecodingKey::from_rsa_pem(public_key.as_bytes()) {
+                return Err(format!(
+                    "Invalid `public_key` for auth source `{}`: {e}",
+                    auth_source.name
+                )
+                .into());
+            }
+        }
+    }
+
+    if let Some(tracing) = &runtime_config.tracing {
+        if !(0.0..=1.0).contains(&tracing.sampling_ratio) {
+            return Err("Invalid `tracing.sampling_ratio`: it must be between 0 and 1".into());
+        }
+    }
+
+    Ok(runtime_config)
+}
+
+impl RuntimeConfig {
+    pub fn get_websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig::default()
+            .write_buffer_size(self.websocket_config.write_buffer_size)
+            .max_write_buffer_size(self.websocket_config.max_write_buffer_size)
+            .max_message_size(Some(self.websocket_config.max_message_size))
+            .max_frame_size(Some(self.websocket_config.max_frame_size))
+            .accept_unmasked_frames(self.websocket_config.accept_unmasked_frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(vars: &[(&str, &str)]) -> Result<Value> {
+        let mut config: Value = serde_yaml::from_str("bind_to: 0.0.0.0:8080").unwrap();
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        apply_env_overrides(&mut config, vars)?;
+        Ok(config)
+    }
+
+    #[test]
+    fn env_overrides_set_known_settings() {
+        let config = apply(&[
+            ("GATEWAY_BIND_TO", "127.0.0.1:9090"),
+            ("GATEWAY_METRICS_AUTH__BEARER_TOKEN", "secret"),
+            ("HOME", "/root"),
+        ])
+        .unwrap();
+
+        assert_eq!(config["bind_to"], "127.0.0.1:9090");
+        assert_eq!(config["metrics_auth"]["bearer_token"], "secret");
+        assert!(config.get("home").is_none());
+    }
+
+    #[test]
+    fn env_overrides_skip_unknown_variables() {
+        let config = apply(&[
+            ("GATEWAY_ADMIN_SERVICE_HOST", "10.0.0.1"),
+            ("GATEWAY_ADMIN_PORT", "tcp://10.0.0.1:8080"),
+            ("GATEWAY_ADMIN_SERVICE_PORT__X", "8080"),
+        ])
+        .unwrap();
+
+        let expected: Value = serde_yaml::from_str("bind_to: 0.0.0.0:8080").unwrap();
+        assert_eq!(config, expected);
+    }
+}
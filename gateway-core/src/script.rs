@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use anyhow::{anyhow, bail, Result};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method, StatusCode};
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST, FLOAT, INT};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::{register_counter, register_histogram};
+
+/// A Rhai script run on each request of an API once its permission is checked, which can rewrite
+/// its forwarded path and headers or answer it itself.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct ScriptSpec {
+    pub source: String,
+    /// Operations the script can run for a request, going over answering it with `500`.
+    #[serde(default = "max_operations_default")]
+    pub max_operations: u64,
+}
+
+fn max_operations_default() -> u64 {
+    100_000
+}
+
+/// Names and values of the labels of a metric updated by a script, sorted by name.
+fn split_labels(labels: &Map) -> (Vec<&str>, Vec<String>) {
+    labels
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.to_string()))
+        .unzip()
+}
+
+/// Increment the counter `name` of the series of `labels`, registering it on first use.
+fn counter_inc(name: &str, labels: Map) -> Result<(), Box<EvalAltResult>> {
+    let (label_names, label_values) = split_labels(&labels);
+    let label_values: Vec<&str> = label_values.iter().map(String::as_str).collect();
+    register_counter(name, "Counter of a script.", &label_names)
+        .and_then(|counter| counter.inc(&label_values))
+        .map_err(|e| e.to_string().into())
+}
+
+/// Observe `value` in the histogram `name` for the series of `labels`, registering it on first
+/// use.
+fn histogram_observe(name: &str, labels: Map, value: FLOAT) -> Result<(), Box<EvalAltResult>> {
+    let (label_names, label_values) = split_labels(&labels);
+    let label_values: Vec<&str> = label_values.iter().map(String::as_str).collect();
+    register_histogram(name, "Histogram of a script.", &label_names, None)
+        .and_then(|histogram| histogram.observe(&label_values, value))
+        .map_err(|e| e.to_string().into())
+}
+
+struct Script {
+    spec: ScriptSpec,
+    engine: Engine,
+    ast: AST,
+}
+
+/// Compiled scripts by app, compiled again when their spec changes.
+static SCRIPTS: LazyLock<RwLock<HashMap<String, Arc<Script>>>> = LazyLock::new(Default::default);
+
+/// Build an engine running at most `max_operations` operations, with bounded sizes and without
+/// `eval`.
+pub(crate) fn new_engine(max_operations: u64) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(max_operations)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(1024 * 1024)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .disable_symbol("eval");
+    engine
+}
+
+fn compile(spec: &ScriptSpec) -> Result<Script> {
+    let mut engine = new_engine(spec.max_operations);
+    engine.on_print(|text| info!("event='Script: {text}'"));
+    engine.on_debug(|text, _, _| debug!("event='Script: {text}'"));
+    engine
+        .register_fn("counter_inc", counter_inc)
+        .register_fn("histogram_observe", histogram_observe)
+        .register_fn(
+            "histogram_observe",
+            |name: &str, labels: Map, value: INT| histogram_observe(name, labels, value as FLOAT),
+        );
+
+    let ast = engine.compile(&spec.source)?;
+    Ok(Script {
+        spec: spec.clone(),
+        engine,
+        ast,
+    })
+}
+
+/// Check that the source of `spec` compiles.
+pub fn check_script(spec: &ScriptSpec) -> Result<()> {
+    compile(spec).map(|_| ())
+}
+
+fn get_script(app: &str, spec: &ScriptSpec) -> Result<Arc<Script>> {
+    if let Some(script) = SCRIPTS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(app)
+        .filter(|script| script.spec == *spec)
+    {
+        return Ok(script.clone());
+    }
+
+    let script = Arc::new(compile(spec)?);
+    SCRIPTS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(app.to_string(), script.clone());
+    Ok(script)
+}
+
+/// Outcome of a script for a single request.
+pub enum ScriptAction {
+    /// Forward the request to this path, with its query.
+    Forward(String),
+    /// Answer the request with this status and body.
+    Respond(StatusCode, String),
+}
+
+/// Run the script of `app`, with a `request` map holding the `method`, the forwarded `path` with
+/// its query, the `token_id` of the `user` and the `headers` whose values are valid strings.
+/// Changes to `path` and `headers` are kept, and returning a map with a `status` and an optional
+/// `body` answers the request.
+pub fn run_script(
+    app: &str,
+    spec: &ScriptSpec,
+    method: &Method,
+    path: &str,
+    user: &str,
+    headers: &mut HeaderMap,
+) -> Result<ScriptAction> {
+    let script = get_script(app, spec)?;
+
+    let exposed: HashMap<String, String> = headers
+        .keys()
+        .filter_map(|name| {
+            let value = headers.get(name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    let mut request = Map::new();
+    request.insert("method".into(), method.as_str().into());
+    request.insert("path".into(), path.into());
+    request.insert("user".into(), user.into());
+    request.insert(
+        "headers".into(),
+        Dynamic::from_map(
+            exposed
+                .iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+        ),
+    );
+
+    let mut scope = Scope::new();
+    scope.push("request", request);
+    let result: Dynamic = script
+        .engine
+        .eval_ast_with_scope(&mut scope, &script.ast)
+        .map_err(|e| anyhow!("{e}"))?;
+
+    if let Some(response) = result.try_cast::<Map>() {
+        let status = response
+            .get("status")
+            .and_then(|status| status.as_int().ok())
+            .ok_or_else(|| anyhow!("The returned response has no `status`"))?;
+        let status_code = u16::try_from(status)
+            .ok()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .ok_or_else(|| anyhow!("The returned status {status} is not valid"))?;
+        let body = match response.get("body") {
+            Some(body) => body.to_string(),
+            None => String::new(),
+        };
+        return Ok(ScriptAction::Respond(status_code, body));
+    }
+
+    let request = scope
+        .get_value::<Map>("request")
+        .ok_or_else(|| anyhow!("`request` is not a map anymore"))?;
+    let Some(path) = request
+        .get("path")
+        .and_then(|path| path.clone().into_string().ok())
+    else {
+        bail!("`request.path` is not a string");
+    };
+    let Some(new_headers) = request
+        .get("headers")
+        .and_then(|headers| headers.clone().try_cast::<Map>())
+    else {
+        bail!("`request.headers` is not a map");
+    };
+
+    for name in exposed.keys() {
+        if !new_headers.contains_key(name.as_str()) {
+            headers.remove(name);
+        }
+    }
+    for (name, value) in new_headers {
+        let Ok(value) = value.into_string() else {
+            bail!("The value of header {name} is not a string");
+        };
+        if exposed.get(name.as_str()) == Some(&value) {
+            continue;
+        }
+        let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) else {
+            bail!("Header {name}: {value} is not valid");
+        };
+        headers.insert(name, value);
+    }
+
+    Ok(ScriptAction::Forward(path))
+}
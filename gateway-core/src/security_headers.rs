@@ -0,0 +1,90 @@
+use hyper::header::{
+    HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+    X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use hyper::HeaderMap;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Security headers added to responses, such as `frame_options: DENY`. Headers set by the
+/// upstream server are kept unless `override_upstream` is set.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SecurityHeadersSpec {
+    /// Only sent by browsers over HTTPS, such as `max-age=31536000; includeSubDomains`.
+    pub strict_transport_security: Option<String>,
+    /// `X-Content-Type-Options`, such as `nosniff`.
+    pub content_type_options: Option<String>,
+    /// `X-Frame-Options`, such as `DENY`.
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub content_security_policy: Option<String>,
+    /// Replace the headers set by the upstream server, defaults to false.
+    pub override_upstream: Option<bool>,
+}
+
+/// Marks the responses whose security headers were injected with the policy of their api.
+#[derive(Clone, Copy)]
+pub(crate) struct SecurityHeadersInjected;
+
+impl SecurityHeadersSpec {
+    fn headers(&self) -> [(HeaderName, &Option<String>); 5] {
+        [
+            (STRICT_TRANSPORT_SECURITY, &self.strict_transport_security),
+            (X_CONTENT_TYPE_OPTIONS, &self.content_type_options),
+            (X_FRAME_OPTIONS, &self.frame_options),
+            (REFERRER_POLICY, &self.referrer_policy),
+            (CONTENT_SECURITY_POLICY, &self.content_security_policy),
+        ]
+    }
+
+    /// Check that the values are valid header values.
+    pub fn check(&self) -> Result<(), String> {
+        for (name, value) in self.headers() {
+            if let Some(value) = value {
+                if HeaderValue::from_str(value).is_err() {
+                    return Err(format!("{value} isn't a valid {name} header value"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Policy of an api, whose settings take precedence over the global ones.
+    pub(crate) fn merge(global: Option<&Self>, api: &Self) -> Self {
+        let global = global.cloned().unwrap_or_default();
+        Self {
+            strict_transport_security: api
+                .strict_transport_security
+                .clone()
+                .or(global.strict_transport_security),
+            content_type_options: api
+                .content_type_options
+                .clone()
+                .or(global.content_type_options),
+            frame_options: api.frame_options.clone().or(global.frame_options),
+            referrer_policy: api.referrer_policy.clone().or(global.referrer_policy),
+            content_security_policy: api
+                .content_security_policy
+                .clone()
+                .or(global.content_security_policy),
+            override_upstream: api.override_upstream.or(global.override_upstream),
+        }
+    }
+
+    /// Add the headers to a response, an empty value disabling its header.
+    pub(crate) fn inject(&self, headers: &mut HeaderMap) {
+        let override_upstream = self.override_upstream.unwrap_or_default();
+        for (name, value) in self.headers() {
+            let Some(value) = value.as_deref().filter(|value| !value.is_empty()) else {
+                continue;
+            };
+            if headers.contains_key(&name) && !override_upstream {
+                continue;
+            }
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
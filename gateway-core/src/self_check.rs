@@ -0,0 +1,96 @@
+use std::future::Future;
+use std::process::exit;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::time::timeout;
+
+use crate::auth::{load_token_sources, set_token_sources};
+use crate::fetch_crd::check_api_access;
+use crate::fetch_dir::read_api_dir;
+use crate::permission::check_perm_uri;
+use crate::runtime_config::runtime_config;
+use crate::websocket::init_websocket_tls;
+
+/// Exit codes of the failed checks, the runtime config file being invalid exiting with 1. The
+/// `api_dir` check shares the code of the `kubernetes` one, which it replaces.
+const AUTH_SOURCES_EXIT_CODE: i32 = 2;
+const WEBSOCKET_TLS_EXIT_CODE: i32 = 3;
+const KUBERNETES_EXIT_CODE: i32 = 4;
+const PERM_URIS_EXIT_CODE: i32 = 5;
+
+/// Time given to each check reaching a remote service.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct CheckResult {
+    name: String,
+    exit_code: i32,
+    result: Result<()>,
+}
+
+async fn with_timeout(check: impl Future<Output = Result<()>>) -> Result<()> {
+    timeout(CHECK_TIMEOUT, check)
+        .await
+        .map_err(|_| anyhow!("timed out after {CHECK_TIMEOUT:?}"))?
+}
+
+/// Check the dependencies of the gateway before serving: the public keys of the auth sources, the
+/// CAs of `websocket_tls`, the access to the `ApiDefinition`s (or their directory) and each perm
+/// URI. Every check is run and reported, then the process exits with the code of the first failed
+/// check, if any.
+pub async fn run_self_check() {
+    let runtime_config = runtime_config();
+    let mut checks = Vec::new();
+
+    let result = load_token_sources(&runtime_config.auth_sources)
+        .await
+        .map(set_token_sources);
+    checks.push(CheckResult {
+        name: "auth_sources".to_string(),
+        exit_code: AUTH_SOURCES_EXIT_CODE,
+        result,
+    });
+
+    checks.push(CheckResult {
+        name: "websocket_tls".to_string(),
+        exit_code: WEBSOCKET_TLS_EXIT_CODE,
+        result: init_websocket_tls(),
+    });
+
+    checks.push(match &runtime_config.api_dir {
+        Some(api_dir) => CheckResult {
+            name: "api_dir".to_string(),
+            exit_code: KUBERNETES_EXIT_CODE,
+            result: read_api_dir(&api_dir.path).map(|_| ()),
+        },
+        None => CheckResult {
+            name: "kubernetes".to_string(),
+            exit_code: KUBERNETES_EXIT_CODE,
+            result: with_timeout(check_api_access(runtime_config.crds_namespaces.as_deref())).await,
+        },
+    });
+
+    for perm_uri in &runtime_config.perm_uris {
+        checks.push(CheckResult {
+            name: format!("perm_uri {}", perm_uri.uri),
+            exit_code: PERM_URIS_EXIT_CODE,
+            result: with_timeout(check_perm_uri(perm_uri)).await,
+        });
+    }
+
+    println!("Self-check:");
+    for check in &checks {
+        match &check.result {
+            Ok(()) => println!("  ok    {}", check.name),
+            Err(e) => println!("  FAIL  {}: {e}", check.name),
+        }
+    }
+
+    if let Some(failed) = checks.iter().find(|check| check.result.is_err()) {
+        error!(
+            "event='Self-check failed on {}, exiting with {}'",
+            failed.name, failed.exit_code
+        );
+        exit(failed.exit_code);
+    }
+}
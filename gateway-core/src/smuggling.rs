@@ -0,0 +1,827 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{ready, Context, Poll};
+
+use hyper::header::{
+    HeaderName, CONNECTION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER,
+    TRANSFER_ENCODING, UPGRADE,
+};
+use hyper::HeaderMap;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::metrics::commit_smuggling_rejection;
+
+/// Headers which only apply to the connection between the client and the gateway.
+pub(crate) const HOP_BY_HOP_HEADERS: [HeaderName; 9] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    HeaderName::from_static("proxy-connection"),
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// Longest chunk size line scanned, longer ones being left to hyper.
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// Headers listed by `Connection`, which only apply to the connection too.
+pub(crate) fn get_connection_headers(headers: &HeaderMap) -> Vec<HeaderName> {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect()
+}
+
+/// Remove the hop-by-hop headers of a request forwarded upstream, whose body is framed again by
+/// the client of the gateway. Must be called before adding the identity headers, which could be
+/// listed by `Connection`.
+pub(crate) fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    for name in get_connection_headers(headers) {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Way of framing a request which could be understood differently by the gateway and the
+/// upstream server.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Violation {
+    /// Both `Transfer-Encoding` and `Content-Length`.
+    AmbiguousLength,
+    /// Several different or invalid `Content-Length`s.
+    InvalidContentLength,
+    /// `Transfer-Encoding` not ending with `chunked`, or in an HTTP/1.0 request.
+    InvalidTransferEncoding,
+    /// A header line continuing the previous one.
+    ObsFold,
+    /// A chunk extension which is neither `;name` nor `;name=value`.
+    InvalidChunkExtension,
+}
+
+impl Violation {
+    /// Label of the violation in `http_smuggling_rejections_total`.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Violation::AmbiguousLength => "ambiguous_length",
+            Violation::InvalidContentLength => "content_length",
+            Violation::InvalidTransferEncoding => "transfer_encoding",
+            Violation::ObsFold => "obs_fold",
+            Violation::InvalidChunkExtension => "chunk_extension",
+        }
+    }
+}
+
+/// State of a connection shared between its `FramingGuard` and the service answering its
+/// requests.
+#[derive(Default)]
+pub(crate) struct FramingState {
+    violation: OnceLock<Violation>,
+    upgraded: AtomicBool,
+}
+
+impl FramingState {
+    /// First violation of the connection, whose requests are then all rejected.
+    pub(crate) fn violation(&self) -> Option<Violation> {
+        self.violation.get().copied()
+    }
+
+    /// Stop scanning the connection, which now carries another protocol.
+    pub(crate) fn set_upgraded(&self) {
+        self.upgraded.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Part of the request stream expected next.
+enum State {
+    Head(Vec<u8>),
+    Body(u64),
+    ChunkLine(Vec<u8>),
+    ChunkData(u64),
+    /// Line ending after the data of a chunk.
+    ChunkEnd,
+    Trailers(Vec<u8>),
+    /// Not scanned anymore, after a violation or something left to hyper.
+    Done,
+}
+
+/// Position after the empty line ending a request head or trailers, if any. Bare `\n` line
+/// endings are accepted as hyper does.
+fn find_block_end(buf: &[u8], from: usize) -> Option<usize> {
+    (from..buf.len()).find_map(|index| match &buf[index..] {
+        [b'\n', b'\n', ..] => Some(index + 2),
+        [b'\n', b'\r', b'\n', ..] => Some(index + 3),
+        _ => None,
+    })
+}
+
+/// Parse `Content-Length` values, which may be lists of the same length.
+fn parse_content_length(values: &[&[u8]]) -> Option<u64> {
+    let mut length = None;
+    for value in values {
+        for part in value.split(|&b| b == b',') {
+            let part = part.trim_ascii();
+            if part.is_empty() || !part.iter().all(u8::is_ascii_digit) {
+                return None;
+            }
+            let part = std::str::from_utf8(part).ok()?.parse().ok()?;
+            if length.is_some_and(|length| length != part) {
+                return None;
+            }
+            length = Some(part);
+        }
+    }
+
+    length
+}
+
+/// Check the framing of a request head, returning how its body is sent.
+fn check_head(head: &[u8]) -> Result<State, Violation> {
+    let mut lines = head
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+    let is_http_10 = lines
+        .next()
+        .is_some_and(|request_line| request_line.ends_with(b"HTTP/1.0"));
+
+    let mut transfer_encodings = Vec::new();
+    let mut content_lengths = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        if line[0] == b' ' || line[0] == b'\t' {
+            return Err(Violation::ObsFold);
+        }
+        // Invalid header lines are rejected by hyper.
+        let Some(colon) = line.iter().position(|&b| b == b':') else {
+            continue;
+        };
+        let (name, value) = (&line[..colon], line[colon + 1..].trim_ascii());
+        if name.eq_ignore_ascii_case(b"transfer-encoding") {
+            transfer_encodings.push(value);
+        } else if name.eq_ignore_ascii_case(b"content-length") {
+            content_lengths.push(value);
+        }
+    }
+
+    if !transfer_encodings.is_empty() {
+        if !content_lengths.is_empty() {
+            return Err(Violation::AmbiguousLength);
+        }
+        let is_chunked = transfer_encodings
+            .last()
+            .and_then(|value| value.rsplit(|&b| b == b',').next())
+            .is_some_and(|coding| coding.trim_ascii().eq_ignore_ascii_case(b"chunked"));
+        if is_http_10 || !is_chunked {
+            return Err(Violation::InvalidTransferEncoding);
+        }
+        return Ok(State::ChunkLine(Vec::new()));
+    }
+    if content_lengths.is_empty() {
+        return Ok(State::Head(Vec::new()));
+    }
+    match parse_content_length(&content_lengths) {
+        Some(0) => Ok(State::Head(Vec::new())),
+        Some(length) => Ok(State::Body(length)),
+        None => Err(Violation::InvalidContentLength),
+    }
+}
+
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn skip_whitespace(line: &[u8], mut index: usize) -> usize {
+    while line.get(index).is_some_and(|&b| b == b' ' || b == b'\t') {
+        index += 1;
+    }
+    index
+}
+
+fn skip_token(line: &[u8], mut index: usize) -> Result<usize, Violation> {
+    let start = index;
+    while line.get(index).copied().is_some_and(is_tchar) {
+        index += 1;
+    }
+    if index == start {
+        return Err(Violation::InvalidChunkExtension);
+    }
+    Ok(index)
+}
+
+/// Character allowed in a quoted string, escaped or not.
+fn is_quoted_char(b: u8) -> bool {
+    b == b'\t' || (b >= b' ' && b != 0x7f)
+}
+
+/// Skip a quoted string starting at `index`, escaped characters included.
+fn skip_quoted_string(line: &[u8], mut index: usize) -> Result<usize, Violation> {
+    index += 1;
+    loop {
+        match line.get(index) {
+            Some(b'"') => return Ok(index + 1),
+            Some(b'\\') if line.get(index + 1).copied().is_some_and(is_quoted_char) => index += 2,
+            Some(&b) if b != b'\\' && is_quoted_char(b) => index += 1,
+            _ => return Err(Violation::InvalidChunkExtension),
+        }
+    }
+}
+
+/// Check the extensions following the size of a chunk, such as `;name=value;flag`.
+fn check_chunk_extensions(line: &[u8]) -> Result<(), Violation> {
+    let mut index = skip_whitespace(line, 0);
+    while index < line.len() {
+        if line[index] != b';' {
+            return Err(Violation::InvalidChunkExtension);
+        }
+        index = skip_token(line, skip_whitespace(line, index + 1))?;
+        index = skip_whitespace(line, index);
+        if line.get(index) == Some(&b'=') {
+            index = skip_whitespace(line, index + 1);
+            index = match line.get(index) {
+                Some(b'"') => skip_quoted_string(line, index)?,
+                _ => skip_token(line, index)?,
+            };
+            index = skip_whitespace(line, index);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check a chunk size line, returning the size of the chunk, or `None` if hyper should reject it.
+fn check_chunk_line(line: &[u8]) -> Result<Option<u64>, Violation> {
+    let digits = line.iter().take_while(|b| b.is_ascii_hexdigit()).count();
+    if digits == 0 || digits > 16 {
+        return Ok(None);
+    }
+    check_chunk_extensions(&line[digits..])?;
+
+    let size = std::str::from_utf8(&line[..digits])
+        .ok()
+        .and_then(|digits| u64::from_str_radix(digits, 16).ok());
+    Ok(size)
+}
+
+/// Append `bytes` to a request head or trailers, returning how many of them it took once it is
+/// complete.
+fn read_block(buf: &mut Vec<u8>, bytes: &[u8]) -> Option<usize> {
+    let previous_len = buf.len();
+    buf.extend_from_slice(bytes);
+    // Trailers may be empty, ending with their first line.
+    let end = if buf.starts_with(b"\n") || buf.starts_with(b"\r\n") {
+        buf.iter().position(|&b| b == b'\n').map(|index| index + 1)
+    } else {
+        find_block_end(buf, previous_len.saturating_sub(2))
+    }?;
+    buf.truncate(end);
+    Some(end - previous_len)
+}
+
+/// Follow the framing of the requests of a connection, as sent by the client.
+struct Scanner {
+    state: State,
+    /// Longest request head or trailers scanned, longer ones being rejected by hyper.
+    max_head_size: usize,
+}
+
+impl Scanner {
+    /// Scan the bytes read from the client, returning the first violation and whether the head
+    /// of its request was among these bytes.
+    fn scan(&mut self, mut bytes: &[u8]) -> Result<(), (Violation, bool)> {
+        let mut head_read = false;
+        while !bytes.is_empty() {
+            let consumed = match &mut self.state {
+                // Empty lines before a request line are ignored.
+                State::Head(buf) if buf.is_empty() && matches!(bytes[0], b'\r' | b'\n') => 1,
+                State::Head(buf) => match read_block(buf, bytes) {
+                    Some(consumed) => {
+                        head_read = true;
+                        match check_head(buf) {
+                            Ok(state) => self.state = state,
+                            Err(violation) => {
+                                self.state = State::Done;
+                                return Err((violation, head_read));
+                            }
+                        }
+                        consumed
+                    }
+                    None if buf.len() > self.max_head_size => {
+                        self.state = State::Done;
+                        return Ok(());
+                    }
+                    None => bytes.len(),
+                },
+                State::Trailers(buf) => match read_block(buf, bytes) {
+                    Some(consumed) => {
+                        self.state = State::Head(Vec::new());
+                        consumed
+                    }
+                    None if buf.len() > self.max_head_size => {
+                        self.state = State::Done;
+                        return Ok(());
+                    }
+                    None => bytes.len(),
+                },
+                State::Body(remaining) | State::ChunkData(remaining) => {
+                    let consumed = bytes.len().min(*remaining as usize);
+                    *remaining -= consumed as u64;
+                    if *remaining == 0 {
+                        self.state = match self.state {
+                            State::Body(_) => State::Head(Vec::new()),
+                            _ => State::ChunkEnd,
+                        };
+                    }
+                    consumed
+                }
+                State::ChunkLine(buf) => {
+                    let Some(end) = bytes.iter().position(|&b| b == b'\n') else {
+                        buf.extend_from_slice(bytes);
+                        if buf.len() > MAX_CHUNK_LINE {
+                            self.state = State::Done;
+                        }
+                        return Ok(());
+                    };
+                    buf.extend_from_slice(&bytes[..end]);
+                    match check_chunk_line(buf.strip_suffix(b"\r").unwrap_or(buf)) {
+                        Ok(Some(0)) => self.state = State::Trailers(Vec::new()),
+                        Ok(Some(size)) => self.state = State::ChunkData(size),
+                        Ok(None) => self.state = State::Done,
+                        Err(violation) => {
+                            self.state = State::Done;
+                            return Err((violation, head_read));
+                        }
+                    }
+                    end + 1
+                }
+                State::ChunkEnd => {
+                    match bytes[0] {
+                        b'\r' => (),
+                        b'\n' => self.state = State::ChunkLine(Vec::new()),
+                        // Rejected by hyper.
+                        _ => self.state = State::Done,
+                    }
+                    1
+                }
+                State::Done => return Ok(()),
+            };
+            bytes = &bytes[consumed..];
+        }
+
+        Ok(())
+    }
+}
+
+/// Connection from a client, checking that its requests are framed without ambiguity before
+/// hyper reads them. The requests of a connection with a violation are rejected by the service
+/// with `400`, unless hyper already rejects them, the connection being closed. A violation in
+/// the body of a request already forwarded fails the connection instead.
+pub(crate) struct FramingGuard<T> {
+    inner: T,
+    scanner: Scanner,
+    state: Arc<FramingState>,
+}
+
+impl<T> FramingGuard<T> {
+    pub(crate) fn new(inner: T, max_head_size: usize, state: Arc<FramingState>) -> Self {
+        Self {
+            inner,
+            scanner: Scanner {
+                state: State::Head(Vec::new()),
+                max_head_size,
+            },
+            state,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for FramingGuard<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if this.state.upgraded.load(Ordering::Relaxed) {
+            return Poll::Ready(Ok(()));
+        }
+
+        if let Err((violation, head_read)) = this.scanner.scan(&buf.filled()[filled..]) {
+            if this.state.violation.set(violation).is_ok() {
+                warn!(
+                    "event='Rejecting ambiguous request: {}'",
+                    violation.as_str()
+                );
+                commit_smuggling_rejection(violation.as_str());
+            }
+            if matches!(violation, Violation::InvalidChunkExtension) && !head_read {
+                buf.set_filled(filled);
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid chunk extension",
+                )));
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for FramingGuard<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::runtime_config::set_config_path;
+
+    fn scanner() -> Scanner {
+        Scanner {
+            state: State::Head(Vec::new()),
+            max_head_size: 8192,
+        }
+    }
+
+    /// Scan `reads` as successive reads of a connection, returning the label of the first
+    /// violation.
+    fn scan(reads: &[&[u8]]) -> Result<(), &'static str> {
+        let mut scanner = scanner();
+        for read in reads {
+            scanner
+                .scan(read)
+                .map_err(|(violation, _)| violation.as_str())?;
+        }
+        Ok(())
+    }
+
+    /// Scan the head of a request with `headers`.
+    fn scan_head(request_line: &str, headers: &str) -> Result<(), &'static str> {
+        scan(&[format!("{request_line}\r\nHost: example.com\r\n{headers}\r\n").as_bytes()])
+    }
+
+    #[test]
+    fn transfer_encoding_and_content_length_are_ambiguous() {
+        let headers = "Transfer-Encoding: chunked\r\nContent-Length: 5\r\n";
+        assert_eq!(
+            scan_head("POST / HTTP/1.1", headers),
+            Err("ambiguous_length")
+        );
+        let headers = "content-length: 5\r\nTRANSFER-ENCODING: chunked\r\n";
+        assert_eq!(
+            scan_head("POST / HTTP/1.1", headers),
+            Err("ambiguous_length")
+        );
+    }
+
+    #[test]
+    fn content_lengths_must_agree() {
+        for headers in [
+            "Content-Length: 5\r\n",
+            "Content-Length: 5\r\nContent-Length: 5\r\n",
+            "Content-Length: 5, 5\r\n",
+        ] {
+            assert_eq!(scan_head("POST / HTTP/1.1", headers), Ok(()), "{headers}");
+        }
+        for headers in [
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+            "Content-Length: 5, 6\r\n",
+            "Content-Length: +5\r\n",
+            "Content-Length: -1\r\n",
+            "Content-Length: 0x5\r\n",
+            "Content-Length: 5,\r\n",
+            "Content-Length:\r\n",
+            "Content-Length: 99999999999999999999\r\n",
+        ] {
+            assert_eq!(
+                scan_head("POST / HTTP/1.1", headers),
+                Err("content_length"),
+                "{headers}"
+            );
+        }
+    }
+
+    #[test]
+    fn content_lengths_are_parsed() {
+        assert_eq!(parse_content_length(&[b"42"]), Some(42));
+        assert_eq!(parse_content_length(&[b"42", b" 42 ,42"]), Some(42));
+        assert_eq!(parse_content_length(&[b"0"]), Some(0));
+        assert_eq!(parse_content_length(&[b"42", b"43"]), None);
+        assert_eq!(parse_content_length(&[b"4 2"]), None);
+        assert_eq!(parse_content_length(&[b"42,,42"]), None);
+        assert_eq!(parse_content_length(&[]), None);
+    }
+
+    #[test]
+    fn obs_folds_are_rejected() {
+        for headers in [
+            "X-Long: first\r\n second\r\n",
+            "X-Long: first\r\n\tsecond\r\n",
+        ] {
+            assert_eq!(scan_head("GET / HTTP/1.1", headers), Err("obs_fold"));
+        }
+    }
+
+    #[test]
+    fn transfer_encodings_must_end_with_chunked() {
+        for headers in [
+            "Transfer-Encoding: chunked\r\n",
+            "Transfer-Encoding: gzip, Chunked\r\n",
+            "Transfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n",
+        ] {
+            assert_eq!(scan_head("POST / HTTP/1.1", headers), Ok(()), "{headers}");
+        }
+        for headers in [
+            "Transfer-Encoding: gzip\r\n",
+            "Transfer-Encoding: chunked, gzip\r\n",
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n",
+            "Transfer-Encoding: xchunked\r\n",
+        ] {
+            assert_eq!(
+                scan_head("POST / HTTP/1.1", headers),
+                Err("transfer_encoding"),
+                "{headers}"
+            );
+        }
+    }
+
+    #[test]
+    fn http_10_requests_cannot_be_chunked() {
+        assert_eq!(
+            scan_head("POST / HTTP/1.0", "Transfer-Encoding: chunked\r\n"),
+            Err("transfer_encoding")
+        );
+        assert_eq!(
+            scan_head("POST / HTTP/1.0", "Content-Length: 0\r\n"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn heads_are_checked() {
+        let check = |head: &[u8]| check_head(head).map_err(|violation| violation.as_str());
+        assert!(matches!(
+            check(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"),
+            Ok(State::Head(buf)) if buf.is_empty()
+        ));
+        assert!(matches!(
+            check(b"POST / HTTP/1.1\r\nContent-Length: 0\r\n\r\n"),
+            Ok(State::Head(_))
+        ));
+        assert!(matches!(
+            check(b"POST / HTTP/1.1\nContent-Length: 7\n\n"),
+            Ok(State::Body(7))
+        ));
+        assert!(matches!(
+            check(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            Ok(State::ChunkLine(_))
+        ));
+        // Lines without a colon are left to hyper.
+        assert!(matches!(
+            check(b"GET / HTTP/1.1\r\nnot a header\r\n\r\n"),
+            Ok(State::Head(_))
+        ));
+        assert!(matches!(
+            check(b"GET / HTTP/1.1\r\n folded\r\n\r\n"),
+            Err("obs_fold")
+        ));
+    }
+
+    #[test]
+    fn chunk_extensions_are_checked() {
+        for extensions in [
+            "",
+            " ",
+            ";name",
+            ";name=value",
+            " ; name = value ;flag",
+            ";name=\"quoted value\"",
+            ";name=\"escaped \\\" quote\"",
+            ";a=1;b;c=\"\"",
+        ] {
+            assert!(
+                check_chunk_extensions(extensions.as_bytes()).is_ok(),
+                "{extensions}"
+            );
+        }
+        for extensions in [
+            ";",
+            ";=value",
+            ";name=",
+            ";name=two words",
+            ";name=\"unterminated",
+            ";name=\"bad \\\x01 escape\"",
+            ";name=\"control \x01\"",
+            "name",
+            "; name value",
+            ";na(me",
+        ] {
+            assert!(
+                check_chunk_extensions(extensions.as_bytes()).is_err(),
+                "{extensions}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_chunk_extensions_are_rejected() {
+        let head = "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(
+            scan(&[format!("{head}5;name=value\r\nhello\r\n0\r\n\r\n").as_bytes()]),
+            Ok(())
+        );
+        assert_eq!(
+            scan(&[format!("{head}5;=value\r\nhello\r\n0\r\n\r\n").as_bytes()]),
+            Err("chunk_extension")
+        );
+        // In a later chunk, and in a chunk line split across reads.
+        assert_eq!(
+            scan(&[
+                format!("{head}5\r\nhello\r\n3;na").as_bytes(),
+                b"me=\"a\" b\r\nabc\r\n0\r\n\r\n"
+            ]),
+            Err("chunk_extension")
+        );
+        // Chunk sizes hyper rejects are left to it.
+        assert_eq!(scan(&[format!("{head}zz;=\r\n").as_bytes()]), Ok(()));
+    }
+
+    #[test]
+    fn blocks_are_read_across_reads() {
+        let mut buf = Vec::new();
+        assert_eq!(
+            read_block(&mut buf, b"GET / HTTP/1.1\r\nHost: a\r\n\r"),
+            None
+        );
+        assert_eq!(read_block(&mut buf, b"\nGET /next HTTP/1.1"), Some(1));
+        assert_eq!(buf, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+
+        let mut buf = Vec::new();
+        assert_eq!(read_block(&mut buf, b"GET / HTTP/1.1\r\nHost: a\r\n"), None);
+        assert_eq!(read_block(&mut buf, b"\r\n"), Some(2));
+
+        let mut buf = Vec::new();
+        assert_eq!(read_block(&mut buf, b"GET / HTTP/1.1\nHost: a\n"), None);
+        assert_eq!(read_block(&mut buf, b"\nrest"), Some(1));
+
+        // Empty trailers end with their first line.
+        let mut buf = Vec::new();
+        assert_eq!(read_block(&mut buf, b"\r\nGET"), Some(2));
+        let mut buf = Vec::new();
+        assert_eq!(read_block(&mut buf, b"\r"), None);
+        assert_eq!(read_block(&mut buf, b"\nGET"), Some(1));
+    }
+
+    #[test]
+    fn heads_split_across_reads_are_checked() {
+        let ambiguous =
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n";
+        for split in 1..ambiguous.len() {
+            let (first, second) = ambiguous.split_at(split);
+            assert_eq!(scan(&[first, second]), Err("ambiguous_length"), "{split}");
+        }
+
+        // The second request starts right after the `\r\n\r` | `\n` of the first one.
+        assert_eq!(
+            scan(&[
+                b"GET / HTTP/1.1\r\nHost: a\r\n\r",
+                b"\nGET / HTTP/1.1\r\nX-Long: first\r\n second\r\n\r\n",
+            ]),
+            Err("obs_fold")
+        );
+        assert_eq!(
+            scan(&[
+                b"GET / HTTP/1.1\r\nHost: a\r\n\r",
+                b"\nGET / HTTP/1.1\r\nHost: a\r\n\r\n",
+            ]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn valid_pipelined_requests_are_not_flagged() {
+        // Bodies are skipped, even when they look like invalid heads.
+        let requests: &[u8] = b"POST /a HTTP/1.1\r\nContent-Length: 28\r\n\r\n\
+            Transfer-Encoding: x\r\n x\r\n\r\n\
+            \r\n\
+            GET /b HTTP/1.1\r\nHost: a\r\n\r\n\
+            POST /c HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n0\r\n\r\n\
+            POST /d HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+            3\r\n\r\n\r\n0\r\n\r\n\
+            GET /e HTTP/1.1\r\n\r\n";
+        assert_eq!(scan(&[requests]), Ok(()));
+
+        // Byte by byte.
+        let mut scanner = scanner();
+        for byte in requests.chunks(1) {
+            assert!(scanner.scan(byte).is_ok());
+        }
+        assert!(matches!(&scanner.state, State::Head(buf) if buf.is_empty()));
+    }
+
+    #[test]
+    fn trailers_end_the_chunked_body() {
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let mut scanner = scanner();
+        assert!(scanner
+            .scan(format!("{head}5\r\nhello\r\n0\r\n").as_bytes())
+            .is_ok());
+        assert!(matches!(&scanner.state, State::Trailers(buf) if buf.is_empty()));
+        assert!(scanner.scan(b"X-Checksum: 1\r\nX-Other").is_ok());
+        assert!(matches!(&scanner.state, State::Trailers(buf) if !buf.is_empty()));
+        assert!(scanner.scan(b": 2\r\n\r\n").is_ok());
+        assert!(matches!(&scanner.state, State::Head(buf) if buf.is_empty()));
+
+        // Trailers are not checked as heads, but the next request is.
+        assert_eq!(
+            scan(&[format!(
+                "{head}0\r\nContent-Length: 1\r\n\r\n\
+                 GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"
+            )
+            .as_bytes()]),
+            Err("content_length")
+        );
+        assert_eq!(
+            scan(&[format!("{head}0\n\nGET / HTTP/1.1\n folded\n\n").as_bytes()]),
+            Err("obs_fold")
+        );
+    }
+
+    #[tokio::test]
+    async fn violations_in_bodies_fail_the_connection() {
+        set_config_path(PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/bench/config.yaml"
+        )));
+
+        // In the head, the request is read and then rejected by the service.
+        let state = Arc::new(FramingState::default());
+        let request: &[u8] = b"GET / HTTP/1.1\r\n folded\r\n\r\n";
+        let mut guard = FramingGuard::new(request, 8192, state.clone());
+        let mut read = Vec::new();
+        guard.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, request);
+        assert!(matches!(state.violation(), Some(Violation::ObsFold)));
+
+        // In the body of a request already forwarded, the connection fails.
+        let state = Arc::new(FramingState::default());
+        let head: &[u8] = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let mut guard =
+            FramingGuard::new(head.chain(&b"5;=\r\nhello\r\n"[..]), 8192, state.clone());
+        let mut read = Vec::new();
+        let error = guard.read_to_end(&mut read).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read, head);
+        assert!(matches!(
+            state.violation(),
+            Some(Violation::InvalidChunkExtension)
+        ));
+
+        // Upgraded connections are not scanned anymore.
+        let state = Arc::new(FramingState::default());
+        state.set_upgraded();
+        let mut guard = FramingGuard::new(request, 8192, state.clone());
+        guard.read_to_end(&mut Vec::new()).await.unwrap();
+        assert!(state.violation().is_none());
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::Result;
+use hyper::{HeaderMap, Request, StatusCode};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::runtime::Tokio;
+use opentelemetry_sdk::trace::span_processor_with_async_runtime::BatchSpanProcessor;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+
+use crate::runtime_config::runtime_config;
+
+const TRACER_NAME: &str = "gateway";
+
+/// Install the global tracer provider and W3C trace context propagator if `tracing` is
+/// configured. When it is not, the global no-op tracer is used and no header is propagated.
+///
+/// The returned provider must be shut down before exiting to flush pending spans.
+pub fn init_tracing() -> Result<Option<SdkTracerProvider>> {
+    let runtime_config = runtime_config();
+    let Some(config) = &runtime_config.tracing else {
+        return Ok(None);
+    };
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.otlp_endpoint)
+        .build()?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_span_processor(BatchSpanProcessor::builder(exporter, Tokio).build())
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sampling_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+
+    info!("event='Tracing enabled toward {}'", &config.otlp_endpoint);
+    Ok(Some(provider))
+}
+
+/// Start the span of an incoming request, child of the `traceparent` sent by the client if any.
+pub fn start_server_span<B>(req: &Request<B>) -> Context {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(req.method().to_string())
+        .with_kind(SpanKind::Server)
+        .with_attributes([
+            KeyValue::new("http.request.method", req.method().to_string()),
+            KeyValue::new("url.path", req.uri().path().to_string()),
+        ])
+        .start_with_context(&tracer, &parent);
+
+    parent.with_span(span)
+}
+
+/// Start a span child of `cx`, used for the upstream call and websocket tunnels.
+pub fn start_child_span(cx: &Context, name: &'static str, kind: SpanKind) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(name)
+        .with_kind(kind)
+        .start_with_context(&tracer, cx);
+
+    cx.with_span(span)
+}
+
+/// Write the `traceparent` of `cx` into the headers forwarded to the upstream server.
+pub fn inject_context(cx: &Context, headers: &mut HeaderMap) {
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(cx, &mut HeaderInjector(headers))
+    });
+}
+
+/// Record the final status of the span of `cx` and end it.
+pub fn end_span(cx: &Context, status_code: Option<StatusCode>) {
+    let span = cx.span();
+
+    match status_code {
+        Some(status_code) => {
+            span.set_attribute(KeyValue::new(
+                "http.response.status_code",
+                i64::from(status_code.as_u16()),
+            ));
+            if status_code.is_server_error() {
+                span.set_status(Status::error(status_code.to_string()));
+            }
+        }
+        None => span.set_status(Status::error("No response")),
+    }
+
+    span.end();
+}
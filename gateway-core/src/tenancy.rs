@@ -0,0 +1,61 @@
+//! Tenants of the requests, rendered from the claims of their token with `tenancy.tenant`, and
+//! their concurrent requests.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use crate::auth::Claims;
+use crate::metrics::{commit_tenant_in_flight, tenant_label};
+use crate::runtime_config::TenancyConfig;
+
+/// Get the tenant of a user, if the claims of the template are in its token.
+pub(crate) fn resolve_tenant(claims: &Claims, tenancy: &TenancyConfig) -> Option<String> {
+    claims
+        .render_template(&tenancy.tenant)
+        .filter(|tenant| !tenant.is_empty())
+}
+
+/// Requests of each tenant being served.
+static IN_FLIGHT: LazyLock<Mutex<HashMap<String, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// One of the requests of a tenant being served, released when dropped.
+pub(crate) struct TenantPermit {
+    tenant: String,
+    label: String,
+}
+
+impl TenantPermit {
+    /// Take a slot unless `max_concurrent_requests` of the tenant are already being served.
+    pub(crate) fn acquire(tenant: &str, max_concurrent_requests: Option<usize>) -> Option<Self> {
+        {
+            let mut in_flight = IN_FLIGHT.lock().unwrap();
+            let count = in_flight.get(tenant).copied().unwrap_or(0);
+            if max_concurrent_requests.is_some_and(|max| count >= max) {
+                return None;
+            }
+            in_flight.insert(tenant.to_string(), count + 1);
+        }
+
+        let label = tenant_label(tenant);
+        commit_tenant_in_flight(&label, 1.0);
+        Some(Self {
+            tenant: tenant.to_string(),
+            label,
+        })
+    }
+}
+
+impl Drop for TenantPermit {
+    fn drop(&mut self) {
+        commit_tenant_in_flight(&self.label, -1.0);
+
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.tenant) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.tenant);
+            }
+        }
+    }
+}
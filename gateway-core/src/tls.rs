@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use rustls::client::WantsClientCert;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::server::WantsServerCert;
+use rustls::{ClientConfig, ConfigBuilder, RootCertStore, ServerConfig, SupportedProtocolVersion};
+
+use crate::runtime_config::{TlsPolicyConfig, TlsVersion};
+
+/// Keep the items of `all` named in `names`, in the order of `names`, or all of them if `names`
+/// is empty. Names are compared without case.
+fn select<T: Copy>(
+    all: &[T],
+    names: &[String],
+    name_of: impl Fn(&T) -> String,
+    setting: &str,
+) -> Result<Vec<T>, String> {
+    if names.is_empty() {
+        return Ok(all.to_vec());
+    }
+
+    names
+        .iter()
+        .map(|name| {
+            all.iter()
+                .find(|item| name_of(item).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| {
+                    let known: Vec<_> = all.iter().map(&name_of).collect();
+                    format!("unknown `{setting}` `{name}`, expected one of {known:?}")
+                })
+        })
+        .collect()
+}
+
+/// Crypto provider restricted to the cipher suites and curves of the policy.
+fn crypto_provider(policy: &TlsPolicyConfig) -> Result<CryptoProvider, String> {
+    let provider = ring::default_provider();
+    Ok(CryptoProvider {
+        cipher_suites: select(
+            &provider.cipher_suites,
+            &policy.cipher_suites,
+            |suite| format!("{:?}", suite.suite()),
+            "cipher_suites",
+        )?,
+        kx_groups: select(
+            &provider.kx_groups,
+            &policy.curves,
+            |group| format!("{:?}", group.name()),
+            "curves",
+        )?,
+        ..provider
+    })
+}
+
+const TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+fn protocol_versions(min_version: TlsVersion) -> &'static [&'static SupportedProtocolVersion] {
+    match min_version {
+        TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+        TlsVersion::Tls13 => TLS13_ONLY,
+    }
+}
+
+/// Start a client configuration following the TLS policy, failing if the policy leaves no usable
+/// cipher suite or curve.
+pub(crate) fn client_config_builder(
+    policy: &TlsPolicyConfig,
+    roots: RootCertStore,
+) -> Result<ConfigBuilder<ClientConfig, WantsClientCert>, String> {
+    Ok(
+        ClientConfig::builder_with_provider(Arc::new(crypto_provider(policy)?))
+            .with_protocol_versions(protocol_versions(policy.min_version))
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots),
+    )
+}
+
+/// Start a server configuration following the cipher suites and curves of the TLS policy, with
+/// TLS 1.3 only as required by QUIC.
+pub(crate) fn quic_server_config_builder(
+    policy: &TlsPolicyConfig,
+) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>, String> {
+    Ok(
+        ServerConfig::builder_with_provider(Arc::new(crypto_provider(policy)?))
+            .with_protocol_versions(TLS13_ONLY)
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth(),
+    )
+}
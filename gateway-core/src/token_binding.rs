@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::USER_AGENT;
+use hyper::HeaderMap;
+use ipnet::IpNet;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Claims;
+
+/// Bind each token to the client which first used it on the API, later requests of the token
+/// from another client being answered with `403`.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TokenBindingSpec {
+    /// Bind to the network of the client IP, of `ipv4_prefix` or `ipv6_prefix` bits.
+    #[serde(default = "ip_default")]
+    pub ip: bool,
+    #[serde(default = "ipv4_prefix_default")]
+    pub ipv4_prefix: u8,
+    #[serde(default = "ipv6_prefix_default")]
+    pub ipv6_prefix: u8,
+    /// Bind to the `User-Agent` header.
+    #[serde(default)]
+    pub user_agent: bool,
+}
+
+fn ip_default() -> bool {
+    true
+}
+
+fn ipv4_prefix_default() -> u8 {
+    24
+}
+
+fn ipv6_prefix_default() -> u8 {
+    64
+}
+
+impl TokenBindingSpec {
+    pub fn check(&self) -> Result<(), String> {
+        if !self.ip && !self.user_agent {
+            return Err("at least one of `ip` and `user_agent` must be true".to_string());
+        }
+        if self.ipv4_prefix > 32 || self.ipv6_prefix > 128 {
+            return Err("`ipv4_prefix` must be at most 32 and `ipv6_prefix` 128".to_string());
+        }
+        Ok(())
+    }
+
+    fn fingerprint(&self, client_ip: IpAddr, headers: &HeaderMap) -> Fingerprint {
+        let prefix = match client_ip {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        Fingerprint {
+            network: self
+                .ip
+                .then(|| IpNet::new(client_ip, prefix).ok().map(|net| net.trunc()))
+                .flatten(),
+            user_agent: self.user_agent.then(|| {
+                headers
+                    .get(USER_AGENT)
+                    .map(|user_agent| user_agent.as_bytes().to_vec())
+                    .unwrap_or_default()
+            }),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq)]
+struct Fingerprint {
+    network: Option<IpNet>,
+    user_agent: Option<Vec<u8>>,
+}
+
+/// Fingerprint of the first client of each token, by app and `token_id`, with the expiration of
+/// the token.
+#[derive(Default)]
+struct Bindings {
+    tokens: HashMap<(String, String), (Fingerprint, usize)>,
+    /// Number of bindings above which the expired ones are removed before inserting another.
+    prune_at: usize,
+}
+
+const MIN_PRUNE_AT: usize = 1024;
+
+static BINDINGS: LazyLock<Mutex<Bindings>> = LazyLock::new(Default::default);
+
+/// Bind the token to the client if it is its first use on the app, returning whether the client
+/// matches the one the token is bound to.
+pub(crate) fn check_binding(
+    app: &str,
+    spec: &TokenBindingSpec,
+    claims: &Claims,
+    client_ip: IpAddr,
+    headers: &HeaderMap,
+) -> bool {
+    let fingerprint = spec.fingerprint(client_ip, headers);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as usize);
+
+    let mut bindings = BINDINGS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let key = (app.to_string(), claims.token_id.clone());
+    if let Some((bound, exp)) = bindings.tokens.get(&key) {
+        if *exp > now {
+            return *bound == fingerprint;
+        }
+    }
+    if bindings.tokens.len() >= bindings.prune_at {
+        bindings.tokens.retain(|_, (_, exp)| *exp > now);
+        bindings.prune_at = MIN_PRUNE_AT.max(bindings.tokens.len() * 2);
+    }
+    bindings.tokens.insert(key, (fingerprint, claims.exp));
+    true
+}
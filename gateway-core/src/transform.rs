@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, RwLock};
+
+use hyper::header::{Entry, HeaderName, HeaderValue, LOCATION};
+use hyper::{HeaderMap, StatusCode};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded;
+
+use crate::api::ApiDefinitionSpec;
+use crate::runtime_config::runtime_config;
+
+/// Compiled `response_scrubbing` patterns, which were checked when loading the runtime config.
+static SCRUBBING_PATTERNS: LazyLock<RwLock<HashMap<String, Regex>>> =
+    LazyLock::new(Default::default);
+
+/// Rewrite of the forwarded paths matching `from`, whose `{name}` segments are captured and
+/// replaced in `to`, such as `/users/{id}` to `/v2/accounts/{id}`.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct PathRewriteSpec {
+    pub from: String,
+    pub to: String,
+}
+
+/// Declarative changes to the requests of an API before they are forwarded, applied in the order
+/// of the fields.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, JsonSchema)]
+pub struct RequestTransformSpec {
+    /// Only the first matching rewrite is applied, the query being kept.
+    #[serde(default)]
+    pub rewrite_path: Vec<PathRewriteSpec>,
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+    /// New name of each header, keeping its values.
+    #[serde(default)]
+    pub rename_headers: BTreeMap<String, String>,
+    /// Headers replacing any with the same name.
+    #[serde(default)]
+    pub add_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub remove_query: Vec<String>,
+    /// Query parameters replacing any with the same name.
+    #[serde(default)]
+    pub add_query: BTreeMap<String, String>,
+}
+
+/// Declarative changes to the responses of the upstream server of an API.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, JsonSchema)]
+pub struct ResponseTransformSpec {
+    /// Status codes replacing those of upstream responses, such as `404: 204`, the body of
+    /// responses mapped to `204` or `304` being dropped.
+    #[serde(default)]
+    pub map_status: BTreeMap<u16, u16>,
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+    /// Headers replacing any with the same name.
+    #[serde(default)]
+    pub add_headers: BTreeMap<String, String>,
+    /// Rewrite `Location` headers pointing to the upstream server so that they stay under the
+    /// app.
+    #[serde(default)]
+    pub rewrite_location: bool,
+}
+
+fn check_headers<'a>(
+    names: impl Iterator<Item = &'a String>,
+    values: impl Iterator<Item = &'a String>,
+) -> Result<(), String> {
+    for name in names {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(format!("{name} isn't a valid header name"));
+        }
+    }
+    for value in values {
+        if HeaderValue::from_str(value).is_err() {
+            return Err(format!("{value} isn't a valid header value"));
+        }
+    }
+    Ok(())
+}
+
+fn add_headers(headers: &mut HeaderMap, added: &BTreeMap<String, String>) {
+    for (name, value) in added {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+fn is_param(segment: &str) -> bool {
+    segment.len() > 2 && segment.starts_with('{') && segment.ends_with('}')
+}
+
+impl PathRewriteSpec {
+    fn check(&self) -> Result<(), String> {
+        if !self.from.starts_with('/') || !self.to.starts_with('/') {
+            return Err(format!(
+                "rewrite_path: {} and {} should start with `/`",
+                self.from, self.to
+            ));
+        }
+        for segment in self.to.split('/').filter(|segment| is_param(segment)) {
+            if !self.from.split('/').any(|from| from == segment) {
+                return Err(format!(
+                    "rewrite_path: {segment} of {} isn't captured by {}",
+                    self.to, self.from
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrite `path` if it matches `from`.
+    fn rewrite(&self, path: &str) -> Option<String> {
+        let from: Vec<&str> = self.from.split('/').collect();
+        let segments: Vec<&str> = path.split('/').collect();
+        if from.len() != segments.len() {
+            return None;
+        }
+
+        let mut captures = HashMap::new();
+        for (pattern, segment) in from.iter().zip(&segments) {
+            if is_param(pattern) {
+                captures.insert(*pattern, *segment);
+            } else if pattern != segment {
+                return None;
+            }
+        }
+
+        Some(
+            self.to
+                .split('/')
+                .map(|segment| captures.get(segment).copied().unwrap_or(segment))
+                .collect::<Vec<_>>()
+                .join("/"),
+        )
+    }
+}
+
+impl RequestTransformSpec {
+    pub fn check(&self) -> Result<(), String> {
+        for rewrite in &self.rewrite_path {
+            rewrite.check()?;
+        }
+        check_headers(
+            self.remove_headers
+                .iter()
+                .chain(self.rename_headers.keys())
+                .chain(self.rename_headers.values())
+                .chain(self.add_headers.keys()),
+            self.add_headers.values(),
+        )
+    }
+
+    /// Transform the headers of the request, returning its new forwarded path and query.
+    pub fn apply(&self, forwarded_uri: &str, headers: &mut HeaderMap) -> String {
+        let (path, query) = match forwarded_uri.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (forwarded_uri, None),
+        };
+
+        let path = self
+            .rewrite_path
+            .iter()
+            .find_map(|rewrite| rewrite.rewrite(path))
+            .unwrap_or_else(|| path.to_string());
+
+        for name in &self.remove_headers {
+            headers.remove(name.as_str());
+        }
+        for (from, to) in &self.rename_headers {
+            let (Ok(from), Ok(to)) = (
+                HeaderName::from_bytes(from.as_bytes()),
+                HeaderName::from_bytes(to.as_bytes()),
+            ) else {
+                continue;
+            };
+            if let Entry::Occupied(entry) = headers.entry(from) {
+                let (_, values) = entry.remove_entry_mult();
+                let values: Vec<HeaderValue> = values.collect();
+                headers.remove(&to);
+                for value in values {
+                    headers.append(&to, value);
+                }
+            }
+        }
+        add_headers(headers, &self.add_headers);
+
+        if self.remove_query.is_empty() && self.add_query.is_empty() {
+            return match query {
+                Some(query) => format!("{path}?{query}"),
+                None => path,
+            };
+        }
+
+        let mut serializer = form_urlencoded::Serializer::new(String::new());
+        for (name, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            if !self.remove_query.iter().any(|removed| *removed == name)
+                && !self.add_query.contains_key(name.as_ref())
+            {
+                serializer.append_pair(&name, &value);
+            }
+        }
+        serializer.extend_pairs(&self.add_query);
+        let query = serializer.finish();
+
+        if query.is_empty() {
+            path
+        } else {
+            format!("{path}?{query}")
+        }
+    }
+}
+
+impl ResponseTransformSpec {
+    pub fn check(&self) -> Result<(), String> {
+        for (from, to) in &self.map_status {
+            for status in [from, to] {
+                if StatusCode::from_u16(*status).is_err() {
+                    return Err(format!("map_status: {status} isn't a valid status code"));
+                }
+            }
+        }
+        check_headers(
+            self.remove_headers.iter().chain(self.add_headers.keys()),
+            self.add_headers.values(),
+        )
+    }
+
+    /// Transform the status and headers of a response of the upstream server of `api`.
+    pub fn apply(
+        &self,
+        api: &ApiDefinitionSpec,
+        status_code: &mut StatusCode,
+        headers: &mut HeaderMap,
+    ) {
+        if let Some(mapped) = self
+            .map_status
+            .get(&status_code.as_u16())
+            .and_then(|mapped| StatusCode::from_u16(*mapped).ok())
+        {
+            *status_code = mapped;
+        }
+
+        for name in &self.remove_headers {
+            headers.remove(name.as_str());
+        }
+        add_headers(headers, &self.add_headers);
+
+        if self.rewrite_location {
+            let location = headers
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| rewrite_location(api, location))
+                .and_then(|location| HeaderValue::from_str(&location).ok());
+            if let Some(location) = location {
+                headers.insert(LOCATION, location);
+            }
+        }
+    }
+}
+
+/// Replace the URL of the upstream server, or its `forward_path`, at the start of `location` by
+/// the app.
+fn rewrite_location(api: &ApiDefinitionSpec, location: &str) -> Option<String> {
+    let rest = location
+        .strip_prefix(&api.uri_http)
+        .or_else(|| {
+            location
+                .starts_with('/')
+                .then(|| location.strip_prefix(&api.forward_path))
+                .flatten()
+        })
+        .filter(|rest| rest.is_empty() || rest.starts_with(['/', '?', '#']))?;
+
+    Some(format!("{}{rest}", api.app_name))
+}
+
+fn get_scrubbing_pattern(pattern: &str) -> Option<Regex> {
+    if let Some(regex) = SCRUBBING_PATTERNS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(pattern)
+    {
+        return Some(regex.clone());
+    }
+
+    let regex = Regex::new(pattern).ok()?;
+    SCRUBBING_PATTERNS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(pattern.to_string(), regex.clone());
+    Some(regex)
+}
+
+/// Remove and rewrite the headers of an upstream response listed in `response_scrubbing`.
+pub(crate) fn scrub_response_headers(headers: &mut HeaderMap) {
+    let runtime_config = runtime_config();
+    let scrubbing = &runtime_config.response_scrubbing;
+    for name in &scrubbing.remove_headers {
+        headers.remove(name.as_str());
+    }
+
+    for rewrite in &scrubbing.rewrite_headers {
+        let Ok(name) = HeaderName::from_bytes(rewrite.header.as_bytes()) else {
+            continue;
+        };
+        let Entry::Occupied(entry) = headers.entry(&name) else {
+            continue;
+        };
+        let Some(regex) = get_scrubbing_pattern(&rewrite.pattern) else {
+            continue;
+        };
+
+        let (_, values) = entry.remove_entry_mult();
+        let values: Vec<HeaderValue> = values
+            .filter_map(|value| match value.to_str() {
+                Ok(value) => {
+                    let value = regex.replace_all(value, rewrite.replacement.as_str());
+                    let value = value.trim();
+                    (!value.is_empty())
+                        .then(|| HeaderValue::from_str(value).ok())
+                        .flatten()
+                }
+                // Values which are not text cannot be checked, so they are removed.
+                Err(_) => None,
+            })
+            .collect();
+        for value in values {
+            headers.append(&name, value);
+        }
+    }
+}
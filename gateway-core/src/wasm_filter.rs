@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::fs;
+use std::mem::take;
+use std::sync::{LazyLock, RwLock};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Body;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::{HeaderMap, StatusCode};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use wasmi::{
+    Caller, Config, Engine, Extern, Instance, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder,
+};
+
+use crate::access_log::SharedAccessLog;
+use crate::{get_response, into_boxed_response, BoxResponse};
+
+/// Fuel given to each callback, bounding the instructions it runs.
+const FUEL_PER_CALLBACK: u64 = 100_000_000;
+/// Memory a module instance can grow to.
+const MAX_MEMORY_BYTES: usize = 64 * 1024 * 1024;
+
+static ENGINE: LazyLock<Engine> = LazyLock::new(|| {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    Engine::new(&config)
+});
+
+/// Compiled modules by path, replaced when an `ApiDefinition` referring to them is applied.
+static MODULES: LazyLock<RwLock<HashMap<String, Module>>> = LazyLock::new(Default::default);
+
+/// A WASM module run on the requests of an API and their responses, which can inspect and rewrite
+/// their headers and bodies or answer them itself.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct WasmFilterSpec {
+    /// Path of the module in the gateway container, usually mounted from a `ConfigMap`.
+    pub path: String,
+    /// Buffer bodies to run `on_request_body` and `on_response_body`, which is not done for
+    /// websocket upgrades.
+    #[serde(default)]
+    pub bodies: bool,
+    /// Bytes of a buffered body, larger request bodies being rejected with `413` and larger
+    /// response bodies with `502`.
+    #[serde(default = "max_body_size_default")]
+    pub max_body_size: usize,
+}
+
+fn max_body_size_default() -> usize {
+    1024 * 1024
+}
+
+/// Compile the module of `spec`, replacing the one cached for its path.
+pub fn load_wasm_filter(spec: &WasmFilterSpec) -> Result<()> {
+    let wasm = fs::read(&spec.path).map_err(|e| anyhow!("Could not read {}: {e}", spec.path))?;
+    let module = Module::new(&ENGINE, &wasm)?;
+    for export in ["memory", "gateway_alloc"] {
+        if module.get_export(export).is_none() {
+            bail!("Module {} does not export `{export}`", spec.path);
+        }
+    }
+
+    MODULES
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(spec.path.clone(), module);
+    Ok(())
+}
+
+fn get_module(spec: &WasmFilterSpec) -> Result<Module> {
+    let cached = MODULES
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .get(&spec.path)
+        .cloned();
+    match cached {
+        Some(module) => Ok(module),
+        None => {
+            load_wasm_filter(spec)?;
+            get_module(spec)
+        }
+    }
+}
+
+/// Body rewritten by a filter, whose length replaces the one of the message.
+pub fn filtered_body(headers: &mut HeaderMap, body: Bytes) -> Full<Bytes> {
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, body.len().into());
+    Full::new(body)
+}
+
+/// Why a filter stopped a request, which is answered with `status_code`.
+pub struct Rejection {
+    pub status_code: StatusCode,
+    pub error: String,
+}
+
+impl Rejection {
+    /// Answer the request, the error being recorded in its access log.
+    pub fn into_response(self, access_log: &SharedAccessLog) -> Result<BoxResponse<Bytes>> {
+        access_log.lock().set_error(self.error);
+        get_response(
+            self.status_code,
+            self.status_code
+                .canonical_reason()
+                .unwrap_or_default()
+                .as_bytes(),
+        )
+        .map(into_boxed_response)
+    }
+
+    fn internal(error: anyhow::Error) -> Self {
+        Self {
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            error: format!("Wasm filter: {error}"),
+        }
+    }
+}
+
+/// Headers or body of the message being filtered, reachable from the host functions.
+struct FilterState {
+    headers: HeaderMap,
+    body: Bytes,
+    limits: StoreLimits,
+}
+
+/// An instance of a filter module, living as long as a request.
+pub struct WasmFilter {
+    store: Store<FilterState>,
+    instance: Instance,
+    max_body_size: usize,
+}
+
+impl WasmFilter {
+    pub fn new(spec: &WasmFilterSpec) -> Result<Self, Rejection> {
+        Self::instantiate(spec).map_err(Rejection::internal)
+    }
+
+    fn instantiate(spec: &WasmFilterSpec) -> Result<Self> {
+        let module = get_module(spec)?;
+        let state = FilterState {
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(MAX_MEMORY_BYTES)
+                .build(),
+        };
+        let mut store = Store::new(&ENGINE, state);
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(FUEL_PER_CALLBACK)?;
+
+        let instance = host_functions()?
+            .instantiate(&mut store, &module)?
+            .start(&mut store)?;
+
+        Ok(Self {
+            store,
+            instance,
+            max_body_size: spec.max_body_size,
+        })
+    }
+
+    pub fn on_request_headers(&mut self, headers: &mut HeaderMap) -> Result<(), Rejection> {
+        self.on_headers("on_request_headers", headers)
+    }
+
+    pub fn on_response_headers(&mut self, headers: &mut HeaderMap) -> Result<(), Rejection> {
+        self.on_headers("on_response_headers", headers)
+    }
+
+    pub async fn on_request_body<B>(&mut self, body: B) -> Result<Bytes, Rejection>
+    where
+        B: Body<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let body = self.collect(body).await.map_err(|error| Rejection {
+            status_code: StatusCode::PAYLOAD_TOO_LARGE,
+            error: format!("Wasm filter: could not buffer the request body: {error}"),
+        })?;
+        self.on_body("on_request_body", body)
+    }
+
+    pub async fn on_response_body<B>(&mut self, body: B) -> Result<Bytes, Rejection>
+    where
+        B: Body<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let body = self.collect(body).await.map_err(|error| Rejection {
+            status_code: StatusCode::BAD_GATEWAY,
+            error: format!("Wasm filter: could not buffer the response body: {error}"),
+        })?;
+        self.on_body("on_response_body", body)
+    }
+
+    async fn collect<B>(&self, body: B) -> Result<Bytes>
+    where
+        B: Body<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Limited::new(body, self.max_body_size)
+            .collect()
+            .await
+            .map(|collected| collected.to_bytes())
+            .map_err(|e| anyhow!("{e}"))
+    }
+
+    fn on_headers(&mut self, callback: &str, headers: &mut HeaderMap) -> Result<(), Rejection> {
+        self.store.data_mut().headers = take(headers);
+        let result = self.call(callback);
+        *headers = take(&mut self.store.data_mut().headers);
+        result
+    }
+
+    fn on_body(&mut self, callback: &str, body: Bytes) -> Result<Bytes, Rejection> {
+        self.store.data_mut().body = body;
+        self.call(callback)?;
+        Ok(take(&mut self.store.data_mut().body))
+    }
+
+    /// Run `callback` if the module exports it, a non-zero result being the status to answer
+    /// the request with.
+    fn call(&mut self, callback: &str) -> Result<(), Rejection> {
+        let Some(func) = self.instance.get_func(&self.store, callback) else {
+            return Ok(());
+        };
+
+        let status = self
+            .store
+            .set_fuel(FUEL_PER_CALLBACK)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| Ok(func.typed::<(), i32>(&self.store)?))
+            .and_then(|func| Ok(func.call(&mut self.store, ())?))
+            .map_err(|e| Rejection::internal(anyhow!("{callback} failed: {e}")))?;
+
+        match status {
+            0 => Ok(()),
+            status => match u16::try_from(status).map(StatusCode::from_u16) {
+                Ok(Ok(status_code)) => Err(Rejection {
+                    status_code,
+                    error: format!("Rejected by the wasm filter in {callback}"),
+                }),
+                _ => Err(Rejection::internal(anyhow!(
+                    "{callback} returned the invalid status {status}"
+                ))),
+            },
+        }
+    }
+}
+
+fn memory(caller: &Caller<'_, FilterState>) -> Result<Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("Module does not export `memory`"))
+}
+
+fn read_bytes(
+    caller: &Caller<'_, FilterState>,
+    ptr: i32,
+    len: i32,
+) -> Result<Vec<u8>, wasmi::Error> {
+    let mut buffer = vec![0; len as u32 as usize];
+    memory(caller)?
+        .read(caller, ptr as u32 as usize, &mut buffer)
+        .map_err(|e| wasmi::Error::new(e.to_string()))?;
+    Ok(buffer)
+}
+
+/// Copy `data` in memory allocated with `gateway_alloc`, writing its address at `ptr_ptr` and
+/// its length at `len_ptr`.
+fn return_bytes(
+    caller: &mut Caller<'_, FilterState>,
+    data: &[u8],
+    ptr_ptr: i32,
+    len_ptr: i32,
+) -> Result<(), wasmi::Error> {
+    let alloc = caller
+        .get_export("gateway_alloc")
+        .and_then(Extern::into_func)
+        .ok_or_else(|| wasmi::Error::new("Module does not export `gateway_alloc`"))?
+        .typed::<i32, i32>(&*caller)?;
+    let len = data.len() as i32;
+    let ptr = alloc.call(&mut *caller, len)?;
+
+    let memory = memory(caller)?;
+    for (offset, bytes) in [
+        (ptr, data),
+        (ptr_ptr, &ptr.to_le_bytes()[..]),
+        (len_ptr, &len.to_le_bytes()[..]),
+    ] {
+        memory
+            .write(&mut *caller, offset as u32 as usize, bytes)
+            .map_err(|e| wasmi::Error::new(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Serialize headers as proxy-wasm does: the number of pairs, the length of each name and value,
+/// then each name and value followed by a null byte, numbers being 32 bits little-endian.
+fn serialize_headers(headers: &HeaderMap) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    buffer.extend((headers.len() as u32).to_le_bytes());
+    for (name, value) in headers {
+        buffer.extend((name.as_str().len() as u32).to_le_bytes());
+        buffer.extend((value.len() as u32).to_le_bytes());
+    }
+    for (name, value) in headers {
+        buffer.extend(name.as_str().as_bytes());
+        buffer.push(0);
+        buffer.extend(value.as_bytes());
+        buffer.push(0);
+    }
+    buffer
+}
+
+/// Functions imported by modules from the `gateway` namespace, all lengths being in bytes.
+fn host_functions() -> Result<Linker<FilterState>> {
+    let mut linker = Linker::new(&ENGINE);
+
+    // Write the headers of the message, serialized by `serialize_headers`.
+    linker.func_wrap(
+        "gateway",
+        "get_headers",
+        |mut caller: Caller<'_, FilterState>,
+         ptr_ptr: i32,
+         len_ptr: i32|
+         -> Result<(), wasmi::Error> {
+            let headers = serialize_headers(&caller.data().headers);
+            return_bytes(&mut caller, &headers, ptr_ptr, len_ptr)
+        },
+    )?;
+    // Write the first value of a header, returning 1 if it is missing.
+    linker.func_wrap(
+        "gateway",
+        "get_header",
+        |mut caller: Caller<'_, FilterState>,
+         name_ptr: i32,
+         name_len: i32,
+         ptr_ptr: i32,
+         len_ptr: i32|
+         -> Result<i32, wasmi::Error> {
+            let name = read_bytes(&caller, name_ptr, name_len)?;
+            let Some(value) = HeaderName::from_bytes(&name)
+                .ok()
+                .and_then(|name| caller.data().headers.get(name).cloned())
+            else {
+                return Ok(1);
+            };
+            return_bytes(&mut caller, value.as_bytes(), ptr_ptr, len_ptr)?;
+            Ok(0)
+        },
+    )?;
+    // Replace the values of a header, returning 1 if the name or the value is invalid.
+    linker.func_wrap(
+        "gateway",
+        "set_header",
+        |mut caller: Caller<'_, FilterState>,
+         name_ptr: i32,
+         name_len: i32,
+         value_ptr: i32,
+         value_len: i32|
+         -> Result<i32, wasmi::Error> {
+            let name = read_bytes(&caller, name_ptr, name_len)?;
+            let value = read_bytes(&caller, value_ptr, value_len)?;
+            let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(&name),
+                HeaderValue::from_bytes(&value),
+            ) else {
+                return Ok(1);
+            };
+            caller.data_mut().headers.insert(name, value);
+            Ok(0)
+        },
+    )?;
+    // Remove all the values of a header.
+    linker.func_wrap(
+        "gateway",
+        "remove_header",
+        |mut caller: Caller<'_, FilterState>,
+         name_ptr: i32,
+         name_len: i32|
+         -> Result<(), wasmi::Error> {
+            let name = read_bytes(&caller, name_ptr, name_len)?;
+            if let Ok(name) = HeaderName::from_bytes(&name) {
+                caller.data_mut().headers.remove(name);
+            }
+            Ok(())
+        },
+    )?;
+    // Write the buffered body, empty outside of body callbacks.
+    linker.func_wrap(
+        "gateway",
+        "get_body",
+        |mut caller: Caller<'_, FilterState>,
+         ptr_ptr: i32,
+         len_ptr: i32|
+         -> Result<(), wasmi::Error> {
+            let body = caller.data().body.clone();
+            return_bytes(&mut caller, &body, ptr_ptr, len_ptr)
+        },
+    )?;
+    // Replace the buffered body.
+    linker.func_wrap(
+        "gateway",
+        "set_body",
+        |mut caller: Caller<'_, FilterState>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+            let body = read_bytes(&caller, ptr, len)?;
+            caller.data_mut().body = body.into();
+            Ok(())
+        },
+    )?;
+    // Log a message, with levels from 0 (trace) to 4 (error).
+    linker.func_wrap(
+        "gateway",
+        "log",
+        |caller: Caller<'_, FilterState>,
+         level: i32,
+         ptr: i32,
+         len: i32|
+         -> Result<(), wasmi::Error> {
+            let message = read_bytes(&caller, ptr, len)?;
+            let level = match level {
+                0 => log::Level::Trace,
+                1 => log::Level::Debug,
+                2 => log::Level::Info,
+                3 => log::Level::Warn,
+                _ => log::Level::Error,
+            };
+            log!(
+                target: "wasm_filter",
+                level,
+                "event='{}'",
+                String::from_utf8_lossy(&message)
+            );
+            Ok(())
+        },
+    )?;
+
+    Ok(linker)
+}
@@ -0,0 +1,892 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use http_body_util::{Empty, Full};
+use hyper::body::{Body, Incoming};
+use hyper::client::conn::http1;
+use hyper::header::{
+    HeaderValue, CONNECTION, HOST, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL, UPGRADE,
+};
+use hyper::upgrade::OnUpgrade;
+use hyper::{HeaderMap, Request, Response, StatusCode, Uri};
+use hyper_tungstenite::{upgrade, HyperWebsocket};
+use hyper_util::rt::TokioIo;
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt};
+use opentelemetry::{Context, KeyValue};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use tokio::io::copy_bidirectional;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{interval, sleep, timeout};
+use tokio::{select, spawn, try_join};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::MaybeTlsStream;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector, WebSocketStream};
+
+use crate::access_log::SharedAccessLog;
+use crate::api::WebsocketSpec;
+use crate::auth::get_claims;
+use crate::error_reporting::with_task_context;
+use crate::message_filter::{apply_filters, FilterAction};
+use crate::metrics::{Direction, SocketMetricsGuard};
+use crate::permission::{has_perm, PermLock, PermissionId};
+use crate::runtime_config::runtime_config;
+use crate::smuggling::{get_connection_headers, HOP_BY_HOP_HEADERS};
+use crate::telemetry::start_child_span;
+use crate::tls::client_config_builder;
+use crate::{get_response, BAD_GATEWAY, TOO_MANY_REQUESTS};
+
+static TLS_CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+/// Build the TLS configuration of `wss://` backends from `websocket_tls`.
+pub fn init_websocket_tls() -> Result<()> {
+    let runtime_config = runtime_config();
+    let config = &runtime_config.websocket_tls;
+    let mut roots = RootCertStore::empty();
+
+    if config.native_roots {
+        let native_certs = rustls_native_certs::load_native_certs();
+        for err in &native_certs.errors {
+            warn!("event='Fail to load a native certificate: {err}'");
+        }
+        roots.add_parsable_certificates(native_certs.certs);
+    }
+
+    for ca_file in &config.ca_files {
+        let mut reader = BufReader::new(
+            File::open(ca_file).map_err(|e| anyhow!("Cannot open {}: {e}", ca_file.display()))?,
+        );
+        for cert in rustls_pemfile::certs(&mut reader) {
+            roots.add(cert.map_err(|e| anyhow!("Invalid {}: {e}", ca_file.display()))?)?;
+        }
+    }
+
+    let tls_config = client_config_builder(&runtime_config.tls_policy, roots)
+        .map_err(|e| anyhow!("Invalid `tls_policy`: {e}"))?
+        .with_no_client_auth();
+
+    // Only initialized once at startup.
+    let _ = TLS_CONFIG.set(Arc::new(tls_config));
+    Ok(())
+}
+
+type ServerWebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_upgrade(
+    app: &str,
+    request: Request<impl Body>,
+    ws_uri_string: &str,
+    websocket: &WebsocketSpec,
+    session: TokenSession,
+    cx: &Context,
+    access_log: &SharedAccessLog,
+) -> Result<Response<Full<Bytes>>> {
+    let Some(permit) = TunnelPermit::acquire(app, &session.token_id, websocket) else {
+        access_log.lock().set_error("Too many websocket tunnels");
+
+        return get_response(StatusCode::TOO_MANY_REQUESTS, TOO_MANY_REQUESTS);
+    };
+
+    let tunnel_id = NEXT_TUNNEL_ID.fetch_add(1, Ordering::Relaxed);
+    access_log.lock().tunnel_id = Some(tunnel_id);
+
+    if websocket.passthrough {
+        return handle_passthrough(
+            app,
+            request,
+            ws_uri_string,
+            tunnel_id,
+            permit,
+            cx,
+            access_log,
+        )
+        .await;
+    }
+
+    let app = app.to_string();
+
+    // Open connection from Gateway to backend
+    let (ws_server, protocol) = match create_ws_server(&request, ws_uri_string).await {
+        Ok(server) => server,
+        Err(err) => {
+            access_log.lock().set_error(format!("Websocket: {err}"));
+
+            return get_response(StatusCode::BAD_GATEWAY, BAD_GATEWAY);
+        }
+    };
+
+    // Upgrade connection from client to Gateway
+    let (mut response, ws_client) =
+        upgrade(request, Some(runtime_config().get_websocket_config()))?;
+
+    // The subprotocols offered by the client were forwarded, the one selected by the backend is
+    // given back to the client.
+    if let Some(protocol) = protocol {
+        response
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+
+    info!("event='Websocket tunnel {tunnel_id} of {app} opened'");
+
+    // If there was no error, we can run the websocket tunnel in its own background task
+    let tunnel_cx = start_child_span(cx, "websocket tunnel", SpanKind::Internal);
+    tunnel_cx
+        .span()
+        .set_attribute(KeyValue::new("gateway.tunnel_id", tunnel_id as i64));
+    let websocket = websocket.clone();
+    let context = format!("websocket tunnel {tunnel_id} of {app}");
+    spawn(with_task_context(context, async move {
+        let _permit = permit;
+        let tunnel = TunnelState::new(tunnel_id, session);
+        if let Err(err) = serve_websocket(&app, &websocket, tunnel, ws_client, ws_server).await {
+            warn!("event='Error in websocket tunnel {tunnel_id}: {err:?}'");
+            tunnel_cx.span().set_status(Status::error(err.to_string()));
+        }
+        tunnel_cx.span().end();
+    }));
+
+    Ok(response)
+}
+
+/// Relay the raw upgraded connection, so that the client and the backend negotiate extensions
+/// such as `permessage-deflate` between themselves. Messages are not seen by the gateway, only
+/// bytes are counted once the tunnel is closed.
+#[allow(clippy::too_many_arguments)]
+async fn handle_passthrough(
+    app: &str,
+    mut request: Request<impl Body>,
+    ws_uri_string: &str,
+    tunnel_id: u64,
+    permit: TunnelPermit,
+    cx: &Context,
+    access_log: &SharedAccessLog,
+) -> Result<Response<Full<Bytes>>> {
+    let app = app.to_string();
+
+    let mut backend_response = match send_raw_upgrade(&request, ws_uri_string).await {
+        Ok(backend_response) => backend_response,
+        Err(err) => {
+            access_log.lock().set_error(format!("Websocket: {err}"));
+
+            return get_response(StatusCode::BAD_GATEWAY, BAD_GATEWAY);
+        }
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .body(Full::default())?;
+    *response.headers_mut() = backend_response.headers().clone();
+
+    let client_upgrade = hyper::upgrade::on(&mut request);
+    let server_upgrade = hyper::upgrade::on(&mut backend_response);
+
+    info!("event='Websocket tunnel {tunnel_id} of {app} opened in passthrough'");
+
+    let tunnel_cx = start_child_span(cx, "websocket tunnel", SpanKind::Internal);
+    tunnel_cx
+        .span()
+        .set_attribute(KeyValue::new("gateway.tunnel_id", tunnel_id as i64));
+    let context = format!("websocket tunnel {tunnel_id} of {app}");
+    spawn(with_task_context(context, async move {
+        let _permit = permit;
+        if let Err(err) = serve_passthrough(&app, tunnel_id, client_upgrade, server_upgrade).await {
+            warn!("event='Error in websocket tunnel {tunnel_id}: {err:?}'");
+            tunnel_cx.span().set_status(Status::error(err.to_string()));
+        }
+        tunnel_cx.span().end();
+    }));
+
+    Ok(response)
+}
+
+/// Open a connection to the host of `uri`, wrapped in TLS for `wss://`.
+async fn connect_backend(uri: &Uri) -> Result<MaybeTlsStream<TcpStream>> {
+    let host = uri.host().ok_or_else(|| anyhow!("No host in {uri}"))?;
+    let is_tls = uri.scheme_str() == Some("wss");
+    let port = uri.port_u16().unwrap_or(if is_tls { 443 } else { 80 });
+
+    let stream = TcpStream::connect((host, port)).await?;
+    if !is_tls {
+        return Ok(MaybeTlsStream::Plain(stream));
+    }
+
+    let tls_config = TLS_CONFIG
+        .get()
+        .cloned()
+        .ok_or_else(|| anyhow!("Websocket TLS is not initialized"))?;
+    let server_name = ServerName::try_from(host.to_string())?;
+    let stream = TlsConnector::from(tls_config)
+        .connect(server_name, stream)
+        .await?;
+
+    Ok(MaybeTlsStream::Rustls(stream))
+}
+
+/// Forward the upgrade request of the client as is, and return the `101 Switching Protocols`
+/// response of the backend.
+async fn send_raw_upgrade(
+    forwarded_request: &Request<impl Body>,
+    ws_uri_string: &str,
+) -> Result<Response<Incoming>> {
+    let uri: Uri = ws_uri_string.parse()?;
+    let stream = connect_backend(&uri).await?;
+
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    spawn(async move {
+        if let Err(err) = connection.with_upgrades().await {
+            warn!("event='Error in websocket backend connection: {err:?}'");
+        }
+    });
+
+    let mut request = Request::builder()
+        .method(forwarded_request.method())
+        .uri(uri.path_and_query().map(|x| x.as_str()).unwrap_or("/"))
+        .body(Empty::<Bytes>::new())?;
+    *request.headers_mut() = get_upgrade_headers(forwarded_request.headers(), &uri)?;
+
+    let response = sender.send_request(request).await?;
+    match response.status() {
+        StatusCode::SWITCHING_PROTOCOLS => Ok(response),
+        status => bail!(
+            "Unexpected status during socket initialization: {}",
+            status.canonical_reason().unwrap_or_else(|| status.as_str()),
+        ),
+    }
+}
+
+async fn serve_passthrough(
+    app: &str,
+    tunnel_id: u64,
+    client_upgrade: OnUpgrade,
+    server_upgrade: OnUpgrade,
+) -> Result<()> {
+    let (client, server) = try_join!(client_upgrade, server_upgrade)?;
+    let socket_metrics = SocketMetricsGuard::new(app);
+
+    let (received, sent) =
+        copy_bidirectional(&mut TokioIo::new(client), &mut TokioIo::new(server)).await?;
+    socket_metrics.commit_bytes(Direction::Received, received);
+    socket_metrics.commit_bytes(Direction::Sent, sent);
+    log_tunnel_closed(tunnel_id, app, &socket_metrics);
+
+    Ok(())
+}
+
+/// Log the end of a tunnel with its duration and the bytes which went through it.
+fn log_tunnel_closed(tunnel_id: u64, app: &str, socket_metrics: &SocketMetricsGuard) {
+    info!(
+        "event='Websocket tunnel {tunnel_id} of {app} closed after {:.3}s, {} bytes received, {} bytes sent'",
+        socket_metrics.elapsed().as_secs_f64(),
+        socket_metrics.get_bytes(Direction::Received),
+        socket_metrics.get_bytes(Direction::Sent),
+    );
+}
+
+/// Get the headers of the upgrade request sent to the backend at `uri`: those of the client
+/// without its hop-by-hop ones (`Connection` and the headers it lists included), with the `Host`
+/// of the backend. The identity headers were already injected and `Authorization` removed.
+fn get_upgrade_headers(client_headers: &HeaderMap, uri: &Uri) -> Result<HeaderMap> {
+    let connection_headers = get_connection_headers(client_headers);
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in client_headers {
+        if !HOP_BY_HOP_HEADERS.contains(name) && !connection_headers.contains(name) {
+            headers.append(name, value.clone());
+        }
+    }
+
+    let host = uri
+        .authority()
+        .ok_or_else(|| anyhow!("No host in {uri}"))?
+        .as_str();
+    headers.insert(HOST, HeaderValue::from_str(host)?);
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+
+    Ok(headers)
+}
+
+/// Open the connection to the backend, forwarding the headers of the client request, and return
+/// it along with the subprotocol selected by the backend.
+async fn create_ws_server(
+    forwarded_request: &Request<impl Body>,
+    ws_uri_string: &str,
+) -> Result<(ServerWebSocket, Option<HeaderValue>)> {
+    let uri: Uri = ws_uri_string.parse()?;
+    let mut request = Request::builder()
+        .method(forwarded_request.method())
+        .version(forwarded_request.version())
+        .extension(forwarded_request.extensions().clone())
+        .uri(uri.clone())
+        .body(())
+        .map_err(|err| anyhow!("Failed to build forwarded request: {err:?}"))?;
+
+    *request.headers_mut() = get_upgrade_headers(forwarded_request.headers(), &uri)?;
+    // Extensions are not supported when messages are relayed one by one.
+    request.headers_mut().remove(SEC_WEBSOCKET_EXTENSIONS);
+
+    let (ws_server, response) = connect_async_tls_with_config(
+        request,
+        Some(runtime_config().get_websocket_config()),
+        false,
+        TLS_CONFIG.get().cloned().map(Connector::Rustls),
+    )
+    .await?;
+
+    match response.status() {
+        StatusCode::SWITCHING_PROTOCOLS => Ok((
+            ws_server,
+            response.headers().get(SEC_WEBSOCKET_PROTOCOL).cloned(),
+        )),
+        status => bail!(
+            "Unexpected status during socket initialization: {}",
+            status.canonical_reason().unwrap_or_else(|| status.as_str()),
+        ),
+    }
+}
+
+/// Identifier of the next tunnel, unique within the process.
+static NEXT_TUNNEL_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Payload of the pings sent by the gateway, whose pongs are not relayed.
+const KEEPALIVE_PAYLOAD: &[u8] = b"gateway-keepalive";
+
+/// Number of open tunnels per app, and per app and `token_id`.
+#[derive(Default)]
+struct OpenTunnels {
+    per_app: HashMap<String, usize>,
+    per_user: HashMap<(String, String), usize>,
+}
+
+static OPEN_TUNNELS: LazyLock<Mutex<OpenTunnels>> =
+    LazyLock::new(|| Mutex::new(OpenTunnels::default()));
+
+/// Set once the gateway is shutting down, for the tunnels to close.
+static SHUTDOWN: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+/// Set while the instance is drained by `POST /admin/drain`, for the tunnels to close. Unlike
+/// `SHUTDOWN`, it is reset by `DELETE /admin/drain`.
+static DRAIN: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+/// Number of tunnels in frame mode, which are closed gracefully on shutdown.
+static FRAME_TUNNELS: LazyLock<watch::Sender<usize>> = LazyLock::new(|| watch::channel(0).0);
+
+/// Count a tunnel in `FRAME_TUNNELS` until dropped.
+struct FrameTunnelGuard;
+
+impl FrameTunnelGuard {
+    fn new() -> Self {
+        FRAME_TUNNELS.send_modify(|count| *count += 1);
+        Self
+    }
+}
+
+impl Drop for FrameTunnelGuard {
+    fn drop(&mut self) {
+        FRAME_TUNNELS.send_modify(|count| *count -= 1);
+    }
+}
+
+/// Resolve once the gateway is shutting down or the instance is drained, with the reason of the
+/// close frames.
+async fn wait_shutdown() -> &'static str {
+    let mut shutdown = SHUTDOWN.subscribe();
+    let mut drain = DRAIN.subscribe();
+    // The senders are never dropped.
+    select! {
+        _ = shutdown.wait_for(|shutdown| *shutdown) => "gateway shutting down",
+        _ = drain.wait_for(|drain| *drain) => "gateway draining",
+    }
+}
+
+/// Close the tunnels in frame mode with `1001`, as well as those opened from now on, until
+/// called again with `false`. Unlike `drain_tunnels`, the process keeps running.
+pub fn set_tunnels_draining(draining: bool) {
+    DRAIN.send_replace(draining);
+}
+
+/// Close the tunnels in frame mode with `1001`, waiting up to `grace_period` for them to end.
+/// Passthrough tunnels are cut when the process exits.
+pub async fn drain_tunnels(grace_period: Duration) {
+    SHUTDOWN.send_replace(true);
+
+    let mut tunnels = FRAME_TUNNELS.subscribe();
+    info!(
+        "event='Draining {} websocket tunnels'",
+        *tunnels.borrow_and_update()
+    );
+    if timeout(grace_period, tunnels.wait_for(|count| *count == 0))
+        .await
+        .is_err()
+    {
+        warn!(
+            "event='{} websocket tunnels still open after the shutdown grace period'",
+            *tunnels.borrow()
+        );
+    }
+}
+
+/// A slot within the tunnel limits of an app, released when dropped.
+struct TunnelPermit {
+    app: String,
+    token_id: String,
+}
+
+impl TunnelPermit {
+    /// Take a slot unless `max_tunnels` or `max_tunnels_per_user` is reached.
+    fn acquire(app: &str, token_id: &str, websocket: &WebsocketSpec) -> Option<Self> {
+        let mut open_tunnels = OPEN_TUNNELS.lock().unwrap();
+        let user = (app.to_string(), token_id.to_string());
+
+        let app_count = open_tunnels.per_app.get(app).copied().unwrap_or(0);
+        let user_count = open_tunnels.per_user.get(&user).copied().unwrap_or(0);
+        if websocket.max_tunnels.is_some_and(|max| app_count >= max)
+            || websocket
+                .max_tunnels_per_user
+                .is_some_and(|max| user_count >= max)
+        {
+            return None;
+        }
+
+        *open_tunnels.per_app.entry(app.to_string()).or_default() += 1;
+        *open_tunnels.per_user.entry(user).or_default() += 1;
+
+        Some(Self {
+            app: app.to_string(),
+            token_id: token_id.to_string(),
+        })
+    }
+}
+
+impl Drop for TunnelPermit {
+    fn drop(&mut self) {
+        let mut open_tunnels = OPEN_TUNNELS.lock().unwrap();
+
+        if let Some(count) = open_tunnels.per_app.get_mut(&self.app) {
+            *count -= 1;
+            if *count == 0 {
+                open_tunnels.per_app.remove(&self.app);
+            }
+        }
+
+        let user = (self.app.clone(), self.token_id.clone());
+        if let Some(count) = open_tunnels.per_user.get_mut(&user) {
+            *count -= 1;
+            if *count == 0 {
+                open_tunnels.per_user.remove(&user);
+            }
+        }
+    }
+}
+
+/// Prefix of the text messages sent by clients to renew their token, followed by the value of an
+/// `Authorization` header.
+const TOKEN_RENEWAL_PREFIX: &str = "gateway-renew ";
+
+/// Identity of the client of a tunnel, to close it once its token expires or its permission is
+/// revoked.
+pub struct TokenSession {
+    pub token_id: String,
+    /// Expiration of the token, in seconds since the Unix epoch.
+    pub exp: u64,
+    /// Permission required by the endpoint, if checked.
+    pub permission: Option<PermissionId>,
+    pub perm_lock: PermLock,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// State shared by both directions of a tunnel, times being in milliseconds since `start_time`.
+struct TunnelState {
+    id: u64,
+    start_time: Instant,
+    last_activity: AtomicU64,
+    last_client_pong: AtomicU64,
+    last_server_pong: AtomicU64,
+    /// Whether a close frame was forwarded in either direction.
+    close_forwarded: AtomicBool,
+    /// Code and reason of the close frames sent when a relay fails on purpose.
+    close_reason: Mutex<Option<(CloseCode, &'static str)>>,
+    session: TokenSession,
+    /// Expiration of the latest token of the client, in seconds since the Unix epoch.
+    token_exp: AtomicU64,
+}
+
+impl TunnelState {
+    fn new(id: u64, session: TokenSession) -> Self {
+        Self {
+            id,
+            token_exp: AtomicU64::new(session.exp),
+            session,
+            start_time: Instant::now(),
+            last_activity: AtomicU64::new(0),
+            last_client_pong: AtomicU64::new(0),
+            last_server_pong: AtomicU64::new(0),
+            close_forwarded: AtomicBool::new(false),
+            close_reason: Mutex::new(None),
+        }
+    }
+
+    /// Replace the token of the client if it is valid and identifies the same client.
+    async fn renew_token(&self, authorization: &str) {
+        match get_claims(authorization).await {
+            Some((claims, _)) if claims.token_id == self.session.token_id => {
+                self.token_exp.store(claims.exp as u64, Ordering::Relaxed);
+                info!("event='Websocket token of tunnel {} renewed'", self.id);
+            }
+            Some(_) => warn!(
+                "event='Websocket token renewal with another token_id refused in tunnel {}'",
+                self.id
+            ),
+            None => warn!(
+                "event='Websocket token renewal with an invalid token refused in tunnel {}'",
+                self.id
+            ),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start_time.elapsed().as_millis() as u64
+    }
+
+    /// Get the last keepalive pongs of the source and of the destination of messages going in
+    /// `direction`.
+    fn get_pongs(&self, direction: Direction) -> (&AtomicU64, &AtomicU64) {
+        match direction {
+            Direction::Received => (&self.last_client_pong, &self.last_server_pong),
+            Direction::Sent => (&self.last_server_pong, &self.last_client_pong),
+        }
+    }
+}
+
+/// Relay the messages of `rx` to `tx` until either side closes its socket. If `ping_interval` is
+/// set, `tx` is pinged and the relay fails if it misses its pongs for `pong_timeout`.
+///
+/// Up to the `buffer` limit of `direction`, messages are written to `tx` without waiting for it
+/// to be flushed, which is done while waiting for the next message. Past it, `rx` is not read
+/// until `tx` is flushed, applying backpressure to the faster side.
+///
+/// A close frame is forwarded as is, and the relay stops. The close frame then answered by the
+/// other side is not forwarded back, as the websocket library already acknowledged the first one.
+async fn relay<Tx, Rx>(
+    tx: &mut Tx,
+    mut rx: Rx,
+    direction: Direction,
+    websocket: &WebsocketSpec,
+    socket_metrics: &SocketMetricsGuard<'_>,
+    tunnel: &TunnelState,
+) -> Result<()>
+where
+    Tx: Sink<Message, Error = tungstenite::Error> + Unpin,
+    Rx: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let (rx_pong, tx_pong) = tunnel.get_pongs(direction);
+    let ping_interval = websocket.ping_interval.map(Duration::from_secs);
+    let pong_timeout = websocket
+        .pong_timeout
+        .map(Duration::from_secs)
+        .or(ping_interval)
+        .unwrap_or_default();
+    let mut ping_ticker = ping_interval.map(|ping_interval| interval(ping_interval));
+    let buffer_limit = websocket.buffer.limit(direction);
+    // Bytes written to `tx` since its last flush, if any message was.
+    let mut buffered = None;
+
+    loop {
+        let message = select! {
+            message = rx.next() => message,
+            res = tx.flush(), if buffered.is_some() => {
+                if let Err(e) = res {
+                    warn!(
+                        "event='Fail to send message to {} in tunnel {}: {:?}'",
+                        direction.destination(),
+                        tunnel.id,
+                        e
+                    );
+                    return Err(e.into());
+                }
+                buffered = None;
+                continue;
+            }
+            _ = async { ping_ticker.as_mut().unwrap().tick().await }, if ping_ticker.is_some() => {
+                let since_pong = Duration::from_millis(
+                    tunnel.now().saturating_sub(tx_pong.load(Ordering::Relaxed)),
+                );
+                if since_pong > ping_interval.unwrap_or_default() + pong_timeout {
+                    bail!("No pong received from the {} side", direction.destination());
+                }
+                tx.send(Message::Ping(Bytes::from_static(KEEPALIVE_PAYLOAD))).await?;
+                continue;
+            }
+        };
+
+        let message = match message {
+            None => return Ok(()),
+            Some(Err(e)) => {
+                warn!(
+                    "event='Error in {} message of tunnel {}: {:?}'",
+                    direction.source(),
+                    tunnel.id,
+                    e
+                );
+                return Err(e.into());
+            }
+            Some(Ok(message)) => message,
+        };
+
+        if matches!(&message, Message::Pong(payload) if payload == KEEPALIVE_PAYLOAD) {
+            rx_pong.store(tunnel.now(), Ordering::Relaxed);
+            continue;
+        }
+
+        if let (Message::Text(text), Direction::Received) = (&message, direction) {
+            if let Some(authorization) = text.strip_prefix(TOKEN_RENEWAL_PREFIX) {
+                // Never relayed, not to leak the token to the server.
+                let allow_renewal = websocket
+                    .token_enforcement
+                    .as_ref()
+                    .is_some_and(|token_enforcement| token_enforcement.allow_renewal);
+                if allow_renewal {
+                    tunnel.renew_token(authorization).await;
+                } else {
+                    warn!(
+                        "event='Websocket token renewal dropped in tunnel {}: renewal is not allowed'",
+                        tunnel.id
+                    );
+                }
+                continue;
+            }
+        }
+
+        if let Message::Close(close_frame) = &message {
+            if tunnel.close_forwarded.swap(true, Ordering::Relaxed) {
+                // Acknowledgement of the close frame forwarded by the other direction.
+                return Ok(());
+            }
+            debug!(
+                "event='Forwarding close frame from {}: {:?}'",
+                direction.source(),
+                close_frame
+            );
+        }
+
+        tunnel.last_activity.store(tunnel.now(), Ordering::Relaxed);
+        socket_metrics.commit_message(direction, &message);
+
+        let message = if message.is_text() || message.is_binary() {
+            match apply_filters(&websocket.filters, direction, message) {
+                FilterAction::Forward(message) => message,
+                FilterAction::Drop => continue,
+                FilterAction::Close(code, reason) => {
+                    *tunnel.close_reason.lock().unwrap() = Some((code, reason));
+                    bail!(
+                        "Message from the {} side rejected: {reason}",
+                        direction.source()
+                    );
+                }
+            }
+        } else {
+            message
+        };
+
+        // The message is moved to the other side as is: its payload is a `Bytes` slice of the read
+        // buffer of tungstenite, which reuses the buffer once the slices of sent messages are
+        // dropped, so that relaying a single-frame message does not allocate.
+        let is_close = message.is_close();
+        let total = buffered.unwrap_or(0) + message.len();
+        let res = if is_close || total >= buffer_limit {
+            buffered = None;
+            tx.send(message).await
+        } else {
+            buffered = Some(total);
+            tx.feed(message).await
+        };
+        if let Err(e) = res {
+            warn!(
+                "event='Fail to send message to {} in tunnel {}: {:?}'",
+                direction.destination(),
+                tunnel.id,
+                e
+            );
+            return Err(e.into());
+        }
+        if is_close {
+            return Ok(());
+        }
+    }
+}
+
+/// Send a close frame then flush the sink, ignoring errors as the side may already be closed.
+async fn send_close<S>(sink: &mut S, code: CloseCode, reason: &'static str)
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let close_frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    if let Err(e) = sink.send(Message::Close(Some(close_frame))).await {
+        debug!("event='Fail to send close frame: {:?}'", e);
+    }
+}
+
+/// Read `rx` until its close frame, or until it ends.
+async fn wait_close<Rx>(rx: &mut Rx)
+where
+    Rx: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    while let Some(Ok(message)) = rx.next().await {
+        if message.is_close() {
+            return;
+        }
+    }
+}
+
+/// Resolve with the code and reason to close the tunnel once its idle timeout, maximum duration
+/// or token expiration is reached, or once its permission is revoked. Never resolve if the API
+/// has none of these limits.
+async fn watch_limits(
+    websocket: &WebsocketSpec,
+    tunnel: &TunnelState,
+) -> (CloseCode, &'static str) {
+    let max_duration = websocket.max_duration.map(Duration::from_secs);
+    let idle_timeout = websocket.idle_timeout.map(Duration::from_secs);
+    let token_enforcement = websocket.token_enforcement.as_ref();
+    if max_duration.is_none() && idle_timeout.is_none() && token_enforcement.is_none() {
+        return future::pending().await;
+    }
+
+    loop {
+        let elapsed = tunnel.start_time.elapsed();
+        let mut next_check = Duration::MAX;
+
+        if let Some(max_duration) = max_duration {
+            if elapsed >= max_duration {
+                return (CloseCode::Away, "maximum session duration reached");
+            }
+            next_check = next_check.min(max_duration - elapsed);
+        }
+
+        if let Some(idle_timeout) = idle_timeout {
+            let idle = elapsed.saturating_sub(Duration::from_millis(
+                tunnel.last_activity.load(Ordering::Relaxed),
+            ));
+            if idle >= idle_timeout {
+                return (CloseCode::Away, "idle timeout reached");
+            }
+            next_check = next_check.min(idle_timeout - idle);
+        }
+
+        if let Some(token_enforcement) = token_enforcement {
+            let deadline = tunnel
+                .token_exp
+                .load(Ordering::Relaxed)
+                .saturating_add(token_enforcement.grace_period);
+            let now = unix_now();
+            if now >= deadline {
+                return (CloseCode::Policy, "token expired");
+            }
+            next_check = next_check.min(Duration::from_secs(deadline - now));
+
+            // Permissions only change when they are fetched again.
+            let session = &tunnel.session;
+            if let Some(permission) = &session.permission {
+                if !has_perm(&session.perm_lock, *permission, &session.token_id) {
+                    return (CloseCode::Policy, "permission revoked");
+                }
+                next_check = next_check.min(runtime_config().perm_update_delay);
+            }
+        }
+
+        sleep(next_check).await;
+    }
+}
+
+async fn serve_websocket(
+    app: &str,
+    websocket: &WebsocketSpec,
+    tunnel: TunnelState,
+    ws_client: HyperWebsocket,
+    ws_server: ServerWebSocket,
+) -> Result<()> {
+    let _frame_tunnel = FrameTunnelGuard::new();
+    let ws_client = ws_client.await?;
+    let (mut tx_client, mut rx_client) = ws_client.split();
+    let (mut tx_server, mut rx_server) = ws_server.split();
+    let socket_metrics = &SocketMetricsGuard::new(app);
+
+    let close = {
+        let client_to_server = relay(
+            &mut tx_server,
+            &mut rx_client,
+            Direction::Received,
+            websocket,
+            socket_metrics,
+            &tunnel,
+        );
+        let server_to_client = relay(
+            &mut tx_client,
+            &mut rx_server,
+            Direction::Sent,
+            websocket,
+            socket_metrics,
+            &tunnel,
+        );
+
+        select! {
+            res = future::try_join(client_to_server, server_to_client) => match res {
+                Ok(_) => None,
+                Err(e) => {
+                    warn!("event='Websocket error in tunnel {}: {:?}'", tunnel.id, e);
+                    let close_reason = tunnel.close_reason.lock().unwrap().take();
+                    Some(close_reason.unwrap_or((CloseCode::Error, "websocket tunnel error")))
+                }
+            },
+            (code, reason) = watch_limits(websocket, &tunnel) => {
+                info!("event='Closing websocket tunnel {} of {app}: {reason}'", tunnel.id);
+                Some((code, reason))
+            }
+            reason = wait_shutdown() => Some((CloseCode::Away, reason)),
+        }
+    };
+
+    // Both sides are told why the tunnel is closed, the one which failed ignoring it.
+    if let Some((code, reason)) = close {
+        send_close(&mut tx_client, code, reason).await;
+        send_close(&mut tx_server, code, reason).await;
+    }
+
+    // On shutdown or drain, both sides are given a chance to acknowledge the close frames before
+    // the sockets are dropped.
+    if *SHUTDOWN.borrow() || *DRAIN.borrow() {
+        let grace_period = runtime_config().shutdown_grace_period;
+        let _ = timeout(
+            grace_period,
+            future::join(wait_close(&mut rx_client), wait_close(&mut rx_server)),
+        )
+        .await;
+    }
+
+    log_tunnel_closed(tunnel.id, app, socket_metrics);
+
+    Ok(())
+}
@@ -0,0 +1,232 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::{Args, Parser, Subcommand};
+use serde_yaml::{Mapping, Value};
+
+use gateway_core::api::ApiMode;
+use gateway_core::bench::{run_bench, BenchOptions};
+use gateway_core::fetch_crd::list_apis;
+use gateway_core::fetch_dir::read_api_dir;
+use gateway_core::openapi::import_endpoints;
+use gateway_core::permission_audit::verify_audit_file;
+use gateway_core::runtime_config::{config_schema, runtime_config, set_config_path, set_profile};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    arg_required_else_help = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Runtime config file to serve with, same as `serve <CONFIG>`.
+    config: Option<PathBuf>,
+    /// Profile of the runtime config file to serve with.
+    #[arg(long, requires = "config")]
+    profile: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ConfigArgs {
+    /// Runtime config file, as YAML, TOML or JSON.
+    config: PathBuf,
+    /// Profile of the runtime config file to apply, overriding `GATEWAY_PROFILE`.
+    #[arg(long)]
+    profile: Option<String>,
+}
+
+impl ConfigArgs {
+    /// Set where the runtime config is read from, before it is first read.
+    pub fn init(self) {
+        set_config_path(self.config);
+        set_profile(self.profile);
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the gateway.
+    Serve(ConfigArgs),
+    /// Check the runtime config file, exiting with a non-zero status if it is not valid.
+    Validate(ConfigArgs),
+    /// Print the CustomResourceDefinition of `ApiDefinition`s.
+    PrintCrd,
+    /// Print the JSON Schema of the runtime config file.
+    PrintConfigSchema,
+    /// Print the routes of the `ApiDefinition`s currently served with the runtime config file.
+    Routes(ConfigArgs),
+    /// Print the `forward_strict` mode of an `ApiDefinition` with an endpoint for each operation
+    /// of an OpenAPI document.
+    ImportOpenapi {
+        /// OpenAPI 3 document, as YAML or JSON.
+        document: PathBuf,
+    },
+    /// Check the hash chain of a `permission_audit` file, exiting with a non-zero status if it was
+    /// tampered with.
+    VerifyPermissionAudit {
+        /// File of the `permission_audit` setting.
+        file: PathBuf,
+    },
+    /// Send synthetic HTTP and websocket traffic through the gateway to a mock upstream, printing
+    /// the throughput and latency percentiles.
+    Bench(BenchArgs),
+}
+
+#[derive(Args)]
+pub struct BenchArgs {
+    #[command(flatten)]
+    config: ConfigArgs,
+    /// Number of HTTP requests.
+    #[arg(long, default_value_t = 10_000)]
+    requests: usize,
+    /// Number of HTTP requests in flight at once.
+    #[arg(long, default_value_t = 32)]
+    concurrency: usize,
+    /// Number of HTTP requests streaming a body of `--body-size` bytes, echoed by the upstream.
+    #[arg(long, default_value_t = 100)]
+    streamed_requests: usize,
+    /// Size of the streamed bodies, in bytes.
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    body_size: usize,
+    /// Number of websocket tunnels opened at once.
+    #[arg(long, default_value_t = 32)]
+    websocket_connections: usize,
+    /// Number of messages echoed through each websocket tunnel.
+    #[arg(long, default_value_t = 100)]
+    websocket_messages: usize,
+}
+
+impl Cli {
+    pub fn into_command(self) -> Command {
+        match (self.command, self.config) {
+            (Some(command), _) => command,
+            (None, Some(config)) => Command::Serve(ConfigArgs {
+                config,
+                profile: self.profile,
+            }),
+            (None, None) => unreachable!("Either a subcommand or a config is required"),
+        }
+    }
+}
+
+/// The CRD installed by the chart. It is not derived from `ApiDefinition`, whose tagged enums do
+/// not translate to a structural schema.
+const CRD: &str = include_str!("../chart/crds/apidefinitions.yaml");
+
+pub fn print_crd() -> Result<()> {
+    print!("{CRD}");
+    Ok(())
+}
+
+pub fn print_config_schema() -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(&config_schema())?);
+    Ok(())
+}
+
+pub fn print_openapi_endpoints(document: &Path) -> Result<()> {
+    let endpoints = import_endpoints(&document.to_string_lossy())?;
+
+    let mut mode = Mapping::new();
+    mode.insert("kind".into(), "forward_strict".into());
+    mode.insert("endpoints".into(), serde_yaml::to_value(endpoints)?);
+    let mut spec = Mapping::new();
+    spec.insert("mode".into(), Value::Mapping(mode));
+    print!("{}", serde_yaml::to_string(&spec)?);
+    Ok(())
+}
+
+pub fn verify_permission_audit(file: &Path) -> Result<()> {
+    let count = verify_audit_file(file)?;
+    println!("{count} permission snapshots verified");
+    Ok(())
+}
+
+pub async fn bench(args: BenchArgs) -> Result<()> {
+    args.config.init();
+    let options = BenchOptions {
+        requests: args.requests,
+        concurrency: args.concurrency,
+        streamed_requests: args.streamed_requests,
+        body_size: args.body_size,
+        websocket_connections: args.websocket_connections,
+        websocket_messages: args.websocket_messages,
+    };
+
+    for result in run_bench(&options).await? {
+        println!(
+            "{}: {} in {:.2?} ({:.0}/s), {} errors",
+            result.name,
+            result.count,
+            result.elapsed,
+            result.rate(),
+            result.errors
+        );
+        if result.bytes > 0 {
+            println!(
+                "  throughput {:.1} MiB/s",
+                result.throughput() / (1024.0 * 1024.0)
+            );
+        }
+        println!(
+            "  latency p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+            result.percentile(50.0),
+            result.percentile(90.0),
+            result.percentile(99.0),
+            result.percentile(100.0)
+        );
+    }
+    Ok(())
+}
+
+pub async fn print_routes() -> Result<()> {
+    let runtime_config = runtime_config();
+    let mut apis = match &runtime_config.api_dir {
+        Some(api_dir) => read_api_dir(&api_dir.path)?,
+        None => {
+            list_apis(
+                &runtime_config.crd_label,
+                runtime_config.crds_namespaces.as_deref(),
+            )
+            .await?
+        }
+    };
+    apis.sort_by(|a, b| a.spec.app_name.cmp(&b.spec.app_name));
+
+    for mut api in apis {
+        api.build_uri();
+        let app = &api.spec.app_name;
+        if !api.spec.enabled {
+            println!("{app} -> {} (disabled)", api.spec.uri_http);
+            continue;
+        }
+        match &api.spec.mode {
+            ApiMode::ForwardAll => println!("{app} -> {} (forward all)", api.spec.uri_http),
+            ApiMode::ForwardStrict(endpoints) => {
+                println!("{app} -> {}", api.spec.uri_http);
+                for endpoint in endpoints {
+                    let mut endpoint = endpoint.clone();
+                    endpoint.build_permission(&app[1..]);
+                    let permission = if endpoint.check_permission {
+                        endpoint.permission.as_str()
+                    } else {
+                        "no permission check"
+                    };
+                    let websocket = if endpoint.is_websocket {
+                        " websocket"
+                    } else {
+                        ""
+                    };
+                    println!(
+                        "  {} {}{websocket} ({permission})",
+                        endpoint.method, endpoint.path
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}